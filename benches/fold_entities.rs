@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use my_todo::{
+    repositories::todo::{fold_entities, test_utils::fold_entities_nested},
+    test_support,
+};
+
+// todoの数とtodoごとのlabelの数を変え、labelと結合した行をまとめる時間を測る
+fn bench_fold_entities(c: &mut Criterion) {
+    let labels = test_support::labels(8);
+    let mut group = c.benchmark_group("fold_entities");
+    for todos in [1_000, 10_000] {
+        for fan_out in [0, 1, 4, 8] {
+            let rows = test_support::todo_rows(&test_support::todos(todos, &labels, fan_out));
+            group.throughput(Throughput::Elements(rows.len() as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}_todos", todos), format!("{}_labels", fan_out)),
+                &rows,
+                |b, rows| b.iter_batched(|| rows.clone(), fold_entities, BatchSize::LargeInput),
+            );
+        }
+    }
+    group.finish();
+}
+
+// 10,000件のtodoで、以前のtodoごとに結果を走査するfoldと比べる
+fn bench_fold_entities_vs_nested(c: &mut Criterion) {
+    let labels = test_support::labels(8);
+    let mut group = c.benchmark_group("fold_entities_vs_nested");
+    // 以前の実装は1回に数十〜数百msかかるため、サンプル数を減らす
+    group.sample_size(10);
+    for fan_out in [0, 4] {
+        let rows = test_support::todo_rows(&test_support::todos(10_000, &labels, fan_out));
+        group.throughput(Throughput::Elements(rows.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("index_map", format!("{}_labels", fan_out)),
+            &rows,
+            |b, rows| b.iter_batched(|| rows.clone(), fold_entities, BatchSize::LargeInput),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("nested", format!("{}_labels", fan_out)),
+            &rows,
+            |b, rows| b.iter_batched(|| rows.clone(), fold_entities_nested, BatchSize::LargeInput),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_fold_entities, bench_fold_entities_vs_nested);
+criterion_main!(benches);
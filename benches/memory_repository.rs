@@ -0,0 +1,84 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use my_todo::{
+    repositories::{
+        label::Label,
+        todo::{
+            memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID, TodoQuery, TodoRepository,
+            UpdateTodo,
+        },
+    },
+    test_support,
+};
+use tokio::runtime::Runtime;
+
+/// 測る前に作成しておくtodoの数
+const TODOS: usize = 1_000;
+/// 1回の計測で並行に動かすタスクの数
+const TASKS: usize = 64;
+
+async fn populated(labels: Vec<Label>) -> TodoRepositoryForMemory {
+    let label_ids: Vec<i32> = labels.iter().map(|label| label.id).collect();
+    let repository = TodoRepositoryForMemory::for_test(labels);
+    test_support::seed_todos(
+        &repository,
+        TEST_USER_ID,
+        test_support::create_todos(TODOS, &label_ids, 2),
+    )
+    .await;
+    repository
+}
+
+// TASKS個のタスクのうちread_percent%が読み込み(1件の取得と一覧を交互)、残りが更新をする
+async fn mixed_workload(repository: &TodoRepositoryForMemory, read_percent: usize) {
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|i| {
+            let repository = repository.clone();
+            tokio::spawn(async move {
+                // 同じshardに偏らないよう、作成済みのidに散らす
+                let id = (i * 7919 % TODOS) as i32 + 1;
+                if i * 100 / TASKS < read_percent {
+                    if i % 2 == 0 {
+                        repository.find(TEST_USER_ID, id).await.map(|_| ())
+                    } else {
+                        let query = TodoQuery {
+                            limit: Some(50),
+                            ..TodoQuery::default()
+                        };
+                        repository.all(TEST_USER_ID, query).await.map(|_| ())
+                    }
+                } else {
+                    // 完了にする更新は全てのshardをロックし、未完了に戻す更新は1つだけをロックする
+                    let payload = UpdateTodo::completion(i % 2 == 0);
+                    repository
+                        .update(TEST_USER_ID, id, payload)
+                        .await
+                        .map(|_| ())
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap().expect("failed operation");
+    }
+}
+
+fn bench_mixed_workload(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let repository = runtime.block_on(populated(test_support::labels(8)));
+    let mut group = c.benchmark_group("memory_repository");
+    group.throughput(Throughput::Elements(TASKS as u64));
+    for read_percent in [100, 90, 50, 0] {
+        group.bench_with_input(
+            BenchmarkId::new("mixed_workload", format!("{}%_reads", read_percent)),
+            &read_percent,
+            |b, &read_percent| {
+                b.to_async(&runtime)
+                    .iter(|| mixed_workload(&repository, read_percent))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_mixed_workload);
+criterion_main!(benches);
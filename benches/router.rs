@@ -0,0 +1,97 @@
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use my_todo::{
+    auth::{AuthConfig, USER_ID_HEADER},
+    create_app,
+    handlers::ListFormat,
+    repositories::{
+        activity::memory::ActivityRepositoryForMemory,
+        label::memory::LabelRepositoryForMemory,
+        todo::{memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID},
+        webhook::memory::WebhookRepositoryForMemory,
+    },
+    test_support, DEFAULT_REQUEST_TIMEOUT,
+};
+use tokio::runtime::Runtime;
+use tower::ServiceExt;
+
+/// 測る前に作成しておくtodoの数
+const TODOS: usize = 1_000;
+
+// メモリ上のrepositoryにTODOS件のtodoを作成したapp
+async fn app() -> Router {
+    let labels = test_support::labels(8);
+    let label_ids: Vec<i32> = labels.iter().map(|label| label.id).collect();
+    let repository = TodoRepositoryForMemory::for_test(labels);
+    test_support::seed_todos(
+        &repository,
+        TEST_USER_ID,
+        test_support::create_todos(TODOS, &label_ids, 2),
+    )
+    .await;
+    create_app(
+        repository,
+        LabelRepositoryForMemory::new(),
+        ActivityRepositoryForMemory::new(),
+        WebhookRepositoryForMemory::new(),
+        AuthConfig::default(),
+        DEFAULT_REQUEST_TIMEOUT,
+        ListFormat::Bare,
+    )
+}
+
+fn request(method: Method, uri: &str, json: Option<&'static str>) -> Request<Body> {
+    let builder = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(USER_ID_HEADER, TEST_USER_ID);
+    match json {
+        Some(json) => builder
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(json)),
+        None => builder.body(Body::empty()),
+    }
+    .unwrap()
+}
+
+// middlewareを含めたrouterで、レスポンスの本文を読み終えるまで
+async fn send(app: &Router, req: Request<Body>, expected: StatusCode) {
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), expected);
+    axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+}
+
+fn bench_router(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("router");
+    group.throughput(Throughput::Elements(1));
+    let cases = [
+        ("find_todo", Method::GET, "/todos/1", None, StatusCode::OK),
+        ("all_todo", Method::GET, "/todos", None, StatusCode::OK),
+        (
+            "create_todo",
+            Method::POST,
+            "/todos",
+            Some(r#"{ "text": "bench", "labels": [1, 2] }"#),
+            StatusCode::CREATED,
+        ),
+    ];
+    for (name, method, uri, json, expected) in cases {
+        // 作成で件数が増えても他のケースに影響しないよう、ケースごとにappを作る
+        let app = runtime.block_on(app());
+        group.bench_function(name, |b| {
+            b.to_async(&runtime)
+                .iter(|| send(&app, request(method.clone(), uri, json), expected))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_router);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+use std::{env, process::Command};
+
+// GET /version で返すgitのコミットとビルド日時をコンパイル時の環境変数に埋め込む
+fn main() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let build_time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+
+    // gRPCのserver/clientをproto/todo.protoから生成する. protocはvendorしたものを使う
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("protoc is not available");
+    env::set_var("PROTOC", protoc);
+    tonic_build::compile_protos("proto/todo.proto").expect("failed to compile proto/todo.proto");
+
+    println!("cargo:rustc-env=GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=BUILD_TIME={}", build_time);
+    // ソースかコミットが変わった時だけ再実行する
+    println!("cargo:rerun-if-changed=src");
+    // sqlx::migrate!で埋め込むmigrationsの追加も反映する
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,3 @@
+ALTER TABLE todos
+    ADD COLUMN due_date   DATE,
+    ADD COLUMN recurrence TEXT NOT NULL DEFAULT 'none';
@@ -0,0 +1,3 @@
+ALTER TABLE todos
+    ADD COLUMN remind_at   TIMESTAMPTZ,
+    ADD COLUMN reminded_at TIMESTAMPTZ;
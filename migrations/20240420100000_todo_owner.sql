@@ -0,0 +1,4 @@
+-- 既存のtodoは所有者不明としてuser_id=0に割り当てる
+ALTER TABLE todos ADD COLUMN user_id INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE todos ALTER COLUMN user_id DROP DEFAULT;
+CREATE INDEX todos_user_id_idx ON todos (user_id);
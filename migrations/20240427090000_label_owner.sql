@@ -0,0 +1,3 @@
+-- owner_idがNULLのlabelは全ユーザーで共有する. 既存のlabelは共有扱い
+ALTER TABLE labels ADD COLUMN owner_id INTEGER;
+CREATE INDEX labels_owner_id_idx ON labels (owner_id);
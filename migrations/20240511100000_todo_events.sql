@@ -0,0 +1,11 @@
+CREATE TABLE todo_events
+(
+    id         SERIAL PRIMARY KEY,
+    todo_id    INTEGER     NOT NULL,
+    actor_id   INTEGER     NOT NULL,
+    kind       TEXT        NOT NULL,
+    payload    JSONB       NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX todo_events_actor_id_idx ON todo_events (actor_id, id DESC);
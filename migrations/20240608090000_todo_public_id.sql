@@ -0,0 +1,4 @@
+ALTER TABLE todos
+    ADD COLUMN public_id UUID NOT NULL DEFAULT gen_random_uuid();
+
+CREATE UNIQUE INDEX todos_public_id_idx ON todos (public_id);
@@ -0,0 +1,4 @@
+ALTER TABLE todos
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+UPDATE todos SET updated_at = coalesce(completed_at, created_at);
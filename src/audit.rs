@@ -0,0 +1,230 @@
+use axum::{extract::Request, http::HeaderMap, middleware::Next, response::Response};
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::future::Future;
+
+use crate::handlers::REQUEST_ID_HEADER;
+use crate::repositories::{
+    activity::{NewTodoEvent, TodoDiff, TodoEvent, TodoEventKind, TodoEventPayload},
+    todo::TodoEntity,
+};
+
+/// 監査ログのtarget. RUST_LOG=info,audit=offのように、他のログと別に絞り込める
+pub const AUDIT_TARGET: &str = "audit";
+
+tokio::task_local! {
+    // 処理中のリクエストのid. repositoryは呼び出し元を知らないので、監査ログにはここから入れる
+    static REQUEST_ID: String;
+}
+
+/// futureの中で残す監査ログにrequest_idを含める
+pub async fn with_request_id<F: Future>(request_id: Option<String>, future: F) -> F::Output {
+    match request_id {
+        Some(request_id) => REQUEST_ID.scope(request_id, future).await,
+        None => future.await,
+    }
+}
+
+/// x-request-idの値
+pub fn request_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// リクエストの処理中に残す監査ログに、そのリクエストのidを含める.
+/// idを振るSetRequestIdLayerの内側に置く
+pub async fn scope_request_id(req: Request, next: Next) -> Response {
+    with_request_id(request_id(req.headers()), next.run(req)).await
+}
+
+/// 監査ログに残す変更の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    /// 以前の変更の取り消し
+    Undo,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Update => "update",
+            AuditAction::Delete => "delete",
+            AuditAction::Undo => "undo",
+        }
+    }
+}
+
+// 監査ログに残すtodoの要約. 変更を追える項目だけを持つ
+#[derive(Serialize)]
+struct TodoSummary<'a> {
+    text: &'a str,
+    completed: bool,
+    labels: Vec<i32>,
+    due_date: Option<NaiveDate>,
+    parent_id: Option<i32>,
+}
+
+// 変更前・変更後のtodoが無い時の要約
+const ABSENT: &str = "-";
+
+fn summary(todo: &TodoEntity) -> String {
+    let summary = TodoSummary {
+        text: &todo.text,
+        completed: todo.completed,
+        labels: todo.labels.iter().map(|label| label.id).collect(),
+        due_date: todo.due_date,
+        parent_id: todo.parent_id,
+    };
+    serde_json::to_string(&summary).expect("failed to serialize todo summary")
+}
+
+// 変わった項目ごとの変更前(from)か変更後(to)の値
+fn diff_summary(diff: &TodoDiff, side: &str) -> String {
+    let Value::Object(fields) = serde_json::to_value(diff).expect("failed to serialize todo diff")
+    else {
+        unreachable!("todo diff is serialized as an object");
+    };
+    // 次回分や一緒に完了にしたsubtaskのidは変更前後を持たないので含めない
+    let values = fields
+        .into_iter()
+        .filter_map(|(field, change)| Some((field, change.get(side)?.clone())))
+        .collect();
+    Value::Object(values).to_string()
+}
+
+// 変更前後の要約. removedならsnapshotは変更前、そうでなければ変更後のtodo
+fn summaries(payload: &TodoEventPayload, removed: bool) -> (String, String) {
+    match payload {
+        TodoEventPayload::Snapshot(todo) | TodoEventPayload::Deletion { todo, .. } if removed => {
+            (summary(todo), ABSENT.to_string())
+        }
+        TodoEventPayload::Snapshot(todo) | TodoEventPayload::Deletion { todo, .. } => {
+            (ABSENT.to_string(), summary(todo))
+        }
+        TodoEventPayload::Diff(diff) => (diff_summary(diff, "from"), diff_summary(diff, "to")),
+        TodoEventPayload::Undo { change, .. } => summaries(change, removed),
+    }
+}
+
+/// todoへの変更1件分の監査ログ. 変更前後はactivityの記録と同じく、
+/// 作成・削除ではtodo全体、更新では変わった項目だけを要約する
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub user_id: i32,
+    pub action: AuditAction,
+    pub todo_id: i32,
+    pub before: String,
+    pub after: String,
+}
+
+impl AuditEntry {
+    /// 作成・更新・削除の記録から作る. 取り消しはfrom_undoで作る
+    pub fn from_event(event: &NewTodoEvent) -> Self {
+        let action = match event.kind {
+            TodoEventKind::Created => AuditAction::Create,
+            TodoEventKind::Deleted => AuditAction::Delete,
+            TodoEventKind::Undone => AuditAction::Undo,
+            TodoEventKind::Updated | TodoEventKind::Completed | TodoEventKind::Reminded => {
+                AuditAction::Update
+            }
+        };
+        let (before, after) = summaries(&event.payload, event.kind == TodoEventKind::Deleted);
+        Self {
+            user_id: event.actor_id,
+            action,
+            todo_id: event.todo_id,
+            before,
+            after,
+        }
+    }
+
+    /// targetを取り消した記録から作る. 作成の取り消しではtodoが消え、削除の取り消しでは作り直される
+    pub fn from_undo(event: &NewTodoEvent, target: &TodoEvent) -> Self {
+        let (before, after) = summaries(&event.payload, target.kind == TodoEventKind::Created);
+        Self {
+            action: AuditAction::Undo,
+            before,
+            after,
+            ..Self::from_event(event)
+        }
+    }
+
+    /// 変更が確定した後(commitした後)に呼ぶ. 処理中のリクエストがあればそのidも残す
+    pub fn emit(&self) {
+        let request_id = REQUEST_ID
+            .try_with(|request_id| request_id.clone())
+            .unwrap_or_else(|_| ABSENT.to_string());
+        tracing::info!(
+            target: AUDIT_TARGET,
+            todo_id = self.todo_id,
+            user_id = self.user_id,
+            action = self.action.as_str(),
+            before = self.before,
+            after = self.after,
+            timestamp = Utc::now().to_rfc3339(),
+            request_id,
+            "todo {}",
+            self.action.as_str()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::todo::test_utils::TEST_USER_ID;
+
+    fn todo() -> TodoEntity {
+        TodoEntity::new(1, "todo".to_string(), vec![])
+    }
+
+    #[test]
+    fn summarizes_only_changed_fields_of_update() {
+        let completed = TodoEntity {
+            text: "done".to_string(),
+            completed: true,
+            ..todo()
+        };
+        let event = NewTodoEvent::changed(TEST_USER_ID, &todo(), &completed).unwrap();
+        let entry = AuditEntry::from_event(&event);
+        assert_eq!(entry.action, AuditAction::Update);
+        assert_eq!(entry.before, r#"{"completed":false,"text":"todo"}"#);
+        assert_eq!(entry.after, r#"{"completed":true,"text":"done"}"#);
+    }
+
+    #[test]
+    fn undo_of_create_removes_todo() {
+        let created = NewTodoEvent::created(TEST_USER_ID, &todo());
+        let target = TodoEvent {
+            id: 1,
+            todo_id: created.todo_id,
+            actor_id: created.actor_id,
+            kind: created.kind,
+            payload: created.payload.clone(),
+            created_at: Utc::now(),
+        };
+        let undone =
+            NewTodoEvent::undone(TEST_USER_ID, &target, TodoEventPayload::Snapshot(todo()));
+
+        let entry = AuditEntry::from_undo(&undone, &target);
+        assert_eq!(entry.action, AuditAction::Undo);
+        assert_eq!(entry.before, summary(&todo()));
+        assert_eq!(entry.after, "-");
+
+        // 削除の取り消しでは作り直したtodoが変更後になる
+        let target = TodoEvent {
+            kind: TodoEventKind::Deleted,
+            ..target
+        };
+        let entry = AuditEntry::from_undo(&undone, &target);
+        assert_eq!(entry.before, "-");
+        assert_eq!(entry.after, summary(&todo()));
+    }
+}
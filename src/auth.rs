@@ -0,0 +1,195 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::{collections::HashMap, env, fmt, sync::Arc};
+
+use crate::handlers::AllowedMethodsProbe;
+
+/// Bearerトークンによる認証の設定
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthConfig {
+    /// 受け付けるトークン. Noneなら認証しない
+    pub token: Option<String>,
+    /// trueならGETなどの参照系のリクエストにもトークンを要求する
+    pub protect_reads: bool,
+    /// ユーザーごとのトークンとそのユーザーのid.
+    /// 空でなければユーザーはトークンから決め、X-User-Idはトークンのユーザーと一致する時だけ受け付ける
+    pub users: HashMap<String, i32>,
+}
+
+impl AuthConfig {
+    /// `API_TOKEN`、`AUTH_PROTECT_READS`と`API_USER_TOKENS`から設定を読み込む.
+    /// `API_USER_TOKENS`は`<token>=<user_id>`をカンマで区切って並べる
+    pub fn from_env() -> Self {
+        Self {
+            token: env::var("API_TOKEN").ok().filter(|token| !token.is_empty()),
+            protect_reads: env::var("AUTH_PROTECT_READS")
+                .map(|flag| {
+                    flag.parse()
+                        .expect("invalid [AUTH_PROTECT_READS], expected true or false")
+                })
+                .unwrap_or(false),
+            users: env::var("API_USER_TOKENS")
+                .map(|users| {
+                    parse_user_tokens(&users)
+                        .expect("invalid [API_USER_TOKENS], expected <token>=<user_id>,...")
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    fn requires_token(&self, method: &Method) -> bool {
+        let is_read = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+        self.requires_token_for(is_read)
+    }
+
+    /// 参照系(is_read)の操作はprotect_readsの時だけトークンを要求する
+    pub fn requires_token_for(&self, is_read: bool) -> bool {
+        (self.token.is_some() || !self.users.is_empty()) && (self.protect_reads || !is_read)
+    }
+
+    /// 渡されたトークンで認証できるか. トークンが設定されていなければ常に通す
+    pub fn accepts(&self, token: Option<&str>) -> bool {
+        if self.token.is_none() && self.users.is_empty() {
+            return true;
+        }
+        token.is_some_and(|token| {
+            self.token.as_deref() == Some(token) || self.users.contains_key(token)
+        })
+    }
+
+    /// トークンとX-User-Idの値(claimed)からリクエストしたユーザーを決める.
+    /// ユーザーごとのトークンが無い時(開発時など)だけ、X-User-Idをそのまま信じる
+    pub fn user_id(&self, token: Option<&str>, claimed: Option<i32>) -> Result<i32, AuthError> {
+        if self.users.is_empty() {
+            return claimed.ok_or(AuthError::MissingUserId);
+        }
+        let user_id = *token
+            .and_then(|token| self.users.get(token))
+            .ok_or(AuthError::UnknownToken)?;
+        match claimed {
+            Some(claimed) if claimed != user_id => Err(AuthError::UserIdMismatch),
+            _ => Ok(user_id),
+        }
+    }
+}
+
+// `<token>=<user_id>,...`を読む
+fn parse_user_tokens(value: &str) -> Option<HashMap<String, i32>> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (token, user_id) = entry.trim().split_once('=')?;
+            Some((token.to_string(), user_id.parse().ok()?))
+        })
+        .collect()
+}
+
+/// リクエストしたユーザーを決められない理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// ユーザーごとのトークンが無く、X-User-Idも無い
+    MissingUserId,
+    /// ユーザーごとのトークンがあるのに、どのユーザーのトークンでもない
+    UnknownToken,
+    /// X-User-Idがトークンのユーザーと違う
+    UserIdMismatch,
+}
+
+impl AuthError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::MissingUserId | AuthError::UnknownToken => StatusCode::UNAUTHORIZED,
+            AuthError::UserIdMismatch => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingUserId => write!(f, "Missing X-User-Id header"),
+            AuthError::UnknownToken => write!(f, "Missing or unknown user token"),
+            AuthError::UserIdMismatch => write!(f, "X-User-Id does not match the token"),
+        }
+    }
+}
+
+/// `Authorization: Bearer <token>`からトークンを取り出す
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// `Authorization: Bearer <token>`を検証し、一致しなければ401を返すmiddleware.
+/// UserIdがトークンからユーザーを決められるよう、設定をrequestのextensionsに入れる
+pub async fn require_bearer_token(
+    State(config): State<Arc<AuthConfig>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    req.extensions_mut().insert(config.clone());
+    // Allowヘッダーの問い合わせはTRACEに置き換わっているが、元のOPTIONSとして扱う
+    let method = if req.extensions().get::<AllowedMethodsProbe>().is_some() {
+        &Method::OPTIONS
+    } else {
+        req.method()
+    };
+    if !config.requires_token(method) {
+        return Ok(next.run(req).await);
+    }
+
+    if config.accepts(bearer_token(req.headers())) {
+        Ok(next.run(req).await)
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// リクエストしたユーザーのid. ユーザーごとのトークンが設定されていればBearerトークンから決め、
+/// 無ければ`X-User-Id`ヘッダーから取り出す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserId(pub i32);
+
+pub const USER_ID_HEADER: &str = "x-user-id";
+
+#[async_trait]
+impl<S> FromRequestParts<S> for UserId
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let claimed = match parts.headers.get(USER_ID_HEADER) {
+            Some(value) => Some(
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .ok_or((
+                        StatusCode::BAD_REQUEST,
+                        "Invalid X-User-Id header".to_string(),
+                    ))?,
+            ),
+            None => None,
+        };
+        // 認証のmiddlewareを通らないrouteではユーザーごとのトークンが無い扱いになる
+        let config = parts
+            .extensions
+            .get::<Arc<AuthConfig>>()
+            .cloned()
+            .unwrap_or_default();
+        config
+            .user_id(bearer_token(&parts.headers), claimed)
+            .map(UserId)
+            .map_err(|e| (e.status(), e.to_string()))
+    }
+}
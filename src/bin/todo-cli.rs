@@ -0,0 +1,367 @@
+use clap::{Parser, Subcommand};
+use my_todo::{
+    auth::USER_ID_HEADER,
+    repositories::{
+        label::{Label, LabelQuery},
+        todo::{CreateTodo, TodoEntity, TodoQuery, UpdateTodo},
+    },
+};
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{io::Write, process::ExitCode, slice};
+use thiserror::Error;
+
+/// 起動中のtodo APIを操作するコマンドラインクライアント
+#[derive(Debug, Parser)]
+#[command(name = "todo-cli")]
+struct Cli {
+    /// APIのURL
+    #[arg(long, env = "TODO_API_URL", default_value = "http://localhost:3000")]
+    base_url: String,
+    /// Bearerトークン. APIでAPI_TOKENを設定している場合に必要
+    #[arg(long, env = "API_TOKEN")]
+    token: Option<String>,
+    /// 操作するユーザーのid(X-User-Id)
+    #[arg(long, env = "TODO_USER_ID")]
+    user: i32,
+    /// 表ではなくJSONで出力する
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// todoの一覧を表示する
+    List {
+        /// 完了済みのtodoだけを表示する
+        #[arg(long)]
+        completed: bool,
+    },
+    /// todoを追加する
+    Add {
+        text: String,
+        /// 付けるlabelの名前. 複数指定できる
+        #[arg(long = "label")]
+        labels: Vec<String>,
+    },
+    /// todoを完了にする
+    Done { id: i32 },
+    /// todoを削除する
+    Rm { id: i32 },
+}
+
+#[derive(Debug, Error)]
+enum CliError {
+    #[error("{status}: {message}")]
+    Http { status: StatusCode, message: String },
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("label not found: {0}")]
+    UnknownLabel(String),
+    #[error("failed to write output: {0}")]
+    Output(#[from] std::io::Error),
+}
+
+impl CliError {
+    /// 終了コード. HTTPのエラーは種類ごとに分ける
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Http { status, .. } => match *status {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => 3,
+                StatusCode::NOT_FOUND => 4,
+                status if status.is_client_error() => 5,
+                _ => 6,
+            },
+            CliError::UnknownLabel(_) => 5,
+            CliError::Request(_) | CliError::Output(_) => 1,
+        }
+    }
+}
+
+struct Api {
+    client: Client,
+    base_url: String,
+    token: Option<String>,
+    user: i32,
+}
+
+impl Api {
+    fn new(cli: &Cli) -> Self {
+        Self {
+            client: Client::new(),
+            base_url: cli.base_url.trim_end_matches('/').to_string(),
+            token: cli.token.clone(),
+            user: cli.user,
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}{}", self.base_url, path))
+            .header(USER_ID_HEADER, self.user);
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, CliError> {
+        Ok(Self::send_empty(request).await?.json().await?)
+    }
+
+    // 2xx以外はレスポンスの本文をメッセージにしてエラーにする
+    async fn send_empty(request: RequestBuilder) -> Result<reqwest::Response, CliError> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let message = response.text().await.unwrap_or_default();
+        Err(CliError::Http { status, message })
+    }
+
+    // labelの名前をidに変換する. 大文字小文字は区別しない
+    async fn label_ids(&self, names: &[String]) -> Result<Vec<i32>, CliError> {
+        let mut ids = Vec::with_capacity(names.len());
+        for name in names {
+            let query = LabelQuery {
+                q: Some(name.clone()),
+                ..Default::default()
+            };
+            let labels: Vec<Label> = self
+                .send(self.request(Method::GET, "/labels").query(&query))
+                .await?;
+            let label = labels
+                .into_iter()
+                .find(|label| label.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| CliError::UnknownLabel(name.clone()))?;
+            ids.push(label.id);
+        }
+        Ok(ids)
+    }
+}
+
+async fn run(cli: &Cli, api: &Api, out: &mut impl Write) -> Result<(), CliError> {
+    match &cli.command {
+        Command::List { completed } => {
+            let query = TodoQuery {
+                completed: completed.then_some(true),
+                ..Default::default()
+            };
+            let todos: Vec<TodoEntity> = api
+                .send(api.request(Method::GET, "/todos").query(&query))
+                .await?;
+            print(out, cli.json, &todos, &todos)
+        }
+        Command::Add { text, labels } => {
+            let payload = CreateTodo::new(text.clone(), api.label_ids(labels).await?);
+            let todo: TodoEntity = api
+                .send(api.request(Method::POST, "/todos").json(&payload))
+                .await?;
+            print(out, cli.json, &todo, slice::from_ref(&todo))
+        }
+        Command::Done { id } => {
+            let todo: TodoEntity = api
+                .send(
+                    api.request(Method::PATCH, &format!("/todos/{}", id))
+                        .json(&UpdateTodo::completion(true)),
+                )
+                .await?;
+            print(out, cli.json, &todo, slice::from_ref(&todo))
+        }
+        Command::Rm { id } => {
+            Api::send_empty(api.request(Method::DELETE, &format!("/todos/{}", id))).await?;
+            if cli.json {
+                writeln!(out, "{}", serde_json::json!({ "deleted": id }))?;
+            } else {
+                writeln!(out, "deleted {}", id)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// jsonならvalueをそのまま、そうでなければtodosを表にして出力する
+fn print(
+    out: &mut impl Write,
+    json: bool,
+    value: &impl Serialize,
+    todos: &[TodoEntity],
+) -> Result<(), CliError> {
+    if json {
+        serde_json::to_writer(&mut *out, value).map_err(std::io::Error::from)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    writeln!(out, "{:>4}  {:<4}  TEXT", "ID", "DONE")?;
+    for todo in todos {
+        let labels = todo
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect::<Vec<_>>();
+        let done = if todo.completed { "x" } else { "" };
+        write!(out, "{:>4}  {:<4}  {}", todo.id, done, todo.text)?;
+        if !labels.is_empty() {
+            write!(out, " [{}]", labels.join(", "))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let api = Api::new(&cli);
+    match run(&cli, &api, &mut std::io::stdout()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{
+        extract::{Path, Query},
+        http::{header::AUTHORIZATION, HeaderMap},
+        routing::{get, patch},
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    fn todo_json(id: i32, text: &str, completed: bool, labels: Value) -> Value {
+        json!({
+            "id": id,
+            "user_id": 1,
+            "text": text,
+            "completed": completed,
+            "labels": labels,
+            "due_date": null,
+            "recurrence": "none",
+            "remind_at": null,
+            "reminded_at": null,
+            "created_at": "2024-01-01T00:00:00Z",
+            "completed_at": null,
+        })
+    }
+
+    // APIの代わりに決まったレスポンスを返すサーバーを起動し、URLを返す
+    async fn start_stub() -> String {
+        let app = Router::new()
+            .route(
+                "/todos",
+                get(|Query(query): Query<HashMap<String, String>>| async move {
+                    let todos = if query.get("completed").map(String::as_str) == Some("true") {
+                        vec![todo_json(2, "done", true, json!([]))]
+                    } else {
+                        vec![
+                            todo_json(1, "buy milk", false, json!([{ "id": 3, "name": "work" }])),
+                            todo_json(2, "done", true, json!([])),
+                        ]
+                    };
+                    Json(todos)
+                })
+                .post(|Json(payload): Json<Value>| async move {
+                    let labels = match payload["labels"].as_array() {
+                        Some(ids) if !ids.is_empty() => json!([{ "id": 3, "name": "work" }]),
+                        _ => json!([]),
+                    };
+                    Json(todo_json(
+                        5,
+                        payload["text"].as_str().unwrap(),
+                        false,
+                        labels,
+                    ))
+                }),
+            )
+            .route(
+                "/todos/:id",
+                patch(|Path(id): Path<i32>| async move {
+                    if id == 999 {
+                        return Err((StatusCode::NOT_FOUND, "NotFound, id is 999"));
+                    }
+                    Ok(Json(todo_json(id, "buy milk", true, json!([]))))
+                })
+                .delete(|headers: HeaderMap| async move {
+                    match headers.get(AUTHORIZATION) {
+                        Some(value) if value == "Bearer secret" => StatusCode::NO_CONTENT,
+                        _ => StatusCode::UNAUTHORIZED,
+                    }
+                }),
+            )
+            .route(
+                "/labels",
+                get(|| async { Json(json!([{ "id": 3, "name": "Work" }])) }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    async fn run_cli(args: &[&str]) -> (Result<(), CliError>, String) {
+        let url = start_stub().await;
+        let mut argv = vec!["todo-cli", "--base-url", &url, "--user", "1"];
+        argv.extend_from_slice(args);
+        let cli = Cli::parse_from(argv);
+        let api = Api::new(&cli);
+        let mut out = Vec::new();
+        let result = run(&cli, &api, &mut out).await;
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    #[tokio::test]
+    async fn lists_todos_as_table() {
+        let (result, out) = run_cli(&["list"]).await;
+        assert!(result.is_ok());
+        assert_eq!(
+            out,
+            "  ID  DONE  TEXT\n   1        buy milk [work]\n   2  x     done\n"
+        );
+
+        let (result, out) = run_cli(&["list", "--completed"]).await;
+        assert!(result.is_ok());
+        assert_eq!(out, "  ID  DONE  TEXT\n   2  x     done\n");
+    }
+
+    #[tokio::test]
+    async fn adds_todo_with_label_name_as_json() {
+        let (result, out) = run_cli(&["add", "write report", "--label", "work", "--json"]).await;
+        assert!(result.is_ok());
+        let todo: TodoEntity = serde_json::from_str(&out).unwrap();
+        assert_eq!(todo.text, "write report");
+        assert_eq!(todo.labels[0].name, "work");
+
+        let (result, _) = run_cli(&["add", "write report", "--label", "home"]).await;
+        let error = result.unwrap_err();
+        assert!(matches!(error, CliError::UnknownLabel(ref name) if name == "home"));
+        assert_eq!(error.exit_code(), 5);
+    }
+
+    #[tokio::test]
+    async fn maps_http_errors_to_exit_codes() {
+        let (result, out) = run_cli(&["done", "1"]).await;
+        assert!(result.is_ok());
+        assert_eq!(out, "  ID  DONE  TEXT\n   1  x     buy milk\n");
+
+        let (result, _) = run_cli(&["done", "999"]).await;
+        let error = result.unwrap_err();
+        assert_eq!(error.to_string(), "404 Not Found: NotFound, id is 999");
+        assert_eq!(error.exit_code(), 4);
+
+        let (result, _) = run_cli(&["rm", "1"]).await;
+        assert_eq!(result.unwrap_err().exit_code(), 3);
+        let (result, out) = run_cli(&["--token", "secret", "rm", "1"]).await;
+        assert!(result.is_ok());
+        assert_eq!(out, "deleted 1\n");
+    }
+}
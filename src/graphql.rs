@@ -0,0 +1,223 @@
+use async_graphql::{Context, EmptySubscription, Error, Object, Result, Schema};
+use validator::Validate;
+
+use crate::auth::UserId;
+use crate::handlers::label::CreateLabel;
+use crate::repositories::{
+    label::{Label, LabelQuery, LabelRepository},
+    todo::{CreateTodo, TodoEntity, TodoQuery, TodoRepository, UpdateTodo},
+};
+
+/// POST /graphql で実行するschema. リクエストしたユーザーはUserIdとしてrequestのdataに渡す
+pub type TodoSchema<T, L> = Schema<QueryRoot<T, L>, MutationRoot<T, L>, EmptySubscription>;
+
+pub fn schema<T: TodoRepository + Clone, L: LabelRepository>(
+    todos: T,
+    labels: L,
+) -> TodoSchema<T, L> {
+    Schema::build(
+        QueryRoot {
+            todos: todos.clone(),
+            labels: labels.clone(),
+        },
+        MutationRoot { todos, labels },
+        EmptySubscription,
+    )
+    .finish()
+}
+
+// labelsはtodoと同じ問い合わせで取得済みのため、todoごとに引き直すことはない
+pub struct QueryRoot<T, L> {
+    todos: T,
+    labels: L,
+}
+
+#[Object]
+impl<T: TodoRepository, L: LabelRepository> QueryRoot<T, L> {
+    /// 条件はGET /todosのクエリパラメータと同じ
+    async fn todos(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: TodoQuery,
+    ) -> Result<Vec<TodoEntity>> {
+        let UserId(user_id) = ctx.data()?;
+        Ok(self.todos.all(*user_id, filter).await?)
+    }
+
+    async fn todo(&self, ctx: &Context<'_>, id: i32) -> Result<TodoEntity> {
+        let UserId(user_id) = ctx.data()?;
+        Ok(self.todos.find(*user_id, id).await?)
+    }
+
+    /// qを指定するとnameに部分一致(大文字小文字を区別しない)するlabelだけを返す
+    async fn labels(&self, ctx: &Context<'_>, q: Option<String>) -> Result<Vec<Label>> {
+        let UserId(user_id) = ctx.data()?;
+        let query = LabelQuery {
+            q,
+            ..Default::default()
+        };
+        Ok(self.labels.all(*user_id, query).await?)
+    }
+}
+
+pub struct MutationRoot<T, L> {
+    todos: T,
+    labels: L,
+}
+
+#[Object]
+impl<T: TodoRepository, L: LabelRepository> MutationRoot<T, L> {
+    async fn create_todo(&self, ctx: &Context<'_>, input: CreateTodo) -> Result<TodoEntity> {
+        let UserId(user_id) = ctx.data()?;
+        validate(&input)?;
+        Ok(self.todos.create(*user_id, input).await?)
+    }
+
+    async fn update_todo(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        input: UpdateTodo,
+    ) -> Result<TodoEntity> {
+        let UserId(user_id) = ctx.data()?;
+        validate(&input)?;
+        Ok(self.todos.update(*user_id, id, input).await?)
+    }
+
+    /// 削除できればtrueを返す
+    async fn delete_todo(&self, ctx: &Context<'_>, id: i32) -> Result<bool> {
+        let UserId(user_id) = ctx.data()?;
+        self.todos.delete(*user_id, id).await?;
+        Ok(true)
+    }
+
+    /// sharedでなければリクエストしたユーザーの非公開labelになる
+    async fn create_label(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        #[graphql(default)] shared: bool,
+    ) -> Result<Label> {
+        let UserId(user_id) = ctx.data()?;
+        let payload = CreateLabel { name, shared };
+        validate(&payload)?;
+        let owner_id = (!payload.shared).then_some(*user_id);
+        Ok(self.labels.create(owner_id, payload.name).await?)
+    }
+}
+
+// ValidatedJsonと同じ文言でGraphQLのエラーにする
+fn validate(payload: &impl Validate) -> Result<()> {
+    payload
+        .validate()
+        .map_err(|e| Error::new(format!("Validation error: [{}]", e).replace('\n', ", ")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::memory::LabelRepositoryForMemory,
+        todo::{memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID},
+    };
+    use async_graphql::{Request, Variables};
+    use serde_json::json;
+
+    fn test_schema() -> TodoSchema<TodoRepositoryForMemory, LabelRepositoryForMemory> {
+        // createLabelで作ったlabelをtodoに付けられるよう、todoと同じlabelのrepositoryを渡す
+        let labels = LabelRepositoryForMemory::new();
+        schema(
+            TodoRepositoryForMemory::for_test(vec![]).with_labels(&labels),
+            labels,
+        )
+    }
+
+    fn request(query: &str, variables: serde_json::Value) -> Request {
+        Request::new(query)
+            .variables(Variables::from_json(variables))
+            .data(UserId(TEST_USER_ID))
+    }
+
+    #[tokio::test]
+    async fn creates_and_queries_todos_with_labels() {
+        let schema = test_schema();
+        let res = schema
+            .execute(request(
+                r#"mutation { createLabel(name: "work") { id name ownerId } }"#,
+                json!({}),
+            ))
+            .await;
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let label = res.data.into_json().unwrap()["createLabel"].clone();
+        assert_eq!(
+            label,
+            json!({ "id": 1, "name": "work", "ownerId": TEST_USER_ID })
+        );
+
+        let res = schema
+            .execute(request(
+                r#"mutation ($input: CreateTodoInput!) { createTodo(input: $input) { id } }"#,
+                json!({ "input": { "text": "graphql", "labels": [1] } }),
+            ))
+            .await;
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+
+        // 選択したフィールドだけが返る
+        let res = schema
+            .execute(request(
+                r#"{
+                    todos(filter: { label: 1 }) { text completed labels { name } }
+                    labels { name }
+                }"#,
+                json!({}),
+            ))
+            .await;
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap(),
+            json!({
+                "todos": [{ "text": "graphql", "completed": false, "labels": [{ "name": "work" }] }],
+                "labels": [{ "name": "work" }],
+            })
+        );
+
+        let res = schema
+            .execute(request(
+                r#"mutation { updateTodo(id: 1, input: { completed: true }) { completed } deleteTodo(id: 1) }"#,
+                json!({}),
+            ))
+            .await;
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap(),
+            json!({ "updateTodo": { "completed": true }, "deleteTodo": true })
+        );
+    }
+
+    #[tokio::test]
+    async fn surfaces_validation_and_repository_errors() {
+        let schema = test_schema();
+        let res = schema
+            .execute(request(
+                r#"mutation { createTodo(input: { text: "" }) { id } }"#,
+                json!({}),
+            ))
+            .await;
+        assert_eq!(res.errors.len(), 1);
+        assert!(
+            res.errors[0].message.starts_with("Validation error"),
+            "{}",
+            res.errors[0].message
+        );
+
+        let res = schema
+            .execute(request(r#"{ todo(id: 999) { id } }"#, json!({})))
+            .await;
+        assert_eq!(res.errors.len(), 1);
+        assert!(
+            res.errors[0].message.contains("NotFound"),
+            "{}",
+            res.errors[0].message
+        );
+    }
+}
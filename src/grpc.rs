@@ -0,0 +1,468 @@
+use std::sync::Arc;
+
+use tokio::{net::TcpListener, sync::watch};
+use tonic::{
+    metadata::MetadataMap,
+    transport::{server::TcpIncoming, Server},
+    Request, Response, Status,
+};
+use validator::Validate;
+
+use crate::auth::{AuthConfig, AuthError, USER_ID_HEADER};
+use crate::handlers::label::CreateLabel;
+use crate::repositories::{
+    label::{Label, LabelQuery, LabelRepository},
+    todo::{CreateTodo, TodoEntity, TodoQuery, TodoRepository, UpdateTodo},
+    RepositoryError,
+};
+
+pub mod proto {
+    tonic::include_proto!("todo.v1");
+}
+
+use proto::{
+    label_service_server::{LabelService, LabelServiceServer},
+    todo_service_server::{TodoService, TodoServiceServer},
+};
+
+/// gRPCのサーバーをlistenerで起動し、shutdownにtrueが送られるまで待ち受ける
+pub async fn serve<T: TodoRepository, L: LabelRepository>(
+    listener: TcpListener,
+    todos: T,
+    labels: L,
+    auth: AuthConfig,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let auth = Arc::new(auth);
+    let incoming =
+        TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow::anyhow!(e))?;
+    Server::builder()
+        .add_service(TodoServiceServer::new(TodoGrpc {
+            repository: todos,
+            auth: auth.clone(),
+        }))
+        .add_service(LabelServiceServer::new(LabelGrpc {
+            repository: labels,
+            auth,
+        }))
+        .serve_with_incoming_shutdown(incoming, async move {
+            shutdown.wait_for(|stop| *stop).await.ok();
+        })
+        .await?;
+    Ok(())
+}
+
+/// TodoRepositoryをTodoServiceとして公開する
+#[derive(Debug, Clone)]
+pub struct TodoGrpc<T> {
+    repository: T,
+    auth: Arc<AuthConfig>,
+}
+
+#[tonic::async_trait]
+impl<T: TodoRepository> TodoService for TodoGrpc<T> {
+    async fn create(
+        &self,
+        request: Request<proto::CreateTodoRequest>,
+    ) -> Result<Response<proto::Todo>, Status> {
+        let user_id = authenticate(&self.auth, request.metadata(), false)?;
+        let request = request.into_inner();
+        let payload = CreateTodo::new(request.text, request.labels);
+        validate(&payload)?;
+        let todo = self
+            .repository
+            .create(user_id, payload)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn get(
+        &self,
+        request: Request<proto::GetTodoRequest>,
+    ) -> Result<Response<proto::Todo>, Status> {
+        let user_id = authenticate(&self.auth, request.metadata(), true)?;
+        let todo = self
+            .repository
+            .find(user_id, request.into_inner().id)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn list(
+        &self,
+        request: Request<proto::ListTodosRequest>,
+    ) -> Result<Response<proto::ListTodosResponse>, Status> {
+        let user_id = authenticate(&self.auth, request.metadata(), true)?;
+        let request = request.into_inner();
+        let query = TodoQuery {
+            completed: request.completed,
+            label: request.label,
+            q: request.q,
+            created_from: None,
+            created_to: None,
+            sort: None,
+            limit: request.limit,
+            offset: request.offset,
+        };
+        let todos = self.repository.all(user_id, query).await.map_err(status)?;
+        Ok(Response::new(proto::ListTodosResponse {
+            todos: todos.into_iter().map(proto::Todo::from).collect(),
+        }))
+    }
+
+    async fn update(
+        &self,
+        request: Request<proto::UpdateTodoRequest>,
+    ) -> Result<Response<proto::Todo>, Status> {
+        let user_id = authenticate(&self.auth, request.metadata(), false)?;
+        let request = request.into_inner();
+        let payload = UpdateTodo::new(
+            request.text,
+            request.completed,
+            request.labels.map(|labels| labels.ids),
+        );
+        validate(&payload)?;
+        let todo = self
+            .repository
+            .update(user_id, request.id, payload)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteTodoRequest>,
+    ) -> Result<Response<proto::DeleteTodoResponse>, Status> {
+        let user_id = authenticate(&self.auth, request.metadata(), false)?;
+        self.repository
+            .delete(user_id, request.into_inner().id)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(proto::DeleteTodoResponse {}))
+    }
+}
+
+/// LabelRepositoryをLabelServiceとして公開する
+#[derive(Debug, Clone)]
+pub struct LabelGrpc<L> {
+    repository: L,
+    auth: Arc<AuthConfig>,
+}
+
+#[tonic::async_trait]
+impl<L: LabelRepository> LabelService for LabelGrpc<L> {
+    async fn create(
+        &self,
+        request: Request<proto::CreateLabelRequest>,
+    ) -> Result<Response<proto::Label>, Status> {
+        let user_id = authenticate(&self.auth, request.metadata(), false)?;
+        let request = request.into_inner();
+        let payload = CreateLabel {
+            name: request.name,
+            shared: request.shared,
+        };
+        validate(&payload)?;
+        // sharedでなければリクエストしたユーザーの非公開labelになる
+        let owner_id = (!payload.shared).then_some(user_id);
+        let label = self
+            .repository
+            .create(owner_id, payload.name)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(label.into()))
+    }
+
+    async fn list(
+        &self,
+        request: Request<proto::ListLabelsRequest>,
+    ) -> Result<Response<proto::ListLabelsResponse>, Status> {
+        let user_id = authenticate(&self.auth, request.metadata(), true)?;
+        let query = LabelQuery {
+            q: request.into_inner().q,
+            ..Default::default()
+        };
+        let labels = self.repository.all(user_id, query).await.map_err(status)?;
+        Ok(Response::new(proto::ListLabelsResponse {
+            labels: labels.into_iter().map(proto::Label::from).collect(),
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteLabelRequest>,
+    ) -> Result<Response<proto::DeleteLabelResponse>, Status> {
+        let user_id = authenticate(&self.auth, request.metadata(), false)?;
+        self.repository
+            .delete(user_id, request.into_inner().id)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(proto::DeleteLabelResponse {}))
+    }
+}
+
+// HTTPと同じくauthorizationのBearerトークンとx-user-idのmetadataでユーザーを特定する.
+// 参照系(is_read)はprotect_readsの時だけトークンを要求する
+#[allow(clippy::result_large_err)] // Statusはtonicのハンドラーの戻り値に合わせる
+fn authenticate(auth: &AuthConfig, metadata: &MetadataMap, is_read: bool) -> Result<i32, Status> {
+    let token = metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if auth.requires_token_for(is_read) && !auth.accepts(token) {
+        return Err(Status::unauthenticated("Invalid token"));
+    }
+    let claimed = match metadata.get(USER_ID_HEADER) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| Status::invalid_argument("Invalid x-user-id metadata"))?,
+        ),
+        None => None,
+    };
+    auth.user_id(token, claimed).map_err(|e| match e {
+        AuthError::MissingUserId => Status::unauthenticated("Missing x-user-id metadata"),
+        AuthError::UnknownToken => Status::unauthenticated(e.to_string()),
+        AuthError::UserIdMismatch => {
+            Status::permission_denied("x-user-id does not match the token")
+        }
+    })
+}
+
+// repositoryのエラーをgRPCのステータスに変換する
+fn status(error: anyhow::Error) -> Status {
+    match error.downcast_ref::<RepositoryError>() {
+        Some(RepositoryError::NotFound(_)) => Status::not_found(error.to_string()),
+        Some(RepositoryError::LabelNotAvailable(_) | RepositoryError::InvalidQuery(_)) => {
+            Status::invalid_argument(error.to_string())
+        }
+        Some(RepositoryError::Duplicate(_)) => Status::already_exists(error.to_string()),
+        Some(RepositoryError::Timeout(_)) => Status::deadline_exceeded(error.to_string()),
+        _ => Status::internal(error.to_string()),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn validate(payload: &impl Validate) -> Result<(), Status> {
+    payload.validate().map_err(|e| {
+        Status::invalid_argument(format!("Validation error: [{}]", e).replace('\n', ", "))
+    })
+}
+
+impl From<Label> for proto::Label {
+    fn from(label: Label) -> Self {
+        Self {
+            id: label.id,
+            name: label.name,
+            owner_id: label.owner_id,
+        }
+    }
+}
+
+impl From<TodoEntity> for proto::Todo {
+    fn from(todo: TodoEntity) -> Self {
+        Self {
+            id: todo.id,
+            text: todo.text,
+            completed: todo.completed,
+            labels: todo.labels.into_iter().map(proto::Label::from).collect(),
+            due_date: todo.due_date.map(|date| date.to_string()),
+            created_at: todo.created_at.to_rfc3339(),
+            completed_at: todo.completed_at.map(|at| at.to_rfc3339()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::memory::LabelRepositoryForMemory,
+        todo::{memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID},
+    };
+    use proto::{label_service_client::LabelServiceClient, todo_service_client::TodoServiceClient};
+    use std::collections::HashMap;
+    use tonic::{transport::Channel, Code};
+
+    // memoryのrepositoryでサーバーを起動し、接続先のURLを返す
+    async fn start_server(auth: AuthConfig) -> (String, watch::Sender<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(serve(
+            listener,
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            auth,
+            shutdown_rx,
+        ));
+        (url, shutdown_tx)
+    }
+
+    fn request<T>(message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert(USER_ID_HEADER, TEST_USER_ID.into());
+        request
+    }
+
+    #[tokio::test]
+    async fn serves_todo_crud_over_grpc() {
+        let (url, shutdown_tx) = start_server(AuthConfig::default()).await;
+        let mut client: TodoServiceClient<Channel> =
+            TodoServiceClient::connect(url.clone()).await.unwrap();
+
+        let created = client
+            .create(request(proto::CreateTodoRequest {
+                text: "grpc".to_string(),
+                labels: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(created.text, "grpc");
+        assert!(!created.completed);
+
+        let found = client
+            .get(request(proto::GetTodoRequest { id: created.id }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(found, created);
+
+        let updated = client
+            .update(request(proto::UpdateTodoRequest {
+                id: created.id,
+                text: None,
+                completed: Some(true),
+                labels: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(updated.completed);
+        assert!(updated.completed_at.is_some());
+
+        let todos = client
+            .list(request(proto::ListTodosRequest {
+                completed: Some(true),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .todos;
+        assert_eq!(todos, vec![updated]);
+
+        client
+            .delete(request(proto::DeleteTodoRequest { id: created.id }))
+            .await
+            .unwrap();
+        let error = client
+            .get(request(proto::GetTodoRequest { id: created.id }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Code::NotFound);
+
+        let mut labels = LabelServiceClient::connect(url).await.unwrap();
+        let label = labels
+            .create(request(proto::CreateLabelRequest {
+                name: "work".to_string(),
+                shared: false,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(label.owner_id, Some(TEST_USER_ID));
+        let listed = labels
+            .list(request(proto::ListLabelsRequest {
+                q: Some("WO".to_string()),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .labels;
+        assert_eq!(listed, vec![label]);
+
+        shutdown_tx.send(true).unwrap();
+    }
+
+    #[tokio::test]
+    async fn maps_validation_and_auth_failures_to_status() {
+        let auth = AuthConfig {
+            token: Some("secret".to_string()),
+            protect_reads: false,
+            ..Default::default()
+        };
+        let (url, shutdown_tx) = start_server(auth).await;
+        let mut client = TodoServiceClient::connect(url).await.unwrap();
+
+        // 書き込みにはトークンが必要
+        let error = client
+            .create(request(proto::CreateTodoRequest {
+                text: "grpc".to_string(),
+                labels: vec![],
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Code::Unauthenticated);
+
+        let mut invalid = request(proto::CreateTodoRequest {
+            text: String::new(),
+            labels: vec![],
+        });
+        invalid
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        let error = client.create(invalid).await.unwrap_err();
+        assert_eq!(error.code(), Code::InvalidArgument);
+
+        // 参照にはトークンは不要だがユーザーの指定は必要
+        let error = client
+            .list(Request::new(proto::ListTodosRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Code::Unauthenticated);
+
+        shutdown_tx.send(true).unwrap();
+    }
+
+    #[tokio::test]
+    async fn takes_user_from_user_token() {
+        let auth = AuthConfig {
+            users: HashMap::from([("alice-token".to_string(), TEST_USER_ID)]),
+            ..Default::default()
+        };
+        let (url, shutdown_tx) = start_server(auth).await;
+        let mut client = TodoServiceClient::connect(url).await.unwrap();
+        let with_token = |user_id: Option<i32>| {
+            let mut req = Request::new(proto::ListTodosRequest::default());
+            req.metadata_mut()
+                .insert("authorization", "Bearer alice-token".parse().unwrap());
+            if let Some(user_id) = user_id {
+                req.metadata_mut()
+                    .insert(USER_ID_HEADER, user_id.to_string().parse().unwrap());
+            }
+            req
+        };
+
+        assert!(client.list(with_token(None)).await.is_ok());
+        let error = client
+            .list(with_token(Some(TEST_USER_ID + 1)))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Code::PermissionDenied);
+        let error = client
+            .list(request(proto::ListTodosRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Code::Unauthenticated);
+
+        shutdown_tx.send(true).unwrap();
+    }
+}
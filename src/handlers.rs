@@ -1,17 +1,538 @@
 use axum::{
     async_trait,
-    extract::{FromRequest, Request},
-    http::StatusCode,
+    body::HttpBody,
+    extract::{rejection::JsonRejection, FromRequest, FromRequestParts, Path, Query, Request},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
     Json,
 };
-use serde::de::DeserializeOwned;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures_util::FutureExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    any::Any,
+    env,
+    num::IntErrorKind,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use uuid::Uuid;
 use validator::Validate;
 
+use crate::auth::UserId;
+use crate::repositories::{todo::TodoRepository, RepositoryError};
+
+use links::ListLinks;
+
+pub mod activity;
+pub mod cache;
+pub mod debug;
+pub mod fields;
+pub mod graphql;
+pub mod health;
+pub mod ids;
 pub mod label;
+pub mod links;
 pub mod todo;
+pub mod version;
+pub mod webhook;
+pub mod ws;
+
+// repositoryのエラーをステータスコードに変換する. 個別に扱わないエラーはfallbackになる
+fn error_status(error: anyhow::Error, fallback: StatusCode) -> StatusCode {
+    match error.downcast_ref::<RepositoryError>() {
+        Some(RepositoryError::NotFound(_) | RepositoryError::PublicIdNotFound(_)) => {
+            StatusCode::NOT_FOUND
+        }
+        Some(RepositoryError::InvalidQuery(_) | RepositoryError::InvalidParent(_)) => {
+            StatusCode::BAD_REQUEST
+        }
+        Some(RepositoryError::LabelNotAvailable(_)) => StatusCode::UNPROCESSABLE_ENTITY,
+        Some(RepositoryError::Timeout(_)) => StatusCode::GATEWAY_TIMEOUT,
+        Some(RepositoryError::NothingToUndo(_)) => StatusCode::CONFLICT,
+        Some(RepositoryError::Unexpected(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => fallback,
+    }
+}
+
+/// リクエストごとのid. 無ければSetRequestIdLayerが付け、レスポンスにも同じ値を返す
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// エラー時にJSONで返す本文
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// 一覧を返すendpointのレスポンスの形式. 既定は配列のまま返す
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListFormat {
+    #[default]
+    Bare,
+    /// 配列をdataに入れ、件数やページの情報をmetaに入れる
+    Envelope,
+}
+
+impl ListFormat {
+    /// 環境変数LIST_FORMAT(bare, envelope)から読む. 未設定ならbare
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env::var("LIST_FORMAT") {
+            Ok(value) => serde_json::from_value(serde_json::Value::String(value))
+                .map_err(|e| anyhow::anyhow!("invalid [LIST_FORMAT]: {}", e)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// 形式に合わせて一覧を返す. metaはEnvelopeでだけ使う
+    pub fn respond<T: Serialize>(self, data: Vec<T>, meta: ListMeta) -> Response {
+        self.respond_with_links(data, meta, None)
+    }
+
+    /// linksはEnvelopeでは_linksに、配列のままならLinkヘッダーに入れる
+    pub fn respond_with_links<T: Serialize>(
+        self,
+        data: Vec<T>,
+        meta: ListMeta,
+        links: Option<ListLinks>,
+    ) -> Response {
+        match self {
+            ListFormat::Bare => {
+                let mut response = (StatusCode::OK, Json(data)).into_response();
+                if let Some(value) = links.as_ref().and_then(link_header) {
+                    response.headers_mut().insert(header::LINK, value);
+                }
+                response
+            }
+            ListFormat::Envelope => {
+                let body = Envelope { data, meta, links };
+                (StatusCode::OK, Json(body)).into_response()
+            }
+        }
+    }
+}
+
+// create_appがExtensionとして全てのリクエストに付ける. 無ければ既定の形式にする
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ListFormat {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<ListFormat>()
+            .copied()
+            .unwrap_or_default())
+    }
+}
+
+// RFC 8288のLinkヘッダーの値にする
+fn link_header(links: &ListLinks) -> Option<HeaderValue> {
+    let rels = [
+        ("self", Some(&links.self_link)),
+        ("next", links.next.as_ref()),
+        ("prev", links.prev.as_ref()),
+    ];
+    let value = rels
+        .into_iter()
+        .filter_map(|(rel, link)| link.map(|link| format!(r#"<{}>; rel="{}""#, link.href, rel)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    HeaderValue::from_str(&value).ok()
+}
+
+/// ListFormat::Envelopeでの一覧のレスポンス
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Envelope<T> {
+    pub data: Vec<T>,
+    pub meta: ListMeta,
+    /// リンクを求められた時だけ付ける
+    #[serde(rename = "_links", default, skip_serializing_if = "Option::is_none")]
+    pub links: Option<ListLinks>,
+}
+
+/// 一覧の件数とページの情報
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct ListMeta {
+    /// limit, offsetを除いた条件に当てはまる件数
+    pub total: i64,
+    /// ページの件数. 上限が無ければNone
+    pub limit: Option<i64>,
+    pub offset: i64,
+    /// 次のページを取得する時のoffset. 続きが無ければNone
+    pub next_cursor: Option<i64>,
+}
+
+impl ListMeta {
+    /// offsetからlen件を返したページの情報
+    pub fn page(total: i64, limit: Option<i64>, offset: i64, len: usize) -> Self {
+        let next = offset + len as i64;
+        Self {
+            total,
+            limit,
+            offset,
+            next_cursor: (len > 0 && next < total).then_some(next),
+        }
+    }
+
+    /// ページに分けずに全件を返した一覧の情報
+    pub fn all(len: usize) -> Self {
+        Self::page(len as i64, None, 0, len)
+    }
+}
+
+// HTTP-dateの形式. 送るのは先頭のIMF-fixdateだけだが、受け取る時は古い2つの形式も読む(RFC 9110 5.6.7)
+const HTTP_DATE_FORMATS: [&str; 3] = [
+    "%a, %d %b %Y %H:%M:%S GMT",
+    "%A, %d-%b-%y %H:%M:%S GMT",
+    "%a %b %e %H:%M:%S %Y",
+];
+
+// HTTP-dateを読む. どの形式でもなければNoneとし、ヘッダーが無い時と同じに扱う
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    HTTP_DATE_FORMATS.iter().find_map(|format| {
+        NaiveDateTime::parse_from_str(value, format)
+            .ok()
+            .map(|date| date.and_utc())
+    })
+}
+
+/// Last-Modifiedを付けてresponseを返す. If-Modified-Sinceがlast_modified以降なら本文の無い304にする.
+/// HTTPの日時は秒までしか無いため、秒未満を切り捨てて比べる
+pub fn with_last_modified(
+    headers: &HeaderMap,
+    last_modified: DateTime<Utc>,
+    response: impl IntoResponse,
+) -> Response {
+    let not_modified = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_http_date)
+        .is_some_and(|since| last_modified.timestamp() <= since.timestamp());
+    let mut response = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        response.into_response()
+    };
+    let http_date = last_modified.format(HTTP_DATE_FORMATS[0]).to_string();
+    response.headers_mut().insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&http_date).expect("http date is not a valid header value"),
+    );
+    response
+}
+
+/// 一覧のlimitとoffset. limitは1以上、offsetは0以上で、範囲外や数値でなければJSONの400を返す.
+/// 一覧のendpointはlimit, offsetをここから読み、repositoryへのクエリに入れる
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct Pagination {
+    /// 未指定ならendpointの既定の件数(todo, activityは50件)
+    pub limit: Option<i64>,
+    /// 未指定なら0
+    pub offset: Option<i64>,
+}
+
+impl Pagination {
+    /// 1度に返す件数の上限. これより大きいlimitはこの値にする
+    pub const MAX_LIMIT: i64 = 500;
+
+    /// 実際に返す件数の上限. 未指定ならdefault
+    pub fn limit(&self, default: i64) -> i64 {
+        self.limit.unwrap_or(default).min(Self::MAX_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0)
+    }
+
+    fn validate(self) -> Result<Self, String> {
+        if let Some(limit) = self.limit.filter(|limit| *limit < 1) {
+            return Err(format!("Invalid limit: [{}] must be 1 or more", limit));
+        }
+        if let Some(offset) = self.offset.filter(|offset| *offset < 0) {
+            return Err(format!("Invalid offset: [{}] must be 0 or more", offset));
+        }
+        Ok(self)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Pagination {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Query(pagination) =
+            Query::<Pagination>::try_from_uri(&parts.uri).map_err(|rejection| {
+                bad_request(format!("Invalid query: [{}]", rejection.body_text()))
+            })?;
+        pagination.validate().map_err(bad_request)
+    }
+}
+
+/// パスのtodoのid. 連番のidか公開id(UUID)で指定でき、公開idは連番のidに解決する.
+/// 連番のidは1以上i32::MAX以下でなければrepositoryに問い合わせずJSONの400を返す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TodoId(pub i32);
+
+/// パスのlabelのid. 範囲はTodoIdと同じ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LabelId(pub i32);
+
+/// パスのwebhookのid. 範囲はTodoIdと同じ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WebhookId(pub i32);
+
+// errorをJSONの本文にした400を返す
+fn bad_request(error: String) -> Response {
+    error_response(StatusCode::BAD_REQUEST, error)
+}
+
+// 形式は正しいが処理できない内容のerrorをJSONの本文にした422を返す
+fn unprocessable_entity(error: String) -> Response {
+    error_response(StatusCode::UNPROCESSABLE_ENTITY, error)
+}
+
+// repositoryのエラーをJSONの本文にしたレスポンスにする. 想定外のエラーは内容を返さない
+fn repository_error(error: anyhow::Error, fallback: StatusCode) -> Response {
+    let message = match error.downcast_ref::<RepositoryError>() {
+        Some(RepositoryError::Unexpected(_)) | None => "Internal server error".to_string(),
+        Some(error) => error.to_string(),
+    };
+    error_response(error_status(error, fallback), message)
+}
+
+// errorをJSONの本文にしたstatusのレスポンス
+fn error_response(status: StatusCode, error: String) -> Response {
+    let body = ErrorResponse {
+        error,
+        request_id: None,
+    };
+    (status, Json(body)).into_response()
+}
+
+async fn path_segment<S: Send + Sync>(parts: &mut Parts, state: &S) -> Result<String, Response> {
+    let Path(segment) = Path::<String>::from_request_parts(parts, state)
+        .await
+        .map_err(|rejection| bad_request(format!("Invalid id: [{}]", rejection.body_text())))?;
+    Ok(segment)
+}
+
+// 全てのパスのidはここで読む. i32やi64を超える桁数の値も、axumの平文の400ではなく範囲外として返す.
+// エラーは400の本文にする文言
+fn parse_id(segment: &str) -> Result<i32, String> {
+    let out_of_range = || {
+        format!(
+            "Invalid id: [{} is not between 1 and {}]",
+            segment,
+            i32::MAX
+        )
+    };
+    let id = segment.parse::<i64>().map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => out_of_range(),
+        _ => format!("Invalid id: [{}: {}]", segment, e),
+    })?;
+    i32::try_from(id)
+        .ok()
+        .filter(|id| *id > 0)
+        .ok_or_else(out_of_range)
+}
+
+async fn path_id<S: Send + Sync>(parts: &mut Parts, state: &S) -> Result<i32, Response> {
+    parse_id(&path_segment(parts, state).await?).map_err(bad_request)
+}
+
+// todoのroutesはrepositoryをStateに持つので、公開idの解決に使う
+#[async_trait]
+impl<T: TodoRepository> FromRequestParts<Arc<T>> for TodoId {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        repository: &Arc<T>,
+    ) -> Result<Self, Self::Rejection> {
+        let segment = path_segment(parts, repository).await?;
+        // 移行期間の間は連番のidも受け付ける. 外部のクライアントは公開idだけを使う想定
+        let Ok(public_id) = segment.parse::<Uuid>() else {
+            return parse_id(&segment).map(TodoId).map_err(bad_request);
+        };
+        let UserId(user_id) = UserId::from_request_parts(parts, repository)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        repository
+            .find_by_public_id(user_id, public_id)
+            .await
+            .map(|todo| TodoId(todo.id))
+            .map_err(|e| repository_error(e, StatusCode::NOT_FOUND))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for LabelId {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        path_id(parts, state).await.map(LabelId)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for WebhookId {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        path_id(parts, state).await.map(WebhookId)
+    }
+}
+
+// handlerのpanicを500に変換した回数
+static PANICS_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// 起動してからhandlerのpanicを500に変換した回数
+pub fn panics_total() -> u64 {
+    PANICS_TOTAL.load(Ordering::Relaxed)
+}
+
+/// handlerがpanicしても接続を切らず、request idを含むJSONの500を返すmiddleware.
+/// backtraceはpanic時にpanic hookが記録する
+pub async fn catch_panic(req: Request, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let payload = match AssertUnwindSafe(next.run(req)).catch_unwind().await {
+        Ok(res) => return res,
+        Err(payload) => payload,
+    };
+    PANICS_TOTAL.fetch_add(1, Ordering::Relaxed);
+    tracing::error!(
+        "handler panicked, request id is [{}]: {}",
+        request_id.as_deref().unwrap_or("-"),
+        panic_message(&*payload)
+    );
+    let body = ErrorResponse {
+        error: "Internal server error".to_string(),
+        request_id,
+    };
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
+}
+
+// panic!に渡された文字列. それ以外の値でpanicした場合は種類が分からない
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic payload")
+}
+
+/// パスはあるがメソッドが使えない場合の405に、Allowヘッダーを残したままJSONの本文を付けるmiddleware.
+/// AllowヘッダーはaxumがRouterのroute定義から付ける
+pub async fn method_not_allowed(req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let res = next.run(req).await;
+    if res.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return res;
+    }
+    let body = ErrorResponse {
+        error: format!("Method [{}] is not allowed", method),
+        request_id: None,
+    };
+    let mut json = (StatusCode::METHOD_NOT_ALLOWED, Json(body)).into_response();
+    if let Some(allow) = res.headers().get(header::ALLOW) {
+        json.headers_mut().insert(header::ALLOW, allow.clone());
+    }
+    json
+}
+
+// application/jsonか、application/merge-patch+jsonのような+jsonのメディアタイプか
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .is_some_and(|mime| {
+            mime.type_() == mime::APPLICATION
+                && (mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON))
+        })
+}
+
+// Content-TypeがJSONでないことをJSONの本文にした415を返す
+fn unsupported_media_type() -> Response {
+    let body = ErrorResponse {
+        error: "Expected request with `Content-Type: application/json`".to_string(),
+        request_id: None,
+    };
+    (StatusCode::UNSUPPORTED_MEDIA_TYPE, Json(body)).into_response()
+}
+
+/// 本文のあるPOST, PUT, PATCHのContent-TypeがJSONでなければ、本文を読まずに415を返すmiddleware.
+/// 本文の無いリクエスト(POST /todos/:id/undoなど)はContent-Typeを問わない
+pub async fn require_json(req: Request, next: Next) -> Response {
+    let mutating = matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH);
+    let has_body = req.body().size_hint().exact() != Some(0);
+    if mutating && has_body && !is_json(req.headers()) {
+        return unsupported_media_type();
+    }
+    next.run(req).await
+}
+
+/// allowed_methodsがOPTIONSをTRACEに置き換えたリクエストに付ける印.
+/// 認証はこの印のあるリクエストをOPTIONSとして扱う
+#[derive(Debug, Clone, Copy)]
+pub struct AllowedMethodsProbe;
+
+/// CORSのpreflightでないOPTIONSに、pathで使えるメソッドをAllowヘッダーで示す204を返すmiddleware.
+/// CorsLayerは全てのOPTIONSをpreflightとして応答するため、その外側に置く
+pub async fn allowed_methods(mut req: Request, next: Next) -> Response {
+    if req.method() != Method::OPTIONS
+        || req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return next.run(req).await;
+    }
+    // どのrouteも受け付けないTRACEで問い合わせ、405に付くAllowヘッダーを使う.
+    // pathが無い場合の404や認証の401はそのまま返す
+    *req.method_mut() = Method::TRACE;
+    req.extensions_mut().insert(AllowedMethodsProbe);
+    let res = next.run(req).await;
+    if res.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return res;
+    }
+    let mut options = StatusCode::NO_CONTENT.into_response();
+    if let Some(allow) = res.headers().get(header::ALLOW) {
+        options.headers_mut().insert(header::ALLOW, allow.clone());
+    }
+    options
+}
+
+// 型の不一致はフィールドを、知らないフィールドはその名前だけを示す.
+// unknown fieldのメッセージには受け付けるフィールドが全て並ぶため、名前だけを取り出す
+fn json_error_message(e: &serde_path_to_error::Error<serde_json::Error>) -> String {
+    let inner = e.inner().to_string();
+    if let Some(field) = inner
+        .strip_prefix("unknown field `")
+        .and_then(|rest| rest.split('`').next())
+    {
+        return format!("Json parse error: [unknown field `{}`]", field);
+    }
+    match e.path().to_string().as_str() {
+        "." => format!("Json parse error: [{}]", inner),
+        path => format!("Json parse error: [field `{}`: {}]", path, inner),
+    }
+}
 
+/// JSONの本文を読み取って検証するextractor.
+/// 本文を読み取るのでhandlerの最後の引数にする. State、Path、UserIdなどの順序は問わない
 #[derive(Debug)]
-pub struct ValidatedJson<T>(T);
+pub struct ValidatedJson<T>(pub T);
 
 #[async_trait]
 impl<T, B> FromRequest<B> for ValidatedJson<T>
@@ -19,18 +540,25 @@ where
     T: DeserializeOwned + Validate,
     B: Send + Sync,
 {
-    type Rejection = (StatusCode, String);
+    type Rejection = Response;
 
     async fn from_request(req: Request, state: &B) -> Result<Self, Self::Rejection> {
-        let Json(value) = Json::<T>::from_request(req, state)
+        // Content-Typeと構文はaxumに任せ、型の不一致はどのフィールドかを含めて返す.
+        // Content-Typeが無ければrequire_jsonと同じく415、本文が大きすぎれば413にする
+        let Json(json) = Json::<serde_json::Value>::from_request(req, state)
             .await
             .map_err(|rejection| {
-                let message = format!("Json parse error: [{}]", rejection);
-                (StatusCode::BAD_REQUEST, message)
+                let status = match rejection {
+                    JsonRejection::MissingJsonContentType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    JsonRejection::BytesRejection(_) => rejection.status(),
+                    _ => StatusCode::BAD_REQUEST,
+                };
+                error_response(status, format!("Json parse error: [{}]", rejection))
             })?;
+        let value: T = serde_path_to_error::deserialize(json)
+            .map_err(|e| bad_request(json_error_message(&e)))?;
         value.validate().map_err(|rejection| {
-            let message = format!("Validation error: [{}]", rejection).replace("\n", ", ");
-            (StatusCode::BAD_REQUEST, message)
+            bad_request(format!("Validation error: [{}]", rejection).replace("\n", ", "))
         })?;
 
         Ok(ValidatedJson(value))
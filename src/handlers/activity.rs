@@ -0,0 +1,36 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use std::sync::Arc;
+
+use crate::auth::UserId;
+use crate::repositories::activity::{ActivityQuery, ActivityRepository};
+
+use super::{error_status, ListFormat, ListMeta, Pagination};
+
+// リクエストしたユーザーによるtodoの変更履歴を新しい順に返す
+pub async fn all_activity<A: ActivityRepository>(
+    UserId(user_id): UserId,
+    format: ListFormat,
+    pagination: Pagination,
+    State(repository): State<Arc<A>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let query = ActivityQuery {
+        limit: Some(pagination.limit(ActivityQuery::DEFAULT_LIMIT)),
+        offset: pagination.offset,
+    };
+    let events = repository
+        .list(user_id, query.clone())
+        .await
+        .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    // 件数はEnvelopeで返す時だけ数える
+    let meta = match format {
+        ListFormat::Envelope => {
+            let total = repository
+                .count(user_id)
+                .await
+                .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+            ListMeta::page(total, Some(query.limit()), query.offset(), events.len())
+        }
+        ListFormat::Bare => ListMeta::default(),
+    };
+    Ok(format.respond(events, meta))
+}
@@ -0,0 +1,75 @@
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::auth::USER_ID_HEADER;
+
+/// routeのグループごとに、GETの成功したレスポンスに付けるCache-Control.
+/// 変更のリクエストやエラー、グループに当てはまらないrouteには常にno-storeを付ける.
+/// mainやテストがExtensionとして付ける. 無ければ既定の値を使う
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheControl {
+    /// /todos以下の一覧と取得
+    pub todos: HeaderValue,
+    /// /labels
+    pub labels: HeaderValue,
+}
+
+impl Default for CacheControl {
+    fn default() -> Self {
+        Self {
+            todos: HeaderValue::from_static("private, max-age=30"),
+            // labelもユーザーごとに見えるものが違うので、共有キャッシュには置かせない
+            labels: HeaderValue::from_static("private, max-age=300"),
+        }
+    }
+}
+
+impl CacheControl {
+    /// パスが属するグループの値. どのグループでもなければNone
+    fn for_path(&self, path: &str) -> Option<&HeaderValue> {
+        let in_group = |prefix: &str| {
+            path.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        if in_group("/todos") {
+            Some(&self.todos)
+        } else if in_group("/labels") {
+            Some(&self.labels)
+        } else {
+            None
+        }
+    }
+}
+
+/// レスポンスにCache-Controlを付けるmiddleware. 共有キャッシュがユーザーごとに分けて保存するよう、
+/// キャッシュできるレスポンスには認証のヘッダーをVaryに加える
+pub async fn cache_control(req: Request, next: Next) -> Response {
+    let policy = req
+        .extensions()
+        .get::<CacheControl>()
+        .cloned()
+        .unwrap_or_default();
+    let cacheable = matches!(*req.method(), Method::GET | Method::HEAD);
+    let value = cacheable
+        .then(|| policy.for_path(req.uri().path()).cloned())
+        .flatten();
+    let mut res = next.run(req).await;
+    let status = res.status();
+    let value = value.filter(|_| status.is_success() || status == StatusCode::NOT_MODIFIED);
+    let headers = res.headers_mut();
+    match value {
+        Some(value) => {
+            headers.insert(header::CACHE_CONTROL, value);
+            headers.append(header::VARY, header::AUTHORIZATION.into());
+            headers.append(header::VARY, HeaderValue::from_static(USER_ID_HEADER));
+        }
+        None => {
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        }
+    }
+    res
+}
@@ -0,0 +1,33 @@
+use axum::{http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+/// GET /debug/pool のレスポンス. DBの接続が足りなくなっていないかを調べるのに使う
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PoolStats {
+    /// 開いている接続の数
+    pub size: u32,
+    pub idle: usize,
+    pub in_use: u32,
+    pub max_connections: u32,
+}
+
+impl PoolStats {
+    pub fn of(pool: &PgPool) -> Self {
+        let size = pool.size();
+        let idle = pool.num_idle();
+        Self {
+            size,
+            idle,
+            // sizeとnum_idleは別々に読むため、間に接続が閉じると0を下回りうる
+            in_use: size.saturating_sub(idle as u32),
+            max_connections: pool.options().get_max_connections(),
+        }
+    }
+}
+
+// 接続プールの状態. mainがPgPoolをExtensionとして付ける. memoryのrepositoryでは無いため404
+pub async fn debug_pool(pool: Option<Extension<PgPool>>) -> Result<Json<PoolStats>, StatusCode> {
+    let Extension(pool) = pool.ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(PoolStats::of(&pool)))
+}
@@ -0,0 +1,89 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::bad_request;
+
+/// ?fields=で指定できるtodoの項目. next_occurrenceは更新の結果にしか無いため含めない
+pub const TODO_FIELDS: [&str; 14] = [
+    "id",
+    "public_id",
+    "user_id",
+    "text",
+    "completed",
+    "labels",
+    "due_date",
+    "recurrence",
+    "remind_at",
+    "reminded_at",
+    "created_at",
+    "completed_at",
+    "updated_at",
+    "parent_id",
+];
+
+/// ?fields=id,text,completed で指定された、返すtodoの項目. 未指定なら全ての項目を返す.
+/// idと_linksは指定しなくても常に返す
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TodoFields(Option<Vec<String>>);
+
+#[derive(Debug, Deserialize, Default)]
+struct FieldsParams {
+    fields: Option<String>,
+}
+
+impl TodoFields {
+    /// カンマ区切りの項目名を読む. 空の要素は無視し、知らない項目名はエラーにする
+    pub fn parse(fields: &str) -> Result<Self, String> {
+        let fields = fields
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(|field| {
+                if TODO_FIELDS.contains(&field) {
+                    Ok(field.to_string())
+                } else {
+                    Err(format!(
+                        "Invalid fields: [{}] is not a todo field, valid fields are {}",
+                        field,
+                        TODO_FIELDS.join(", ")
+                    ))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self(Some(fields)))
+    }
+
+    /// itemをJSONにし、指定された項目だけを残す
+    pub fn project<T: Serialize>(&self, item: T) -> Value {
+        let mut value = serde_json::to_value(item).expect("failed to serialize todo");
+        if let (Some(fields), Value::Object(map)) = (&self.0, &mut value) {
+            map.retain(|key, _| key == "id" || key == "_links" || fields.contains(key));
+        }
+        value
+    }
+
+    pub fn project_all<T: Serialize>(&self, items: Vec<T>) -> Vec<Value> {
+        items.into_iter().map(|item| self.project(item)).collect()
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for TodoFields {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let params = Query::<FieldsParams>::try_from_uri(&parts.uri)
+            .map(|Query(params)| params)
+            .unwrap_or_default();
+        match params.fields {
+            Some(fields) => Self::parse(&fields).map_err(bad_request),
+            None => Ok(Self::default()),
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use async_graphql::http::GraphiQLSource;
+use axum::{extract::State, response::Html, Json};
+
+use crate::auth::UserId;
+use crate::graphql::TodoSchema;
+use crate::repositories::{label::LabelRepository, todo::TodoRepository};
+
+// GraphQLのqueryを実行する. エラーはステータスコードではなくレスポンスのerrorsで返す
+pub async fn graphql<T: TodoRepository, L: LabelRepository>(
+    user_id: UserId,
+    State(schema): State<TodoSchema<T, L>>,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request.data(user_id)).await)
+}
+
+// ブラウザからqueryを試すためのGraphiQL
+pub async fn graphiql() -> Html<String> {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
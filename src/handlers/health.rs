@@ -0,0 +1,67 @@
+use axum::{async_trait, http::StatusCode, Extension};
+use sqlx::{migrate::Migrator, PgPool};
+use std::{sync::Arc, time::Duration};
+
+use crate::repositories::with_timeout;
+
+/// このビルドに含まれるmigrations. 全て適用済みになるまでreadyにしない
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// /readyの確認の上限時間. 接続が確立できずに待ち続けるとロードバランサーの確認が先に打ち切られる
+pub const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// リクエストを受けられるかを確かめる依存先
+#[async_trait]
+pub trait ReadinessCheck: Send + Sync + 'static {
+    async fn check(&self) -> anyhow::Result<()>;
+}
+
+// 問い合わせができ、migrationsが全て適用済みならready
+#[async_trait]
+impl ReadinessCheck for PgPool {
+    async fn check(&self) -> anyhow::Result<()> {
+        let applied: Vec<i64> =
+            sqlx::query_scalar("select version from _sqlx_migrations where success")
+                .fetch_all(self)
+                .await?;
+        let pending: Vec<i64> = MIGRATOR
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+            .map(|migration| migration.version)
+            .filter(|version| !applied.contains(version))
+            .collect();
+        if !pending.is_empty() {
+            anyhow::bail!("migrations {:?} are not applied", pending);
+        }
+        Ok(())
+    }
+}
+
+/// /readyで確かめる依存先. mainがExtensionとして付ける. 無ければ待つ依存先が無いものとして常にready
+#[derive(Clone)]
+pub struct Readiness(Arc<dyn ReadinessCheck>);
+
+impl Readiness {
+    pub fn new(check: impl ReadinessCheck) -> Self {
+        Self(Arc::new(check))
+    }
+}
+
+// liveness. プロセスが応答できれば200. 依存先は確かめないため、DBの停止で再起動されることはない
+pub async fn health() -> &'static str {
+    "ok"
+}
+
+// readiness. 依存先がリクエストを受けられるまで503を返し、ロードバランサーに振り分けを待たせる
+pub async fn ready(readiness: Option<Extension<Readiness>>) -> (StatusCode, &'static str) {
+    let Some(Extension(Readiness(check))) = readiness else {
+        return (StatusCode::OK, "ready");
+    };
+    match with_timeout(READY_TIMEOUT, check.check()).await {
+        Ok(()) => (StatusCode::OK, "ready"),
+        Err(e) => {
+            tracing::warn!("not ready: {:#}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+        }
+    }
+}
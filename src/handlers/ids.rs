@@ -0,0 +1,84 @@
+use axum::{
+    body::Body,
+    extract::{Query, Request},
+    http::{header, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Acceptのメディアタイプにこのパラメータを付けるとidを文字列で返す(例: application/json; ids=string)
+pub const IDS_AS_STRINGS_PARAM: &str = "ids=string";
+
+#[derive(Debug, Deserialize, Default)]
+struct IdParams {
+    ids_as_strings: Option<bool>,
+}
+
+// ?ids_as_strings=trueかAcceptのids=stringで有効になる
+fn wants_ids_as_strings(parts: &Parts) -> bool {
+    let params = Query::<IdParams>::try_from_uri(&parts.uri)
+        .map(|Query(params)| params)
+        .unwrap_or_default();
+    params.ids_as_strings == Some(true)
+        || parts
+            .headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split([',', ';']))
+            .any(|param| param.trim() == IDS_AS_STRINGS_PARAM)
+}
+
+// idと*_idのキーの整数を文字列にする. nullや文字列(public_idなど)はそのまま
+fn stringify_ids(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if (key == "id" || key.ends_with("_id")) && value.is_i64() {
+                    *value = Value::String(value.to_string());
+                } else {
+                    stringify_ids(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(stringify_ids),
+        _ => {}
+    }
+}
+
+/// 求められた時だけ、JSONのレスポンスのidを文字列にするmiddleware.
+/// JavaScriptのnumberで表せない大きな整数を、クライアントが丸めずに扱えるようにする
+pub async fn ids_as_strings(req: Request, next: Next) -> Response {
+    let (parts, body) = req.into_parts();
+    let enabled = wants_ids_as_strings(&parts);
+    let res = next.run(Request::from_parts(parts, body)).await;
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(mime::APPLICATION_JSON.as_ref()));
+    if !enabled || !is_json {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("failed to read response body: {}", e);
+            return axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(mut value) => {
+            stringify_ids(&mut value);
+            Body::from(serde_json::to_vec(&value).expect("failed to serialize json"))
+        }
+        Err(_) => Body::from(bytes),
+    };
+    // 長さが変わるため付け直させる
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, body)
+}
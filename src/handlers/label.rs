@@ -1,5 +1,5 @@
 use axum::{
-    extract::{Extension, Path},
+    extract::{Query, State},
     http::StatusCode,
     response::IntoResponse,
     Json,
@@ -9,41 +9,67 @@ use validator::{self, Validate};
 
 use std::sync::Arc;
 
-use crate::repositories::label::LabelRepository;
+use crate::auth::UserId;
+use crate::repositories::label::{LabelQuery, LabelRepository};
 
-use super::ValidatedJson;
+use super::{error_status, LabelId, ListFormat, ListMeta, ValidatedJson};
 
 pub async fn create_label<T: LabelRepository>(
-    Extension(repository): Extension<Arc<T>>,
+    UserId(user_id): UserId,
+    State(repository): State<Arc<T>>,
     ValidatedJson(payload): ValidatedJson<CreateLabel>,
 ) -> Result<impl IntoResponse, StatusCode> {
+    // sharedでなければリクエストしたユーザーの非公開labelになる
+    let owner_id = (!payload.shared).then_some(user_id);
     let label = repository
-        .create(payload.name)
+        .create(owner_id, payload.name)
         .await
-        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+        .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))?;
 
     Ok((StatusCode::CREATED, Json(label)))
 }
 
+// ?qを指定するとnameに部分一致(大文字小文字を区別しない)するlabelだけを返す. 既定はname順で、?sort=idでid順
 pub async fn all_label<T: LabelRepository>(
-    Extension(repository): Extension<Arc<T>>,
+    UserId(user_id): UserId,
+    format: ListFormat,
+    State(repository): State<Arc<T>>,
+    Query(query): Query<LabelQuery>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let labels = repository.all().await.unwrap();
+    let labels = repository
+        .all(user_id, query)
+        .await
+        .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let meta = ListMeta::all(labels.len());
+    Ok(format.respond(labels, meta))
+}
+
+// todoに1つ以上付いているlabelだけをname順で返す
+pub async fn used_label<T: LabelRepository>(
+    UserId(user_id): UserId,
+    State(repository): State<Arc<T>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let labels = repository
+        .used(user_id)
+        .await
+        .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))?;
     Ok((StatusCode::OK, Json(labels)))
 }
 
 pub async fn delete_label<T: LabelRepository>(
-    Extension(repository): Extension<Arc<T>>,
-    Path(id): Path<i32>,
+    UserId(user_id): UserId,
+    State(repository): State<Arc<T>>,
+    LabelId(id): LabelId,
 ) -> StatusCode {
     repository
-        .delete(id)
+        .delete(user_id, id)
         .await
         .map(|_| StatusCode::NO_CONTENT)
-        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+        .unwrap_or_else(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
+#[serde(deny_unknown_fields)]
 pub struct CreateLabel {
     #[validate(length(
         min = 1,
@@ -51,4 +77,7 @@ pub struct CreateLabel {
         message = "At least 1 character and less than 100 characters."
     ))]
     pub name: String,
+    /// trueなら全ユーザーで共有するlabelにする
+    #[serde(default)]
+    pub shared: bool,
 }
@@ -0,0 +1,193 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{header, request::Parts},
+};
+use serde::{Deserialize, Serialize};
+use std::env;
+
+use crate::repositories::todo::TodoEntity;
+
+use super::ListMeta;
+
+/// リバースプロキシが取り除いたパスの先頭を伝えるヘッダー
+pub const FORWARDED_PREFIX_HEADER: &str = "x-forwarded-prefix";
+
+/// Acceptのprofileにこの値を指定するとリンクを付ける
+pub const HATEOAS_PROFILE: &str = "hateoas";
+
+/// 外から見たAPIの先頭のパス(例: /api/v1). リンクの先頭に付ける
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BasePath(String);
+
+impl BasePath {
+    /// 先頭に/を付け、末尾の/を取り除く. 空や/だけなら先頭のパスは無い
+    pub fn new(path: &str) -> Self {
+        match path.trim_matches('/') {
+            "" => Self::default(),
+            path => Self(format!("/{}", path)),
+        }
+    }
+
+    /// 環境変数BASE_PATHから読む. 未設定なら先頭のパスは無い
+    pub fn from_env() -> Self {
+        env::var("BASE_PATH")
+            .map(|path| Self::new(&path))
+            .unwrap_or_default()
+    }
+}
+
+/// HALと同じ形のリンク
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub href: String,
+}
+
+/// todoに付けるリンク
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TodoLinks {
+    #[serde(rename = "self")]
+    pub self_link: Link,
+    pub labels: Link,
+    pub subtasks: Link,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Link>,
+}
+
+/// 一覧に付けるリンク. next, prevはページが無ければNone
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ListLinks {
+    #[serde(rename = "self")]
+    pub self_link: Link,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<Link>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev: Option<Link>,
+}
+
+/// _linksを付けたレスポンスの要素. リンクを付けなければ元のJSONと同じになる
+#[derive(Debug, Serialize)]
+pub struct Linked<T, L> {
+    #[serde(flatten)]
+    pub item: T,
+    #[serde(rename = "_links", skip_serializing_if = "Option::is_none")]
+    pub links: Option<L>,
+}
+
+/// レスポンスに付けるリンクを組み立てる. ?hateoas=trueかAcceptのprofile=hateoasで有効になり、
+/// 無効ならリンクを付けない
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Links {
+    enabled: bool,
+    // X-Forwarded-PrefixとBasePathを繋げたもの
+    prefix: String,
+    path: String,
+    query: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct LinkParams {
+    hateoas: Option<bool>,
+}
+
+impl Links {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn href(&self, path: &str) -> Link {
+        Link {
+            href: format!("{}{}", self.prefix, path),
+        }
+    }
+
+    pub fn todo(&self, todo: TodoEntity) -> Linked<TodoEntity, TodoLinks> {
+        let links = self.enabled.then(|| TodoLinks {
+            self_link: self.href(&format!("/todos/{}", todo.id)),
+            labels: self.href("/labels"),
+            subtasks: self.href(&format!("/todos/{}/subtasks", todo.id)),
+            parent: todo
+                .parent_id
+                .map(|parent_id| self.href(&format!("/todos/{}", parent_id))),
+        });
+        Linked { item: todo, links }
+    }
+
+    pub fn todos(&self, todos: Vec<TodoEntity>) -> Vec<Linked<TodoEntity, TodoLinks>> {
+        todos.into_iter().map(|todo| self.todo(todo)).collect()
+    }
+
+    /// リクエストしたURLのoffsetだけを変えて、前後のページへのリンクを作る
+    pub fn list(&self, meta: &ListMeta) -> Option<ListLinks> {
+        if !self.enabled {
+            return None;
+        }
+        let page = |offset: i64| self.href(&format!("{}?{}", self.path, self.with_offset(offset)));
+        let prev = match meta.limit {
+            Some(limit) if meta.offset > 0 => Some(page((meta.offset - limit).max(0))),
+            _ => None,
+        };
+        let self_link = match self.query.as_str() {
+            "" => self.href(&self.path),
+            query => self.href(&format!("{}?{}", self.path, query)),
+        };
+        Some(ListLinks {
+            self_link,
+            next: meta.next_cursor.map(page),
+            prev,
+        })
+    }
+
+    // クエリのoffsetを置き換える. 他のパラメータはエンコードされたまま残す
+    fn with_offset(&self, offset: i64) -> String {
+        self.query
+            .split('&')
+            .filter(|param| !param.is_empty() && !param.starts_with("offset="))
+            .map(str::to_string)
+            .chain([format!("offset={}", offset)])
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+// Acceptのいずれかのメディアタイプにprofile=hateoasがあるか
+fn accepts_hateoas(parts: &Parts) -> bool {
+    parts
+        .headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split([',', ';']))
+        .filter_map(|param| param.trim().strip_prefix("profile="))
+        .any(|profile| profile.trim_matches('"') == HATEOAS_PROFILE)
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Links {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let params = Query::<LinkParams>::try_from_uri(&parts.uri)
+            .map(|Query(params)| params)
+            .unwrap_or_default();
+        let enabled = params.hateoas == Some(true) || accepts_hateoas(parts);
+        let forwarded = parts
+            .headers
+            .get(FORWARDED_PREFIX_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(BasePath::new)
+            .unwrap_or_default();
+        // BasePathはmainがExtensionとして付ける. 無ければ先頭のパスは無い
+        let base = parts
+            .extensions
+            .get::<BasePath>()
+            .cloned()
+            .unwrap_or_default();
+        Ok(Links {
+            enabled,
+            prefix: format!("{}{}", forwarded.0, base.0),
+            path: parts.uri.path().to_string(),
+            query: parts.uri.query().unwrap_or_default().to_string(),
+        })
+    }
+}
@@ -1,66 +1,403 @@
 use axum::{
-    extract::{Extension, Path},
-    http::StatusCode,
-    response::IntoResponse,
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
-use std::sync::Arc;
+use chrono::Utc;
+use futures_util::{future, stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, sync::Arc};
 
-use crate::repositories::todo::{CreateTodo, TodoRepository, UpdateTodo};
+use crate::auth::UserId;
+use crate::repositories::{
+    todo::{
+        BulkUpdateTodo, CompletionCount, CompletionReportQuery, CreateTodo, TimeseriesQuery,
+        TodoEntity, TodoPage, TodoQuery, TodoRepository, UpdateTodo,
+    },
+    RepositoryError,
+};
 
-use super::ValidatedJson;
+use super::{
+    bad_request, error_status, fields::TodoFields, links::Links, repository_error,
+    unprocessable_entity, with_last_modified, ListFormat, ListMeta, Pagination, TodoId,
+    ValidatedJson,
+};
 
 // todoを作成
 pub async fn create_todo<T: TodoRepository>(
-    Extension(repository): Extension<Arc<T>>,
+    UserId(user_id): UserId,
+    links: Links,
+    State(repository): State<Arc<T>>,
     ValidatedJson(payload): ValidatedJson<CreateTodo>,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<impl IntoResponse, Response> {
     let todo = repository
-        .create(payload)
+        .create(user_id, payload)
         .await
-        .or(Err(StatusCode::NOT_FOUND))?;
+        .map_err(|e| write_error(e, StatusCode::NOT_FOUND))?;
 
-    Ok((StatusCode::CREATED, Json(todo)))
+    Ok((StatusCode::CREATED, Json(links.todo(todo))))
 }
 
-// 指定したidのtodoを取得
+// 作成・更新の失敗をレスポンスにする. 存在しない(または参照できない)labelはどのidかを本文で示す
+fn write_error(e: anyhow::Error, fallback: StatusCode) -> Response {
+    match e.downcast_ref::<RepositoryError>() {
+        Some(error @ RepositoryError::LabelNotAvailable(_)) => {
+            unprocessable_entity(error.to_string())
+        }
+        _ => error_status(e, fallback).into_response(),
+    }
+}
+
+// 指定したidのtodoを取得. If-Modified-Since以降に変更が無ければ304
 pub async fn find_todo<T: TodoRepository>(
-    Path(id): Path<i32>, // pathにi32を含む場合はこのように書くとidを受け取れる
-    Extension(repository): Extension<Arc<T>>,
+    UserId(user_id): UserId,
+    TodoId(id): TodoId, // 範囲外のidはrepositoryに問い合わせずBadRequest
+    links: Links,
+    fields: TodoFields,
+    headers: HeaderMap,
+    State(repository): State<Arc<T>>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository.find(id).await.or(Err(StatusCode::NOT_FOUND))?; // find失敗でNotFound
-    Ok((StatusCode::OK, Json(todo)))
+    let todo = repository
+        .find(user_id, id)
+        .await
+        .map_err(|e| error_status(e, StatusCode::NOT_FOUND))?; // find失敗でNotFound
+    let last_modified = todo.updated_at;
+    let body = Json(fields.project(links.todo(todo)));
+    Ok(with_last_modified(
+        &headers,
+        last_modified,
+        (StatusCode::OK, body),
+    ))
 }
 
-// todoを全て取得しvector型で返す.
+/// GET /todos?ids= で一度に取得できるtodoの数
+pub const MAX_REQUESTED_IDS: usize = 100;
+
+/// GET /todos でlimitを指定しなかった時の件数
+pub const DEFAULT_TODO_LIMIT: i64 = 50;
+
+/// GET /todos のレスポンスに付ける、limit, offsetを除いた条件に当てはまるtodoの件数.
+/// Envelopeのmeta.totalと同じ値. idsを指定した場合は見つかったtodoの数
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/// GET /todos のidsパラメータ. カンマ区切りのid
+#[derive(Debug, Deserialize, Default)]
+pub struct TodoIds {
+    ids: Option<String>,
+}
+
+// クエリ(completed, label, q, sort, limit, offset)で絞り込んだtodoをvector型で返す.
+// idsを指定した場合は他の条件を使わず、そのtodoだけをidsの順に返す. fieldsで返す項目を絞れる
+#[allow(clippy::too_many_arguments)] // extractorごとに引数を取るため
 pub async fn all_todo<T: TodoRepository>(
-    Extension(repository): Extension<Arc<T>>,
+    UserId(user_id): UserId,
+    format: ListFormat,
+    links: Links,
+    fields: TodoFields,
+    pagination: Pagination,
+    State(repository): State<Arc<T>>,
+    Query(mut query): Query<TodoQuery>,
+    Query(TodoIds { ids }): Query<TodoIds>,
+) -> Result<impl IntoResponse, Response> {
+    let internal_error = |e| repository_error(e, StatusCode::INTERNAL_SERVER_ERROR);
+    // limitを指定しなくても全件は返さない. 問い合わせのlimitとしてrepositoryに渡す
+    let limit = pagination.limit(DEFAULT_TODO_LIMIT);
+    query.limit = Some(limit);
+    query.offset = pagination.offset;
+    let (todo, meta) = match ids {
+        Some(ids) => {
+            let ids = parse_ids(&ids).map_err(bad_request)?;
+            let todo = repository
+                .find_many(user_id, &ids)
+                .await
+                .map_err(internal_error)?;
+            let meta = ListMeta::all(todo.len());
+            (todo, meta)
+        }
+        // ページとlimit, offsetを除いた同じ条件の件数をまとめて取得する
+        None => {
+            let TodoPage { todos, total } = repository
+                .page(user_id, query)
+                .await
+                .map_err(internal_error)?;
+            let meta = ListMeta::page(total, Some(limit), pagination.offset(), todos.len());
+            (todos, meta)
+        }
+    };
+    let total = HeaderValue::from(meta.total);
+    let list_links = links.list(&meta);
+    let data = fields.project_all(links.todos(todo));
+    let mut response = format.respond_with_links(data, meta, list_links);
+    response.headers_mut().insert(TOTAL_COUNT_HEADER, total);
+    Ok(response)
+}
+
+// "1,5,9"をidのリストにする. 空の要素は無視する
+fn parse_ids(ids: &str) -> Result<Vec<i32>, String> {
+    let ids = ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .map_err(|_| format!("Invalid ids: [{}] is not a todo id", id))
+        })
+        .collect::<Result<Vec<i32>, String>>()?;
+    if ids.len() > MAX_REQUESTED_IDS {
+        return Err(format!(
+            "Too many ids: at most {} ids can be requested at once",
+            MAX_REQUESTED_IDS
+        ));
+    }
+    Ok(ids)
+}
+
+/// GET /todos/export の書き出し形式
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// 1行に1件のJSON(application/x-ndjson)
+    #[default]
+    Ndjson,
+    /// 見出し行付きのCSV. labelsはlabelの名前を;で繋ぐ
+    Csv,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+/// GET /todos/export のformatパラメータ. 絞り込みは一覧と同じTodoQueryで指定する
+#[derive(Debug, Deserialize, Default)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+const CSV_HEADER: &str =
+    "id,public_id,text,completed,due_date,created_at,completed_at,updated_at,parent_id,labels\r\n";
+
+// カンマ、引用符、改行を含む値は引用符で囲み、引用符を2つにする
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+fn csv_row(todo: &TodoEntity) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let labels: Vec<&str> = todo
+        .labels
+        .iter()
+        .map(|label| label.name.as_str())
+        .collect();
+    let fields = [
+        todo.id.to_string(),
+        todo.public_id.to_string(),
+        csv_field(&todo.text).into_owned(),
+        todo.completed.to_string(),
+        optional(todo.due_date.map(|date| date.to_string())),
+        todo.created_at.to_rfc3339(),
+        optional(todo.completed_at.map(|at| at.to_rfc3339())),
+        todo.updated_at.to_rfc3339(),
+        optional(todo.parent_id.map(|id| id.to_string())),
+        csv_field(&labels.join(";")).into_owned(),
+    ];
+    fields.join(",") + "\r\n"
+}
+
+// クエリ(completed, label, q, sort, limit, offset)に当てはまるtodoを全件書き出す.
+// 一覧と違い全件を読み込んでから返さず、repositoryから届いた順に1件ずつ送る
+pub async fn export_todo<T: TodoRepository>(
+    UserId(user_id): UserId,
+    _pagination: Pagination, // limit, offsetの範囲を確かめる
+    State(repository): State<Arc<T>>,
+    Query(query): Query<TodoQuery>,
+    Query(ExportParams { format }): Query<ExportParams>,
+) -> impl IntoResponse {
+    let todos = repository.all_stream(user_id, query).inspect_err(|e| {
+        // ヘッダーは送った後なので、接続を切ってクライアントに途中で終わったことを知らせる
+        tracing::error!("failed to export todos: {:?}", e);
+    });
+    let body = match format {
+        ExportFormat::Ndjson => todos
+            .map(|todo| {
+                let mut line = serde_json::to_vec(&todo?)?;
+                line.push(b'\n');
+                Ok::<_, anyhow::Error>(line)
+            })
+            .boxed(),
+        ExportFormat::Csv => stream::once(future::ready(Ok(CSV_HEADER.as_bytes().to_vec())))
+            .chain(todos.map_ok(|todo| csv_row(&todo).into_bytes()))
+            .boxed(),
+    };
+    (
+        [(header::CONTENT_TYPE, format.content_type())],
+        Body::from_stream(body),
+    )
+}
+
+// todoの件数をlabelごとの内訳と合わせて集計する
+pub async fn stats_todo<T: TodoRepository>(
+    UserId(user_id): UserId,
+    State(repository): State<Arc<T>>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository.all().await.unwrap();
-    Ok((StatusCode::OK, Json(todo)))
+    let stats = repository
+        .stats(user_id, Utc::now().date_naive())
+        .await
+        .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((StatusCode::OK, Json(stats)))
 }
 
-// todoをupdate
+// 期間内に作成・完了されたtodoの件数を日または週ごとに返す
+pub async fn timeseries_todo<T: TodoRepository>(
+    UserId(user_id): UserId,
+    State(repository): State<Arc<T>>,
+    Query(query): Query<TimeseriesQuery>,
+) -> Result<impl IntoResponse, Response> {
+    // 範囲が不正なら、その理由を本文にしたBadRequest
+    let points = repository
+        .timeseries(user_id, query)
+        .await
+        .map_err(|e| repository_error(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((StatusCode::OK, Json(points)))
+}
+
+// 期間内に完了したtodoの件数を日ごとに返す. 完了の無い日も0件で含む
+pub async fn completion_report_todo<T: TodoRepository>(
+    UserId(user_id): UserId,
+    State(repository): State<Arc<T>>,
+    Query(query): Query<CompletionReportQuery>,
+) -> Result<impl IntoResponse, Response> {
+    let counts: Vec<CompletionCount> = repository
+        .timeseries(user_id, query.into())
+        .await
+        .map_err(|e| repository_error(e, StatusCode::INTERNAL_SERVER_ERROR))?
+        .into_iter()
+        .map(CompletionCount::from)
+        .collect();
+    Ok((StatusCode::OK, Json(counts)))
+}
+
+// todoをupdate. application/merge-patch+jsonとしても受け付け、nullの項目は消す
 pub async fn update_todo<T: TodoRepository>(
-    Path(id): Path<i32>,
-    Extension(repository): Extension<Arc<T>>,
+    UserId(user_id): UserId,
+    TodoId(id): TodoId,
+    links: Links,
+    State(repository): State<Arc<T>>,
     ValidatedJson(payload): ValidatedJson<UpdateTodo>,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<impl IntoResponse, Response> {
     let todo = repository
-        .update(id, payload)
+        .update(user_id, id, payload)
         .await
-        .or(Err(StatusCode::NOT_FOUND))?; // update失敗でNotFound
-    Ok((StatusCode::CREATED, Json(todo)))
+        .map_err(|e| write_error(e, StatusCode::NOT_FOUND))?; // update失敗でNotFound
+    Ok((StatusCode::CREATED, Json(links.todo(todo))))
+}
+
+/// PATCH /todos のレスポンス
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BulkUpdated {
+    /// 変更したtodoの数. 既にsetと同じ値だったtodoは数えない
+    pub updated: usize,
+}
+
+// クエリ(completed, label, q)で絞り込んだtodoをまとめて変更し、変更した件数を返す.
+// 誤って全てのtodoを変えないよう、絞り込みの無いリクエストは400にする
+pub async fn bulk_update_todo<T: TodoRepository>(
+    UserId(user_id): UserId,
+    State(repository): State<Arc<T>>,
+    Query(filter): Query<TodoQuery>,
+    ValidatedJson(set): ValidatedJson<BulkUpdateTodo>,
+) -> Result<impl IntoResponse, Response> {
+    if !filter.has_filter() {
+        return Err(bad_request(
+            "A filter (completed, label or q) is required to update todos in bulk".to_string(),
+        ));
+    }
+    if filter.limit.is_some() || filter.offset.is_some() {
+        return Err(bad_request(
+            "limit and offset cannot be used to update todos in bulk".to_string(),
+        ));
+    }
+    let todos = repository
+        .update_where(user_id, filter, set)
+        .await
+        .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR).into_response())?;
+    Ok((
+        StatusCode::OK,
+        Json(BulkUpdated {
+            updated: todos.len(),
+        }),
+    ))
+}
+
+// todoのsubtaskをid順に返す. 孫以下は含まない
+pub async fn all_subtask<T: TodoRepository>(
+    UserId(user_id): UserId,
+    TodoId(id): TodoId,
+    format: ListFormat,
+    links: Links,
+    fields: TodoFields,
+    State(repository): State<Arc<T>>,
+) -> Result<impl IntoResponse, Response> {
+    // 親が無ければ、そのidを本文にしたNotFound
+    let todos = repository
+        .subtasks(user_id, id)
+        .await
+        .map_err(|e| repository_error(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let meta = ListMeta::all(todos.len());
+    let list_links = links.list(&meta);
+    let data = fields.project_all(links.todos(todos));
+    Ok(format.respond_with_links(data, meta, list_links))
+}
+
+// todoへの直前の変更を取り消し、取り消しの記録を返す. 取り消せる変更が無ければ理由を添えてConflict
+pub async fn undo_todo<T: TodoRepository>(
+    UserId(user_id): UserId,
+    TodoId(id): TodoId,
+    State(repository): State<Arc<T>>,
+) -> Result<impl IntoResponse, Response> {
+    let event = repository
+        .undo(user_id, id)
+        .await
+        .map_err(|e| repository_error(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((StatusCode::OK, Json(event)))
+}
+
+/// DELETE /todos/:id のクエリパラメータ
+#[derive(Debug, Deserialize, Default)]
+pub struct DeleteParams {
+    /// trueなら削除せず、削除されるものを返す
+    #[serde(default)]
+    dry_run: bool,
 }
 
-// todoを削除
+// todoを削除. dry_run=trueなら削除せずに、削除されるtodoと関連する行の数を200で返す
 pub async fn delete_todo<T: TodoRepository>(
-    Path(id): Path<i32>,
-    Extension(repository): Extension<Arc<T>>,
-) -> StatusCode {
+    UserId(user_id): UserId,
+    TodoId(id): TodoId,
+    State(repository): State<Arc<T>>,
+    Query(DeleteParams { dry_run }): Query<DeleteParams>,
+) -> Response {
+    if dry_run {
+        return match repository.preview_delete(user_id, id).await {
+            Ok(preview) => (StatusCode::OK, Json(preview)).into_response(),
+            Err(e) => error_status(e, StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+        };
+    }
     repository
-        .delete(id) // return -> Result<()>
+        .delete(user_id, id) // return -> Result<()>
         .await
         .map(|_| StatusCode::NO_CONTENT) // 戻り値のハンドリング
-        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR) // 戻り値のハンドリング
+        .unwrap_or_else(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR)) // 戻り値のハンドリング
+        .into_response()
 }
@@ -0,0 +1,19 @@
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+/// GET /version のレスポンス. git_shaとbuild_timeはbuild.rsで埋め込む
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub version: String,
+    pub git_sha: String,
+    pub build_time: String,
+}
+
+// 動いているビルドを確認するためのバージョン情報
+pub async fn version() -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: env!("GIT_SHA").to_string(),
+        build_time: env!("BUILD_TIME").to_string(),
+    })
+}
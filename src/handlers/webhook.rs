@@ -0,0 +1,91 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use std::sync::Arc;
+
+use crate::auth::UserId;
+use crate::repositories::webhook::{CreateWebhook, UpdateWebhook, WebhookRepository};
+
+use super::{error_status, ListFormat, ListMeta, ValidatedJson, WebhookId};
+
+// webhookを登録. secretを返すのはこのレスポンスだけ
+pub async fn create_webhook<T: WebhookRepository>(
+    UserId(user_id): UserId,
+    State(repository): State<Arc<T>>,
+    ValidatedJson(payload): ValidatedJson<CreateWebhook>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let webhook = repository
+        .create(user_id, payload)
+        .await
+        .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+pub async fn find_webhook<T: WebhookRepository>(
+    UserId(user_id): UserId,
+    WebhookId(id): WebhookId,
+    State(repository): State<Arc<T>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let webhook = repository
+        .find(user_id, id)
+        .await
+        .map_err(|e| error_status(e, StatusCode::NOT_FOUND))?;
+    Ok((StatusCode::OK, Json(webhook.masked())))
+}
+
+pub async fn all_webhook<T: WebhookRepository>(
+    UserId(user_id): UserId,
+    format: ListFormat,
+    State(repository): State<Arc<T>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let webhooks = repository
+        .all(user_id)
+        .await
+        .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let webhooks: Vec<_> = webhooks
+        .into_iter()
+        .map(|webhook| webhook.masked())
+        .collect();
+    let meta = ListMeta::all(webhooks.len());
+    Ok(format.respond(webhooks, meta))
+}
+
+pub async fn update_webhook<T: WebhookRepository>(
+    UserId(user_id): UserId,
+    WebhookId(id): WebhookId,
+    State(repository): State<Arc<T>>,
+    ValidatedJson(payload): ValidatedJson<UpdateWebhook>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let webhook = repository
+        .update(user_id, id, payload)
+        .await
+        .map_err(|e| error_status(e, StatusCode::NOT_FOUND))?;
+    Ok((StatusCode::OK, Json(webhook.masked())))
+}
+
+// webhookへの配信記録を新しい順に返す
+pub async fn all_delivery<T: WebhookRepository>(
+    UserId(user_id): UserId,
+    WebhookId(id): WebhookId,
+    format: ListFormat,
+    State(repository): State<Arc<T>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let deliveries = repository
+        .deliveries(user_id, id)
+        .await
+        .map_err(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let meta = ListMeta::all(deliveries.len());
+    Ok(format.respond(deliveries, meta))
+}
+
+// 削除したwebhookには以降配信されない
+pub async fn delete_webhook<T: WebhookRepository>(
+    UserId(user_id): UserId,
+    WebhookId(id): WebhookId,
+    State(repository): State<Arc<T>>,
+) -> StatusCode {
+    repository
+        .delete(user_id, id)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .unwrap_or_else(|e| error_status(e, StatusCode::INTERNAL_SERVER_ERROR))
+}
@@ -0,0 +1,246 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::HeaderMap,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::Instant,
+};
+
+use crate::audit;
+use crate::auth::{bearer_token, AuthConfig, USER_ID_HEADER};
+use crate::repositories::{
+    activity::{ActivityRepository, TodoEvent},
+    todo::{TodoEntity, TodoRepository, UpdateTodo},
+};
+
+/// クライアントにpingを送る間隔
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// この間クライアントから何も届かなければ(pongを含む)切断する
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// GET /ws のState. 変更の配信にactivity、接続時の認証にAuthConfigを使う
+pub struct WsState<T, A> {
+    pub todos: Arc<T>,
+    pub activity: Arc<A>,
+    pub auth: Arc<AuthConfig>,
+}
+
+// deriveではT, AにもCloneを要求してしまうため手で実装する
+impl<T, A> Clone for WsState<T, A> {
+    fn clone(&self) -> Self {
+        Self {
+            todos: self.todos.clone(),
+            activity: self.activity.clone(),
+            auth: self.auth.clone(),
+        }
+    }
+}
+
+/// GET /ws のクエリパラメータ. ブラウザはヘッダーを付けられないためクエリでも認証できる
+#[derive(Debug, Deserialize, Default)]
+pub struct WsParams {
+    token: Option<String>,
+    user_id: Option<i32>,
+}
+
+/// クライアントから送られるコマンド
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Command {
+    /// 接続時に認証しなかった場合に最初に送る.
+    /// ユーザーごとのトークンを使う場合、user_idは省略できる
+    Auth {
+        token: Option<String>,
+        #[serde(default)]
+        user_id: Option<i32>,
+    },
+    /// 完了状態を反転する
+    Toggle {
+        id: i32,
+    },
+    Delete {
+        id: i32,
+    },
+}
+
+/// サーバーから送るメッセージ
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Authenticated {
+        user_id: i32,
+    },
+    /// 認証したユーザーのtodoへの変更. 他の接続やREST APIからの変更も含む
+    Event {
+        event: TodoEvent,
+    },
+    /// コマンドの結果. deleteではtodoがNone
+    Ok {
+        todo: Option<TodoEntity>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+// WebSocketに切り替え、変更の配信とコマンドの受け付けを行う.
+// 認証は接続時のクエリ/ヘッダーか最初のauthメッセージで行う
+pub async fn ws_todo<T: TodoRepository, A: ActivityRepository>(
+    upgrade: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(params): Query<WsParams>,
+    State(WsState {
+        todos,
+        activity,
+        auth,
+    }): State<WsState<T, A>>,
+) -> Response {
+    let token = params.token.as_deref().or(bearer_token(&headers));
+    let claimed = params.user_id.or_else(|| {
+        headers
+            .get(USER_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    });
+    let user_id = auth
+        .accepts(token)
+        .then(|| auth.user_id(token, claimed).ok())
+        .flatten();
+    // 切り替え前に購読して、接続直後の変更も取りこぼさない
+    let events = activity.subscribe();
+    // 接続後のコマンドによる変更は、接続したリクエストのidで監査ログに残す
+    let request_id = audit::request_id(&headers);
+    upgrade.on_upgrade(move |socket| {
+        audit::with_request_id(request_id, serve(socket, todos, auth, events, user_id))
+    })
+}
+
+async fn serve<T: TodoRepository>(
+    mut socket: WebSocket,
+    repository: Arc<T>,
+    auth: Arc<AuthConfig>,
+    mut events: broadcast::Receiver<TodoEvent>,
+    mut user_id: Option<i32>,
+) {
+    if let Some(user_id) = user_id {
+        if send(&mut socket, &ServerMessage::Authenticated { user_id })
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let mut last_seen = Instant::now();
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => {
+                let Some(Ok(message)) = message else { break };
+                last_seen = Instant::now();
+                match message {
+                    Message::Text(text) => {
+                        Some(handle_command(&*repository, &auth, &mut user_id, &text).await)
+                    }
+                    Message::Close(_) => break,
+                    // pingへの応答はaxumが行う
+                    _ => None,
+                }
+            }
+            event = events.recv() => match event {
+                Ok(event) if Some(event.actor_id) == user_id => Some(ServerMessage::Event { event }),
+                Ok(_) => None,
+                Err(RecvError::Lagged(skipped)) => Some(ServerMessage::Error {
+                    message: format!("Missed {} events", skipped),
+                }),
+                Err(RecvError::Closed) => break,
+            },
+            _ = ping.tick() => {
+                if last_seen.elapsed() >= IDLE_TIMEOUT {
+                    break;
+                }
+                if socket.send(Message::Ping(vec![])).await.is_err() {
+                    break;
+                }
+                None
+            }
+        };
+        if let Some(reply) = reply {
+            if send(&mut socket, &reply).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+// コマンドを1つ処理し、その結果を返す. 失敗してもエラーを返すだけで接続は続ける
+async fn handle_command<T: TodoRepository>(
+    repository: &T,
+    auth: &AuthConfig,
+    user_id: &mut Option<i32>,
+    text: &str,
+) -> ServerMessage {
+    let command = match serde_json::from_str::<Command>(text) {
+        Ok(command) => command,
+        Err(e) => {
+            return ServerMessage::Error {
+                message: format!("Invalid command: [{}]", e),
+            }
+        }
+    };
+    let result = match (command, *user_id) {
+        (Command::Auth { token, user_id: id }, _) => {
+            let token = token.as_deref();
+            if !auth.accepts(token) {
+                return ServerMessage::Error {
+                    message: "Invalid token".to_string(),
+                };
+            }
+            return match auth.user_id(token, id) {
+                Ok(id) => {
+                    *user_id = Some(id);
+                    ServerMessage::Authenticated { user_id: id }
+                }
+                Err(e) => ServerMessage::Error {
+                    message: e.to_string(),
+                },
+            };
+        }
+        (_, None) => {
+            return ServerMessage::Error {
+                message: "Not authenticated".to_string(),
+            }
+        }
+        (Command::Toggle { id }, Some(user_id)) => {
+            async {
+                let todo = repository.find(user_id, id).await?;
+                let todo = repository
+                    .update(user_id, id, UpdateTodo::completion(!todo.completed))
+                    .await?;
+                Ok::<_, anyhow::Error>(Some(todo))
+            }
+            .await
+        }
+        (Command::Delete { id }, Some(user_id)) => {
+            repository.delete(user_id, id).await.map(|_| None)
+        }
+    };
+    match result {
+        Ok(todo) => ServerMessage::Ok { todo },
+        Err(e) => ServerMessage::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).expect("failed to serialize message");
+    socket.send(Message::Text(text)).await
+}
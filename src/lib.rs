@@ -0,0 +1,4135 @@
+pub mod audit;
+pub mod auth;
+pub mod graphql;
+pub mod grpc;
+pub mod handlers;
+pub mod reminders;
+pub mod repositories;
+pub mod seed;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod webhooks;
+
+use crate::repositories::{
+    activity::ActivityRepository, todo::TodoRepository, webhook::WebhookRepository,
+};
+use auth::{require_bearer_token, AuthConfig, USER_ID_HEADER};
+use axum::{
+    middleware,
+    routing::{delete, get, post},
+    Extension, Router,
+};
+use handlers::{
+    activity::all_activity,
+    allowed_methods,
+    cache::cache_control,
+    catch_panic,
+    debug::debug_pool,
+    graphql::{graphiql, graphql},
+    health::{health, ready},
+    ids::ids_as_strings,
+    label::{all_label, create_label, delete_label, used_label},
+    method_not_allowed, require_json,
+    todo::{
+        all_subtask, all_todo, bulk_update_todo, completion_report_todo, create_todo, delete_todo,
+        export_todo, find_todo, stats_todo, timeseries_todo, undo_todo, update_todo,
+    },
+    version::version,
+    webhook::{
+        all_delivery, all_webhook, create_webhook, delete_webhook, find_webhook, update_webhook,
+    },
+    ws::{ws_todo, WsState},
+    ListFormat,
+};
+use hyper::header::{HeaderName, AUTHORIZATION, CONTENT_TYPE};
+use repositories::label::LabelRepository;
+use std::{sync::Arc, time::Duration};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    cors::{AllowOrigin, Any, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    timeout::TimeoutLayer,
+};
+
+/// これより小さいレスポンスは圧縮しても効果が薄いのでそのまま返す
+const COMPRESSION_MIN_SIZE: u16 = 1024;
+
+/// 1リクエストの処理の上限時間. 超えると408を返す
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// # create_app
+/// This function create app and define routing
+///
+/// ## argumentation
+/// * repository: something that is impl TodoRepository
+/// * list_format: 一覧のendpointを配列のまま返すか、dataとmetaを持つオブジェクトで返すか
+///
+/// ## Return
+/// * app route: Router
+pub fn create_app<
+    Todo: TodoRepository,
+    Label: LabelRepository,
+    Activity: ActivityRepository,
+    Webhook: WebhookRepository,
+>(
+    todo_repository: Todo,
+    label_repository: Label,
+    activity_repository: Activity,
+    webhook_repository: Webhook,
+    auth: AuthConfig,
+    request_timeout: Duration,
+    list_format: ListFormat,
+) -> Router {
+    let auth = Arc::new(auth);
+    let todos = Arc::new(todo_repository);
+    let schema = graphql::schema(todos.clone(), label_repository.clone());
+    let labels = Arc::new(label_repository);
+    let activity = Arc::new(activity_repository);
+    let webhooks = Arc::new(webhook_repository);
+    // GraphiQLは開発ビルドでのみ公開する
+    let graphql_route = if cfg!(debug_assertions) {
+        post(graphql::<Arc<Todo>, Label>).get(graphiql)
+    } else {
+        post(graphql::<Arc<Todo>, Label>)
+    };
+    // 各routeには使うrepositoryだけをStateとして渡す
+    let router = Router::new()
+        .route("/", get(root))
+        .route("/version", get(version))
+        .route(
+            "/todos",
+            post(create_todo::<Todo>)
+                .get(all_todo::<Todo>)
+                .patch(bulk_update_todo::<Todo>)
+                .with_state(todos.clone()),
+        )
+        .route(
+            "/todos/export",
+            get(export_todo::<Todo>).with_state(todos.clone()),
+        )
+        .route(
+            "/todos/stats",
+            get(stats_todo::<Todo>).with_state(todos.clone()),
+        )
+        .route(
+            "/todos/stats/timeseries",
+            get(timeseries_todo::<Todo>).with_state(todos.clone()),
+        )
+        .route(
+            "/todos/report/completions",
+            get(completion_report_todo::<Todo>).with_state(todos.clone()),
+        )
+        .route(
+            "/todos/:id",
+            get(find_todo::<Todo>)
+                .delete(delete_todo::<Todo>)
+                .patch(update_todo::<Todo>)
+                .with_state(todos.clone()),
+        )
+        .route(
+            "/todos/:id/undo",
+            post(undo_todo::<Todo>).with_state(todos.clone()),
+        )
+        .route(
+            "/todos/:id/subtasks",
+            get(all_subtask::<Todo>).with_state(todos.clone()),
+        )
+        .route(
+            "/labels",
+            post(create_label::<Label>)
+                .get(all_label::<Label>)
+                .with_state(labels.clone()),
+        )
+        .route(
+            "/labels/used",
+            get(used_label::<Label>).with_state(labels.clone()),
+        )
+        .route(
+            "/labels/:id",
+            delete(delete_label::<Label>).with_state(labels),
+        )
+        .route(
+            "/activity",
+            get(all_activity::<Activity>).with_state(activity.clone()),
+        )
+        .route(
+            "/webhooks",
+            post(create_webhook::<Webhook>)
+                .get(all_webhook::<Webhook>)
+                .with_state(webhooks.clone()),
+        )
+        .route(
+            "/webhooks/:id",
+            get(find_webhook::<Webhook>)
+                .patch(update_webhook::<Webhook>)
+                .delete(delete_webhook::<Webhook>)
+                .with_state(webhooks.clone()),
+        )
+        .route(
+            "/webhooks/:id/deliveries",
+            get(all_delivery::<Webhook>).with_state(webhooks),
+        )
+        // GraphQLのidはschemaの型のまま返すため、ここまでのrouteにだけ付ける
+        .layer(middleware::from_fn(ids_as_strings))
+        .route("/graphql", graphql_route.with_state(schema));
+    // 接続プールの状態は開発ビルドでのみ公開する
+    let router = if cfg!(debug_assertions) {
+        router.route("/debug/pool", get(debug_pool))
+    } else {
+        router
+    };
+    // panicした時の応答を確かめるためのroute
+    #[cfg(test)]
+    let router = router.route("/panic", get(panic_handler));
+    router
+        .layer(Extension(list_format))
+        // 認証の後、本文を読む前にContent-Typeを確かめる
+        .layer(middleware::from_fn(require_json))
+        .layer(middleware::from_fn_with_state(
+            auth.clone(),
+            require_bearer_token,
+        ))
+        // ロードバランサーやオーケストレーターがトークン無しで確かめられるよう、認証の外に置く
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        // WebSocketは接続ごとに認証するため、ヘッダーによる認証の外に置く
+        .route(
+            "/ws",
+            get(ws_todo::<Todo, Activity>).with_state(WsState {
+                todos,
+                activity,
+                auth,
+            }),
+        )
+        .layer(middleware::from_fn(method_not_allowed))
+        .layer(middleware::from_fn(catch_panic))
+        // 監査ログにリクエストのidを残す. idを振る下のlayerの内側に置く
+        .layer(middleware::from_fn(audit::scope_request_id))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        // DBの問い合わせ以外で止まったハンドラーも含めて打ち切る
+        .layer(TimeoutLayer::new(request_timeout))
+        // 打ち切りやpanicの応答も含めて全てのレスポンスにCache-Controlを付ける
+        .layer(middleware::from_fn(cache_control))
+        // Accept-Encodingに応じてgzip/brで圧縮する
+        .layer(
+            CompressionLayer::new()
+                .compress_when(DefaultPredicate::new().and(SizeAbove::new(COMPRESSION_MIN_SIZE))),
+        )
+        .layer(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::exact("http://localhost:3001".parse().unwrap()))
+                .allow_methods(Any)
+                .allow_headers(vec![
+                    CONTENT_TYPE,
+                    AUTHORIZATION,
+                    HeaderName::from_static(USER_ID_HEADER),
+                ]),
+        )
+        .layer(middleware::from_fn(allowed_methods))
+}
+
+#[cfg(test)]
+async fn panic_handler() -> &'static str {
+    panic!("test panic")
+}
+
+async fn root() -> &'static str {
+    "Hello, world!"
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::cache::CacheControl;
+    use crate::handlers::links::{BasePath, FORWARDED_PREFIX_HEADER};
+    use crate::handlers::version::Version;
+    use crate::handlers::ws::ServerMessage;
+    use crate::handlers::{panics_total, REQUEST_ID_HEADER};
+    use crate::repositories::{
+        activity::{
+            memory::ActivityRepositoryForMemory, ActivityQuery, ActivityRepository, TodoEvent,
+            TodoEventKind,
+        },
+        cache::CachedTodoRepository,
+        flaky::{self, FlakyTodoRepository},
+        label::{memory::LabelRepositoryForMemory, Label},
+        todo::{
+            memory::TodoRepositoryForMemory,
+            test_utils::{test_now, TEST_USER_ID},
+            BulkUpdateTodo, CreateTodo, DeletePreview, DynTodoRepository, TimeseriesPoint,
+            TimeseriesQuery, TodoEntity, TodoQuery, TodoStats, UpdateTodo,
+        },
+        webhook::{memory::WebhookRepositoryForMemory, Webhook},
+        with_timeout, RepositoryError,
+    };
+    use crate::test_support;
+    use axum::async_trait;
+    use axum::response::Response;
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use chrono::{DateTime, NaiveDate, Utc};
+    use futures_util::{
+        stream::{self, BoxStream},
+        SinkExt, StreamExt,
+    };
+    use sqlx::postgres::PgPoolOptions;
+    use std::collections::HashMap;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    // requestをbuildして作成
+    fn build_todo_req_with_json(path: &str, method: Method, json_body: String) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .method(method)
+            .header(USER_ID_HEADER, TEST_USER_ID)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(json_body))
+            .unwrap()
+    }
+
+    fn build_label_req_with_json(path: &str, method: Method, json_body: String) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .method(method)
+            .header(USER_ID_HEADER, TEST_USER_ID)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(json_body))
+            .unwrap()
+    }
+
+    // response作成
+    fn build_todo_req_with_empty(method: Method, path: &str) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .method(method)
+            .header(USER_ID_HEADER, TEST_USER_ID)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn build_label_req_with_empty(method: Method, path: &str) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .method(method)
+            .header(USER_ID_HEADER, TEST_USER_ID)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn res_to_todo(res: Response) -> TodoEntity {
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let todo: TodoEntity = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Todo instance. body: {}", body));
+        todo
+    }
+
+    async fn res_to_label(res: Response) -> Label {
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let label: Label = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Label instance. body: {}", body));
+        label
+    }
+
+    fn label_fixture() -> (Vec<Label>, Vec<i32>) {
+        let id = 999;
+        (
+            vec![Label {
+                id,
+                name: String::from("test label"),
+                owner_id: None,
+            }],
+            vec![id],
+        )
+    }
+
+    #[tokio::test]
+    async fn should_created_todo() {
+        let (labels, _label_ids) = label_fixture();
+        let expected = TodoEntity::new(1, "should_return_created_todo".to_string(), labels.clone());
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "should_return_created_todo", "labels": [999] }"#.to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::for_test(labels),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(expected, todo);
+    }
+
+    #[tokio::test]
+    async fn should_write_audit_event_for_each_mutation() {
+        use crate::audit::AUDIT_TARGET;
+        use std::{collections::HashMap, fmt, sync::Mutex};
+        use tracing::{field::Field, subscriber::set_default, Event, Subscriber};
+        use tracing_subscriber::{field::Visit, layer::Context, prelude::*, Layer};
+
+        // auditのeventのフィールドを文字列にして集める
+        #[derive(Clone, Default)]
+        struct AuditEvents(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+        struct Fields<'a>(&'a mut HashMap<String, String>);
+
+        impl Visit for Fields<'_> {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.insert(field.name().to_string(), value.to_string());
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0
+                    .insert(field.name().to_string(), format!("{:?}", value));
+            }
+        }
+
+        impl<S: Subscriber> Layer<S> for AuditEvents {
+            fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+                if event.metadata().target() == AUDIT_TARGET {
+                    let mut fields = HashMap::new();
+                    event.record(&mut Fields(&mut fields));
+                    self.0.lock().unwrap().push(fields);
+                }
+            }
+        }
+
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let events = AuditEvents::default();
+        let _guard = set_default(tracing_subscriber::registry().with(events.clone()));
+
+        let mut req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "audited", "labels": [] }"#.to_string(),
+        );
+        req.headers_mut()
+            .insert(REQUEST_ID_HEADER, "audit-request".parse().unwrap());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        let recorded = events.0.lock().unwrap().clone();
+        assert_eq!(recorded.len(), 1);
+        let event = &recorded[0];
+        assert_eq!(event["message"], "todo create");
+        assert_eq!(event["action"], "create");
+        assert_eq!(event["todo_id"], "1");
+        assert_eq!(event["user_id"], TEST_USER_ID.to_string());
+        assert_eq!(event["request_id"], "audit-request");
+        assert_eq!(event["before"], "-");
+        assert_eq!(
+            event["after"],
+            r#"{"text":"audited","completed":false,"labels":[],"due_date":null,"parent_id":null}"#
+        );
+        assert!(DateTime::parse_from_rfc3339(&event["timestamp"]).is_ok());
+
+        // 失敗した変更は記録しない
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "audited", "labels": [404] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        assert_eq!(events.0.lock().unwrap().len(), 1);
+
+        // REST以外からの変更も同じく残り、更新では変わった項目だけを要約する
+        let mut req = build_todo_req_with_json(
+            "/graphql",
+            Method::POST,
+            r#"{ "query": "mutation { updateTodo(id: 1, input: { completed: true }) { id } }" }"#
+                .to_string(),
+        );
+        req.headers_mut()
+            .insert(REQUEST_ID_HEADER, "graphql-request".parse().unwrap());
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let recorded = events.0.lock().unwrap().clone();
+        assert_eq!(recorded.len(), 2);
+        let event = &recorded[1];
+        assert_eq!(event["action"], "update");
+        assert_eq!(event["request_id"], "graphql-request");
+        assert_eq!(
+            event["before"],
+            r#"{"completed":false,"completed_at":null}"#
+        );
+        assert_eq!(
+            event["after"],
+            r#"{"completed":true,"completed_at":"2024-01-01T00:00:00Z"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn should_created_label() {
+        let (labels, _label_ids) = label_fixture();
+        let expected =
+            Label::new(1, "should_return_created_label".to_string()).owned_by(TEST_USER_ID);
+
+        let req = build_label_req_with_json(
+            "/labels",
+            Method::POST,
+            r#"{ "name": "should_return_created_label" }"#.to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::for_test(labels),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let label = res_to_label(res).await;
+        assert_eq!(expected, label);
+    }
+
+    #[tokio::test]
+    async fn should_find_todo() {
+        let (labels, label_ids) = label_fixture();
+        let expected = TodoEntity::new(1, "should_find_todo".to_string(), labels.clone());
+
+        let todo_repository = TodoRepositoryForMemory::for_test(labels.clone());
+        todo_repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("should_find_todo".to_string(), label_ids),
+            )
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(expected, todo);
+    }
+
+    #[tokio::test]
+    async fn should_serve_todos_from_backend_chosen_at_runtime() {
+        #[allow(unused_mut)]
+        let mut backends: Vec<DynTodoRepository> = vec![
+            Arc::new(TodoRepositoryForMemory::for_test(vec![])),
+            Arc::new(CachedTodoRepository::new(
+                TodoRepositoryForMemory::for_test(vec![]),
+                Duration::from_secs(60),
+            )),
+        ];
+        // schemaはdatabaseのdropで消えるので、backendsを使い終わるまで残す
+        #[cfg(feature = "database-test")]
+        let database = crate::repositories::test_database::db_pool_or_skip().await;
+        #[cfg(feature = "database-test")]
+        if let Some((_, pool)) = &database {
+            use crate::repositories::todo::TodoRepositoryForDb;
+            backends.push(Arc::new(TodoRepositoryForDb::new(pool.clone())));
+        }
+
+        for todo_repository in backends {
+            let app = create_app(
+                todo_repository,
+                LabelRepositoryForMemory::new(),
+                ActivityRepositoryForMemory::new(),
+                WebhookRepositoryForMemory::new(),
+                AuthConfig::default(),
+                DEFAULT_REQUEST_TIMEOUT,
+                ListFormat::Bare,
+            );
+            let req = build_todo_req_with_json(
+                "/todos",
+                Method::POST,
+                r#"{ "text": "dyn backend", "labels": [] }"#.to_string(),
+            );
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+            let created = res_to_todo(res).await;
+
+            let path = format!("/todos/{}", created.id);
+            let req = build_todo_req_with_empty(Method::GET, &path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(created, res_to_todo(res).await);
+
+            let req = build_todo_req_with_empty(Method::DELETE, &path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::NO_CONTENT, res.status());
+            // キャッシュするrepositoryでも削除したtodoは返さない
+            let req = build_todo_req_with_empty(Method::GET, &path);
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::NOT_FOUND, res.status());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_return_method_not_allowed_with_allow_header() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "allowed", "labels": [] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        for (method, path, allowed) in [
+            (Method::PUT, "/todos", vec!["GET", "HEAD", "PATCH", "POST"]),
+            (
+                Method::POST,
+                "/todos/1",
+                vec!["DELETE", "GET", "HEAD", "PATCH"],
+            ),
+        ] {
+            let req = build_todo_req_with_empty(method.clone(), path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status(), "{}", path);
+            let mut allow: Vec<&str> = res.headers()[header::ALLOW]
+                .to_str()
+                .unwrap()
+                .split(',')
+                .map(str::trim)
+                .collect();
+            allow.sort();
+            assert_eq!(allow, allowed, "{}", path);
+
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(
+                body,
+                serde_json::json!({ "error": format!("Method [{}] is not allowed", method) })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_answer_options_with_allowed_methods() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let allow = |res: &Response| {
+            let mut allow: Vec<String> = res.headers()[header::ALLOW]
+                .to_str()
+                .unwrap()
+                .split(',')
+                .map(|method| method.trim().to_string())
+                .collect();
+            allow.sort();
+            allow
+        };
+
+        for (path, allowed) in [
+            ("/todos", vec!["GET", "HEAD", "PATCH", "POST"]),
+            ("/todos/1", vec!["DELETE", "GET", "HEAD", "PATCH"]),
+        ] {
+            let req = build_todo_req_with_empty(Method::OPTIONS, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::NO_CONTENT, res.status(), "{}", path);
+            assert_eq!(allow(&res), allowed, "{}", path);
+        }
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status());
+        assert_eq!(allow(&res), vec!["GET", "HEAD", "PATCH", "POST"]);
+
+        // CORSのpreflightはこれまで通りCorsLayerが応答する
+        let req = Request::builder()
+            .uri("/todos")
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, "http://localhost:3001")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, USER_ID_HEADER)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+        // ブラウザからX-User-Id付きで呼べるように許可する
+        let allowed = res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_HEADERS)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(allowed.split(',').any(|name| name.trim() == USER_ID_HEADER));
+    }
+
+    #[tokio::test]
+    async fn should_answer_options_as_read_when_token_is_required() {
+        let app = |protect_reads| {
+            create_app(
+                TodoRepositoryForMemory::for_test(vec![]),
+                LabelRepositoryForMemory::new(),
+                ActivityRepositoryForMemory::new(),
+                WebhookRepositoryForMemory::new(),
+                token_auth(protect_reads),
+                DEFAULT_REQUEST_TIMEOUT,
+                ListFormat::Bare,
+            )
+        };
+        let options = || build_todo_req_with_empty(Method::OPTIONS, "/todos");
+
+        // OPTIONSは参照系なので、書き込みにだけトークンを要求する設定ではトークン無しで答える
+        let res = app(false).oneshot(options()).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+        assert!(res.headers().contains_key(header::ALLOW));
+
+        // 参照系にもトークンを要求する設定では、GETと同じく401になる
+        let res = app(true).oneshot(options()).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+        let mut req = options();
+        req.headers_mut()
+            .insert(AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let res = app(true).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_reject_out_of_range_path_ids_with_json_400() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "in range", "labels": [] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let todo = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+        assert_eq!(todo.text, "in range");
+
+        // 範囲外のidはrepositoryに問い合わせる前に400になる
+        for (method, path, id) in [
+            (Method::GET, "/todos/0", "0"),
+            (Method::GET, "/todos/-5", "-5"),
+            (Method::GET, "/todos/99999999999", "99999999999"),
+            (
+                Method::GET,
+                "/todos/99999999999999999999",
+                "99999999999999999999",
+            ),
+            (
+                Method::PATCH,
+                "/todos/-99999999999999999999",
+                "-99999999999999999999",
+            ),
+            (Method::DELETE, "/labels/0", "0"),
+            (
+                Method::DELETE,
+                "/labels/18446744073709551616",
+                "18446744073709551616",
+            ),
+            (Method::GET, "/webhooks/-1", "-1"),
+        ] {
+            let req = build_todo_req_with_empty(method, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", path);
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(
+                body,
+                serde_json::json!({
+                    "error": format!("Invalid id: [{} is not between 1 and 2147483647]", id)
+                }),
+                "{}",
+                path
+            );
+        }
+
+        // 範囲内の大きなidはrepositoryに問い合わせ、無ければ404になる
+        for (method, path) in [
+            (Method::GET, "/todos/2147483647"),
+            (Method::DELETE, "/labels/2147483647"),
+            (Method::GET, "/webhooks/2147483647"),
+        ] {
+            let req = build_todo_req_with_empty(method, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::NOT_FOUND, res.status(), "{}", path);
+        }
+
+        // 数値でないidも同じ形のJSONで返す
+        let req = build_todo_req_with_empty(Method::GET, "/todos/abc");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert!(
+            body["error"].as_str().unwrap().starts_with("Invalid id: ["),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn should_return_json_500_when_handler_panics() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let panics = panics_total();
+
+        let req = Request::builder()
+            .uri("/panic")
+            .header(REQUEST_ID_HEADER, "panic-request")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "panic-request");
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "Internal server error", "request_id": "panic-request" })
+        );
+        assert!(panics_total() > panics);
+
+        // request idを付けなければ生成したidを返す
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(!res.headers()[REQUEST_ID_HEADER].is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_serve_todo_and_label_routes_from_one_app() {
+        let work = Label::new(1, "work".to_string()).owned_by(TEST_USER_ID);
+        let labels = LabelRepositoryForMemory::new();
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]).with_labels(&labels),
+            labels,
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        let req =
+            build_label_req_with_json("/labels", Method::POST, r#"{ "name": "work" }"#.to_string());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert_eq!(work, res_to_label(res).await);
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "labeled", "labels": [1] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert_eq!(vec![work.clone()], res_to_todo(res).await.labels);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?label=1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(todos.len(), 1);
+
+        let req = build_label_req_with_empty(Method::DELETE, "/labels/1");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_all_todos() {
+        let (labels, label_ids) = label_fixture();
+        let expected = TodoEntity::new(1, "should_get_all_todos".to_string(), labels.clone());
+
+        let todo_repository = TodoRepositoryForMemory::for_test(labels.clone());
+        todo_repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("should_get_all_todos".to_string(), label_ids),
+            )
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let todo: Vec<TodoEntity> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Todo instance. body: {}", body));
+        assert_eq!(vec![expected], todo);
+    }
+
+    #[tokio::test]
+    async fn should_filter_todos_by_completed_and_label() {
+        let labels = vec![
+            Label::new(3, "work".to_string()),
+            Label::new(4, "private".to_string()),
+        ];
+        let todo_repository = TodoRepositoryForMemory::for_test(labels.clone());
+        for (text, label_ids) in [
+            ("open work", vec![3]),
+            ("done work", vec![3]),
+            ("open private", vec![4]),
+        ] {
+            todo_repository
+                .create(TEST_USER_ID, CreateTodo::new(text.to_string(), label_ids))
+                .await
+                .expect("failed create todo");
+        }
+        let req = build_todo_req_with_json(
+            "/todos/2",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        create_app(
+            todo_repository.clone(),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?completed=false&label=3");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Todo instance. body: {}", body));
+        assert_eq!(
+            vec![TodoEntity::new(
+                1,
+                "open work".to_string(),
+                vec![labels[0].clone()]
+            )],
+            todos
+        );
+    }
+
+    #[tokio::test]
+    async fn should_filter_todos_by_created_range() {
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        for (text, created_at) in [
+            ("new year", "2024-01-01T00:00:00Z"),
+            ("next day", "2024-01-02T00:00:00Z"),
+        ] {
+            todo_repository.set_now(created_at.parse().unwrap());
+            todo_repository
+                .create(TEST_USER_ID, CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        let req = build_todo_req_with_empty(
+            Method::GET,
+            "/todos?created_from=2024-01-02T00:00:00Z&created_to=2024-01-02T00:00:00Z",
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            todos.iter().map(|todo| todo.id).collect::<Vec<_>>(),
+            vec![2]
+        );
+
+        // 範囲の逆転と日時でない値はBadRequest
+        for path in [
+            "/todos?created_from=2024-01-02T00:00:00Z&created_to=2024-01-01T00:00:00Z",
+            "/todos?created_from=yesterday",
+        ] {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_get_todos_by_ids_in_requested_order() {
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        test_support::seed_todos(
+            &todo_repository,
+            TEST_USER_ID,
+            ["first", "second", "third"].map(|text| CreateTodo::new(text.to_string(), vec![])),
+        )
+        .await;
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        // 見つからないidは結果に含まれない
+        let req = build_todo_req_with_empty(Method::GET, "/todos?ids=3,9,1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+        let texts: Vec<&str> = todos.iter().map(|todo| todo.text.as_str()).collect();
+        assert_eq!(texts, vec!["third", "first"]);
+
+        let too_many = (1..=101).map(|id| id.to_string()).collect::<Vec<_>>();
+        for (path, expected) in [
+            ("/todos?ids=1,abc".to_string(), "[abc] is not a todo id"),
+            (
+                format!("/todos?ids={}", too_many.join(",")),
+                "at most 100 ids",
+            ),
+        ] {
+            let req = build_todo_req_with_empty(Method::GET, &path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status());
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            let error = body["error"].as_str().unwrap();
+            assert!(error.contains(expected), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_get_all_labels() {
+        let expected = Label::new(1, "should_get_all_labels".to_string());
+        let label_repository = LabelRepositoryForMemory::new();
+        let label = label_repository
+            .create(None, "should_get_all_labels".to_string())
+            .await
+            .expect("failed create label");
+
+        let req = build_label_req_with_empty(Method::GET, "/labels");
+        let res = create_app(
+            TodoRepositoryForMemory::for_test(vec![label]),
+            label_repository,
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let label: Vec<Label> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Label instance. body: {}", body));
+        assert_eq!(vec![expected], label);
+    }
+
+    #[tokio::test]
+    async fn should_search_labels_by_name() {
+        let label_repository = LabelRepositoryForMemory::new();
+        let work = label_repository
+            .create(None, "Work".to_string())
+            .await
+            .expect("failed create label");
+        label_repository
+            .create(None, "private".to_string())
+            .await
+            .expect("failed create label");
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            label_repository,
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        for (q, expected) in [("or", vec![work]), ("none", vec![])] {
+            let req = build_label_req_with_empty(Method::GET, &format!("/labels?q={}", q));
+            let res = app.clone().oneshot(req).await.unwrap();
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let labels: Vec<Label> = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(expected, labels, "q={}", q);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_get_only_used_labels() {
+        let label_repository = LabelRepositoryForMemory::new();
+        let used = label_repository
+            .create(None, "used".to_string())
+            .await
+            .expect("failed create label");
+        let unused = label_repository
+            .create(None, "unused".to_string())
+            .await
+            .expect("failed create label");
+        label_repository.attach(TEST_USER_ID, used.id);
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            label_repository,
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        let res = app
+            .clone()
+            .oneshot(build_label_req_with_empty(Method::GET, "/labels/used"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let labels: Vec<Label> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(labels, vec![used.clone()]);
+
+        // GET /labelsは使われていないlabelも返す
+        let res = app
+            .oneshot(build_label_req_with_empty(Method::GET, "/labels"))
+            .await
+            .unwrap();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let labels: Vec<Label> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(labels, vec![unused, used]);
+    }
+
+    #[tokio::test]
+    async fn should_update_todo() {
+        let (labels, label_ids) = label_fixture();
+        let expected = TodoEntity::new(1, "should_update_todo".to_string(), labels.clone());
+
+        let todo_repository = TodoRepositoryForMemory::for_test(labels);
+        todo_repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("before_update_todo".to_string(), label_ids),
+            )
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{
+            "text": "should_update_todo",
+            "completed": false
+            }"#
+            .to_string(),
+        );
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(expected, todo);
+    }
+
+    #[tokio::test]
+    async fn should_spawn_next_daily_todo_due_tomorrow_on_completion() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "water plants", "labels": [], "recurrence": "daily" }"#.to_string(),
+        );
+        let todo = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+
+        // 期日が無ければ完了した日の翌日が次の期日になる. 完了日時はテスト用の時計で決まる
+        let tomorrow = test_now().date_naive().succ_opt().unwrap();
+        let req = build_todo_req_with_json(
+            &format!("/todos/{}", todo.id),
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        let completed = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+        let next = *completed
+            .next_occurrence
+            .expect("next occurrence not created");
+        assert!(!next.completed);
+        assert_eq!(next.due_date, Some(tomorrow));
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?completed=false");
+        let res = app.oneshot(req).await.unwrap();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let open: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(open, vec![next]);
+    }
+
+    #[tokio::test]
+    async fn should_answer_conditional_get_with_last_modified() {
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        todo_repository
+            .create(TEST_USER_ID, CreateTodo::new("cached".to_string(), vec![]))
+            .await
+            .unwrap();
+        let app = create_app(
+            todo_repository.clone(),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let get = |since: Option<&str>| {
+            let mut req = build_todo_req_with_empty(Method::GET, "/todos/1");
+            if let Some(since) = since {
+                req.headers_mut()
+                    .insert(header::IF_MODIFIED_SINCE, since.parse().unwrap());
+            }
+            app.clone().oneshot(req)
+        };
+        let last_modified = |res: &Response| {
+            res.headers()
+                .get(header::LAST_MODIFIED)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let res = get(None).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let created = last_modified(&res);
+        assert_eq!(created, "Mon, 01 Jan 2024 00:00:00 GMT");
+
+        let res = get(Some(&created)).await.unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+        assert_eq!(last_modified(&res), created);
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(bytes.is_empty());
+
+        let res = get(Some("Sun, 31 Dec 2023 23:59:59 GMT")).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        // 古い形式のHTTP-dateも読み、読めない値やGMT以外の日時は無視する
+        for (since, status) in [
+            ("Monday, 01-Jan-24 00:00:00 GMT", StatusCode::NOT_MODIFIED),
+            ("Mon Jan  1 00:00:00 2024", StatusCode::NOT_MODIFIED),
+            ("Sunday, 31-Dec-23 23:59:59 GMT", StatusCode::OK),
+            ("Mon, 01 Jan 2024 09:00:00 +0900", StatusCode::OK),
+            ("2024-01-02T00:00:00Z", StatusCode::OK),
+            ("yesterday", StatusCode::OK),
+        ] {
+            let res = get(Some(since)).await.unwrap();
+            assert_eq!(status, res.status(), "{}", since);
+        }
+
+        // 秒未満を持つ更新日時も、ヘッダーで返した秒までの値と比べる
+        todo_repository.set_now(test_now() + chrono::TimeDelta::milliseconds(1500));
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        app.clone().oneshot(req).await.unwrap();
+
+        let res = get(Some(&created)).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let updated = last_modified(&res);
+        assert_eq!(updated, "Mon, 01 Jan 2024 00:00:01 GMT");
+        assert!(res_to_todo(res).await.completed);
+
+        let res = get(Some(&updated)).await.unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_send_cache_control_per_route() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let cache_control = |res: &Response| {
+            res.headers()
+                .get(header::CACHE_CONTROL)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+
+        let res = app
+            .clone()
+            .oneshot(build_label_req_with_empty(Method::GET, "/labels"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(cache_control(&res).unwrap(), "private, max-age=300");
+        let vary: Vec<_> = res.headers().get_all(header::VARY).iter().collect();
+        assert!(vary.contains(&&header::HeaderValue::from_static(USER_ID_HEADER)));
+
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos"))
+            .await
+            .unwrap();
+        assert_eq!(cache_control(&res).unwrap(), "private, max-age=30");
+
+        // 変更とエラーはキャッシュさせない
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_json(
+                "/todos",
+                Method::POST,
+                r#"{ "text": "no cache", "labels": [] }"#.to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert_eq!(cache_control(&res).unwrap(), "no-store");
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos/999"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        assert_eq!(cache_control(&res).unwrap(), "no-store");
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/activity"))
+            .await
+            .unwrap();
+        assert_eq!(cache_control(&res).unwrap(), "no-store");
+
+        // テストやmainは値を差し替えられる
+        let app = app.layer(Extension(CacheControl {
+            labels: header::HeaderValue::from_static("no-cache"),
+            ..CacheControl::default()
+        }));
+        let res = app
+            .oneshot(build_label_req_with_empty(Method::GET, "/labels"))
+            .await
+            .unwrap();
+        assert_eq!(cache_control(&res).unwrap(), "no-cache");
+    }
+
+    #[tokio::test]
+    async fn should_return_only_requested_fields() {
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        test_support::seed_todos(
+            &todo_repository,
+            TEST_USER_ID,
+            ["first", "second", "third"].map(|text| CreateTodo::new(text.to_string(), vec![])),
+        )
+        .await;
+        let app = |list_format| {
+            create_app(
+                todo_repository.clone(),
+                LabelRepositoryForMemory::new(),
+                ActivityRepositoryForMemory::new(),
+                WebhookRepositoryForMemory::new(),
+                AuthConfig::default(),
+                DEFAULT_REQUEST_TIMEOUT,
+                list_format,
+            )
+        };
+        let get = |app: Router, path: &str| {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            async move {
+                let res = app.oneshot(req).await.unwrap();
+                let status = res.status();
+                let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                (status, body)
+            }
+        };
+
+        // idは指定しなくても返す
+        let (status, body) = get(app(ListFormat::Bare), "/todos/2?fields=text,completed").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            body,
+            serde_json::json!({ "id": 2, "text": "second", "completed": false })
+        );
+
+        // Envelopeやページ、リンクと組み合わせられる
+        let (status, body) = get(
+            app(ListFormat::Envelope),
+            "/todos?fields=text&sort=id_asc&limit=2&hateoas=true",
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(body["meta"]["total"], 3);
+        assert_eq!(body["meta"]["next_cursor"], 2);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["text"], "first");
+        let mut keys: Vec<&str> = data[0]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, vec!["_links", "id", "text"]);
+
+        let (_, body) = get(app(ListFormat::Bare), "/todos?fields=id").await;
+        assert_eq!(
+            body,
+            serde_json::json!([{ "id": 3 }, { "id": 2 }, { "id": 1 }])
+        );
+
+        // 知らない項目名は有効な項目名を添えて400にする
+        let (status, body) = get(app(ListFormat::Bare), "/todos?fields=text,description").await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        let error = body["error"].as_str().unwrap();
+        assert!(
+            error.starts_with("Invalid fields: [description] is not a todo field"),
+            "{}",
+            error
+        );
+        assert!(error.contains("id, public_id, user_id, text"), "{}", error);
+
+        let (status, _) = get(app(ListFormat::Bare), "/todos/1?fields=secret").await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+    }
+
+    #[tokio::test]
+    async fn should_add_links_only_when_requested() {
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        let parent = todo_repository
+            .create(TEST_USER_ID, CreateTodo::new("trip".to_string(), vec![]))
+            .await
+            .unwrap();
+        test_support::seed_todos(
+            &todo_repository,
+            TEST_USER_ID,
+            ["tickets", "packing", "hotel"]
+                .map(|text| CreateTodo::new(text.to_string(), vec![]).subtask_of(parent.id)),
+        )
+        .await;
+        let app = |list_format| {
+            create_app(
+                todo_repository.clone(),
+                LabelRepositoryForMemory::new(),
+                ActivityRepositoryForMemory::new(),
+                WebhookRepositoryForMemory::new(),
+                AuthConfig::default(),
+                DEFAULT_REQUEST_TIMEOUT,
+                list_format,
+            )
+        };
+        let get = |app: Router, req: Request<Body>| async move {
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+            let link = res
+                .headers()
+                .get(header::LINK)
+                .map(|value| value.to_str().unwrap().to_string());
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            (body, link)
+        };
+
+        // 求められなければ付けない
+        let (body, link) = get(
+            app(ListFormat::Bare),
+            build_todo_req_with_empty(Method::GET, "/todos/2"),
+        )
+        .await;
+        assert!(body.get("_links").is_none());
+        assert_eq!(link, None);
+
+        let (body, _) = get(
+            app(ListFormat::Bare),
+            build_todo_req_with_empty(Method::GET, "/todos/2?hateoas=true"),
+        )
+        .await;
+        assert_eq!(body["text"], "tickets");
+        assert_eq!(
+            body["_links"],
+            serde_json::json!({
+                "self": { "href": "/todos/2" },
+                "labels": { "href": "/labels" },
+                "subtasks": { "href": "/todos/2/subtasks" },
+                "parent": { "href": "/todos/1" },
+            })
+        );
+
+        // プロキシが取り除いた先頭のパスと設定した先頭のパスを繋げる
+        let mut req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        req.headers_mut().insert(
+            header::ACCEPT,
+            r#"application/json; profile="hateoas""#.parse().unwrap(),
+        );
+        req.headers_mut()
+            .insert(FORWARDED_PREFIX_HEADER, "/proxy/".parse().unwrap());
+        let (body, _) = get(
+            app(ListFormat::Bare).layer(Extension(BasePath::new("api/v1"))),
+            req,
+        )
+        .await;
+        assert_eq!(body["_links"]["self"]["href"], "/proxy/api/v1/todos/1");
+        assert_eq!(
+            body["_links"]["subtasks"]["href"],
+            "/proxy/api/v1/todos/1/subtasks"
+        );
+        assert!(body["_links"].get("parent").is_none());
+
+        // 一覧のリンクはoffsetだけを変え、配列のままならLinkヘッダーで返す
+        let (body, link) = get(
+            app(ListFormat::Bare),
+            build_todo_req_with_empty(
+                Method::GET,
+                "/todos?hateoas=true&sort=id_asc&limit=2&offset=1",
+            ),
+        )
+        .await;
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["_links"]["self"]["href"], "/todos/2");
+        assert_eq!(
+            link.unwrap(),
+            r#"</todos?hateoas=true&sort=id_asc&limit=2&offset=1>; rel="self", </todos?hateoas=true&sort=id_asc&limit=2&offset=3>; rel="next", </todos?hateoas=true&sort=id_asc&limit=2&offset=0>; rel="prev""#
+        );
+
+        let mut req = build_todo_req_with_empty(Method::GET, "/todos?hateoas=true&limit=2");
+        req.headers_mut()
+            .insert(FORWARDED_PREFIX_HEADER, "/api".parse().unwrap());
+        let (body, link) = get(app(ListFormat::Envelope), req).await;
+        assert_eq!(link, None);
+        assert_eq!(
+            body["_links"],
+            serde_json::json!({
+                "self": { "href": "/api/todos?hateoas=true&limit=2" },
+                "next": { "href": "/api/todos?hateoas=true&limit=2&offset=2" },
+            })
+        );
+
+        let (body, _) = get(
+            app(ListFormat::Envelope),
+            build_todo_req_with_empty(Method::GET, "/todos/1/subtasks"),
+        )
+        .await;
+        assert!(body.get("_links").is_none());
+        assert!(body["data"][0].get("_links").is_none());
+    }
+
+    #[tokio::test]
+    async fn should_clear_only_null_fields_with_merge_patch() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "renew passport", "labels": [], "due_date": "2024-06-01", "remind_at": "2024-05-31T09:00:00Z" }"#
+                .to_string(),
+        );
+        let todo = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+
+        let merge_patch = |json: &str| {
+            Request::builder()
+                .uri(format!("/todos/{}", todo.id))
+                .method(Method::PATCH)
+                .header(USER_ID_HEADER, TEST_USER_ID)
+                .header(header::CONTENT_TYPE, "application/merge-patch+json")
+                .body(Body::from(json.to_string()))
+                .unwrap()
+        };
+
+        // 省略したdue_dateは変わらない
+        let res = app
+            .clone()
+            .oneshot(merge_patch(r#"{ "completed": true }"#))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let updated = res_to_todo(res).await;
+        assert!(updated.completed);
+        assert_eq!(updated.due_date, todo.due_date);
+        assert!(updated.due_date.is_some());
+
+        // nullを指定したdue_dateだけが消える
+        let res = app
+            .clone()
+            .oneshot(merge_patch(r#"{ "due_date": null }"#))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let updated = res_to_todo(res).await;
+        assert_eq!(updated.due_date, None);
+        assert_eq!(updated.remind_at, todo.remind_at);
+        assert!(updated.completed);
+
+        // Merge Patchでもオブジェクト以外は受け付けない
+        let res = app.oneshot(merge_patch("null")).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_render_ids_as_strings_when_requested() {
+        let labels = vec![Label::new(1, "work".to_string())];
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(labels),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let json = |res: Response| async move {
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+
+        // 入力のidは文字列でも受け付ける
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "parent", "labels": ["1"] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let req = build_todo_req_with_json(
+            "/todos?ids_as_strings=true",
+            Method::POST,
+            r#"{ "text": "child", "labels": [], "parent_id": "1" }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let child = json(res).await;
+        assert_eq!(child["id"], "2");
+        assert_eq!(child["parent_id"], "1");
+        assert_eq!(child["user_id"], TEST_USER_ID.to_string());
+
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(
+                Method::GET,
+                "/todos/1?ids_as_strings=true",
+            ))
+            .await
+            .unwrap();
+        let parent = json(res).await;
+        assert_eq!(parent["id"], "1");
+        assert_eq!(parent["labels"][0]["id"], "1");
+        assert_eq!(parent["parent_id"], serde_json::Value::Null);
+        assert!(parent["public_id"]
+            .as_str()
+            .unwrap()
+            .parse::<Uuid>()
+            .is_ok());
+        assert_eq!(parent["text"], "parent");
+
+        let mut req = build_todo_req_with_empty(Method::GET, "/todos?sort=id_asc");
+        req.headers_mut().insert(
+            header::ACCEPT,
+            "application/json; ids=string".parse().unwrap(),
+        );
+        let todos = json(app.clone().oneshot(req).await.unwrap()).await;
+        assert_eq!(todos[0]["id"], "1");
+        assert_eq!(todos[1]["parent_id"], "1");
+
+        // 既定は数値のまま
+        let res = app
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos/2"))
+            .await
+            .unwrap();
+        let child = json(res).await;
+        assert_eq!(child["id"], 2);
+        assert_eq!(child["parent_id"], 1);
+    }
+
+    #[tokio::test]
+    async fn should_reject_out_of_range_pagination() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        for (query, message) in [
+            ("limit=0", "Invalid limit: [0] must be 1 or more"),
+            ("limit=-1", "Invalid limit: [-1] must be 1 or more"),
+            ("offset=-5", "Invalid offset: [-5] must be 0 or more"),
+        ] {
+            for path in ["/todos", "/activity"] {
+                let uri = format!("{}?{}", path, query);
+                let res = app
+                    .clone()
+                    .oneshot(build_todo_req_with_empty(Method::GET, &uri))
+                    .await
+                    .unwrap();
+                assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", uri);
+                let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                assert_eq!(body["error"], message, "{}", uri);
+            }
+        }
+
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos?limit=ten"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        let res = app
+            .oneshot(build_todo_req_with_empty(
+                Method::GET,
+                "/todos?limit=1&offset=0",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_apply_default_and_max_todo_limit() {
+        use crate::handlers::{
+            todo::{DEFAULT_TODO_LIMIT, TOTAL_COUNT_HEADER},
+            Pagination,
+        };
+
+        let repository = TodoRepositoryForMemory::for_test(vec![]);
+        for i in 0..600 {
+            repository
+                .create(TEST_USER_ID, CreateTodo::new(format!("todo {}", i), vec![]))
+                .await
+                .unwrap();
+        }
+        let app = |format| {
+            create_app(
+                repository.clone(),
+                LabelRepositoryForMemory::new(),
+                ActivityRepositoryForMemory::new(),
+                WebhookRepositoryForMemory::new(),
+                AuthConfig::default(),
+                DEFAULT_REQUEST_TIMEOUT,
+                format,
+            )
+        };
+        for (uri, len, total) in [
+            ("/todos", DEFAULT_TODO_LIMIT, "600"),
+            ("/todos?limit=1000", Pagination::MAX_LIMIT, "600"),
+            ("/todos?limit=500&offset=500", 100, "600"),
+            ("/todos?limit=600", Pagination::MAX_LIMIT, "600"),
+            ("/todos?completed=true", 0, "0"),
+        ] {
+            let res = app(ListFormat::Bare)
+                .oneshot(build_todo_req_with_empty(Method::GET, uri))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, res.status(), "{}", uri);
+            assert_eq!(res.headers()[TOTAL_COUNT_HEADER], total, "{}", uri);
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let todos: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(todos.len() as i64, len, "{}", uri);
+        }
+
+        let res = app(ListFormat::Envelope)
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos"))
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[TOTAL_COUNT_HEADER], "600");
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            body["meta"],
+            serde_json::json!({ "total": 600, "limit": 50, "offset": 0, "next_cursor": 50 })
+        );
+    }
+
+    #[tokio::test]
+    async fn should_send_total_count_of_filtered_todos() {
+        use crate::handlers::todo::TOTAL_COUNT_HEADER;
+
+        let repository = TodoRepositoryForMemory::for_test(vec![]);
+        test_support::seed_todos(
+            &repository,
+            TEST_USER_ID,
+            ["buy milk", "buy eggs", "call mom", "buy bread", "walk dog"]
+                .map(|text| CreateTodo::new(text.to_string(), vec![])),
+        )
+        .await;
+        repository
+            .update(TEST_USER_ID, 2, UpdateTodo::completion(true))
+            .await
+            .unwrap();
+        let app = create_app(
+            repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        // 件数はlimit, offsetに関わらず、絞り込んだ条件に当てはまる数
+        for (uri, len, total) in [
+            ("/todos", 5, "5"),
+            ("/todos?completed=false&limit=1", 1, "4"),
+            ("/todos?q=buy&limit=2", 2, "3"),
+            ("/todos?q=buy&completed=false", 2, "2"),
+            ("/todos?q=BUY&offset=2", 1, "3"),
+            ("/todos?q=buy&offset=10", 0, "3"),
+            ("/todos?q=nothing", 0, "0"),
+            ("/todos?ids=1,3,99", 2, "2"),
+        ] {
+            let res = app
+                .clone()
+                .oneshot(build_todo_req_with_empty(Method::GET, uri))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, res.status(), "{}", uri);
+            assert_eq!(res.headers()[TOTAL_COUNT_HEADER], total, "{}", uri);
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let todos: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(todos.len(), len, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_wrap_lists_in_envelope_when_enabled() {
+        let activity_repository = ActivityRepositoryForMemory::new();
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![])
+                .with_activity(&activity_repository, activity_repository.clone()),
+            LabelRepositoryForMemory::new(),
+            activity_repository,
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Envelope,
+        );
+        for text in ["first", "second", "third", "done"] {
+            let req = build_todo_req_with_json(
+                "/todos",
+                Method::POST,
+                format!(r#"{{ "text": "{}", "labels": [] }}"#, text),
+            );
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+        }
+        let req = build_todo_req_with_json(
+            "/todos/4",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        app.clone().oneshot(req).await.unwrap();
+
+        let list = |path: &str| {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(req).await.unwrap();
+                assert_eq!(StatusCode::OK, res.status());
+                let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+            }
+        };
+
+        // totalはlimit, offsetを除いた条件に当てはまる件数で、全件ではない
+        let body = list("/todos?completed=false&sort=id_asc&limit=2").await;
+        let texts: Vec<&str> = body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|todo| todo["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts, vec!["first", "second"]);
+        assert_eq!(
+            body["meta"],
+            serde_json::json!({ "total": 3, "limit": 2, "offset": 0, "next_cursor": 2 })
+        );
+
+        let body = list("/todos?completed=false&sort=id_asc&limit=2&offset=2").await;
+        assert_eq!(body["data"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            body["meta"],
+            serde_json::json!({ "total": 3, "limit": 2, "offset": 2, "next_cursor": null })
+        );
+
+        // ページに分けない一覧も同じ形で返す
+        let body = list("/labels").await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "data": [],
+                "meta": { "total": 0, "limit": null, "offset": 0, "next_cursor": null }
+            })
+        );
+
+        let body = list("/activity?limit=3").await;
+        assert_eq!(body["data"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            body["meta"],
+            serde_json::json!({ "total": 5, "limit": 3, "offset": 0, "next_cursor": 3 })
+        );
+
+        // 上限を超えるlimitはtodoと同じく上限にする
+        let body = list("/activity?limit=100000000").await;
+        assert_eq!(body["data"].as_array().unwrap().len(), 5);
+        assert_eq!(
+            body["meta"],
+            serde_json::json!({ "total": 5, "limit": 500, "offset": 0, "next_cursor": null })
+        );
+
+        // 1件のtodoは包まない
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let todo = res_to_todo(app.oneshot(req).await.unwrap()).await;
+        assert_eq!(todo.text, "first");
+    }
+
+    #[tokio::test]
+    async fn should_accept_public_id_or_integer_id_in_path() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "public id", "labels": [] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let public_id = json["public_id"]
+            .as_str()
+            .expect("public_id not in response");
+        let created: TodoEntity = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(public_id, created.public_id.to_string());
+
+        for path in [
+            format!("/todos/{}", public_id),
+            format!("/todos/{}", created.id),
+        ] {
+            let req = build_todo_req_with_empty(Method::GET, &path);
+            let todo = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+            assert_eq!(todo, created, "{}", path);
+        }
+
+        let req = build_todo_req_with_json(
+            &format!("/todos/{}", public_id),
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        let todo = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+        assert!(todo.completed);
+
+        let unknown = Uuid::new_v4();
+        let req = build_todo_req_with_empty(Method::GET, &format!("/todos/{}", unknown));
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        // 連番のidのエラーと同じくJSONの本文で返す
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"], format!("NotFound, public id is {}", unknown));
+    }
+
+    #[tokio::test]
+    async fn should_create_and_list_subtasks() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "move house", "labels": [] }"#.to_string(),
+        );
+        let parent = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            format!(
+                r#"{{ "text": "pack books", "labels": [], "parent_id": {} }}"#,
+                parent.id
+            ),
+        );
+        let subtask = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+        assert_eq!(subtask.parent_id, Some(parent.id));
+
+        let req = build_todo_req_with_empty(Method::GET, &format!("/todos/{}/subtasks", parent.id));
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let subtasks: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(subtasks, vec![subtask]);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/99/subtasks");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"], "NotFound, id is 99");
+
+        // 自分自身は親にできない
+        let req = build_todo_req_with_json(
+            &format!("/todos/{}", parent.id),
+            Method::PATCH,
+            format!(r#"{{ "parent_id": {} }}"#, parent.id),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_delete_todo() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::for_test(labels);
+        todo_repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("should_delete_todo".to_string(), label_ids),
+            )
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_preview_delete_without_deleting() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::for_test(labels);
+        let todo = todo_repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("should_preview_delete".to_string(), label_ids.clone()),
+            )
+            .await
+            .expect("failed create todo");
+        todo_repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("subtask".to_string(), vec![]).subtask_of(todo.id),
+            )
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1?dry_run=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let preview: DeletePreview = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            preview,
+            DeletePreview {
+                todo: todo.clone(),
+                todo_labels: label_ids.len() as u64,
+                detached_subtasks: 1,
+            }
+        );
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        assert_eq!(
+            res_to_todo(app.clone().oneshot(req).await.unwrap()).await,
+            todo
+        );
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/99?dry_run=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        // dry_run=falseなら削除する
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1?dry_run=false");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_delete_lable() {
+        let label_repository = LabelRepositoryForMemory::new();
+        let label = label_repository
+            .create(None, "should_delete_label".to_string())
+            .await
+            .expect("failed create label");
+        let req = build_label_req_with_empty(Method::DELETE, "/labels/1");
+        let res = create_app(
+            TodoRepositoryForMemory::for_test(vec![label]),
+            label_repository,
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    // 問い合わせが返ってこないDBを模したrepository. 常にtimeoutする
+    #[derive(Debug, Clone)]
+    struct SlowTodoRepository;
+
+    impl SlowTodoRepository {
+        async fn slow_query<T>() -> anyhow::Result<T> {
+            with_timeout(Duration::from_millis(10), std::future::pending()).await
+        }
+    }
+
+    #[async_trait]
+    impl TodoRepository for SlowTodoRepository {
+        async fn create(&self, _user_id: i32, _payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+            Self::slow_query().await
+        }
+
+        async fn find(&self, _user_id: i32, _id: i32) -> anyhow::Result<TodoEntity> {
+            Self::slow_query().await
+        }
+
+        async fn find_by_public_id(
+            &self,
+            _user_id: i32,
+            _public_id: Uuid,
+        ) -> anyhow::Result<TodoEntity> {
+            Self::slow_query().await
+        }
+
+        async fn all(&self, _user_id: i32, _query: TodoQuery) -> anyhow::Result<Vec<TodoEntity>> {
+            Self::slow_query().await
+        }
+
+        fn all_stream(
+            &self,
+            _user_id: i32,
+            _query: TodoQuery,
+        ) -> BoxStream<'static, anyhow::Result<TodoEntity>> {
+            stream::once(Self::slow_query()).boxed()
+        }
+
+        async fn count(&self, _user_id: i32, _query: TodoQuery) -> anyhow::Result<i64> {
+            Self::slow_query().await
+        }
+
+        async fn find_many(&self, _user_id: i32, _ids: &[i32]) -> anyhow::Result<Vec<TodoEntity>> {
+            Self::slow_query().await
+        }
+
+        async fn update(
+            &self,
+            _user_id: i32,
+            _id: i32,
+            _payload: UpdateTodo,
+        ) -> anyhow::Result<TodoEntity> {
+            Self::slow_query().await
+        }
+
+        async fn update_where(
+            &self,
+            _user_id: i32,
+            _filter: TodoQuery,
+            _set: BulkUpdateTodo,
+        ) -> anyhow::Result<Vec<TodoEntity>> {
+            Self::slow_query().await
+        }
+
+        async fn delete(&self, _user_id: i32, _id: i32) -> anyhow::Result<()> {
+            Self::slow_query().await
+        }
+
+        async fn subtasks(&self, _user_id: i32, _id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+            Self::slow_query().await
+        }
+
+        async fn restore(&self, _user_id: i32, _todo: TodoEntity) -> anyhow::Result<TodoEntity> {
+            Self::slow_query().await
+        }
+
+        async fn claim_due_reminders(
+            &self,
+            _now: DateTime<Utc>,
+        ) -> anyhow::Result<Vec<TodoEntity>> {
+            Self::slow_query().await
+        }
+
+        async fn stats(&self, _user_id: i32, _today: NaiveDate) -> anyhow::Result<TodoStats> {
+            Self::slow_query().await
+        }
+
+        async fn timeseries(
+            &self,
+            _user_id: i32,
+            _query: TimeseriesQuery,
+        ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+            Self::slow_query().await
+        }
+    }
+
+    #[tokio::test]
+    async fn should_return_gateway_timeout_on_slow_query() {
+        for req in [
+            build_todo_req_with_empty(Method::GET, "/todos"),
+            build_todo_req_with_empty(Method::GET, "/todos/1"),
+            build_todo_req_with_empty(Method::GET, "/todos/stats"),
+            build_todo_req_with_empty(Method::DELETE, "/todos/1"),
+        ] {
+            let res = create_app(
+                SlowTodoRepository,
+                LabelRepositoryForMemory::new(),
+                ActivityRepositoryForMemory::new(),
+                WebhookRepositoryForMemory::new(),
+                AuthConfig::default(),
+                DEFAULT_REQUEST_TIMEOUT,
+                ListFormat::Bare,
+            )
+            .oneshot(req)
+            .await
+            .unwrap();
+            assert_eq!(StatusCode::GATEWAY_TIMEOUT, res.status());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_time_out_request_exceeding_limit() {
+        // DBの問い合わせ(10ms)より短い上限で、リクエスト全体のtimeoutが先に効く
+        let res = create_app(
+            SlowTodoRepository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            Duration::from_millis(1),
+            ListFormat::Bare,
+        )
+        .oneshot(build_todo_req_with_empty(Method::GET, "/todos"))
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::REQUEST_TIMEOUT, res.status());
+    }
+
+    // 1件のtodoを持つrepositoryに障害を予約できるようにする
+    async fn flaky_repository() -> FlakyTodoRepository<TodoRepositoryForMemory> {
+        let repository = TodoRepositoryForMemory::for_test(vec![]);
+        repository
+            .create(TEST_USER_ID, CreateTodo::new("flaky".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        FlakyTodoRepository::new(repository)
+    }
+
+    fn flaky_app(repository: FlakyTodoRepository<TodoRepositoryForMemory>) -> Router {
+        create_app(
+            repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+    }
+
+    #[tokio::test]
+    async fn should_map_repository_errors_to_status() {
+        let update = || {
+            build_todo_req_with_json(
+                "/todos/1",
+                Method::PATCH,
+                r#"{ "parent_id": 2 }"#.to_string(),
+            )
+        };
+        let cases: Vec<(flaky::Method, anyhow::Error, Request<Body>, StatusCode)> = vec![
+            (
+                flaky::Method::Create,
+                RepositoryError::Unexpected("connection lost".to_string()).into(),
+                create_todo_req(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                flaky::Method::Find,
+                RepositoryError::NotFound(1).into(),
+                build_todo_req_with_empty(Method::GET, "/todos/1"),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                flaky::Method::Find,
+                RepositoryError::Timeout(Duration::from_secs(5)).into(),
+                build_todo_req_with_empty(Method::GET, "/todos/1"),
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (
+                flaky::Method::Update,
+                RepositoryError::InvalidParent(2).into(),
+                update(),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                flaky::Method::Undo,
+                RepositoryError::NothingToUndo(1).into(),
+                build_todo_req_with_empty(Method::POST, "/todos/1/undo"),
+                StatusCode::CONFLICT,
+            ),
+            // RepositoryError以外の失敗は各handlerの既定の応答になる
+            (
+                flaky::Method::Page,
+                anyhow::anyhow!("connection reset"),
+                build_todo_req_with_empty(Method::GET, "/todos"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (method, error, req, expected) in cases {
+            let path = req.uri().to_string();
+            let repository = flaky_repository().await.fail_next(method, error);
+            let app = flaky_app(repository.clone());
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(expected, res.status(), "{:?} {}", method, path);
+            assert_eq!(repository.pending(method), 0, "{:?} was not called", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_return_json_error_when_listing_fails() {
+        let repository = flaky_repository()
+            .await
+            .fail_next(flaky::Method::Page, anyhow::anyhow!("connection reset"));
+        let app = flaky_app(repository);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        // 想定外のエラーの内容は返さない
+        assert_eq!(body["error"], "Internal server error");
+    }
+
+    #[tokio::test]
+    async fn should_recover_after_repository_panics() {
+        let repository = flaky_repository()
+            .await
+            .panic_next(flaky::Method::Create, "repository panic");
+        let app = flaky_app(repository);
+
+        let req = create_todo_req();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"], "Internal server error");
+
+        // panicは1回だけで、次のリクエストは通る
+        let req = create_todo_req();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_time_out_request_when_repository_stalls() {
+        let repository = flaky_repository()
+            .await
+            .delay_next(flaky::Method::Find, DEFAULT_REQUEST_TIMEOUT * 2);
+        let res = flaky_app(repository)
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos/1"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::REQUEST_TIMEOUT, res.status());
+    }
+
+    fn token_auth(protect_reads: bool) -> AuthConfig {
+        AuthConfig {
+            token: Some("secret".to_string()),
+            protect_reads,
+            ..Default::default()
+        }
+    }
+
+    fn with_bearer(mut req: Request<Body>, token: &str) -> Request<Body> {
+        req.headers_mut().insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        req
+    }
+
+    fn create_todo_req() -> Request<Body> {
+        build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "should_authorize", "labels": [] }"#.to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn should_reject_mutation_without_valid_token() {
+        for req in [
+            create_todo_req(),
+            with_bearer(create_todo_req(), "wrong-token"),
+            with_bearer(
+                build_todo_req_with_json("/todos/1", Method::PATCH, r#"{}"#.to_string()),
+                "wrong-token",
+            ),
+            build_todo_req_with_empty(Method::DELETE, "/todos/1"),
+            build_label_req_with_empty(Method::DELETE, "/labels/1"),
+        ] {
+            let res = create_app(
+                TodoRepositoryForMemory::for_test(vec![]),
+                LabelRepositoryForMemory::new(),
+                ActivityRepositoryForMemory::new(),
+                WebhookRepositoryForMemory::new(),
+                token_auth(false),
+                DEFAULT_REQUEST_TIMEOUT,
+                ListFormat::Bare,
+            )
+            .oneshot(req)
+            .await
+            .unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_accept_mutation_with_valid_token() {
+        let res = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            token_auth(false),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(with_bearer(create_todo_req(), "secret"))
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_leave_reads_open_unless_configured() {
+        let res = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            token_auth(false),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(build_todo_req_with_empty(Method::GET, "/todos"))
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            token_auth(true),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+        let res = app
+            .oneshot(with_bearer(
+                build_todo_req_with_empty(Method::GET, "/todos"),
+                "secret",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_isolate_todos_between_users() {
+        let other_user_id = TEST_USER_ID + 1;
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        let theirs = todo_repository
+            .create(other_user_id, CreateTodo::new("theirs".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos"))
+            .await
+            .unwrap();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+        assert!(todos.is_empty());
+
+        let path = format!("/todos/{}", theirs.id);
+        for req in [
+            build_todo_req_with_empty(Method::GET, &path),
+            build_todo_req_with_json(&path, Method::PATCH, r#"{ "completed": true }"#.to_string()),
+            build_todo_req_with_empty(Method::DELETE, &path),
+        ] {
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::NOT_FOUND, res.status());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_take_user_from_user_token() {
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        todo_repository
+            .create(2, CreateTodo::new("bob's".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig {
+                users: HashMap::from([
+                    ("alice-token".to_string(), 1),
+                    ("bob-token".to_string(), 2),
+                ]),
+                ..Default::default()
+            },
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let list = |token: Option<&'static str>, user_id: Option<i32>| {
+            let mut req = Request::builder().uri("/todos").method(Method::GET);
+            if let Some(token) = token {
+                req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            if let Some(user_id) = user_id {
+                req = req.header(USER_ID_HEADER, user_id);
+            }
+            app.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+
+        // トークンだけでユーザーが決まる
+        let res = list(Some("bob-token"), None).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(todos.len(), 1);
+        assert_eq!(
+            list(Some("bob-token"), Some(2)).await.unwrap().status(),
+            StatusCode::OK
+        );
+
+        // 他のユーザーを名乗っても、トークン無しでヘッダーだけでも読めない
+        let res = list(Some("alice-token"), Some(2)).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+        let res = list(None, Some(2)).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+        let res = list(Some("unknown"), Some(2)).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_reject_request_without_user() {
+        let res = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(
+            Request::builder()
+                .uri("/todos")
+                .method(Method::GET)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_reject_other_users_private_label() {
+        let shared = Label::new(1, "shared".to_string());
+        let private = Label::new(2, "private".to_string()).owned_by(TEST_USER_ID + 1);
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![shared.clone(), private.clone()]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            format!(r#"{{ "text": "shared label", "labels": [{}] }}"#, shared.id),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            format!(
+                r#"{{ "text": "private label", "labels": [{}] }}"#,
+                private.id
+            ),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_name_missing_label_when_creating_or_updating_todo() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        app.clone().oneshot(create_todo_req()).await.unwrap();
+
+        // 作成でも更新でも同じ422になる
+        for (path, method) in [("/todos", Method::POST), ("/todos/1", Method::PATCH)] {
+            let req = build_todo_req_with_json(
+                path,
+                method.clone(),
+                r#"{ "text": "missing label", "labels": [99999] }"#.to_string(),
+            );
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status(), "{}", method);
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            let error = body["error"].as_str().unwrap();
+            assert!(error.contains("99999"), "{} {}", method, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_name_field_with_wrong_type_in_json_error() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        for (json, expected) in [
+            (
+                r#"{ "text": 123 }"#,
+                "field `text`: invalid type: integer `123`, expected a string",
+            ),
+            (
+                r#"{ "text": "labels", "labels": ["work"] }"#,
+                "field `labels[0]`: invalid value: string \"work\", expected an integer id or a string of one",
+            ),
+            (r#"{ "labels": [] }"#, "missing field `text`"),
+        ] {
+            let req = build_todo_req_with_json("/todos", Method::POST, json.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status());
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            let error = body["error"].as_str().unwrap();
+            assert!(error.contains(expected), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_reject_unknown_fields_in_json() {
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        todo_repository
+            .create(TEST_USER_ID, CreateTodo::new("typo".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository.clone(),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        for (path, method, json) in [
+            (
+                "/todos/1",
+                Method::PATCH,
+                r#"{ "text": "x", "compleet": true }"#,
+            ),
+            (
+                "/todos",
+                Method::POST,
+                r#"{ "text": "x", "labels": [], "compleet": true }"#,
+            ),
+            (
+                "/labels",
+                Method::POST,
+                r#"{ "name": "x", "compleet": true }"#,
+            ),
+        ] {
+            let req = build_todo_req_with_json(path, method, json.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", path);
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(
+                body,
+                serde_json::json!({ "error": "Json parse error: [unknown field `compleet`]" })
+            );
+        }
+        // 誤ったフィールドによる変更は適用されない
+        let todo = todo_repository.find(TEST_USER_ID, 1).await.unwrap();
+        assert_eq!(todo.text, "typo");
+        assert!(!todo.completed);
+
+        // 省略できるフィールドは全て省略できる
+        for (path, method, json, expected) in [
+            ("/todos/1", Method::PATCH, "{}", StatusCode::CREATED),
+            (
+                "/todos",
+                Method::POST,
+                r#"{ "text": "x", "labels": [] }"#,
+                StatusCode::CREATED,
+            ),
+            (
+                "/labels",
+                Method::POST,
+                r#"{ "name": "x" }"#,
+                StatusCode::CREATED,
+            ),
+        ] {
+            let req = build_todo_req_with_json(path, method, json.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(expected, res.status(), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_answer_arbitrary_json_bodies_without_panicking() {
+        use crate::handlers::ValidatedJson;
+        use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+        // repositoryを通さず、本文の読み取りと検証だけを試す
+        let app = Router::new()
+            .route(
+                "/create",
+                post(|ValidatedJson(_): ValidatedJson<CreateTodo>| async {
+                    StatusCode::NO_CONTENT
+                }),
+            )
+            .route(
+                "/update",
+                post(|ValidatedJson(_): ValidatedJson<UpdateTodo>| async {
+                    StatusCode::NO_CONTENT
+                }),
+            )
+            .layer(middleware::from_fn(catch_panic));
+
+        // 深い入れ子、範囲外の数値、不正なUTF-8やサロゲートなど
+        let mut bodies: Vec<Vec<u8>> = [
+            "[".repeat(100_000),
+            format!(r#"{{ "text": {}1{} }}"#, "[".repeat(200), "]".repeat(200)),
+            r#"{ "text": "x", "labels": [1e999] }"#.to_string(),
+            r#"{ "text": "x", "labels": [99999999999999999999999] }"#.to_string(),
+            r#"{ "text": "x", "labels": ["-2147483649", -0, 1.0e-400] }"#.to_string(),
+            r#"{ "text": "x", "labels": [], "due_date": "+275760-09-13" }"#.to_string(),
+            r#"{ "text": "\ud800", "labels": [] }"#.to_string(),
+            r#"{ "text": "\u0000", "labels": [], "recurrence": "hourly" }"#.to_string(),
+            format!(r#"{{ "text": "{}", "labels": [] }}"#, "あ".repeat(100_000)),
+            r#"{ "parent_id": "", "remind_at": "2024-13-01T00:00:00Z" }"#.to_string(),
+        ]
+        .into_iter()
+        .map(String::into_bytes)
+        .collect();
+        bodies.push(b"{ \"text\": \"\xff\xfe\", \"labels\": [] }".to_vec());
+
+        // 正しい本文を壊したものと、ランダムなバイト列. FUZZ_ITERATIONSとFUZZ_SEEDで回数と種を変えられる
+        let valid = [
+            r#"{ "text": "todo", "labels": [1, "2"], "due_date": "2024-01-02", "recurrence": "daily" }"#,
+            r#"{ "text": "todo", "completed": true, "labels": [3], "due_date": null, "parent_id": "4" }"#,
+            r#"{ "remind_at": "2024-01-01T09:00:00Z", "complete_subtasks": true }"#,
+        ];
+        let iterations: usize = std::env::var("FUZZ_ITERATIONS")
+            .ok()
+            .and_then(|iterations| iterations.parse().ok())
+            .unwrap_or(300);
+        let seed: u64 = std::env::var("FUZZ_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or(888);
+        let mut rng = StdRng::seed_from_u64(seed);
+        for _ in 0..iterations {
+            let mut body = valid.choose(&mut rng).unwrap().as_bytes().to_vec();
+            for _ in 0..rng.gen_range(0..4) {
+                let at = rng.gen_range(0..=body.len());
+                match rng.gen_range(0..4) {
+                    0 => body.truncate(at),
+                    1 => body.insert(at, rng.gen()),
+                    2 if at < body.len() => body[at] = rng.gen(),
+                    _ => body.insert(at, *b"[]{}\",:0-e".choose(&mut rng).unwrap()),
+                }
+            }
+            bodies.push(body);
+            bodies.push((0..rng.gen_range(0..64)).map(|_| rng.gen()).collect());
+        }
+
+        for body in bodies {
+            for path in ["/create", "/update"] {
+                let req = Request::builder()
+                    .uri(path)
+                    .method(Method::POST)
+                    .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(body.clone()))
+                    .unwrap();
+                let res = app.clone().oneshot(req).await.unwrap();
+                let status = res.status();
+                let context = String::from_utf8_lossy(&body)
+                    .chars()
+                    .take(200)
+                    .collect::<String>();
+                if status == StatusCode::NO_CONTENT {
+                    continue;
+                }
+                // 失敗は全てerrorを持つJSONの400になる
+                assert_eq!(
+                    StatusCode::BAD_REQUEST,
+                    status,
+                    "seed {} {} {}",
+                    seed,
+                    path,
+                    context
+                );
+                let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let error: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                assert!(
+                    error["error"].is_string(),
+                    "seed {} {} {}",
+                    seed,
+                    path,
+                    context
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_reject_non_json_content_type_with_415() {
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        let app = create_app(
+            todo_repository.clone(),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let json = r#"{ "text": "x", "labels": [] }"#;
+
+        for content_type in [Some("text/plain"), Some("application/xml"), None] {
+            let mut req = Request::builder()
+                .uri("/todos")
+                .method(Method::POST)
+                .header(USER_ID_HEADER, TEST_USER_ID);
+            if let Some(content_type) = content_type {
+                req = req.header(header::CONTENT_TYPE, content_type);
+            }
+            let req = req.body(Body::from(json)).unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                res.status(),
+                "{:?}",
+                content_type
+            );
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(
+                body["error"],
+                "Expected request with `Content-Type: application/json`"
+            );
+        }
+        // 本文を読む前に断るため作成されない
+        let todos = todo_repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .unwrap();
+        assert!(todos.is_empty());
+
+        // パラメータ付きのJSONや+jsonは受け付け、本文の無いPOSTはContent-Typeを問わない
+        for content_type in [
+            "application/json; charset=utf-8",
+            "application/merge-patch+json",
+        ] {
+            let req = Request::builder()
+                .uri("/todos")
+                .method(Method::POST)
+                .header(USER_ID_HEADER, TEST_USER_ID)
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(json))
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status(), "{}", content_type);
+        }
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::POST, "/todos/1/undo"))
+            .await
+            .unwrap();
+        assert_ne!(StatusCode::UNSUPPORTED_MEDIA_TYPE, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_return_todo_stats() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::for_test(labels.clone());
+        test_support::seed_todos(
+            &todo_repository,
+            TEST_USER_ID,
+            ["open", "done"].map(|text| CreateTodo::new(text.to_string(), label_ids.clone())),
+        )
+        .await;
+        let req = build_todo_req_with_json(
+            "/todos/2",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        app.clone().oneshot(req).await.unwrap();
+
+        let res = app
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos/stats"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let stats: TodoStats = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!((stats.total, stats.open, stats.completed), (2, 1, 1));
+        assert_eq!(stats.labels.len(), 1);
+        assert_eq!(stats.labels[0].total, 2);
+    }
+
+    #[tokio::test]
+    async fn should_return_timeseries_or_reject_invalid_range() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(
+                Method::GET,
+                "/todos/stats/timeseries?from=2024-01-01&to=2024-01-03",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let points: Vec<TimeseriesPoint> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(points.len(), 3);
+
+        // 範囲が不正なら理由を本文で返す
+        for (path, message) in [
+            (
+                "/todos/stats/timeseries?from=2024-01-03&to=2024-01-01",
+                "Invalid query, from must not be after to",
+            ),
+            (
+                "/todos/stats/timeseries?from=2024-01-01&to=2025-12-31&bucket=day",
+                "Invalid query, range must not exceed 366 buckets",
+            ),
+        ] {
+            let res = app
+                .clone()
+                .oneshot(build_todo_req_with_empty(Method::GET, path))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", path);
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(body["error"], message, "{}", path);
+        }
+
+        let res = app
+            .oneshot(build_todo_req_with_empty(
+                Method::GET,
+                "/todos/stats/timeseries?from=2024-01-01",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_bulk_update_only_filtered_todos() {
+        let activity_repository = ActivityRepositoryForMemory::new();
+        let repository = TodoRepositoryForMemory::for_test(vec![]);
+        test_support::seed_todos(
+            &repository,
+            TEST_USER_ID,
+            ["first", "second", "third"].map(|text| CreateTodo::new(text.to_string(), vec![])),
+        )
+        .await;
+        repository
+            .update(TEST_USER_ID, 3, UpdateTodo::completion(true))
+            .await
+            .unwrap();
+        let app = create_app(
+            repository
+                .clone()
+                .with_activity(&activity_repository, activity_repository.clone()),
+            LabelRepositoryForMemory::new(),
+            activity_repository.clone(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        let req = build_todo_req_with_json(
+            "/todos?completed=false",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body, serde_json::json!({ "updated": 2 }));
+        let todos = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .unwrap();
+        assert!(todos.iter().all(|todo| todo.completed));
+        // 変更したtodoごとに完了として記録する
+        let events = activity_repository
+            .list(TEST_USER_ID, ActivityQuery::default())
+            .await
+            .unwrap();
+        let mut completed: Vec<i32> = events
+            .iter()
+            .filter(|event| event.kind == TodoEventKind::Completed)
+            .map(|event| event.todo_id)
+            .collect();
+        completed.sort();
+        assert_eq!(completed, vec![1, 2]);
+
+        // 絞り込みの無い変更やlimit付きの変更、変更できない項目は受け付けない
+        for (uri, body) in [
+            ("/todos", r#"{ "completed": false }"#),
+            ("/todos?sort=id_asc", r#"{ "completed": false }"#),
+            ("/todos?completed=true&limit=1", r#"{ "completed": false }"#),
+            ("/todos?completed=true", r#"{ "text": "renamed" }"#),
+        ] {
+            let req = build_todo_req_with_json(uri, Method::PATCH, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{} {}", uri, body);
+        }
+        let todos = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .unwrap();
+        assert!(todos.iter().all(|todo| todo.completed));
+    }
+
+    #[tokio::test]
+    async fn should_export_todos_as_ndjson_or_csv() {
+        let repository = TodoRepositoryForMemory::for_test(vec![]);
+        test_support::seed_todos(
+            &repository,
+            TEST_USER_ID,
+            ["plain", "with, \"quotes\""].map(|text| CreateTodo::new(text.to_string(), vec![])),
+        )
+        .await;
+        let app = create_app(
+            repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(
+                Method::GET,
+                "/todos/export?sort=id_asc",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/x-ndjson");
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let texts: Vec<String> = std::str::from_utf8(&bytes)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<TodoEntity>(line).unwrap().text)
+            .collect();
+        assert_eq!(texts, vec!["plain", "with, \"quotes\""]);
+
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(
+                Method::GET,
+                "/todos/export?format=csv&sort=id_desc&limit=1",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/csv; charset=utf-8");
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let csv = std::str::from_utf8(&bytes).unwrap();
+        let lines: Vec<&str> = csv.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 2, "{}", csv);
+        assert!(lines[0].starts_with("id,public_id,text,completed,"));
+        assert!(
+            lines[1].starts_with("2,") && lines[1].contains(",\"with, \"\"quotes\"\"\",false,"),
+            "{}",
+            lines[1]
+        );
+
+        for path in ["/todos/export?format=xml", "/todos/export?limit=0"] {
+            let res = app
+                .clone()
+                .oneshot(build_todo_req_with_empty(Method::GET, path))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_report_daily_completions_or_reject_invalid_range() {
+        let repository = TodoRepositoryForMemory::for_test(vec![]);
+        for (text, completed) in [("a", true), ("b", true), ("c", false)] {
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .unwrap();
+            if completed {
+                repository
+                    .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+                    .await
+                    .unwrap();
+            }
+        }
+        let app = create_app(
+            repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        // memoryのrepositoryは2024-01-01に完了したものとして記録する
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(
+                Method::GET,
+                "/todos/report/completions?from=2023-12-31&to=2024-01-02",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!([
+                { "date": "2023-12-31", "count": 0 },
+                { "date": "2024-01-01", "count": 2 },
+                { "date": "2024-01-02", "count": 0 },
+            ])
+        );
+
+        // 範囲が不正なら理由を本文で返す
+        for (path, message) in [
+            (
+                "/todos/report/completions?from=2024-01-03&to=2024-01-01",
+                "Invalid query, from must not be after to",
+            ),
+            (
+                "/todos/report/completions?from=2024-01-01&to=2025-12-31",
+                "Invalid query, range must not exceed 366 buckets",
+            ),
+        ] {
+            let res = app
+                .clone()
+                .oneshot(build_todo_req_with_empty(Method::GET, path))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", path);
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(body["error"], message, "{}", path);
+        }
+
+        let res = app
+            .oneshot(build_todo_req_with_empty(
+                Method::GET,
+                "/todos/report/completions?to=2024-01-01",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_list_activity_newest_first() {
+        let activity_repository = ActivityRepositoryForMemory::new();
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![])
+                .with_activity(&activity_repository, activity_repository.clone()),
+            LabelRepositoryForMemory::new(),
+            activity_repository,
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "logged", "labels": [] }"#.to_string(),
+        );
+        app.clone().oneshot(req).await.unwrap();
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        app.clone().oneshot(req).await.unwrap();
+
+        let res = app
+            .oneshot(build_todo_req_with_empty(Method::GET, "/activity?limit=10"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let events: Vec<TodoEvent> = serde_json::from_slice(&bytes).unwrap();
+        let kinds: Vec<TodoEventKind> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![TodoEventKind::Completed, TodoEventKind::Created]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_return_crate_version() {
+        let res = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(build_todo_req_with_empty(Method::GET, "/version"))
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let version: Version = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+        assert!(!version.git_sha.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_report_ready_only_when_dependencies_are_up() {
+        use crate::handlers::health::{Readiness, ReadinessCheck};
+
+        struct Stub(bool);
+
+        #[async_trait]
+        impl ReadinessCheck for Stub {
+            async fn check(&self) -> anyhow::Result<()> {
+                if self.0 {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("database is down"))
+                }
+            }
+        }
+
+        // 認証の外にあるため、トークン無しで確かめられる
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig {
+                token: Some("secret".to_string()),
+                protect_reads: true,
+                ..Default::default()
+            },
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let get = |app: Router, path: &'static str| async move {
+            let req = Request::builder()
+                .uri(path)
+                .method(Method::GET)
+                .body(Body::empty())
+                .unwrap();
+            app.oneshot(req).await.unwrap().status()
+        };
+        // 確かめる依存先が無ければready
+        assert_eq!(get(app.clone(), "/ready").await, StatusCode::OK);
+
+        let down = app.clone().layer(Extension(Readiness::new(Stub(false))));
+        assert_eq!(
+            get(down.clone(), "/ready").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // livenessは依存先に関わらず200
+        assert_eq!(get(down, "/health").await, StatusCode::OK);
+        let up = app.clone().layer(Extension(Readiness::new(Stub(true))));
+        assert_eq!(get(up, "/ready").await, StatusCode::OK);
+
+        // 接続できないDBは503になる
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(500))
+            .connect_lazy("postgres://localhost:1/unused")
+            .unwrap();
+        let unreachable = app.clone().layer(Extension(Readiness::new(pool)));
+        assert_eq!(
+            get(unreachable, "/ready").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // migrationsを適用したDBならready
+        #[cfg(feature = "database-test")]
+        if let Some((_database, pool)) = crate::repositories::test_database::db_pool_or_skip().await
+        {
+            let up = app.layer(Extension(Readiness::new(pool)));
+            assert_eq!(get(up, "/ready").await, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_return_pool_stats_only_with_pool() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/debug/pool"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        // 最初の問い合わせまで接続しないため、DBが無くても作れる
+        let pool = PgPoolOptions::new()
+            .max_connections(3)
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap();
+        let res = app
+            .layer(Extension(pool))
+            .oneshot(build_todo_req_with_empty(Method::GET, "/debug/pool"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            stats,
+            serde_json::json!({ "size": 0, "idle": 0, "in_use": 0, "max_connections": 3 })
+        );
+    }
+
+    #[tokio::test]
+    async fn should_execute_graphql_query_for_requesting_user() {
+        let todo_repository = TodoRepositoryForMemory::for_test(vec![]);
+        todo_repository
+            .create(TEST_USER_ID, CreateTodo::new("graphql".to_string(), vec![]))
+            .await
+            .unwrap();
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        )
+        .oneshot(build_todo_req_with_json(
+            "/graphql",
+            Method::POST,
+            r#"{ "query": "{ todos { id text } }" }"#.to_string(),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "data": { "todos": [{ "id": 1, "text": "graphql" }] } })
+        );
+    }
+
+    #[tokio::test]
+    async fn should_manage_webhooks_with_masked_secret() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let req = build_todo_req_with_json(
+            "/webhooks",
+            Method::POST,
+            r#"{ "url": "http://example.com/hook", "events": ["todo.created"] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+        // 作成時だけsecretがそのまま返る
+        let req = build_todo_req_with_json(
+            "/webhooks",
+            Method::POST,
+            r#"{ "url": "https://example.com/hook", "events": ["todo.created", "todo.completed"] }"#
+                .to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let created: Webhook = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(created.secret.len(), 64);
+
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/webhooks"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let webhooks: Vec<Webhook> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(webhooks, vec![created.clone().masked()]);
+        assert!(!webhooks[0].secret.contains(&created.secret[..8]));
+
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::DELETE, "/webhooks/1"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+        let res = app
+            .oneshot(build_todo_req_with_empty(Method::GET, "/webhooks/1"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_compress_only_large_responses() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let gzip_req = || {
+            let mut req = build_todo_req_with_empty(Method::GET, "/todos");
+            req.headers_mut()
+                .insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+            req
+        };
+
+        // 空の一覧はしきい値未満なので圧縮しない
+        let res = app.clone().oneshot(gzip_req()).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+
+        for _ in 0..20 {
+            app.clone().oneshot(create_todo_req()).await.unwrap();
+        }
+        let res = app.oneshot(gzip_req()).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+    }
+
+    #[tokio::test]
+    async fn should_undo_last_change_or_conflict() {
+        let activity_repository = ActivityRepositoryForMemory::new();
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![])
+                .with_activity(&activity_repository, activity_repository.clone()),
+            LabelRepositoryForMemory::new(),
+            activity_repository,
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        app.clone().oneshot(create_todo_req()).await.unwrap();
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "text": "changed" }"#.to_string(),
+        );
+        let changed = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::POST, "/todos/1/undo"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos/1"))
+            .await
+            .unwrap();
+        let todo = res_to_todo(res).await;
+        assert_ne!(todo.text, changed.text);
+
+        // 作成も取り消すと履歴が尽きる
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::POST, "/todos/1/undo"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::POST, "/todos/1/undo"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let error = body["error"].as_str().unwrap();
+        assert!(error.contains("Nothing to undo"), "{}", error);
+    }
+
+    // appをポートに割り当てて起動し、/wsに接続する
+    async fn connect_ws(
+        app: Router,
+        query: &str,
+    ) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service()).await });
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws{}", addr, query))
+            .await
+            .expect("failed to connect websocket");
+        socket
+    }
+
+    async fn next_ws_message<S>(socket: &mut S) -> ServerMessage
+    where
+        S: futures_util::Stream<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>>
+            + Unpin,
+    {
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(1), socket.next())
+                .await
+                .expect("no websocket message")
+                .unwrap()
+                .unwrap();
+            if let WsMessage::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_toggle_todo_and_push_event_over_websocket() {
+        let activity = ActivityRepositoryForMemory::new();
+        let todos =
+            TodoRepositoryForMemory::for_test(vec![]).with_activity(&activity, activity.clone());
+        let todo = todos
+            .create(TEST_USER_ID, CreateTodo::new("live".to_string(), vec![]))
+            .await
+            .unwrap();
+        let app = create_app(
+            todos,
+            LabelRepositoryForMemory::new(),
+            activity,
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let mut socket = connect_ws(app, &format!("?user_id={}", TEST_USER_ID)).await;
+        assert_eq!(
+            next_ws_message(&mut socket).await,
+            ServerMessage::Authenticated {
+                user_id: TEST_USER_ID
+            }
+        );
+
+        socket
+            .send(WsMessage::Text(format!(
+                r#"{{"op":"toggle","id":{}}}"#,
+                todo.id
+            )))
+            .await
+            .unwrap();
+        // 変更の配信とコマンドの結果はどちらが先に届いてもよい
+        let mut replied = None;
+        let mut pushed = None;
+        while replied.is_none() || pushed.is_none() {
+            match next_ws_message(&mut socket).await {
+                ServerMessage::Ok { todo } => replied = todo,
+                ServerMessage::Event { event } => pushed = Some(event),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        assert!(replied.unwrap().completed);
+        let event = pushed.unwrap();
+        assert_eq!(event.kind, TodoEventKind::Completed);
+        assert_eq!(event.todo_id, todo.id);
+
+        // 存在しないtodoへのコマンドはエラーを返し、接続は続く
+        socket
+            .send(WsMessage::Text(r#"{"op":"delete","id":999}"#.to_string()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_ws_message(&mut socket).await,
+            ServerMessage::Error { .. }
+        ));
+        socket
+            .send(WsMessage::Text(r#"{"op":"rename"}"#.to_string()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_ws_message(&mut socket).await,
+            ServerMessage::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_require_websocket_auth_before_commands() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            token_auth(false),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        // トークンが無いのでuser_idは受け付けられない
+        let mut socket = connect_ws(app, &format!("?user_id={}", TEST_USER_ID)).await;
+        socket
+            .send(WsMessage::Text(r#"{"op":"toggle","id":1}"#.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_ws_message(&mut socket).await,
+            ServerMessage::Error {
+                message: "Not authenticated".to_string()
+            }
+        );
+
+        socket
+            .send(WsMessage::Text(
+                r#"{"op":"auth","token":"wrong","user_id":1}"#.to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_ws_message(&mut socket).await,
+            ServerMessage::Error {
+                message: "Invalid token".to_string()
+            }
+        );
+        socket
+            .send(WsMessage::Text(
+                r#"{"op":"auth","token":"secret","user_id":1}"#.to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_ws_message(&mut socket).await,
+            ServerMessage::Authenticated { user_id: 1 }
+        );
+    }
+
+    // 応答の本文を文字列で返す
+    async fn res_to_text(res: Response) -> String {
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    // 時刻と公開idは実行ごとに変わりうるので置き換える. 整数のidはJSONのredactionで置き換える
+    fn snapshot_settings() -> insta::Settings {
+        let mut settings = insta::Settings::clone_current();
+        settings.add_filter(
+            r"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(\.\d+)?(Z|[+-]\d{2}:\d{2})",
+            "[timestamp]",
+        );
+        settings.add_filter(
+            r"[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}",
+            "[public_id]",
+        );
+        settings
+    }
+
+    #[tokio::test]
+    async fn should_keep_json_bodies_of_todo_and_label() {
+        let (labels, label_ids) = label_fixture();
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(labels),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Bare,
+        );
+        let mut bodies = Vec::new();
+        for (text, labels) in [("plain", vec![]), ("labeled", label_ids)] {
+            let req = build_todo_req_with_json(
+                "/todos",
+                Method::POST,
+                serde_json::json!({ "text": text, "labels": labels }).to_string(),
+            );
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+            bodies
+                .push(serde_json::from_str::<serde_json::Value>(&res_to_text(res).await).unwrap());
+        }
+        let req =
+            build_label_req_with_json("/labels", Method::POST, r#"{ "name": "work" }"#.to_string());
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let label: serde_json::Value = serde_json::from_str(&res_to_text(res).await).unwrap();
+
+        snapshot_settings().bind(|| {
+            insta::assert_json_snapshot!("todo_without_labels", bodies[0], { ".id" => "[id]" });
+            insta::assert_json_snapshot!("todo_with_labels", bodies[1], {
+                ".id" => "[id]",
+                ".labels[].id" => "[id]",
+            });
+            insta::assert_json_snapshot!("label", label, { ".id" => "[id]" });
+        });
+    }
+
+    #[tokio::test]
+    async fn should_keep_error_bodies() {
+        let app = flaky_app(flaky_repository().await);
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos/99"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        let not_found = res_to_text(res).await;
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "", "labels": [] }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        let validation = res_to_text(res).await;
+
+        let repository = flaky_repository().await.fail_next(
+            flaky::Method::Create,
+            RepositoryError::Unexpected("connection lost".to_string()),
+        );
+        let res = flaky_app(repository)
+            .oneshot(create_todo_req())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        let internal = res_to_text(res).await;
+
+        snapshot_settings().bind(|| {
+            insta::assert_snapshot!("error_not_found", not_found);
+            insta::assert_snapshot!("error_validation", validation);
+            insta::assert_snapshot!("error_internal", internal);
+        });
+    }
+
+    #[tokio::test]
+    async fn should_keep_list_envelope() {
+        let app = create_app(
+            TodoRepositoryForMemory::for_test(vec![]),
+            LabelRepositoryForMemory::new(),
+            ActivityRepositoryForMemory::new(),
+            WebhookRepositoryForMemory::new(),
+            AuthConfig::default(),
+            DEFAULT_REQUEST_TIMEOUT,
+            ListFormat::Envelope,
+        );
+        for text in ["first", "second"] {
+            let req = build_todo_req_with_json(
+                "/todos",
+                Method::POST,
+                format!(r#"{{ "text": "{}", "labels": [] }}"#, text),
+            );
+            app.clone().oneshot(req).await.unwrap();
+        }
+        let res = app
+            .oneshot(build_todo_req_with_empty(
+                Method::GET,
+                "/todos?sort=id_asc&limit=1",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let body: serde_json::Value = serde_json::from_str(&res_to_text(res).await).unwrap();
+
+        snapshot_settings().bind(|| {
+            insta::assert_json_snapshot!("list_envelope", body, { ".data[].id" => "[id]" });
+        });
+    }
+
+    #[tokio::test]
+    async fn should_keep_export_formats() {
+        let (labels, label_ids) = label_fixture();
+        let repository = TodoRepositoryForMemory::for_test(labels);
+        repository
+            .create(TEST_USER_ID, CreateTodo::new("plain".to_string(), vec![]))
+            .await
+            .unwrap();
+        repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("with, \"quotes\"".to_string(), label_ids),
+            )
+            .await
+            .unwrap();
+        let app = flaky_app(FlakyTodoRepository::new(repository));
+
+        let mut bodies = Vec::new();
+        for path in [
+            "/todos/export?sort=id_asc",
+            "/todos/export?format=csv&sort=id_asc",
+        ] {
+            let res = app
+                .clone()
+                .oneshot(build_todo_req_with_empty(Method::GET, path))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, res.status(), "{}", path);
+            bodies.push(res_to_text(res).await);
+        }
+
+        // JSONのredactionが使えないので、行ごとのidも文字列のfilterで置き換える
+        let mut settings = snapshot_settings();
+        settings.add_filter(r#""id":\d+"#, r#""id":"[id]""#);
+        settings.add_filter(r"(?m)^\d+,", "[id],");
+        settings.bind(|| {
+            insta::assert_snapshot!("export_ndjson", bodies[0]);
+            insta::assert_snapshot!("export_csv", bodies[1]);
+        });
+    }
+}
@@ -1,335 +1,557 @@
-mod handlers;
-mod repositories;
-
-use crate::repositories::{
-    label::LabelRepositoryForDb,
-    todo::{TodoRepository, TodoRepositoryForDb},
-};
-use axum::{
-    extract::Extension,
-    routing::{delete, get, post},
-    Router,
-};
+use anyhow::Context;
+use axum::{Extension, Router};
+use clap::{Args, Parser, Subcommand};
 use dotenv::dotenv;
-use handlers::{
-    label::{all_label, create_label, delete_label},
-    todo::{all_todo, create_todo, delete_todo, find_todo, update_todo},
+#[cfg(feature = "redis")]
+use my_todo::repositories::redis_cache::RedisCachedTodoRepository;
+#[cfg(feature = "memory")]
+use my_todo::repositories::{
+    activity::memory::ActivityRepositoryForMemory,
+    label::memory::LabelRepositoryForMemory,
+    todo::memory::{TodoRepositoryForMemory, SNAPSHOT_PATH},
+    webhook::memory::WebhookRepositoryForMemory,
+};
+use my_todo::{
+    auth::AuthConfig,
+    create_app, grpc,
+    handlers::{cache::CacheControl, health::Readiness, links::BasePath, ListFormat},
+    reminders::run_reminder_loop,
+    repositories::{
+        activity::{ActivityRepository, ActivityRepositoryForDb, TodoEvent},
+        cache::CachedTodoRepository,
+        instrumented::InstrumentedRepository,
+        label::{LabelRepository, LabelRepositoryForDb},
+        retry::RetryPolicy,
+        todo::{
+            init_max_todo_text_len, DynTodoRepository, TodoRepository, TodoRepositoryForDb,
+            TodoSort,
+        },
+        webhook::{WebhookRepository, WebhookRepositoryForDb},
+        DEFAULT_QUERY_TIMEOUT,
+    },
+    seed::{self, SeedOptions},
+    webhooks::{
+        run_webhook_delivery, HttpWebhookClient, PublishingActivity, WebhookNotifier,
+        DEFAULT_DELIVERY_POLICY,
+    },
+    DEFAULT_REQUEST_TIMEOUT,
 };
-use hyper::header::CONTENT_TYPE;
-use repositories::label::LabelRepository;
 use sqlx::PgPool;
 use std::net::SocketAddr;
-use std::{env, sync::Arc};
-use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use std::{backtrace::Backtrace, env, panic, process::ExitCode, sync::Arc, time::Duration};
+use tokio::{
+    signal,
+    sync::{mpsc, watch},
+};
+
+/// gRPCを待ち受けるポートの既定値. GRPC_PORTで変更できる
+const DEFAULT_GRPC_PORT: u16 = 50051;
+
+/// webhookの配信待ちイベントの上限. 溢れたイベントは配信しない
+const WEBHOOK_QUEUE_SIZE: usize = 1024;
+
+/// todoアプリのサーバーと管理用のコマンド
+#[derive(Debug, Parser, PartialEq, Eq)]
+#[command(name = "todo-app", version)]
+struct Cli {
+    /// 省略した場合はserveと同じ
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand, PartialEq, Eq)]
+enum Command {
+    /// HTTPとgRPCのサーバーを起動する
+    Serve,
+    /// migrationsを適用して終了する
+    Migrate,
+    /// 開発用のサンプルデータを作成する. APP_ENV=productionでは実行しない
+    Seed(SeedArgs),
+}
+
+#[derive(Debug, Args, PartialEq, Eq)]
+struct SeedArgs {
+    /// 作成後にユーザーが持つtodoの数
+    #[arg(long, default_value_t = 50)]
+    todos: usize,
+    /// 作成するlabelの数
+    #[arg(long, default_value_t = 5)]
+    labels: usize,
+    /// データを作成するユーザー
+    #[arg(long, default_value_t = 1)]
+    user: i32,
+    /// 作成前にtodoとlabelを全て削除する
+    #[arg(long)]
+    fresh: bool,
+}
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     // loggingの初期化
     let log_level = env::var("RUST_LOG").unwrap_or("info".to_string());
     env::set_var("RUST_LOG", log_level);
     tracing_subscriber::fmt::init();
+    // panicの内容とbacktraceはpanicしたその場でしか取れないため、hookでログに残す
+    panic::set_hook(Box::new(|info| {
+        tracing::error!("{}\n{}", info, Backtrace::force_capture());
+    }));
     dotenv().ok();
 
+    let result = match Cli::parse().command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            serve().await;
+            Ok(())
+        }
+        Command::Migrate => migrate().await,
+        Command::Seed(args) => seed(args).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!("{:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// todoなどの保存先. TODO_BACKENDで選ぶ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Postgres,
+    /// プロセス内のメモリ. SNAPSHOT_PATHを設定すると停止後もtodoが残る
+    Memory,
+}
+
+impl Backend {
+    /// 未設定ならpostgres
+    fn from_env() -> Self {
+        match env::var("TODO_BACKEND").as_deref() {
+            Err(_) | Ok("postgres") => Self::Postgres,
+            Ok("memory") => Self::Memory,
+            Ok(backend) => panic!(
+                "invalid [TODO_BACKEND] {}, expected memory or postgres",
+                backend
+            ),
+        }
+    }
+}
+
+async fn serve() {
+    // 1リクエストの上限時間(秒). 未設定なら30秒
+    let request_timeout = env::var("REQUEST_TIMEOUT_SECS")
+        .map(|secs| {
+            Duration::from_secs(
+                secs.parse()
+                    .expect("invalid [REQUEST_TIMEOUT_SECS], expected seconds"),
+            )
+        })
+        .unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+
+    // todoのtextの最大文字数は起動時に1度だけ読み、以降の検証で使う
+    init_max_todo_text_len().expect("failed to read max todo text length");
+
+    let auth = AuthConfig::from_env();
+    if auth.token.is_none() {
+        tracing::warn!("[API_TOKEN] is not set, requests are not authenticated");
+    }
+    if auth.users.is_empty() {
+        tracing::warn!(
+            "[API_USER_TOKENS] is not set, the user is taken from the unverified X-User-Id header"
+        );
+    }
+
+    // 記録したtodoの変更をwebhookへ配信するキュー
+    let (webhook_queue_tx, webhook_queue_rx) = mpsc::channel(WEBHOOK_QUEUE_SIZE);
+    let server = Server {
+        auth,
+        request_timeout,
+        webhook_queue_rx,
+    };
+    match Backend::from_env() {
+        Backend::Postgres => serve_postgres(server, webhook_queue_tx).await,
+        #[cfg(feature = "memory")]
+        Backend::Memory => serve_memory(server, webhook_queue_tx).await,
+        #[cfg(not(feature = "memory"))]
+        Backend::Memory => panic!("[TODO_BACKEND] memory requires the memory feature"),
+    }
+}
+
+async fn serve_postgres(server: Server, webhook_queue_tx: mpsc::Sender<TodoEvent>) {
     let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
     tracing::debug!("start connect database...");
     let pool = PgPool::connect(database_url)
         .await
-        .expect(&format!("fail connect database, url is [{}]", database_url));
+        .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+    // 読み取りを向けるreplica. DATABASE_REPLICA_URLでも指定できる. 未設定ならDATABASE_URLから読む
+    let read_pool =
+        match env::var("DATABASE_READ_URL").or_else(|_| env::var("DATABASE_REPLICA_URL")) {
+            Ok(read_url) => PgPool::connect(&read_url)
+                .await
+                .unwrap_or_else(|_| panic!("fail connect read database, url is [{}]", read_url)),
+            Err(_) => pool.clone(),
+        };
+
+    // 1回のDB問い合わせの上限時間(秒). 未設定なら5秒
+    let query_timeout = env::var("DATABASE_QUERY_TIMEOUT_SECS")
+        .map(|secs| {
+            Duration::from_secs(
+                secs.parse()
+                    .expect("invalid [DATABASE_QUERY_TIMEOUT_SECS], expected seconds"),
+            )
+        })
+        .unwrap_or(DEFAULT_QUERY_TIMEOUT);
 
-    let app = create_app(
-        TodoRepositoryForDb::new(pool.clone()),
-        LabelRepositoryForDb::new(pool.clone()),
+    // 一時的なDBエラーの再試行回数と初回の待ち時間(ミリ秒). 未設定なら2回/50ms
+    let default_retry = RetryPolicy::default();
+    let retry_policy = RetryPolicy {
+        max_retries: env::var("DATABASE_RETRY_MAX")
+            .map(|n| {
+                n.parse()
+                    .expect("invalid [DATABASE_RETRY_MAX], expected count")
+            })
+            .unwrap_or(default_retry.max_retries),
+        base_backoff: env::var("DATABASE_RETRY_BACKOFF_MS")
+            .map(|ms| {
+                Duration::from_millis(
+                    ms.parse()
+                        .expect("invalid [DATABASE_RETRY_BACKOFF_MS], expected milliseconds"),
+                )
+            })
+            .unwrap_or(default_retry.base_backoff),
+    };
+
+    let activity_repository = PublishingActivity::new(
+        ActivityRepositoryForDb::new(pool.clone()).with_query_timeout(query_timeout),
+        webhook_queue_tx,
+    );
+    // DBへの操作ごとにspanを作り、所要時間と失敗の数をmetricsに記録する.
+    // todoの変更は同じトランザクションでactivityにも記録する
+    let todo_repository = InstrumentedRepository::new(
+        TodoRepositoryForDb::new(pool.clone())
+            .with_read_pool(read_pool.clone())
+            .with_query_timeout(query_timeout)
+            .with_retry_policy(retry_policy)
+            .with_default_sort(TodoSort::from_env().expect("failed to read default todo order"))
+            .with_activity(activity_repository.clone()),
     );
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000)); // 127.0.0.1:3000 (localhost:3000)
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    tracing::debug!("listening on {}", addr);
+    let label_repository = InstrumentedRepository::new(
+        LabelRepositoryForDb::new(pool.clone())
+            .with_read_pool(read_pool)
+            .with_query_timeout(query_timeout),
+    );
+    let webhook_repository =
+        WebhookRepositoryForDb::new(pool.clone()).with_query_timeout(query_timeout);
 
-    axum::serve(listener, app.into_make_service())
-        .await // 非同期タスクはawaitされるまで実行されない
-        .unwrap();
+    server
+        .run(
+            todo_repository,
+            label_repository,
+            activity_repository,
+            webhook_repository,
+            |app| {
+                // /readyでDBに問い合わせができ、migrationsが適用済みかを確かめる
+                app.layer(Extension(Readiness::new(pool.clone())))
+                    // 開発ビルドの/debug/poolで接続プールの状態を返す
+                    .layer(Extension(pool))
+            },
+        )
+        .await;
 }
 
-/// # create_app
-/// This function create app and define routing
-///
-/// ## argumentation
-/// * repository: something that is impl TodoRepository
-///
-/// ## Return
-/// * app route: Router
-fn create_app<Todo: TodoRepository, Label: LabelRepository>(
-    todo_repository: Todo,
-    label_repository: Label,
-) -> Router {
-    Router::new()
-        .route("/", get(root))
-        .route("/todos", post(create_todo::<Todo>).get(all_todo::<Todo>))
-        .route(
-            "/todos/:id",
-            get(find_todo::<Todo>)
-                .delete(delete_todo::<Todo>)
-                .patch(update_todo::<Todo>),
-        )
-        .route(
-            "/labels",
-            post(create_label::<Label>).get(all_label::<Label>),
-        )
-        .route("/labels/:id", delete(delete_label::<Label>))
-        .layer(Extension(Arc::new(todo_repository))) // axumアプリ内でrepositoryを共有できる
-        .layer(Extension(Arc::new(label_repository)))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(AllowOrigin::exact("http://localhost:3001".parse().unwrap()))
-                .allow_methods(Any)
-                .allow_headers(vec![CONTENT_TYPE]),
+// DB無しで動かす. todoはSNAPSHOT_PATHのファイルに書き出せば再起動後も残るが、
+// 変更の記録・labelとwebhookはメモリにだけ持つので消える
+#[cfg(feature = "memory")]
+async fn serve_memory(server: Server, webhook_queue_tx: mpsc::Sender<TodoEvent>) {
+    match env::var(SNAPSHOT_PATH) {
+        Ok(path) => tracing::info!("todos are persisted to the snapshot {}", path),
+        Err(_) => tracing::warn!("[SNAPSHOT_PATH] is not set, todos are lost on exit"),
+    }
+    let events = ActivityRepositoryForMemory::new();
+    let activity_repository = PublishingActivity::new(events.clone(), webhook_queue_tx);
+    // /labelsで作成したlabelをtodoに付けられるよう、todoと同じlabelのrepositoryを使う
+    let labels = LabelRepositoryForMemory::new();
+    let todos = TodoRepositoryForMemory::new()
+        .with_labels(&labels)
+        .with_snapshot_from_env()
+        .with_default_sort(TodoSort::from_env().expect("failed to read default todo order"))
+        .with_activity(&events, activity_repository.clone());
+    // DBと同じく、操作ごとにspanを作り、所要時間と失敗の数をmetricsに記録する
+    server
+        .run(
+            InstrumentedRepository::new(todos.clone()),
+            InstrumentedRepository::new(labels),
+            activity_repository,
+            WebhookRepositoryForMemory::new(),
+            |app| app,
         )
+        .await;
+    // 書き出しを待っている変更は、プロセスの終了で書き出しタスクが止まる前に書き出す
+    if let Err(e) = todos.flush_snapshot() {
+        tracing::error!("failed to write snapshot: {:#}", e);
+    }
 }
 
-async fn root() -> &'static str {
-    "Hello, world!"
+// 保存先によらないサーバーの設定
+struct Server {
+    auth: AuthConfig,
+    request_timeout: Duration,
+    webhook_queue_rx: mpsc::Receiver<TodoEvent>,
 }
 
-// test
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::repositories::{
-        label::{test_utils::LabelRepositoryForMemory, Label},
-        todo::{test_utils::TodoRepositoryForMemory, CreateTodo, TodoEntity},
-    };
-    use axum::response::Response;
-    use axum::{
-        body::Body,
-        http::{header, Method, Request, StatusCode},
-    };
-    use tower::ServiceExt;
-
-    // requestをbuildして作成
-    fn build_todo_req_with_json(path: &str, method: Method, json_body: String) -> Request<Body> {
-        Request::builder()
-            .uri(path)
-            .method(method)
-            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
-            .body(Body::from(json_body))
-            .unwrap()
-    }
+impl Server {
+    // HTTPとgRPCのサーバーとバックグラウンドタスクを、停止の合図を受けるまで動かす.
+    // layerで保存先に応じたExtensionを加える
+    async fn run<T, L, A, W>(
+        self,
+        todo_repository: T,
+        label_repository: L,
+        activity_repository: A,
+        webhook_repository: W,
+        layer: impl FnOnce(Router) -> Router,
+    ) where
+        T: TodoRepository + Clone,
+        L: LabelRepository,
+        A: ActivityRepository,
+        W: WebhookRepository,
+    {
+        let Server {
+            auth,
+            request_timeout,
+            webhook_queue_rx,
+        } = self;
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
-    fn build_label_req_with_json(path: &str, method: Method, json_body: String) -> Request<Body> {
-        Request::builder()
-            .uri(path)
-            .method(method)
-            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
-            .body(Body::from(json_body))
-            .unwrap()
-    }
+        // 記録したtodoの変更をwebhookへ配信するバックグラウンドタスク
+        let webhook_task = tokio::spawn(run_webhook_delivery(
+            webhook_repository.clone(),
+            HttpWebhookClient::new(Duration::from_secs(10)),
+            DEFAULT_DELIVERY_POLICY,
+            webhook_queue_rx,
+            shutdown_rx.clone(),
+        ));
 
-    // response作成
-    fn build_todo_req_with_empty(method: Method, path: &str) -> Request<Body> {
-        Request::builder()
-            .uri(path)
-            .method(method)
-            .body(Body::empty())
-            .unwrap()
-    }
+        // リマインド送信のバックグラウンドタスク. リマインドはログに出し、webhookにも配信する.
+        // サーバー停止時に合わせて止める
+        let reminder_task = tokio::spawn(run_reminder_loop(
+            todo_repository.clone(),
+            WebhookNotifier::new(activity_repository.clone()),
+            Duration::from_secs(60),
+            shutdown_rx.clone(),
+        ));
+        // 一覧と取得の結果を保持する時間(秒). 未設定ならキャッシュしない
+        let cache_ttl = env::var("CACHE_TTL_SECS").ok().map(|secs| {
+            Duration::from_secs(
+                secs.parse()
+                    .expect("invalid [CACHE_TTL_SECS], expected seconds"),
+            )
+        });
+        let todo_repository: DynTodoRepository = match cache_ttl {
+            Some(ttl) => cached(todo_repository, ttl, shutdown_rx.clone()).await,
+            None => Arc::new(todo_repository),
+        };
+        // HTTPと同じrepositoryをgRPCでも公開する. HTTPのサーバーが止まった後に止める
+        let grpc_port = env::var("GRPC_PORT")
+            .map(|port| {
+                port.parse()
+                    .expect("invalid [GRPC_PORT], expected port number")
+            })
+            .unwrap_or(DEFAULT_GRPC_PORT);
+        let grpc_addr = SocketAddr::from(([127, 0, 0, 1], grpc_port));
+        let grpc_listener = tokio::net::TcpListener::bind(&grpc_addr).await.unwrap();
+        tracing::debug!("grpc listening on {}", grpc_addr);
+        let grpc_task = tokio::spawn(grpc::serve(
+            grpc_listener,
+            todo_repository.clone(),
+            label_repository.clone(),
+            auth.clone(),
+            shutdown_rx.clone(),
+        ));
 
-    fn build_label_req_with_empty(method: Method, path: &str) -> Request<Body> {
-        Request::builder()
-            .uri(path)
-            .method(method)
-            .body(Body::empty())
-            .unwrap()
-    }
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            activity_repository,
+            webhook_repository,
+            auth,
+            request_timeout,
+            ListFormat::from_env().expect("failed to read list format"),
+        )
+        // リバースプロキシの下で公開する時に、リンクの先頭に付けるパス
+        .layer(Extension(BasePath::from_env()))
+        // GETのレスポンスをCDNやブラウザがキャッシュできる時間
+        .layer(Extension(CacheControl::default()));
+        let app = layer(app);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 3000)); // 127.0.0.1:3000 (localhost:3000)
+        let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+        tracing::debug!("listening on {}", addr);
 
-    async fn res_to_todo(res: Response) -> TodoEntity {
-        let bytes = axum::body::to_bytes(res.into_body(), 128).await.unwrap();
-        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
-        let todo: TodoEntity = serde_json::from_str(&body)
-            .expect(&format!("cannot convert Todo instance. body: {}", body));
-        todo
-    }
+        axum::serve(listener, app.into_make_service())
+            .with_graceful_shutdown(shutdown_signal())
+            .await // 非同期タスクはawaitされるまで実行されない
+            .unwrap();
 
-    async fn res_to_label(res: Response) -> Label {
-        let bytes = axum::body::to_bytes(res.into_body(), 128).await.unwrap();
-        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
-        let label: Label = serde_json::from_str(&body)
-            .expect(&format!("cannot convert Label instance. body: {}", body));
-        label
+        shutdown_tx.send(true).ok();
+        reminder_task.await.ok();
+        webhook_task.await.ok();
+        if let Ok(Err(e)) = grpc_task.await {
+            tracing::error!("grpc server failed: {}", e);
+        }
     }
+}
 
-    fn label_fixture() -> (Vec<Label>, Vec<i32>) {
-        let id = 999;
-        (
-            vec![Label {
-                id,
-                name: String::from("test label"),
-            }],
-            vec![id],
-        )
+// REDIS_URLがあれば全てのインスタンスで共有するRedisに、無ければプロセス内にキャッシュする
+#[cfg(feature = "redis")]
+async fn cached<T: TodoRepository>(
+    repository: T,
+    ttl: Duration,
+    shutdown: watch::Receiver<bool>,
+) -> DynTodoRepository {
+    let Ok(redis_url) = env::var("REDIS_URL") else {
+        return Arc::new(CachedTodoRepository::new(repository, ttl));
+    };
+    let client = redis::Client::open(redis_url).expect("invalid [REDIS_URL]");
+    match redis::aio::ConnectionManager::new(client.clone()).await {
+        Ok(redis) => {
+            let repository = RedisCachedTodoRepository::new(repository, redis, ttl);
+            tokio::spawn(repository.invalidation_listener(client, shutdown));
+            Arc::new(repository)
+        }
+        // インスタンスごとにキャッシュすると他のインスタンスの書き込みが見えないため、キャッシュしない
+        Err(e) => {
+            tracing::warn!("failed to connect redis, todos are not cached: {}", e);
+            Arc::new(repository)
+        }
     }
+}
 
-    #[tokio::test]
-    async fn should_created_todo() {
-        let (labels, _label_ids) = label_fixture();
-        let expected = TodoEntity::new(1, "should_return_created_todo".to_string(), labels.clone());
+#[cfg(not(feature = "redis"))]
+async fn cached<T: TodoRepository>(
+    repository: T,
+    ttl: Duration,
+    _shutdown: watch::Receiver<bool>,
+) -> DynTodoRepository {
+    Arc::new(CachedTodoRepository::new(repository, ttl))
+}
 
-        let req = build_todo_req_with_json(
-            "/todos",
-            Method::POST,
-            r#"{ "text": "should_return_created_todo", "labels": [999] }"#.to_string(),
-        );
-        let res = create_app(
-            TodoRepositoryForMemory::new(labels),
-            LabelRepositoryForMemory::new(),
-        )
-        .oneshot(req)
+// 未適用のmigrationsを順に適用する
+async fn migrate() -> anyhow::Result<()> {
+    let pool = connect().await?;
+    sqlx::migrate!("./migrations")
+        .run(&pool)
         .await
-        .unwrap();
-        let todo = res_to_todo(res).await;
-        assert_eq!(expected, todo);
-    }
-
-    #[tokio::test]
-    async fn should_created_label() {
-        let (labels, _label_ids) = label_fixture();
-        let expected = Label::new(1, "should_return_created_label".to_string());
+        .context("failed to run migrations")?;
+    tracing::info!("migrations are up to date");
+    Ok(())
+}
 
-        let req = build_label_req_with_json(
-            "/labels",
-            Method::POST,
-            r#"{ "name": "should_return_created_label" }"#.to_string(),
-        );
-        let res = create_app(
-            TodoRepositoryForMemory::new(labels),
-            LabelRepositoryForMemory::new(),
-        )
-        .oneshot(req)
-        .await
-        .unwrap();
-        let label = res_to_label(res).await;
-        assert_eq!(expected, label);
+async fn seed(args: SeedArgs) -> anyhow::Result<()> {
+    seed::ensure_not_production()?;
+    let pool = connect().await?;
+    if args.fresh {
+        seed::truncate(&pool).await?;
     }
+    let options = SeedOptions {
+        user_id: args.user,
+        todos: args.todos,
+        labels: args.labels,
+    };
+    let report = seed::seed(
+        &TodoRepositoryForDb::new(pool.clone()),
+        &LabelRepositoryForDb::new(pool),
+        &options,
+    )
+    .await?;
+    tracing::info!(
+        "seeded {} todos and {} labels for user {}",
+        report.todos_created,
+        report.labels_created,
+        args.user
+    );
+    Ok(())
+}
 
-    #[tokio::test]
-    async fn should_find_todo() {
-        let (labels, label_ids) = label_fixture();
-        let expected = TodoEntity::new(1, "should_find_todo".to_string(), labels.clone());
+async fn connect() -> anyhow::Result<PgPool> {
+    let database_url = env::var("DATABASE_URL").context("undefined [DATABASE_URL]")?;
+    PgPool::connect(&database_url)
+        .await
+        .with_context(|| format!("fail connect database, url is [{}]", database_url))
+}
 
-        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
-        todo_repository
-            .create(CreateTodo::new("should_find_todo".to_string(), label_ids))
+// Ctrl+CまたはSIGTERMを受け取るまで待つ
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
             .await
-            .expect("failed create todo");
-        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
-        let res = create_app(todo_repository, LabelRepositoryForMemory::new())
-            .oneshot(req)
-            .await
-            .unwrap();
-        let todo = res_to_todo(res).await;
-        assert_eq!(expected, todo);
-    }
+            .expect("failed to install Ctrl+C handler");
+    };
 
-    #[tokio::test]
-    async fn should_get_all_todos() {
-        let (labels, label_ids) = label_fixture();
-        let expected = TodoEntity::new(1, "should_get_all_todos".to_string(), labels.clone());
-
-        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
-        todo_repository
-            .create(CreateTodo::new(
-                "should_get_all_todos".to_string(),
-                label_ids,
-            ))
-            .await
-            .expect("failed create todo");
-        let req = build_todo_req_with_empty(Method::GET, "/todos");
-        let res = create_app(todo_repository, LabelRepositoryForMemory::new())
-            .oneshot(req)
-            .await
-            .unwrap();
-        let bytes = axum::body::to_bytes(res.into_body(), 128).await.unwrap();
-        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
-        let todo: Vec<TodoEntity> = serde_json::from_str(&body)
-            .expect(&format!("cannot convert Todo instance. body: {}", body));
-        assert_eq!(vec![expected], todo);
-    }
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
 
-    #[tokio::test]
-    async fn should_get_all_labels() {
-        let expected = Label::new(1, "should_get_all_labels".to_string());
-        let label_repository = LabelRepositoryForMemory::new();
-        let label = label_repository
-            .create("should_get_all_labels".to_string())
-            .await
-            .expect("failed create label");
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-        let req = build_label_req_with_empty(Method::GET, "/labels");
-        let res = create_app(TodoRepositoryForMemory::new(vec![label]), label_repository)
-            .oneshot(req)
-            .await
-            .unwrap();
-        let bytes = axum::body::to_bytes(res.into_body(), 128).await.unwrap();
-        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
-        let label: Vec<Label> = serde_json::from_str(&body)
-            .expect(&format!("cannot convert Label instance. body: {}", body));
-        assert_eq!(vec![expected], label);
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
+    tracing::debug!("start graceful shutdown...");
+}
 
-    #[tokio::test]
-    async fn should_update_todo() {
-        let (labels, label_ids) = label_fixture();
-        let expected = TodoEntity::new(1, "should_update_todo".to_string(), labels.clone());
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        let todo_repository = TodoRepositoryForMemory::new(labels);
-        todo_repository
-            .create(CreateTodo::new("before_update_todo".to_string(), label_ids))
-            .await
-            .expect("failed create todo");
-        let req = build_todo_req_with_json(
-            "/todos/1",
-            Method::PATCH,
-            r#"{
-            "id": 1,
-            "text": "should_update_todo",
-            "completed": false
-            }"#
-            .to_string(),
-        );
-        let res = create_app(todo_repository, LabelRepositoryForMemory::new())
-            .oneshot(req)
-            .await
-            .unwrap();
-        let todo = res_to_todo(res).await;
-        assert_eq!(expected, todo);
+    #[test]
+    fn serves_without_subcommand() {
+        let cli = Cli::try_parse_from(["todo-app"]).unwrap();
+        assert_eq!(cli.command, None);
+        let cli = Cli::try_parse_from(["todo-app", "migrate"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Migrate));
     }
 
-    #[tokio::test]
-    async fn should_delete_todo() {
-        let (labels, label_ids) = label_fixture();
-        let todo_repository = TodoRepositoryForMemory::new(labels);
-        todo_repository
-            .create(CreateTodo::new("should_delete_todo".to_string(), label_ids))
-            .await
-            .expect("failed create todo");
-        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
-        let res = create_app(todo_repository, LabelRepositoryForMemory::new())
-            .oneshot(req)
-            .await
-            .unwrap();
-        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    #[test]
+    fn reads_backend_from_env() {
+        env::remove_var("TODO_BACKEND");
+        assert_eq!(Backend::from_env(), Backend::Postgres);
+        env::set_var("TODO_BACKEND", "memory");
+        assert_eq!(Backend::from_env(), Backend::Memory);
+        env::set_var("TODO_BACKEND", "sqlite");
+        assert!(panic::catch_unwind(Backend::from_env).is_err());
+        env::remove_var("TODO_BACKEND");
     }
 
-    #[tokio::test]
-    async fn should_delete_lable() {
-        let label_repository = LabelRepositoryForMemory::new();
-        let label = label_repository
-            .create("should_delete_label".to_string())
-            .await
-            .expect("failed create label");
-        let req = build_label_req_with_empty(Method::DELETE, "/labels/1");
-        let res = create_app(TodoRepositoryForMemory::new(vec![label]), label_repository)
-            .oneshot(req)
-            .await
-            .unwrap();
-        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    #[test]
+    fn parses_seed_arguments() {
+        let cli = Cli::try_parse_from(["todo-app", "seed"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Seed(SeedArgs {
+                todos: 50,
+                labels: 5,
+                user: 1,
+                fresh: false
+            }))
+        );
+        let cli = Cli::try_parse_from([
+            "todo-app", "seed", "--todos", "10", "--labels", "2", "--user", "3", "--fresh",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Seed(SeedArgs {
+                todos: 10,
+                labels: 2,
+                user: 3,
+                fresh: true
+            }))
+        );
+        assert!(Cli::try_parse_from(["todo-app", "seed", "--todos", "many"]).is_err());
+        assert!(Cli::try_parse_from(["todo-app", "unknown"]).is_err());
     }
 }
@@ -0,0 +1,156 @@
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::repositories::todo::{TodoEntity, TodoRepository};
+
+/// リマインドの送信先
+#[async_trait]
+pub trait Notifier: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn notify(&self, todo: &TodoEntity) -> anyhow::Result<()>;
+}
+
+/// リマインドをログに出力するだけのNotifier
+#[derive(Debug, Clone)]
+pub struct LogNotifier;
+
+#[async_trait]
+impl Notifier for LogNotifier {
+    async fn notify(&self, todo: &TodoEntity) -> anyhow::Result<()> {
+        tracing::info!(todo_id = todo.id, text = %todo.text, "reminder");
+        Ok(())
+    }
+}
+
+/// 期限を過ぎたリマインドを確保して送信し、送信した件数を返す.
+///
+/// 確保した時点で通知済みとして記録されるため、送信に失敗したリマインドは再送されない
+pub async fn dispatch_due_reminders<T: TodoRepository, N: Notifier>(
+    repository: &T,
+    notifier: &N,
+    now: DateTime<Utc>,
+) -> anyhow::Result<usize> {
+    let todos = repository.claim_due_reminders(now).await?;
+    for todo in &todos {
+        if let Err(e) = notifier.notify(todo).await {
+            tracing::error!(todo_id = todo.id, "failed to send reminder: {:?}", e);
+        }
+    }
+    Ok(todos.len())
+}
+
+/// intervalごとにリマインドを送信する. shutdownにtrueが送られると終了する
+///
+/// DBのエラーはログに出して次の周期で再試行する
+pub async fn run_reminder_loop<T: TodoRepository, N: Notifier>(
+    repository: T,
+    notifier: N,
+    interval: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                match dispatch_due_reminders(&repository, &notifier, Utc::now()).await {
+                    Ok(0) => {}
+                    Ok(sent) => tracing::debug!("sent {} reminders", sent),
+                    Err(e) => tracing::error!("failed to dispatch reminders: {:?}", e),
+                }
+            }
+            _ = shutdown.changed() => break,
+        }
+    }
+    tracing::debug!("reminder task stopped");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::todo::{
+        memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID, CreateTodo,
+    };
+    use chrono::TimeDelta;
+    use std::sync::{Arc, Mutex};
+
+    // 送信されたtodoのidを記録するNotifier
+    #[derive(Debug, Clone, Default)]
+    struct RecordingNotifier {
+        sent: Arc<Mutex<Vec<i32>>>,
+    }
+
+    #[async_trait]
+    impl Notifier for RecordingNotifier {
+        async fn notify(&self, todo: &TodoEntity) -> anyhow::Result<()> {
+            self.sent.lock().unwrap().push(todo.id);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_sends_due_reminders_exactly_once() {
+        let now = Utc::now();
+        let repository = TodoRepositoryForMemory::for_test(vec![]);
+        for (text, remind_at) in [
+            ("due", now - TimeDelta::minutes(5)),
+            ("just due", now),
+            ("future", now + TimeDelta::hours(1)),
+        ] {
+            repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new(text.to_string(), vec![]).remind_at(remind_at),
+                )
+                .await
+                .expect("failed create todo");
+        }
+        repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("no reminder".to_string(), vec![]),
+            )
+            .await
+            .expect("failed create todo");
+        let notifier = RecordingNotifier::default();
+
+        let sent = dispatch_due_reminders(&repository, &notifier, now)
+            .await
+            .unwrap();
+        assert_eq!(sent, 2);
+        assert_eq!(*notifier.sent.lock().unwrap(), vec![1, 2]);
+        assert_eq!(
+            repository.find(TEST_USER_ID, 1).await.unwrap().reminded_at,
+            Some(now)
+        );
+
+        // 2回目は通知済みのため送信されない
+        let sent = dispatch_due_reminders(&repository, &notifier, now)
+            .await
+            .unwrap();
+        assert_eq!(sent, 0);
+
+        // 時間が経てば残りが送信される
+        let later = now + TimeDelta::hours(2);
+        dispatch_due_reminders(&repository, &notifier, later)
+            .await
+            .unwrap();
+        assert_eq!(*notifier.sent.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn reminder_loop_stops_on_shutdown() {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(run_reminder_loop(
+            TodoRepositoryForMemory::for_test(vec![]),
+            RecordingNotifier::default(),
+            Duration::from_secs(60),
+            shutdown_rx,
+        ));
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("reminder loop did not stop")
+            .unwrap();
+    }
+}
@@ -1,14 +1,110 @@
+// checked_query!などのマクロを以降のmoduleで使うため、最初に宣言する
+#[macro_use]
+mod checked;
+pub mod activity;
+pub mod cache;
+#[cfg(test)]
+pub mod flaky;
+pub mod ids;
+pub mod instrumented;
 pub mod label;
+pub mod recurrence;
+#[cfg(feature = "redis")]
+pub mod redis_cache;
+pub mod retry;
+#[cfg(all(test, feature = "database-test"))]
+pub mod test_database;
 pub mod todo;
+pub mod webhook;
 
+use std::{future::Future, time::Duration};
 use thiserror::Error;
+use uuid::Uuid;
+
+/// DBへの問い合わせ1回あたりのタイムアウトの既定値
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Error)]
-enum RepositoryError {
+pub enum RepositoryError {
     #[error("Unexpected Error: [{0}]")]
     Unexpected(String),
     #[error("NotFound, id is {0}")]
     NotFound(i32),
+    #[error("NotFound, public id is {0}")]
+    PublicIdNotFound(Uuid),
     #[error("Duplicate data, id is {0}")]
     Duplicate(i32),
+    #[error("Label is not available to this user, id is {0}")]
+    LabelNotAvailable(i32),
+    #[error("Invalid query, {0}")]
+    InvalidQuery(String),
+    #[error("Timeout, query did not finish within {0:?}")]
+    Timeout(Duration),
+    #[error("Nothing to undo, todo {0} has no change left to revert")]
+    NothingToUndo(i32),
+    #[error("Parent is not available to this todo, id is {0}")]
+    InvalidParent(i32),
+}
+
+impl RepositoryError {
+    /// メトリクスのラベルに使う種類の名前
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RepositoryError::Unexpected(_) => "unexpected",
+            RepositoryError::NotFound(_) => "not_found",
+            RepositoryError::PublicIdNotFound(_) => "public_id_not_found",
+            RepositoryError::Duplicate(_) => "duplicate",
+            RepositoryError::LabelNotAvailable(_) => "label_not_available",
+            RepositoryError::InvalidQuery(_) => "invalid_query",
+            RepositoryError::Timeout(_) => "timeout",
+            RepositoryError::NothingToUndo(_) => "nothing_to_undo",
+            RepositoryError::InvalidParent(_) => "invalid_parent",
+        }
+    }
+}
+
+/// futureがtimeout以内に終わらなければ打ち切り、RepositoryError::Timeoutを返す
+pub async fn with_timeout<T, F>(timeout: Duration, future: F) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| RepositoryError::Timeout(timeout))?
+}
+
+// like句のワイルドカードをエスケープして部分一致のパターンを作る
+fn like_pattern(q: &str) -> String {
+    let escaped = q
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn with_timeout_returns_timeout_error_for_slow_query() {
+        let timeout = Duration::from_millis(10);
+        let res: anyhow::Result<()> = with_timeout(timeout, async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        })
+        .await;
+
+        let err = res.expect_err("slow query should time out");
+        assert!(matches!(
+            err.downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Timeout(t)) if *t == timeout
+        ));
+    }
+
+    #[tokio::test]
+    async fn with_timeout_passes_through_fast_query() {
+        let res = with_timeout(Duration::from_secs(1), async { Ok(1) }).await;
+        assert_eq!(res.unwrap(), 1);
+    }
 }
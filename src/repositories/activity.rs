@@ -0,0 +1,1306 @@
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, PgExecutor, PgPool};
+use std::{collections::HashSet, future::Future, time::Duration};
+use tokio::sync::broadcast;
+
+use super::{
+    label::Label, recurrence::Recurrence, todo::TodoEntity, with_timeout, DEFAULT_QUERY_TIMEOUT,
+};
+
+/// todoに対する変更の記録先
+#[async_trait]
+pub trait ActivityRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn record(&self, event: NewTodoEvent) -> anyhow::Result<TodoEvent>;
+    /// actor_idが行った変更を新しい順に返す
+    async fn list(&self, actor_id: i32, query: ActivityQuery) -> anyhow::Result<Vec<TodoEvent>>;
+    /// actor_idが行った変更の件数
+    async fn count(&self, actor_id: i32) -> anyhow::Result<i64>;
+    /// actor_idがtodo_idに行った変更を新しい順に全て返す
+    async fn history(&self, actor_id: i32, todo_id: i32) -> anyhow::Result<Vec<TodoEvent>>;
+    /// 以降に記録される変更を受け取る. 全ユーザーの変更が流れるので受け取る側で絞り込む
+    fn subscribe(&self) -> broadcast::Receiver<TodoEvent>;
+    /// recordを通さずに保存された変更(todoの変更と同じトランザクションで書き込んだもの)を、
+    /// recordした時と同じく購読者に知らせる
+    fn publish(&self, event: &TodoEvent);
+}
+
+/// 記録した変更を配信するチャンネルの容量. 受け取りが遅れて溢れた分は失われる
+pub const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum TodoEventKind {
+    Created,
+    Updated,
+    /// 未完了->完了への更新
+    Completed,
+    Deleted,
+    /// 以前の変更の取り消し
+    Undone,
+    /// リマインドの送信. todo自体は変更しない
+    Reminded,
+}
+
+/// 変更前後の値
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FieldChange<T> {
+    pub from: T,
+    pub to: T,
+}
+
+/// 更新で変わった項目だけを持つ差分
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct TodoDiff {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<FieldChange<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed: Option<FieldChange<bool>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<FieldChange<Vec<i32>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due_date: Option<FieldChange<Option<NaiveDate>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence: Option<FieldChange<Recurrence>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remind_at: Option<FieldChange<Option<DateTime<Utc>>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<FieldChange<Option<DateTime<Utc>>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<FieldChange<Option<i32>>>,
+    /// 完了で作った繰り返しの次回分のid. 完了の取り消しで削除する
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spawned_id: Option<i32>,
+    /// 完了と一緒に完了にしたsubtaskのid. 完了の取り消しで未完了に戻す
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub completed_subtasks: Vec<i32>,
+}
+
+fn change<T: PartialEq>(from: T, to: T) -> Option<FieldChange<T>> {
+    (from != to).then_some(FieldChange { from, to })
+}
+
+impl TodoDiff {
+    pub fn between(old: &TodoEntity, new: &TodoEntity) -> Self {
+        let label_ids =
+            |todo: &TodoEntity| todo.labels.iter().map(|label| label.id).collect::<Vec<_>>();
+        Self {
+            text: change(old.text.clone(), new.text.clone()),
+            completed: change(old.completed, new.completed),
+            labels: change(label_ids(old), label_ids(new)),
+            due_date: change(old.due_date, new.due_date),
+            recurrence: change(old.recurrence, new.recurrence),
+            remind_at: change(old.remind_at, new.remind_at),
+            completed_at: change(old.completed_at, new.completed_at),
+            parent_id: change(old.parent_id, new.parent_id),
+            spawned_id: None,
+            completed_subtasks: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// todoの変更された項目を変更前の値に戻したtodoを返す
+    pub fn revert(&self, todo: &TodoEntity) -> TodoEntity {
+        let mut reverted = todo.clone();
+        if let Some(text) = &self.text {
+            reverted.text = text.from.clone();
+        }
+        if let Some(completed) = &self.completed {
+            reverted.completed = completed.from;
+        }
+        if let Some(labels) = &self.labels {
+            // nameはrestoreでidから解決し直される
+            reverted.labels = labels
+                .from
+                .iter()
+                .map(|id| Label {
+                    id: *id,
+                    name: String::new(),
+                    owner_id: None,
+                })
+                .collect();
+        }
+        if let Some(due_date) = &self.due_date {
+            reverted.due_date = due_date.from;
+        }
+        if let Some(recurrence) = &self.recurrence {
+            reverted.recurrence = recurrence.from;
+        }
+        if let Some(remind_at) = &self.remind_at {
+            reverted.remind_at = remind_at.from;
+        }
+        if let Some(completed_at) = &self.completed_at {
+            reverted.completed_at = completed_at.from;
+        }
+        if let Some(parent_id) = &self.parent_id {
+            reverted.parent_id = parent_id.from;
+        }
+        reverted
+    }
+}
+
+/// 作成ではtodo全体、削除ではtodo全体と親から外れたsubtask、更新では差分を記録する
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TodoEventPayload {
+    /// 作成したtodo. 以前の削除の記録もこの形で残っている
+    Snapshot(TodoEntity),
+    Diff(TodoDiff),
+    /// 削除したtodo. detached_subtasksは削除で親から外れたsubtaskのid
+    Deletion {
+        todo: TodoEntity,
+        #[serde(default)]
+        detached_subtasks: Vec<i32>,
+    },
+    /// event_idの変更を取り消した記録. changeは取り消しで行った変更
+    Undo {
+        event_id: i32,
+        change: Box<TodoEventPayload>,
+    },
+}
+
+// 取り消されていない最新の変更. 取り消しの記録自体は取り消し対象にしない
+pub(crate) fn undo_target(history: &[TodoEvent]) -> Option<&TodoEvent> {
+    let mut undone = HashSet::new();
+    history.iter().find(|event| match &event.payload {
+        TodoEventPayload::Undo { event_id, .. } => {
+            undone.insert(*event_id);
+            false
+        }
+        _ if event.kind == TodoEventKind::Reminded => false,
+        _ => !undone.contains(&event.id),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewTodoEvent {
+    pub todo_id: i32,
+    pub actor_id: i32,
+    pub kind: TodoEventKind,
+    pub payload: TodoEventPayload,
+}
+
+impl NewTodoEvent {
+    pub fn created(actor_id: i32, todo: &TodoEntity) -> Self {
+        Self {
+            todo_id: todo.id,
+            actor_id,
+            kind: TodoEventKind::Created,
+            payload: TodoEventPayload::Snapshot(todo.clone()),
+        }
+    }
+
+    /// oldからtodoへの変更を差分として記録する. 変更が無ければNone
+    pub fn changed(actor_id: i32, old: &TodoEntity, todo: &TodoEntity) -> Option<Self> {
+        let diff = TodoDiff::between(old, todo);
+        if diff.is_empty() {
+            return None;
+        }
+        let kind = if !old.completed && todo.completed {
+            TodoEventKind::Completed
+        } else {
+            TodoEventKind::Updated
+        };
+        Some(Self {
+            todo_id: todo.id,
+            actor_id,
+            kind,
+            payload: TodoEventPayload::Diff(diff),
+        })
+    }
+
+    /// updateの記録. 繰り返しTodoの次回分は新しいtodoの作成として記録し、
+    /// 取り消せるように次回分と一緒に完了にしたsubtaskのidを差分に含める
+    pub fn updated(
+        actor_id: i32,
+        old: &TodoEntity,
+        todo: &TodoEntity,
+        completed_subtasks: Vec<i32>,
+    ) -> Vec<Self> {
+        let next = todo.next_occurrence.as_deref();
+        let changed = Self::changed(actor_id, old, todo).map(|mut event| {
+            if let TodoEventPayload::Diff(diff) = &mut event.payload {
+                diff.spawned_id = next.map(|next| next.id);
+                diff.completed_subtasks = completed_subtasks;
+            }
+            event
+        });
+        changed
+            .into_iter()
+            .chain(next.map(|next| Self::created(actor_id, next)))
+            .collect()
+    }
+
+    /// 削除の記録. detached_subtasksは削除で親から外れたsubtaskのid
+    pub fn deleted(actor_id: i32, old: TodoEntity, detached_subtasks: Vec<i32>) -> Self {
+        Self {
+            todo_id: old.id,
+            actor_id,
+            kind: TodoEventKind::Deleted,
+            payload: TodoEventPayload::Deletion {
+                todo: old,
+                detached_subtasks,
+            },
+        }
+    }
+
+    /// targetの取り消しの記録. changeは取り消しで行った変更
+    pub fn undone(actor_id: i32, target: &TodoEvent, change: TodoEventPayload) -> Self {
+        Self {
+            todo_id: target.todo_id,
+            actor_id,
+            kind: TodoEventKind::Undone,
+            payload: TodoEventPayload::Undo {
+                event_id: target.id,
+                change: Box::new(change),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TodoEvent {
+    pub id: i32,
+    pub todo_id: i32,
+    pub actor_id: i32,
+    pub kind: TodoEventKind,
+    pub payload: TodoEventPayload,
+    pub created_at: DateTime<Utc>,
+}
+
+// todo_eventsの1行. payloadはjsonbのまま読み、TodoEventに変える
+#[derive(Debug, FromRow)]
+struct TodoEventRow {
+    id: i32,
+    todo_id: i32,
+    actor_id: i32,
+    kind: TodoEventKind,
+    payload: Json<TodoEventPayload>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<TodoEventRow> for TodoEvent {
+    fn from(row: TodoEventRow) -> Self {
+        Self {
+            id: row.id,
+            todo_id: row.todo_id,
+            actor_id: row.actor_id,
+            kind: row.kind,
+            payload: row.payload.0,
+            created_at: row.created_at,
+        }
+    }
+}
+
+/// activityの一覧の範囲. GET /activity ではPaginationのlimit, offsetから作る
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct ActivityQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl ActivityQuery {
+    /// limit未指定時の件数
+    pub const DEFAULT_LIMIT: i64 = 50;
+
+    /// 実際に返す件数の上限
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(Self::DEFAULT_LIMIT).max(0)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActivityRepositoryForDb {
+    pool: PgPool,
+    query_timeout: Duration,
+    events: broadcast::Sender<TodoEvent>,
+}
+
+impl ActivityRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+        }
+    }
+
+    pub fn with_query_timeout(self, query_timeout: Duration) -> Self {
+        Self {
+            query_timeout,
+            ..self
+        }
+    }
+
+    // DBへの問い合わせをquery_timeoutで打ち切る
+    async fn timed<T>(&self, future: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        with_timeout(self.query_timeout, future).await
+    }
+}
+
+// todoの変更と同じトランザクションでも書き込めるようにexecutorを受け取ってeventを保存する
+pub(crate) async fn insert_event<'e>(
+    executor: impl PgExecutor<'e>,
+    event: &NewTodoEvent,
+) -> anyhow::Result<TodoEvent> {
+    let payload = Json(&event.payload);
+    let event = checked_query_as!(
+        TodoEventRow,
+        r#"
+        insert into todo_events (todo_id, actor_id, kind, payload)
+        values ($1, $2, $3, $4)
+        returning id, todo_id, actor_id, kind as "kind: TodoEventKind",
+            payload as "payload: Json<TodoEventPayload>", created_at
+        "#,
+        event.todo_id,
+        event.actor_id,
+        event.kind as _,
+        payload as _
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(event.into())
+}
+
+// actor_idがtodo_idに行った変更を新しい順に全て取得する
+pub(crate) async fn history_with<'e>(
+    executor: impl PgExecutor<'e>,
+    actor_id: i32,
+    todo_id: i32,
+) -> anyhow::Result<Vec<TodoEvent>> {
+    let events = checked_query_as!(
+        TodoEventRow,
+        r#"
+        select id, todo_id, actor_id, kind as "kind: TodoEventKind",
+            payload as "payload: Json<TodoEventPayload>", created_at
+        from todo_events
+        where actor_id = $1 and todo_id = $2
+        order by id desc
+        "#,
+        actor_id,
+        todo_id
+    )
+    .fetch_all(executor)
+    .await?;
+    Ok(events.into_iter().map(TodoEvent::from).collect())
+}
+
+#[async_trait]
+impl ActivityRepository for ActivityRepositoryForDb {
+    async fn record(&self, event: NewTodoEvent) -> anyhow::Result<TodoEvent> {
+        self.timed(async move {
+            let event = insert_event(&self.pool, &event).await?;
+            self.publish(&event);
+            Ok(event)
+        })
+        .await
+    }
+
+    async fn list(&self, actor_id: i32, query: ActivityQuery) -> anyhow::Result<Vec<TodoEvent>> {
+        self.timed(async move {
+            let events = checked_query_as!(
+                TodoEventRow,
+                r#"
+                select id, todo_id, actor_id, kind as "kind: TodoEventKind",
+                    payload as "payload: Json<TodoEventPayload>", created_at
+                from todo_events
+                where actor_id = $1
+                order by id desc
+                limit $2 offset $3
+                "#,
+                actor_id,
+                query.limit(),
+                query.offset()
+            )
+            .fetch_all(&self.pool)
+            .await?;
+
+            Ok(events.into_iter().map(TodoEvent::from).collect())
+        })
+        .await
+    }
+
+    async fn count(&self, actor_id: i32) -> anyhow::Result<i64> {
+        self.timed(async move {
+            let count = checked_query_scalar!(
+                r#"select count(*) as "count!" from todo_events where actor_id = $1"#,
+                actor_id
+            )
+            .fetch_one(&self.pool)
+            .await?;
+
+            Ok(count)
+        })
+        .await
+    }
+
+    async fn history(&self, actor_id: i32, todo_id: i32) -> anyhow::Result<Vec<TodoEvent>> {
+        self.timed(history_with(&self.pool, actor_id, todo_id))
+            .await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TodoEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, event: &TodoEvent) {
+        // 購読者がいなくても記録は成功させる
+        let _ = self.events.send(event.clone());
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use crate::repositories::recurrence::Recurrence;
+    use crate::repositories::test_database::db_pool_or_skip;
+    use crate::repositories::todo::{
+        test_utils::{test_now, TEST_USER_ID},
+        BulkUpdateTodo, CreateTodo, TodoQuery, TodoRepository, TodoRepositoryForDb, UpdateTodo,
+    };
+    use crate::repositories::RepositoryError;
+
+    #[tokio::test]
+    async fn activity_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let activity = ActivityRepositoryForDb::new(pool.clone());
+        let repository = TodoRepositoryForDb::new(pool.clone()).with_activity(activity.clone());
+        let mut published = activity.subscribe();
+
+        let todo = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[activity_scenario] text".to_string(), vec![]),
+            )
+            .await
+            .expect("[create] returned Err");
+        repository
+            .delete(TEST_USER_ID, todo.id)
+            .await
+            .expect("[delete] returned Err");
+
+        let events = activity
+            .list(TEST_USER_ID, ActivityQuery::default())
+            .await
+            .expect("[list] returned Err");
+        let kinds: Vec<TodoEventKind> = events
+            .iter()
+            .filter(|event| event.todo_id == todo.id)
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(kinds, vec![TodoEventKind::Deleted, TodoEventKind::Created]);
+        // commitした後に購読者にも知らせる
+        assert_eq!(published.recv().await.unwrap().kind, TodoEventKind::Created);
+        assert_eq!(published.recv().await.unwrap().kind, TodoEventKind::Deleted);
+
+        // 削除の取り消しで同じidのtodoが作り直される
+        repository
+            .undo(TEST_USER_ID, todo.id)
+            .await
+            .expect("[undo] returned Err");
+        let restored = repository
+            .find(TEST_USER_ID, todo.id)
+            .await
+            .expect("[find] returned Err");
+        // 作り直した時刻が更新日時になる
+        assert!(restored.updated_at >= todo.updated_at);
+        assert_eq!(
+            restored,
+            TodoEntity {
+                updated_at: restored.updated_at,
+                ..todo
+            }
+        );
+        assert_eq!(published.recv().await.unwrap().kind, TodoEventKind::Undone);
+
+        // 一括更新も変更前の完了日時との差分で記録する
+        repository
+            .update_where(
+                TEST_USER_ID,
+                TodoQuery::default(),
+                BulkUpdateTodo { completed: true },
+            )
+            .await
+            .expect("[update_where] returned Err");
+        let completed = published.recv().await.unwrap();
+        assert_eq!(completed.kind, TodoEventKind::Completed);
+        let TodoEventPayload::Diff(diff) = completed.payload else {
+            panic!("expected diff, got {:?}", completed.payload);
+        };
+        assert_eq!(diff.completed, change(false, true));
+        assert_eq!(diff.completed_at.map(|change| change.from), Some(None));
+    }
+
+    #[tokio::test]
+    async fn concurrent_undos_walk_back_one_event_each() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let activity = ActivityRepositoryForDb::new(pool.clone());
+        let repository = TodoRepositoryForDb::new(pool.clone()).with_activity(activity.clone());
+        let todo = repository
+            .create(TEST_USER_ID, CreateTodo::new("undo".to_string(), vec![]))
+            .await
+            .expect("[create] returned Err");
+        repository
+            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+            .await
+            .expect("[update] returned Err");
+
+        // todoの行をロックしておき、2つの取り消しが両方とも待っている状態から同時に進める.
+        // 別々の接続からの取り消しでも、同じ変更を二重に取り消さず1つずつ遡る
+        let mut blocker = pool.begin().await.expect("fail begin");
+        sqlx::query("select id from todos where id = $1 for update")
+            .bind(todo.id)
+            .execute(&mut *blocker)
+            .await
+            .expect("fail lock todo");
+        let undos: Vec<_> = (0..2)
+            .map(|_| {
+                let repository = repository.clone();
+                tokio::spawn(async move { repository.undo(TEST_USER_ID, todo.id).await })
+            })
+            .collect();
+        loop {
+            let waiting: i64 = sqlx::query_scalar(
+                "select count(*) from pg_stat_activity where wait_event_type = 'Lock'",
+            )
+            .fetch_one(&pool)
+            .await
+            .expect("fail count waiting");
+            // 並列に動く他のテストのロック待ちも数えうるので、少なくとも2つ待っていれば進める
+            if waiting >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        blocker.rollback().await.expect("fail rollback");
+
+        let mut undone = Vec::new();
+        for undo in undos {
+            undone.push(undo.await.expect("undo panicked"));
+        }
+        let mut undone: Vec<i32> = undone
+            .into_iter()
+            .map(|event| match event.expect("[undo] returned Err").payload {
+                TodoEventPayload::Undo { event_id, .. } => event_id,
+                payload => panic!("expected undo, got {:?}", payload),
+            })
+            .collect();
+        undone.sort();
+        let history = activity
+            .history(TEST_USER_ID, todo.id)
+            .await
+            .expect("[history] returned Err");
+        let mut changes: Vec<i32> = history
+            .iter()
+            .filter(|event| event.kind != TodoEventKind::Undone)
+            .map(|event| event.id)
+            .collect();
+        changes.sort();
+        assert_eq!(undone, changes);
+        let error = repository.find(TEST_USER_ID, todo.id).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RepositoryError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn nothing_is_persisted_when_recording_fails() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let activity = ActivityRepositoryForDb::new(pool.clone());
+        let repository = TodoRepositoryForDb::new(pool.clone()).with_activity(activity.clone());
+        let todo = repository
+            .create(TEST_USER_ID, CreateTodo::new("kept".to_string(), vec![]))
+            .await
+            .expect("[create] returned Err");
+        let mut published = activity.subscribe();
+
+        // テスト用のschemaなので、記録先を消して記録だけを失敗させる
+        sqlx::query("drop table todo_events")
+            .execute(&pool)
+            .await
+            .expect("fail drop todo_events");
+
+        repository
+            .create(TEST_USER_ID, CreateTodo::new("dropped".to_string(), vec![]))
+            .await
+            .expect_err("[create] returned Ok");
+        repository
+            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+            .await
+            .expect_err("[update] returned Ok");
+        repository
+            .update_where(
+                TEST_USER_ID,
+                TodoQuery::default(),
+                BulkUpdateTodo { completed: true },
+            )
+            .await
+            .expect_err("[update_where] returned Ok");
+        repository
+            .delete(TEST_USER_ID, todo.id)
+            .await
+            .expect_err("[delete] returned Ok");
+
+        let todos = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .expect("[all] returned Err");
+        assert_eq!(todos, vec![todo]);
+        assert!(published.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn undo_completion_removes_spawned_occurrence() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let activity = ActivityRepositoryForDb::new(pool.clone());
+        let repository = TodoRepositoryForDb::new(pool.clone()).with_activity(activity.clone());
+        let todo = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("water plants".to_string(), vec![])
+                    .recurring(Recurrence::Daily, Some(test_now().date_naive())),
+            )
+            .await
+            .expect("[create] returned Err");
+        let completed = repository
+            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+            .await
+            .expect("[update] returned Err");
+        let spawned = completed.next_occurrence.expect("no next occurrence");
+
+        repository
+            .undo(TEST_USER_ID, todo.id)
+            .await
+            .expect("[undo] returned Err");
+        assert!(repository.find(TEST_USER_ID, spawned.id).await.is_err());
+        let reopened = repository
+            .find(TEST_USER_ID, todo.id)
+            .await
+            .expect("[find] returned Err");
+        assert!(!reopened.completed);
+
+        // 完了し直しても次の回は1つだけ
+        repository
+            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+            .await
+            .expect("[update] returned Err");
+        let open: Vec<_> = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .expect("[all] returned Err")
+            .into_iter()
+            .filter(|todo| !todo.completed)
+            .collect();
+        assert_eq!(open.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn undo_restores_subtasks() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let activity = ActivityRepositoryForDb::new(pool.clone());
+        let repository = TodoRepositoryForDb::new(pool.clone()).with_activity(activity.clone());
+        let parent = repository
+            .create(TEST_USER_ID, CreateTodo::new("parent".to_string(), vec![]))
+            .await
+            .expect("[create] returned Err");
+        let child = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("child".to_string(), vec![]).subtask_of(parent.id),
+            )
+            .await
+            .expect("[create] returned Err");
+
+        // 子まで完了したのを取り消すと子も未完了に戻る
+        repository
+            .update(
+                TEST_USER_ID,
+                parent.id,
+                UpdateTodo::builder()
+                    .completed(true)
+                    .complete_subtasks(true)
+                    .build(),
+            )
+            .await
+            .expect("[update] returned Err");
+        repository
+            .undo(TEST_USER_ID, parent.id)
+            .await
+            .expect("[undo] returned Err");
+        let reopened = repository
+            .find(TEST_USER_ID, child.id)
+            .await
+            .expect("[find] returned Err");
+        assert!(!reopened.completed);
+        assert!(reopened.completed_at.is_none());
+
+        // 親の削除で外れた子は、削除の取り消しで親に戻る
+        repository
+            .delete(TEST_USER_ID, parent.id)
+            .await
+            .expect("[delete] returned Err");
+        let detached = repository
+            .find(TEST_USER_ID, child.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(detached.parent_id, None);
+        repository
+            .undo(TEST_USER_ID, parent.id)
+            .await
+            .expect("[undo] returned Err");
+        let relinked = repository
+            .find(TEST_USER_ID, child.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(relinked.parent_id, Some(parent.id));
+    }
+}
+
+#[cfg(any(test, feature = "memory"))]
+pub mod memory {
+    use std::sync::{Arc, RwLock, RwLockWriteGuard};
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    pub struct ActivityRepositoryForMemory {
+        events: Arc<RwLock<Vec<TodoEvent>>>,
+        sender: broadcast::Sender<TodoEvent>,
+    }
+
+    impl ActivityRepositoryForMemory {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// 記録を書き込みロックする. TodoRepositoryForMemoryはtodoのロックを持ったままこれを取り、
+        /// todoの変更と記録を他の変更が挟まらない1つの操作にする
+        pub(crate) fn lock(&self) -> LockedEvents<'_> {
+            LockedEvents(self.events.write().unwrap())
+        }
+    }
+
+    impl Default for ActivityRepositoryForMemory {
+        fn default() -> Self {
+            Self {
+                events: Arc::default(),
+                sender: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            }
+        }
+    }
+
+    /// 書き込みロックを取った記録
+    pub(crate) struct LockedEvents<'a>(RwLockWriteGuard<'a, Vec<TodoEvent>>);
+
+    impl LockedEvents<'_> {
+        /// idを払い出して記録する. 購読者へは知らせないので、ロックを外した後にpublishする
+        pub(crate) fn push(&mut self, event: NewTodoEvent) -> TodoEvent {
+            let event = TodoEvent {
+                id: (self.0.len() + 1) as i32,
+                todo_id: event.todo_id,
+                actor_id: event.actor_id,
+                kind: event.kind,
+                payload: event.payload,
+                created_at: Utc::now(),
+            };
+            self.0.push(event.clone());
+            event
+        }
+
+        /// ActivityRepository::historyと同じく新しい順に返す
+        pub(crate) fn history(&self, actor_id: i32, todo_id: i32) -> Vec<TodoEvent> {
+            history_of(&self.0, actor_id, todo_id)
+        }
+    }
+
+    fn history_of(events: &[TodoEvent], actor_id: i32, todo_id: i32) -> Vec<TodoEvent> {
+        events
+            .iter()
+            .rev()
+            .filter(|event| event.actor_id == actor_id && event.todo_id == todo_id)
+            .cloned()
+            .collect()
+    }
+
+    #[async_trait]
+    impl ActivityRepository for ActivityRepositoryForMemory {
+        async fn record(&self, event: NewTodoEvent) -> anyhow::Result<TodoEvent> {
+            let event = self.lock().push(event);
+            self.publish(&event);
+            Ok(event)
+        }
+
+        async fn list(
+            &self,
+            actor_id: i32,
+            query: ActivityQuery,
+        ) -> anyhow::Result<Vec<TodoEvent>> {
+            let events = self.events.read().unwrap();
+            Ok(events
+                .iter()
+                .rev()
+                .filter(|event| event.actor_id == actor_id)
+                .skip(query.offset() as usize)
+                .take(query.limit() as usize)
+                .cloned()
+                .collect())
+        }
+
+        async fn count(&self, actor_id: i32) -> anyhow::Result<i64> {
+            let events = self.events.read().unwrap();
+            let count = events
+                .iter()
+                .filter(|event| event.actor_id == actor_id)
+                .count();
+            Ok(count as i64)
+        }
+
+        async fn history(&self, actor_id: i32, todo_id: i32) -> anyhow::Result<Vec<TodoEvent>> {
+            let events = self.events.read().unwrap();
+            Ok(history_of(&events, actor_id, todo_id))
+        }
+
+        fn subscribe(&self) -> broadcast::Receiver<TodoEvent> {
+            self.sender.subscribe()
+        }
+
+        fn publish(&self, event: &TodoEvent) {
+            let _ = self.sender.send(event.clone());
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::repositories::{
+            recurrence::Recurrence,
+            todo::{
+                memory::TodoRepositoryForMemory,
+                test_utils::{test_now, TEST_USER_ID},
+                CreateTodo, TodoQuery, TodoRepository, UpdateTodo,
+            },
+            RepositoryError,
+        };
+
+        fn repository() -> (TodoRepositoryForMemory, ActivityRepositoryForMemory) {
+            let activity = ActivityRepositoryForMemory::new();
+            let repository = TodoRepositoryForMemory::for_test(vec![])
+                .with_activity(&activity, activity.clone());
+            (repository, activity)
+        }
+
+        #[tokio::test]
+        async fn update_records_only_changed_fields() {
+            let (repository, activity) = repository();
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new("before".to_string(), vec![]))
+                .await
+                .unwrap();
+            let payload: UpdateTodo =
+                serde_json::from_str(r#"{ "text": "after", "completed": false }"#).unwrap();
+            repository
+                .update(TEST_USER_ID, todo.id, payload)
+                .await
+                .unwrap();
+
+            let events = activity
+                .list(TEST_USER_ID, ActivityQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(events.len(), 2);
+            let event = &events[0];
+            assert_eq!(event.kind, TodoEventKind::Updated);
+            assert_eq!(
+                event.payload,
+                TodoEventPayload::Diff(TodoDiff {
+                    text: Some(FieldChange {
+                        from: "before".to_string(),
+                        to: "after".to_string(),
+                    }),
+                    ..TodoDiff::default()
+                })
+            );
+            // 変わっていないcompletedは含まれない
+            assert_eq!(
+                serde_json::to_value(&event.payload).unwrap(),
+                serde_json::json!({ "diff": { "text": { "from": "before", "to": "after" } } })
+            );
+        }
+
+        #[tokio::test]
+        async fn failed_mutation_records_nothing() {
+            let (repository, activity) = repository();
+            let mut published = activity.subscribe();
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new("todo".to_string(), vec![]))
+                .await
+                .unwrap();
+            let payload = UpdateTodo::new(None, None, Some(vec![404]));
+            repository
+                .update(TEST_USER_ID, todo.id, payload)
+                .await
+                .unwrap_err();
+            repository
+                .delete(TEST_USER_ID + 1, todo.id)
+                .await
+                .unwrap_err();
+
+            let events = activity
+                .list(TEST_USER_ID, ActivityQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(published.recv().await.unwrap(), events[0]);
+            assert!(published.try_recv().is_err());
+        }
+
+        #[tokio::test]
+        async fn records_each_mutation_newest_first() {
+            let (repository, activity) = repository();
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new("todo".to_string(), vec![]))
+                .await
+                .unwrap();
+            let payload: UpdateTodo = serde_json::from_str(r#"{ "completed": true }"#).unwrap();
+            repository
+                .update(TEST_USER_ID, todo.id, payload)
+                .await
+                .unwrap();
+            repository.delete(TEST_USER_ID, todo.id).await.unwrap();
+            // 他のユーザーの変更は含まれない
+            repository
+                .create(
+                    TEST_USER_ID + 1,
+                    CreateTodo::new("theirs".to_string(), vec![]),
+                )
+                .await
+                .unwrap();
+
+            let events = activity
+                .list(TEST_USER_ID, ActivityQuery::default())
+                .await
+                .unwrap();
+            let kinds: Vec<TodoEventKind> = events.iter().map(|event| event.kind).collect();
+            assert_eq!(
+                kinds,
+                vec![
+                    TodoEventKind::Deleted,
+                    TodoEventKind::Completed,
+                    TodoEventKind::Created,
+                ]
+            );
+            assert_eq!(
+                events[0].payload,
+                TodoEventPayload::Deletion {
+                    todo: TodoEntity {
+                        completed: true,
+                        completed_at: Some(test_now()),
+                        ..todo
+                    },
+                    detached_subtasks: vec![],
+                }
+            );
+
+            let page = activity
+                .list(
+                    TEST_USER_ID,
+                    ActivityQuery {
+                        limit: Some(1),
+                        offset: Some(1),
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(page, vec![events[1].clone()]);
+        }
+
+        #[tokio::test]
+        async fn undo_reverts_update() {
+            let (repository, activity) = repository();
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new("before".to_string(), vec![]))
+                .await
+                .unwrap();
+            let payload: UpdateTodo =
+                serde_json::from_str(r#"{ "text": "after", "completed": true }"#).unwrap();
+            repository
+                .update(TEST_USER_ID, todo.id, payload)
+                .await
+                .unwrap();
+
+            let event = repository.undo(TEST_USER_ID, todo.id).await.unwrap();
+            assert_eq!(repository.find(TEST_USER_ID, todo.id).await.unwrap(), todo);
+            assert_eq!(event.kind, TodoEventKind::Undone);
+            assert_eq!(
+                event.payload,
+                TodoEventPayload::Undo {
+                    event_id: 2,
+                    change: Box::new(TodoEventPayload::Diff(TodoDiff {
+                        text: Some(FieldChange {
+                            from: "after".to_string(),
+                            to: "before".to_string(),
+                        }),
+                        completed: Some(FieldChange {
+                            from: true,
+                            to: false,
+                        }),
+                        completed_at: Some(FieldChange {
+                            from: Some(test_now()),
+                            to: None,
+                        }),
+                        ..TodoDiff::default()
+                    })),
+                }
+            );
+            // 取り消しも履歴に残る
+            let events = activity
+                .list(TEST_USER_ID, ActivityQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(events[0], event);
+        }
+
+        #[tokio::test]
+        async fn undo_restores_deleted_todo_with_same_id() {
+            let activity = ActivityRepositoryForMemory::new();
+            let label = Label::new(1, "label".to_string());
+            let repository = TodoRepositoryForMemory::for_test(vec![label.clone()])
+                .with_activity(&activity, activity.clone());
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("todo".to_string(), vec![label.id]),
+                )
+                .await
+                .unwrap();
+            repository.delete(TEST_USER_ID, todo.id).await.unwrap();
+            assert!(repository.find(TEST_USER_ID, todo.id).await.is_err());
+
+            let event = repository.undo(TEST_USER_ID, todo.id).await.unwrap();
+            assert_eq!(repository.find(TEST_USER_ID, todo.id).await.unwrap(), todo);
+            assert_eq!(
+                event.payload,
+                TodoEventPayload::Undo {
+                    event_id: 2,
+                    change: Box::new(TodoEventPayload::Snapshot(todo)),
+                }
+            );
+        }
+
+        #[tokio::test]
+        async fn undo_deletes_created_todo() {
+            let (repository, _) = repository();
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new("todo".to_string(), vec![]))
+                .await
+                .unwrap();
+
+            // 他のユーザーの変更は取り消せない
+            let res = repository.undo(TEST_USER_ID + 1, todo.id).await;
+            assert!(matches!(
+                res.unwrap_err().downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::NothingToUndo(_))
+            ));
+
+            let event = repository.undo(TEST_USER_ID, todo.id).await.unwrap();
+            assert!(repository.find(TEST_USER_ID, todo.id).await.is_err());
+            assert_eq!(
+                event.payload,
+                TodoEventPayload::Undo {
+                    event_id: 1,
+                    change: Box::new(TodoEventPayload::Snapshot(todo)),
+                }
+            );
+        }
+
+        #[tokio::test]
+        async fn double_undo_walks_back_through_history() {
+            let (repository, _) = repository();
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new("first".to_string(), vec![]))
+                .await
+                .unwrap();
+            for text in ["second", "third"] {
+                let payload: UpdateTodo =
+                    serde_json::from_str(&format!(r#"{{ "text": "{}" }}"#, text)).unwrap();
+                repository
+                    .update(TEST_USER_ID, todo.id, payload)
+                    .await
+                    .unwrap();
+            }
+
+            // 取り消しは取り消されず、1つ前の変更が対象になる
+            repository.undo(TEST_USER_ID, todo.id).await.unwrap();
+            let undone = repository.find(TEST_USER_ID, todo.id).await.unwrap();
+            assert_eq!(undone.text, "second");
+            repository.undo(TEST_USER_ID, todo.id).await.unwrap();
+            let undone = repository.find(TEST_USER_ID, todo.id).await.unwrap();
+            assert_eq!(undone.text, "first");
+            repository.undo(TEST_USER_ID, todo.id).await.unwrap();
+            assert!(repository.find(TEST_USER_ID, todo.id).await.is_err());
+
+            // 全て取り消した後はConflictになる
+            let res = repository.undo(TEST_USER_ID, todo.id).await;
+            assert!(matches!(
+                res.unwrap_err().downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::NothingToUndo(id)) if *id == todo.id
+            ));
+        }
+
+        #[tokio::test]
+        async fn undo_completion_removes_spawned_occurrence() {
+            let (repository, _) = repository();
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("water plants".to_string(), vec![])
+                        .recurring(Recurrence::Daily, Some(test_now().date_naive())),
+                )
+                .await
+                .unwrap();
+            let completed = repository
+                .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+                .await
+                .unwrap();
+            let spawned = completed.next_occurrence.expect("no next occurrence");
+
+            repository.undo(TEST_USER_ID, todo.id).await.unwrap();
+            assert!(repository.find(TEST_USER_ID, spawned.id).await.is_err());
+            assert!(
+                !repository
+                    .find(TEST_USER_ID, todo.id)
+                    .await
+                    .unwrap()
+                    .completed
+            );
+
+            // 完了し直しても次の回は1つだけ
+            repository
+                .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+                .await
+                .unwrap();
+            let open: Vec<_> = repository
+                .all(TEST_USER_ID, TodoQuery::default())
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|todo| !todo.completed)
+                .collect();
+            assert_eq!(open.len(), 1);
+        }
+
+        #[tokio::test]
+        async fn undo_completion_reopens_cascaded_subtasks() {
+            let (repository, _) = repository();
+            let parent = repository
+                .create(TEST_USER_ID, CreateTodo::new("parent".to_string(), vec![]))
+                .await
+                .unwrap();
+            let child = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("child".to_string(), vec![]).subtask_of(parent.id),
+                )
+                .await
+                .unwrap();
+            let done = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("done".to_string(), vec![]).subtask_of(parent.id),
+                )
+                .await
+                .unwrap();
+            repository
+                .update(TEST_USER_ID, done.id, UpdateTodo::completion(true))
+                .await
+                .unwrap();
+            repository
+                .update(
+                    TEST_USER_ID,
+                    parent.id,
+                    UpdateTodo::builder()
+                        .completed(true)
+                        .complete_subtasks(true)
+                        .build(),
+                )
+                .await
+                .unwrap();
+
+            repository.undo(TEST_USER_ID, parent.id).await.unwrap();
+            assert!(
+                !repository
+                    .find(TEST_USER_ID, parent.id)
+                    .await
+                    .unwrap()
+                    .completed
+            );
+            let child = repository.find(TEST_USER_ID, child.id).await.unwrap();
+            assert!(!child.completed);
+            assert!(child.completed_at.is_none());
+            // 先に完了していた子はそのまま
+            assert!(
+                repository
+                    .find(TEST_USER_ID, done.id)
+                    .await
+                    .unwrap()
+                    .completed
+            );
+        }
+
+        #[tokio::test]
+        async fn undo_delete_relinks_detached_subtasks() {
+            let (repository, _) = repository();
+            let parent = repository
+                .create(TEST_USER_ID, CreateTodo::new("parent".to_string(), vec![]))
+                .await
+                .unwrap();
+            let child = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("child".to_string(), vec![]).subtask_of(parent.id),
+                )
+                .await
+                .unwrap();
+            repository.delete(TEST_USER_ID, parent.id).await.unwrap();
+            assert_eq!(
+                repository
+                    .find(TEST_USER_ID, child.id)
+                    .await
+                    .unwrap()
+                    .parent_id,
+                None
+            );
+
+            repository.undo(TEST_USER_ID, parent.id).await.unwrap();
+            assert_eq!(
+                repository
+                    .find(TEST_USER_ID, child.id)
+                    .await
+                    .unwrap()
+                    .parent_id,
+                Some(parent.id)
+            );
+        }
+    }
+}
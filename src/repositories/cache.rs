@@ -0,0 +1,406 @@
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::stream::BoxStream;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use super::{
+    activity::TodoEvent,
+    todo::{
+        BulkUpdateTodo, CreateTodo, DeletePreview, TimeseriesPoint, TimeseriesQuery, TodoEntity,
+        TodoQuery, TodoRepository, TodoStats, UpdateTodo,
+    },
+};
+
+/// 1つのdecoratorが保持する結果の上限. 超えたら期限切れを捨て、それでも一杯なら全て捨てる
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+#[derive(Debug)]
+struct Entry<T> {
+    value: T,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    lists: HashMap<(i32, TodoQuery), Entry<Vec<TodoEntity>>>,
+    todos: HashMap<(i32, i32), Entry<TodoEntity>>,
+    // 書き込みの度に増やす. 読み込み中に書き込みがあれば、古い結果を保存しない
+    generation: u64,
+}
+
+impl Store {
+    // 読み込みを始めた時から書き込みが無く、保存する余地があればtrue
+    fn can_insert(&mut self, generation: u64) -> bool {
+        if generation != self.generation {
+            return false;
+        }
+        if self.lists.len() + self.todos.len() >= MAX_CACHE_ENTRIES {
+            let now = Instant::now();
+            self.lists.retain(|_, entry| entry.expires_at > now);
+            self.todos.retain(|_, entry| entry.expires_at > now);
+            if self.lists.len() + self.todos.len() >= MAX_CACHE_ENTRIES {
+                self.lists.clear();
+                self.todos.clear();
+            }
+        }
+        true
+    }
+
+    // user_idがNoneなら全てのユーザーの結果を捨てる
+    fn invalidate(&mut self, user_id: Option<i32>) {
+        self.generation += 1;
+        match user_id {
+            Some(user_id) => {
+                self.lists.retain(|(owner, _), _| *owner != user_id);
+                self.todos.retain(|(owner, _), _| *owner != user_id);
+            }
+            None => {
+                self.lists.clear();
+                self.todos.clear();
+            }
+        }
+    }
+}
+
+// 期限内の結果があれば返す
+fn fresh<K: Hash + Eq, T: Clone>(map: &HashMap<K, Entry<T>>, key: &K) -> Option<T> {
+    map.get(key)
+        .filter(|entry| entry.expires_at > Instant::now())
+        .map(|entry| entry.value.clone())
+}
+
+/// キャッシュから返せた回数と、innerに問い合わせた回数
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// all, findの結果をttlの間メモリに保持するTodoRepositoryのdecorator.
+///
+/// このdecoratorを通る書き込みは、そのユーザーの結果を全て捨てる.
+/// 他のプロセスやinnerを直接使った書き込みはttlが過ぎるまで反映されない
+#[derive(Debug, Clone)]
+pub struct CachedTodoRepository<R> {
+    inner: R,
+    ttl: Duration,
+    store: Arc<RwLock<Store>>,
+    stats: Arc<CacheStats>,
+}
+
+impl<R: TodoRepository> CachedTodoRepository<R> {
+    pub fn new(inner: R, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            store: Arc::default(),
+            stats: Arc::default(),
+        }
+    }
+
+    /// 命中と外れの回数. cloneしたdecoratorとも共有する
+    pub fn cache_stats(&self) -> Arc<CacheStats> {
+        self.stats.clone()
+    }
+
+    fn read_store_ref(&self) -> RwLockReadGuard<'_, Store> {
+        self.store.read().expect("cache store read lock error")
+    }
+
+    fn write_store_ref(&self) -> RwLockWriteGuard<'_, Store> {
+        self.store.write().expect("cache store write lock error")
+    }
+
+    fn hit<T>(&self, value: T) -> anyhow::Result<T> {
+        self.stats.record_hit();
+        Ok(value)
+    }
+
+    // 外れを数え、問い合わせの前の世代を返す
+    fn miss(&self, generation: u64) -> u64 {
+        self.stats.record_miss();
+        generation
+    }
+
+    // 書き込みは失敗しても一部が反映されていることがあるため、結果に関わらず捨てる
+    fn invalidate<T>(&self, user_id: Option<i32>, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        self.write_store_ref().invalidate(user_id);
+        result
+    }
+}
+
+#[async_trait]
+impl<R: TodoRepository> TodoRepository for CachedTodoRepository<R> {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+        let result = self.inner.create(user_id, payload).await;
+        self.invalidate(Some(user_id), result)
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEntity> {
+        let key = (user_id, id);
+        let generation = {
+            let store = self.read_store_ref();
+            if let Some(todo) = fresh(&store.todos, &key) {
+                return self.hit(todo);
+            }
+            self.miss(store.generation)
+        };
+        let todo = self.inner.find(user_id, id).await?;
+        let mut store = self.write_store_ref();
+        if store.can_insert(generation) {
+            let expires_at = Instant::now() + self.ttl;
+            store.todos.insert(
+                key,
+                Entry {
+                    value: todo.clone(),
+                    expires_at,
+                },
+            );
+        }
+        Ok(todo)
+    }
+
+    async fn find_by_public_id(&self, user_id: i32, public_id: Uuid) -> anyhow::Result<TodoEntity> {
+        self.inner.find_by_public_id(user_id, public_id).await
+    }
+
+    async fn all(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<Vec<TodoEntity>> {
+        let key = (user_id, query);
+        let generation = {
+            let store = self.read_store_ref();
+            if let Some(todos) = fresh(&store.lists, &key) {
+                return self.hit(todos);
+            }
+            self.miss(store.generation)
+        };
+        let todos = self.inner.all(user_id, key.1.clone()).await?;
+        let mut store = self.write_store_ref();
+        if store.can_insert(generation) {
+            let expires_at = Instant::now() + self.ttl;
+            store.lists.insert(
+                key,
+                Entry {
+                    value: todos.clone(),
+                    expires_at,
+                },
+            );
+        }
+        Ok(todos)
+    }
+
+    // exportは全件を1度だけ読むため、cacheを通さない
+    fn all_stream(
+        &self,
+        user_id: i32,
+        query: TodoQuery,
+    ) -> BoxStream<'static, anyhow::Result<TodoEntity>> {
+        self.inner.all_stream(user_id, query)
+    }
+
+    async fn count(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<i64> {
+        self.inner.count(user_id, query).await
+    }
+
+    async fn find_many(&self, user_id: i32, ids: &[i32]) -> anyhow::Result<Vec<TodoEntity>> {
+        self.inner.find_many(user_id, ids).await
+    }
+
+    async fn update(
+        &self,
+        user_id: i32,
+        id: i32,
+        payload: UpdateTodo,
+    ) -> anyhow::Result<TodoEntity> {
+        let result = self.inner.update(user_id, id, payload).await;
+        self.invalidate(Some(user_id), result)
+    }
+
+    async fn update_where(
+        &self,
+        user_id: i32,
+        filter: TodoQuery,
+        set: BulkUpdateTodo,
+    ) -> anyhow::Result<Vec<TodoEntity>> {
+        let result = self.inner.update_where(user_id, filter, set).await;
+        self.invalidate(Some(user_id), result)
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let result = self.inner.delete(user_id, id).await;
+        self.invalidate(Some(user_id), result)
+    }
+
+    async fn preview_delete(&self, user_id: i32, id: i32) -> anyhow::Result<DeletePreview> {
+        self.inner.preview_delete(user_id, id).await
+    }
+
+    async fn subtasks(&self, user_id: i32, id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+        self.inner.subtasks(user_id, id).await
+    }
+
+    async fn restore(&self, user_id: i32, todo: TodoEntity) -> anyhow::Result<TodoEntity> {
+        let result = self.inner.restore(user_id, todo).await;
+        self.invalidate(Some(user_id), result)
+    }
+
+    async fn undo(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEvent> {
+        let result = self.inner.undo(user_id, id).await;
+        self.invalidate(Some(user_id), result)
+    }
+
+    // 複数のユーザーのtodoを変更するため、全ての結果を捨てる
+    async fn claim_due_reminders(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<TodoEntity>> {
+        let result = self.inner.claim_due_reminders(now).await;
+        self.invalidate(None, result)
+    }
+
+    async fn stats(&self, user_id: i32, today: NaiveDate) -> anyhow::Result<TodoStats> {
+        self.inner.stats(user_id, today).await
+    }
+
+    async fn timeseries(
+        &self,
+        user_id: i32,
+        query: TimeseriesQuery,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+        self.inner.timeseries(user_id, query).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::todo::{memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID};
+
+    const TTL: Duration = Duration::from_secs(5);
+
+    #[tokio::test(start_paused = true)]
+    async fn serves_cached_reads_until_a_write_invalidates_them() {
+        let inner = TodoRepositoryForMemory::for_test(vec![]);
+        let repository = CachedTodoRepository::new(inner.clone(), TTL);
+        let stats = repository.cache_stats();
+        let todo = repository
+            .create(TEST_USER_ID, CreateTodo::new("cached".to_string(), vec![]))
+            .await
+            .unwrap();
+
+        let all = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(all, vec![todo.clone()]);
+        repository.find(TEST_USER_ID, todo.id).await.unwrap();
+        assert_eq!((stats.hits(), stats.misses()), (0, 2));
+
+        // innerを直接変更してもキャッシュから返す
+        inner
+            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+            .await
+            .unwrap();
+        let cached = repository.find(TEST_USER_ID, todo.id).await.unwrap();
+        assert!(!cached.completed);
+        let all = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .unwrap();
+        assert!(!all[0].completed);
+        assert_eq!((stats.hits(), stats.misses()), (2, 2));
+
+        // decoratorを通した書き込みの後は新しい結果を返す
+        let updated = repository
+            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(false))
+            .await
+            .unwrap();
+        assert_eq!(
+            repository.find(TEST_USER_ID, todo.id).await.unwrap(),
+            updated
+        );
+        repository.delete(TEST_USER_ID, todo.id).await.unwrap();
+        let all = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .unwrap();
+        assert!(all.is_empty());
+        assert_eq!((stats.hits(), stats.misses()), (2, 4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_entries_after_ttl() {
+        let inner = TodoRepositoryForMemory::for_test(vec![]);
+        let repository = CachedTodoRepository::new(inner.clone(), TTL);
+        let query = TodoQuery {
+            completed: Some(false),
+            ..Default::default()
+        };
+        assert!(repository
+            .all(TEST_USER_ID, query.clone())
+            .await
+            .unwrap()
+            .is_empty());
+        inner
+            .create(TEST_USER_ID, CreateTodo::new("later".to_string(), vec![]))
+            .await
+            .unwrap();
+
+        tokio::time::advance(TTL - Duration::from_millis(1)).await;
+        assert!(repository
+            .all(TEST_USER_ID, query.clone())
+            .await
+            .unwrap()
+            .is_empty());
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        let all = repository.all(TEST_USER_ID, query).await.unwrap();
+        assert_eq!(all.len(), 1);
+        let stats = repository.cache_stats();
+        assert_eq!((stats.hits(), stats.misses()), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn keeps_other_users_entries_on_write() {
+        let inner = TodoRepositoryForMemory::for_test(vec![]);
+        let repository = CachedTodoRepository::new(inner, TTL);
+        let other_user = TEST_USER_ID + 1;
+        repository
+            .all(other_user, TodoQuery::default())
+            .await
+            .unwrap();
+        repository
+            .create(TEST_USER_ID, CreateTodo::new("mine".to_string(), vec![]))
+            .await
+            .unwrap();
+        repository
+            .all(other_user, TodoQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(repository.cache_stats().hits(), 1);
+    }
+}
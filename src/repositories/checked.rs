@@ -0,0 +1,119 @@
+//! SQLを.sqlxのメタデータで検査するsqlxのマクロ. 同じ名前のsqlxのマクロと同じに書く.
+//! runtime-queriesを有効にすると検査せず、実行時に問い合わせを組み立てる.
+//! DB無しでSQLを変更する時に使い、.sqlxはDBのある環境でmake sqlx-prepareで作り直す
+
+/// sqlx::query!と同じ. 行を返さない問い合わせに使う
+#[cfg(not(feature = "runtime-queries"))]
+macro_rules! checked_query {
+    ($sql:literal $(, $($args:tt)*)?) => {
+        sqlx::query!($sql $(, $($args)*)?)
+    };
+}
+
+/// sqlx::query_as!と同じ. outはFromRowも実装する
+#[cfg(not(feature = "runtime-queries"))]
+macro_rules! checked_query_as {
+    ($out:path, $sql:literal $(, $($args:tt)*)?) => {
+        sqlx::query_as!($out, $sql $(, $($args)*)?)
+    };
+}
+
+/// sqlx::query_scalar!と同じ
+#[cfg(not(feature = "runtime-queries"))]
+macro_rules! checked_query_scalar {
+    ($sql:literal $(, $($args:tt)*)?) => {
+        sqlx::query_scalar!($sql $(, $($args)*)?)
+    };
+}
+
+#[cfg(feature = "runtime-queries")]
+macro_rules! checked_query {
+    ($sql:literal $(, $($args:tt)*)?) => {
+        sqlx::query_with(runtime_sql!($sql), query_args!($($($args)*)?))
+    };
+}
+
+#[cfg(feature = "runtime-queries")]
+macro_rules! checked_query_as {
+    ($out:path, $sql:literal $(, $($args:tt)*)?) => {
+        sqlx::query_as_with::<_, $out, _>(runtime_sql!($sql), query_args!($($($args)*)?))
+    };
+}
+
+#[cfg(feature = "runtime-queries")]
+macro_rules! checked_query_scalar {
+    ($sql:literal $(, $($args:tt)*)?) => {
+        sqlx::query_scalar_with(runtime_sql!($sql), query_args!($($($args)*)?))
+    };
+}
+
+// 問い合わせごとに一度だけ、列の型の上書きを除いたSQLにする
+#[cfg(feature = "runtime-queries")]
+macro_rules! runtime_sql {
+    ($sql:literal) => {{
+        static SQL: std::sync::LazyLock<String> =
+            std::sync::LazyLock::new(|| $crate::repositories::checked::runtime_sql($sql));
+        SQL.as_str()
+    }};
+}
+
+// sqlxのマクロと同じく、引数を借りてその場でencodeする. 問い合わせは引数を借り続けない
+#[cfg(feature = "runtime-queries")]
+macro_rules! query_args {
+    ($($args:tt)*) => {{
+        #[allow(unused_mut)]
+        let mut args = sqlx::postgres::PgArguments::default();
+        add_args!(args; $($args)*);
+        args
+    }};
+}
+
+// 引数を順に加える. 型の検査を外す`as _`は、sqlxのマクロと同じく外して値を加える
+#[cfg(feature = "runtime-queries")]
+macro_rules! add_args {
+    ($args:ident;) => {};
+    ($args:ident; $(&)? $($arg:ident).+ as _ $(, $($rest:tt)*)?) => {
+        sqlx::Arguments::add(&mut $args, &$($arg).+);
+        add_args!($args; $($($rest)*)?)
+    };
+    ($args:ident; $arg:expr $(, $($rest:tt)*)?) => {
+        sqlx::Arguments::add(&mut $args, &($arg));
+        add_args!($args; $($($rest)*)?)
+    };
+}
+
+/// マクロにだけ意味のある列名の上書き("count!"や"recurrence: Recurrence")を列名だけにする.
+/// 実行時にはFromRowが列名で値を探すため
+#[cfg(any(test, feature = "runtime-queries"))]
+pub(crate) fn runtime_sql(sql: &str) -> String {
+    sql.split('"')
+        .enumerate()
+        .map(|(i, part)| match i % 2 {
+            // 引用符の中の識別子
+            1 => part
+                .split(['!', '?', ':'])
+                .next()
+                .unwrap_or_default()
+                .trim(),
+            _ => part,
+        })
+        .collect::<Vec<_>>()
+        .join("\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runtime_sql_keeps_only_column_names() {
+        assert_eq!(
+            runtime_sql(
+                r#"select count(*) as "count!", recurrence as "recurrence: Recurrence",
+                    labels.id as "label_id?" from todos where text = 'a:b'"#
+            ),
+            r#"select count(*) as "count", recurrence as "recurrence",
+                    labels.id as "label_id" from todos where text = 'a:b'"#
+        );
+    }
+}
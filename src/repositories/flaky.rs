@@ -0,0 +1,305 @@
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use uuid::Uuid;
+
+use super::{
+    activity::TodoEvent,
+    todo::{
+        BulkUpdateTodo, CreateTodo, DeletePreview, TimeseriesPoint, TimeseriesQuery, TodoEntity,
+        TodoPage, TodoQuery, TodoRepository, TodoStats, UpdateTodo,
+    },
+};
+
+/// FlakyTodoRepositoryで失敗させるTodoRepositoryの操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Create,
+    Find,
+    FindByPublicId,
+    All,
+    AllStream,
+    Count,
+    Page,
+    FindMany,
+    Update,
+    UpdateWhere,
+    Delete,
+    PreviewDelete,
+    Subtasks,
+    Restore,
+    Undo,
+    ClaimDueReminders,
+    Stats,
+    Timeseries,
+}
+
+// 次の呼び出しで起こす障害
+enum Fault {
+    Fail(anyhow::Error),
+    Panic(String),
+    Delay(Duration),
+}
+
+/// ハンドラーの失敗時の応答を確かめるためのtest double.
+/// 操作ごとに予約した障害を呼ばれた順に1回ずつ起こし、予約が無ければinnerにそのまま渡す.
+/// cloneは予約を共有するため、create_appに渡した後も予約を追加できる
+#[derive(Clone)]
+pub struct FlakyTodoRepository<R> {
+    inner: R,
+    faults: Arc<Mutex<HashMap<Method, VecDeque<Fault>>>>,
+}
+
+impl<R> FlakyTodoRepository<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            faults: Arc::default(),
+        }
+    }
+
+    /// 次のmethodの呼び出しでerrorを返す. RepositoryError以外のanyhow::Errorも渡せる
+    pub fn fail_next(self, method: Method, error: impl Into<anyhow::Error>) -> Self {
+        self.push(method, Fault::Fail(error.into()))
+    }
+
+    /// 次のmethodの呼び出しでpanicする
+    pub fn panic_next(self, method: Method, message: impl Into<String>) -> Self {
+        self.push(method, Fault::Panic(message.into()))
+    }
+
+    /// 次のmethodの呼び出しをdelayだけ待たせてからinnerに渡す
+    pub fn delay_next(self, method: Method, delay: Duration) -> Self {
+        self.push(method, Fault::Delay(delay))
+    }
+
+    /// まだ起きていない障害の数
+    pub fn pending(&self, method: Method) -> usize {
+        self.faults
+            .lock()
+            .unwrap()
+            .get(&method)
+            .map_or(0, VecDeque::len)
+    }
+
+    fn push(self, method: Method, fault: Fault) -> Self {
+        self.faults
+            .lock()
+            .unwrap()
+            .entry(method)
+            .or_default()
+            .push_back(fault);
+        self
+    }
+
+    fn take(&self, method: Method) -> Option<Fault> {
+        self.faults
+            .lock()
+            .unwrap()
+            .get_mut(&method)
+            .and_then(VecDeque::pop_front)
+    }
+
+    // 予約された障害を起こす. Delayは待った後にOkを返し、innerに渡させる
+    async fn inject(&self, method: Method) -> anyhow::Result<()> {
+        match self.take(method) {
+            None => Ok(()),
+            Some(Fault::Fail(e)) => Err(e),
+            Some(Fault::Panic(message)) => panic!("{}", message),
+            Some(Fault::Delay(delay)) => {
+                tokio::time::sleep(delay).await;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<R: TodoRepository> TodoRepository for FlakyTodoRepository<R> {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+        self.inject(Method::Create).await?;
+        self.inner.create(user_id, payload).await
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEntity> {
+        self.inject(Method::Find).await?;
+        self.inner.find(user_id, id).await
+    }
+
+    async fn find_by_public_id(&self, user_id: i32, public_id: Uuid) -> anyhow::Result<TodoEntity> {
+        self.inject(Method::FindByPublicId).await?;
+        self.inner.find_by_public_id(user_id, public_id).await
+    }
+
+    async fn all(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<Vec<TodoEntity>> {
+        self.inject(Method::All).await?;
+        self.inner.all(user_id, query).await
+    }
+
+    // 失敗はstreamの最初の要素として返す. panicは呼び出した時点で起こす
+    fn all_stream(
+        &self,
+        user_id: i32,
+        query: TodoQuery,
+    ) -> BoxStream<'static, anyhow::Result<TodoEntity>> {
+        match self.take(Method::AllStream) {
+            None => self.inner.all_stream(user_id, query),
+            Some(Fault::Fail(e)) => stream::once(async { Err(e) }).boxed(),
+            Some(Fault::Panic(message)) => panic!("{}", message),
+            Some(Fault::Delay(delay)) => {
+                let todos = self.inner.all_stream(user_id, query);
+                stream::once(async move {
+                    tokio::time::sleep(delay).await;
+                    todos
+                })
+                .flatten()
+                .boxed()
+            }
+        }
+    }
+
+    async fn count(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<i64> {
+        self.inject(Method::Count).await?;
+        self.inner.count(user_id, query).await
+    }
+
+    async fn page(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<TodoPage> {
+        self.inject(Method::Page).await?;
+        self.inner.page(user_id, query).await
+    }
+
+    async fn find_many(&self, user_id: i32, ids: &[i32]) -> anyhow::Result<Vec<TodoEntity>> {
+        self.inject(Method::FindMany).await?;
+        self.inner.find_many(user_id, ids).await
+    }
+
+    async fn update(
+        &self,
+        user_id: i32,
+        id: i32,
+        payload: UpdateTodo,
+    ) -> anyhow::Result<TodoEntity> {
+        self.inject(Method::Update).await?;
+        self.inner.update(user_id, id, payload).await
+    }
+
+    async fn update_where(
+        &self,
+        user_id: i32,
+        filter: TodoQuery,
+        set: BulkUpdateTodo,
+    ) -> anyhow::Result<Vec<TodoEntity>> {
+        self.inject(Method::UpdateWhere).await?;
+        self.inner.update_where(user_id, filter, set).await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.inject(Method::Delete).await?;
+        self.inner.delete(user_id, id).await
+    }
+
+    async fn preview_delete(&self, user_id: i32, id: i32) -> anyhow::Result<DeletePreview> {
+        self.inject(Method::PreviewDelete).await?;
+        self.inner.preview_delete(user_id, id).await
+    }
+
+    async fn subtasks(&self, user_id: i32, id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+        self.inject(Method::Subtasks).await?;
+        self.inner.subtasks(user_id, id).await
+    }
+
+    async fn restore(&self, user_id: i32, todo: TodoEntity) -> anyhow::Result<TodoEntity> {
+        self.inject(Method::Restore).await?;
+        self.inner.restore(user_id, todo).await
+    }
+
+    async fn undo(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEvent> {
+        self.inject(Method::Undo).await?;
+        self.inner.undo(user_id, id).await
+    }
+
+    async fn claim_due_reminders(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<TodoEntity>> {
+        self.inject(Method::ClaimDueReminders).await?;
+        self.inner.claim_due_reminders(now).await
+    }
+
+    async fn stats(&self, user_id: i32, today: NaiveDate) -> anyhow::Result<TodoStats> {
+        self.inject(Method::Stats).await?;
+        self.inner.stats(user_id, today).await
+    }
+
+    async fn timeseries(
+        &self,
+        user_id: i32,
+        query: TimeseriesQuery,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+        self.inject(Method::Timeseries).await?;
+        self.inner.timeseries(user_id, query).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        todo::{memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID},
+        RepositoryError,
+    };
+
+    #[tokio::test]
+    async fn faults_happen_once_in_order_then_pass_through() {
+        let repository = FlakyTodoRepository::new(TodoRepositoryForMemory::for_test(vec![]))
+            .fail_next(Method::Create, RepositoryError::Duplicate(1))
+            .fail_next(Method::Create, anyhow::anyhow!("connection reset"));
+        assert_eq!(repository.pending(Method::Create), 2);
+        let create = || CreateTodo::new("flaky".to_string(), vec![]);
+
+        let err = repository.create(TEST_USER_ID, create()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Duplicate(1))
+        ));
+        let err = repository.create(TEST_USER_ID, create()).await.unwrap_err();
+        assert_eq!(err.to_string(), "connection reset");
+
+        let todo = repository.create(TEST_USER_ID, create()).await.unwrap();
+        assert_eq!(repository.pending(Method::Create), 0);
+        // 他の操作には影響しない
+        assert_eq!(repository.find(TEST_USER_ID, todo.id).await.unwrap(), todo);
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_script() {
+        let repository = FlakyTodoRepository::new(TodoRepositoryForMemory::for_test(vec![]));
+        repository.clone().fail_next(
+            Method::Stats,
+            RepositoryError::Unexpected("down".to_string()),
+        );
+
+        let today = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        assert!(repository.stats(TEST_USER_ID, today).await.is_err());
+        assert!(repository.stats(TEST_USER_ID, today).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_waits_before_passing_through() {
+        let repository = FlakyTodoRepository::new(TodoRepositoryForMemory::for_test(vec![]))
+            .delay_next(Method::All, Duration::from_secs(5));
+
+        let started = tokio::time::Instant::now();
+        let todos = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .unwrap();
+        assert!(todos.is_empty());
+        assert!(started.elapsed() >= Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,112 @@
+use async_graphql::MaybeUndefined;
+use serde::{
+    de::{self, Unexpected, Visitor},
+    Deserialize, Deserializer,
+};
+use std::fmt;
+
+// 入力のidを数値でも文字列("5")でも受け付ける. 大きな整数を扱えないクライアントは
+// idを文字列で受け取るため、そのまま送り返せるようにする
+struct FlexibleId(i32);
+
+struct IdVisitor;
+
+impl<'de> Visitor<'de> for IdVisitor {
+    type Value = i32;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an integer id or a string of one")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<i32, E> {
+        i32::try_from(v).map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<i32, E> {
+        i32::try_from(v).map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<i32, E> {
+        v.parse()
+            .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for FlexibleId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(IdVisitor).map(FlexibleId)
+    }
+}
+
+fn unwrap_all(ids: Vec<FlexibleId>) -> Vec<i32> {
+    ids.into_iter().map(|FlexibleId(id)| id).collect()
+}
+
+pub fn ids<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<i32>, D::Error> {
+    Vec::deserialize(deserializer).map(unwrap_all)
+}
+
+pub fn optional_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i32>, D::Error> {
+    Option::<FlexibleId>::deserialize(deserializer).map(|id| id.map(|FlexibleId(id)| id))
+}
+
+pub fn optional_ids<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<i32>>, D::Error> {
+    Option::<Vec<FlexibleId>>::deserialize(deserializer).map(|ids| ids.map(unwrap_all))
+}
+
+/// nullはNull、省略はUndefinedのまま(フィールドに#[serde(default)]が必要)
+pub fn maybe_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<MaybeUndefined<i32>, D::Error> {
+    Ok(match optional_id(deserializer)? {
+        Some(id) => MaybeUndefined::Value(id),
+        None => MaybeUndefined::Null,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Payload {
+        #[serde(default, deserialize_with = "ids")]
+        labels: Vec<i32>,
+        #[serde(default, deserialize_with = "maybe_id")]
+        parent_id: MaybeUndefined<i32>,
+    }
+
+    #[test]
+    fn accepts_numbers_and_numeric_strings() {
+        let payload: Payload =
+            serde_json::from_str(r#"{ "labels": [1, "2"], "parent_id": "3" }"#).unwrap();
+        assert_eq!(payload.labels, vec![1, 2]);
+        assert_eq!(payload.parent_id, MaybeUndefined::Value(3));
+
+        let payload: Payload = serde_json::from_str(r#"{ "parent_id": null }"#).unwrap();
+        assert_eq!(payload.parent_id, MaybeUndefined::Null);
+        let payload: Payload = serde_json::from_str("{}").unwrap();
+        assert_eq!(payload.parent_id, MaybeUndefined::Undefined);
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_ids() {
+        for json in [
+            r#"{ "labels": ["one"] }"#,
+            r#"{ "labels": [1.5] }"#,
+            r#"{ "parent_id": "2147483648" }"#,
+            r#"{ "parent_id": 2147483648 }"#,
+            r#"{ "parent_id": true }"#,
+        ] {
+            let err = serde_json::from_str::<Payload>(json).unwrap_err();
+            assert!(
+                err.to_string().contains("an integer id or a string of one"),
+                "{}: {}",
+                json,
+                err
+            );
+        }
+    }
+}
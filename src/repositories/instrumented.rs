@@ -0,0 +1,338 @@
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::stream::BoxStream;
+use std::{future::Future, time::Instant};
+use tracing::{info_span, Instrument, Span};
+use uuid::Uuid;
+
+use super::{
+    activity::TodoEvent,
+    label::{Label, LabelQuery, LabelRepository},
+    todo::{
+        BulkUpdateTodo, CreateTodo, DeletePreview, TimeseriesPoint, TimeseriesQuery, TodoEntity,
+        TodoPage, TodoQuery, TodoRepository, TodoStats, UpdateTodo,
+    },
+    RepositoryError,
+};
+
+/// 操作の所要時間(秒)のヒストグラム. repository, operationのラベルを付ける
+pub const DURATION_METRIC: &str = "repository_operation_duration_seconds";
+
+/// 失敗した操作の数. repository, operationに加えて、RepositoryErrorの種類をkindのラベルに付ける
+pub const ERRORS_METRIC: &str = "repository_operation_errors_total";
+
+/// repositoryの操作ごとにtracingのspan(repo.create, repo.findなど)を作り、
+/// 所要時間と失敗の数をmetricsに記録するdecorator. innerが実装するrepositoryのtraitを同じく実装する
+#[derive(Debug, Clone)]
+pub struct InstrumentedRepository<R> {
+    inner: R,
+}
+
+impl<R> InstrumentedRepository<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+// spanの中でfutureを実行し、所要時間と失敗を記録する
+async fn observe<T>(
+    repository: &'static str,
+    operation: &'static str,
+    span: Span,
+    future: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let labels = [("repository", repository), ("operation", operation)];
+    let started = Instant::now();
+    let result = future.instrument(span).await;
+    metrics::histogram!(DURATION_METRIC, &labels).record(started.elapsed().as_secs_f64());
+    if let Err(e) = &result {
+        let kind = e
+            .downcast_ref::<RepositoryError>()
+            .map_or("other", RepositoryError::kind);
+        metrics::counter!(ERRORS_METRIC, &[labels[0], labels[1], ("kind", kind)]).increment(1);
+    }
+    result
+}
+
+#[async_trait]
+impl<R: TodoRepository> TodoRepository for InstrumentedRepository<R> {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+        let span = info_span!("repo.create", repository = "todo", user_id);
+        observe("todo", "create", span, self.inner.create(user_id, payload)).await
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEntity> {
+        let span = info_span!("repo.find", repository = "todo", user_id, id);
+        observe("todo", "find", span, self.inner.find(user_id, id)).await
+    }
+
+    async fn find_by_public_id(&self, user_id: i32, public_id: Uuid) -> anyhow::Result<TodoEntity> {
+        let span = info_span!(
+            "repo.find_by_public_id",
+            repository = "todo",
+            user_id,
+            %public_id
+        );
+        let future = self.inner.find_by_public_id(user_id, public_id);
+        observe("todo", "find_by_public_id", span, future).await
+    }
+
+    async fn all(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<Vec<TodoEntity>> {
+        let span = info_span!("repo.all", repository = "todo", user_id);
+        observe("todo", "all", span, self.inner.all(user_id, query)).await
+    }
+
+    // 読み込みは返したstreamを読む側が進めるため、所要時間は記録しない
+    fn all_stream(
+        &self,
+        user_id: i32,
+        query: TodoQuery,
+    ) -> BoxStream<'static, anyhow::Result<TodoEntity>> {
+        self.inner.all_stream(user_id, query)
+    }
+
+    async fn count(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<i64> {
+        let span = info_span!("repo.count", repository = "todo", user_id);
+        observe("todo", "count", span, self.inner.count(user_id, query)).await
+    }
+
+    async fn page(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<TodoPage> {
+        let span = info_span!("repo.page", repository = "todo", user_id);
+        observe("todo", "page", span, self.inner.page(user_id, query)).await
+    }
+
+    async fn find_many(&self, user_id: i32, ids: &[i32]) -> anyhow::Result<Vec<TodoEntity>> {
+        let span = info_span!("repo.find_many", repository = "todo", user_id, ?ids);
+        observe(
+            "todo",
+            "find_many",
+            span,
+            self.inner.find_many(user_id, ids),
+        )
+        .await
+    }
+
+    async fn update(
+        &self,
+        user_id: i32,
+        id: i32,
+        payload: UpdateTodo,
+    ) -> anyhow::Result<TodoEntity> {
+        let span = info_span!("repo.update", repository = "todo", user_id, id);
+        observe(
+            "todo",
+            "update",
+            span,
+            self.inner.update(user_id, id, payload),
+        )
+        .await
+    }
+
+    async fn update_where(
+        &self,
+        user_id: i32,
+        filter: TodoQuery,
+        set: BulkUpdateTodo,
+    ) -> anyhow::Result<Vec<TodoEntity>> {
+        let span = info_span!("repo.update_where", repository = "todo", user_id);
+        let future = self.inner.update_where(user_id, filter, set);
+        observe("todo", "update_where", span, future).await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let span = info_span!("repo.delete", repository = "todo", user_id, id);
+        observe("todo", "delete", span, self.inner.delete(user_id, id)).await
+    }
+
+    async fn preview_delete(&self, user_id: i32, id: i32) -> anyhow::Result<DeletePreview> {
+        let span = info_span!("repo.preview_delete", repository = "todo", user_id, id);
+        let future = self.inner.preview_delete(user_id, id);
+        observe("todo", "preview_delete", span, future).await
+    }
+
+    async fn subtasks(&self, user_id: i32, id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+        let span = info_span!("repo.subtasks", repository = "todo", user_id, id);
+        observe("todo", "subtasks", span, self.inner.subtasks(user_id, id)).await
+    }
+
+    async fn restore(&self, user_id: i32, todo: TodoEntity) -> anyhow::Result<TodoEntity> {
+        let span = info_span!("repo.restore", repository = "todo", user_id, id = todo.id);
+        observe("todo", "restore", span, self.inner.restore(user_id, todo)).await
+    }
+
+    async fn undo(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEvent> {
+        let span = info_span!("repo.undo", repository = "todo", user_id, id);
+        observe("todo", "undo", span, self.inner.undo(user_id, id)).await
+    }
+
+    async fn claim_due_reminders(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<TodoEntity>> {
+        let span = info_span!("repo.claim_due_reminders", repository = "todo");
+        let future = self.inner.claim_due_reminders(now);
+        observe("todo", "claim_due_reminders", span, future).await
+    }
+
+    async fn stats(&self, user_id: i32, today: NaiveDate) -> anyhow::Result<TodoStats> {
+        let span = info_span!("repo.stats", repository = "todo", user_id);
+        observe("todo", "stats", span, self.inner.stats(user_id, today)).await
+    }
+
+    async fn timeseries(
+        &self,
+        user_id: i32,
+        query: TimeseriesQuery,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+        let span = info_span!("repo.timeseries", repository = "todo", user_id);
+        observe(
+            "todo",
+            "timeseries",
+            span,
+            self.inner.timeseries(user_id, query),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<R: LabelRepository> LabelRepository for InstrumentedRepository<R> {
+    async fn create(&self, owner_id: Option<i32>, name: String) -> anyhow::Result<Label> {
+        let span = info_span!("repo.create", repository = "label", ?owner_id);
+        observe("label", "create", span, self.inner.create(owner_id, name)).await
+    }
+
+    async fn all(&self, user_id: i32, query: LabelQuery) -> anyhow::Result<Vec<Label>> {
+        let span = info_span!("repo.all", repository = "label", user_id);
+        observe("label", "all", span, self.inner.all(user_id, query)).await
+    }
+
+    async fn used(&self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        let span = info_span!("repo.used", repository = "label", user_id);
+        observe("label", "used", span, self.inner.used(user_id)).await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let span = info_span!("repo.delete", repository = "label", user_id, id);
+        observe("label", "delete", span, self.inner.delete(user_id, id)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::memory::LabelRepositoryForMemory,
+        todo::{memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID},
+    };
+    use metrics_util::{
+        debugging::{DebugValue, DebuggingRecorder},
+        CompositeKey, MetricKind,
+    };
+    use std::{
+        fmt::Write,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{field::Field, span::Attributes, subscriber::set_default, Id, Subscriber};
+    use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+    // 作られたspanの名前とフィールドを記録するLayer
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            let mut span = attrs.metadata().name().to_string();
+            attrs.record(&mut |field: &Field, value: &dyn std::fmt::Debug| {
+                write!(span, " {}={:?}", field, value).unwrap();
+            });
+            self.0.lock().unwrap().push(span);
+        }
+    }
+
+    fn key(kind: MetricKind, name: &str, labels: &[(&str, &str)]) -> CompositeKey {
+        let labels: Vec<metrics::Label> = labels
+            .iter()
+            .map(|(key, value)| metrics::Label::new(key.to_string(), value.to_string()))
+            .collect();
+        CompositeKey::new(kind, metrics::Key::from_parts(name.to_string(), labels))
+    }
+
+    #[tokio::test]
+    async fn records_a_span_and_metrics_per_call() {
+        let spans = SpanRecorder::default();
+        let _subscriber = set_default(tracing_subscriber::registry().with(spans.clone()));
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let _recorder = metrics::set_default_local_recorder(&recorder);
+
+        let todos = InstrumentedRepository::new(TodoRepositoryForMemory::for_test(vec![]));
+        let labels = InstrumentedRepository::new(LabelRepositoryForMemory::new());
+        let todo = todos
+            .create(TEST_USER_ID, CreateTodo::new("traced".to_string(), vec![]))
+            .await
+            .unwrap();
+        todos.find(TEST_USER_ID, todo.id).await.unwrap();
+        todos.find(TEST_USER_ID, 999).await.unwrap_err();
+        labels.create(None, "shared".to_string()).await.unwrap();
+
+        assert_eq!(
+            *spans.0.lock().unwrap(),
+            vec![
+                format!("repo.create repository=\"todo\" user_id={}", TEST_USER_ID),
+                format!(
+                    "repo.find repository=\"todo\" user_id={} id=1",
+                    TEST_USER_ID
+                ),
+                format!(
+                    "repo.find repository=\"todo\" user_id={} id=999",
+                    TEST_USER_ID
+                ),
+                "repo.create repository=\"label\" owner_id=None".to_string(),
+            ]
+        );
+
+        let metrics: Vec<(CompositeKey, DebugValue)> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| (key, value))
+            .collect();
+        let value = |key: CompositeKey| {
+            metrics
+                .iter()
+                .find(|(recorded, _)| *recorded == key)
+                .map(|(_, value)| value)
+                .unwrap_or_else(|| panic!("{:?} was not recorded", key))
+        };
+        let samples = |repository, operation| {
+            let key = key(
+                MetricKind::Histogram,
+                DURATION_METRIC,
+                &[("repository", repository), ("operation", operation)],
+            );
+            match value(key) {
+                DebugValue::Histogram(samples) => samples.len(),
+                value => panic!("unexpected value {:?}", value),
+            }
+        };
+        assert_eq!(samples("todo", "create"), 1);
+        assert_eq!(samples("todo", "find"), 2);
+        assert_eq!(samples("label", "create"), 1);
+
+        let errors = key(
+            MetricKind::Counter,
+            ERRORS_METRIC,
+            &[
+                ("repository", "todo"),
+                ("operation", "find"),
+                ("kind", "not_found"),
+            ],
+        );
+        assert_eq!(value(errors), &DebugValue::Counter(1));
+        assert_eq!(
+            metrics
+                .iter()
+                .filter(|(key, _)| key.key().name() == ERRORS_METRIC)
+                .count(),
+            1
+        );
+    }
+}
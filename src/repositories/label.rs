@@ -1,20 +1,57 @@
-use super::RepositoryError;
+use super::{like_pattern, with_timeout, RepositoryError, DEFAULT_QUERY_TIMEOUT};
 
+use async_graphql::SimpleObject;
 use axum::async_trait;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::{future::Future, time::Duration};
 
 #[async_trait]
 pub trait LabelRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
-    async fn create(&self, name: String) -> anyhow::Result<Label>;
-    async fn all(&self) -> anyhow::Result<Vec<Label>>;
-    async fn delete(&self, id: i32) -> anyhow::Result<()>;
+    /// owner_idがNoneなら全ユーザーで共有するlabelを作成する
+    async fn create(&self, owner_id: Option<i32>, name: String) -> anyhow::Result<Label>;
+    /// 共有labelとuser_idが所有するlabelのうち、queryに一致するものを返す
+    async fn all(&self, user_id: i32, query: LabelQuery) -> anyhow::Result<Vec<Label>>;
+    /// user_idのtodoに1つ以上付いているlabelを重複なくname順で返す
+    async fn used(&self, user_id: i32) -> anyhow::Result<Vec<Label>>;
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()>;
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, sqlx::FromRow)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, sqlx::FromRow, SimpleObject)]
 pub struct Label {
     pub id: i32,
     pub name: String,
+    /// 所有するユーザー. Noneなら共有label
+    #[serde(default)]
+    pub owner_id: Option<i32>,
+}
+
+/// GET /labels のクエリパラメータ
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct LabelQuery {
+    /// nameに含まれる文字列. 大文字小文字は区別しない
+    pub q: Option<String>,
+    /// 指定がなければname順
+    pub sort: Option<LabelSort>,
+}
+
+/// labelの並び順
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelSort {
+    Id,
+    #[default]
+    Name,
+}
+
+impl LabelSort {
+    // 問い合わせに渡す並び順の名前. 同名のlabelはidで順序を確定させる
+    fn key(&self) -> &'static str {
+        match self {
+            LabelSort::Id => "id",
+            LabelSort::Name => "name",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -25,74 +62,146 @@ pub struct UpdateLabel {
 
 #[derive(Debug, Clone)]
 pub struct LabelRepositoryForDb {
-    pool: PgPool,
+    write_pool: PgPool,
+    read_pool: Option<PgPool>,
+    query_timeout: Duration,
 }
 
 impl LabelRepositoryForDb {
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        Self {
+            write_pool: pool,
+            read_pool: None,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+        }
+    }
+
+    /// 一覧の取得をreplicaに向ける. 重複の確認を含め、書き込みはnewのpoolで行う
+    pub fn with_read_pool(self, read_pool: PgPool) -> Self {
+        Self {
+            read_pool: Some(read_pool),
+            ..self
+        }
+    }
+
+    pub fn with_query_timeout(self, query_timeout: Duration) -> Self {
+        Self {
+            query_timeout,
+            ..self
+        }
+    }
+
+    // DBへの問い合わせをquery_timeoutで打ち切る
+    async fn timed<T>(&self, future: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        with_timeout(self.query_timeout, future).await
+    }
+
+    // 読み取り用のpool. 設定されていなければ書き込みと同じpoolを使う
+    fn read_pool(&self) -> &PgPool {
+        self.read_pool.as_ref().unwrap_or(&self.write_pool)
     }
 }
 
 #[async_trait]
 impl LabelRepository for LabelRepositoryForDb {
-    async fn create(&self, name: String) -> anyhow::Result<Label> {
-        let optional_label = sqlx::query_as::<_, Label>(
-            r#"
-            select * from labels where name = $1
-            "#,
-        )
-        .bind(name.clone())
-        .fetch_optional(&self.pool)
-        .await?;
-
-        // labelはTodo1つにつき1つを想定するため重複は許さない
-        if let Some(label) = optional_label {
-            return Err(RepositoryError::Duplicate(label.id).into());
-        }
-
-        let label = sqlx::query_as::<_, Label>(
-            r#"
-            insert into labels ( name )
-            values ( $1 )
-            returning *
-            "#,
-        )
-        .bind(name.clone())
-        .fetch_one(&self.pool)
-        .await?;
-
-        Ok(label)
-    }
-
-    async fn all(&self) -> anyhow::Result<Vec<Label>> {
-        let labels = sqlx::query_as::<_, Label>(
-            r#"
-            select * from labels
-            order by labels.id asc;
-            "#,
-        )
-        .fetch_all(&self.pool)
-        .await?;
-
-        Ok(labels)
-    }
-
-    async fn delete(&self, id: i32) -> anyhow::Result<()> {
-        sqlx::query(
-            r#"
-            delete from labels where id=$1
-            "#,
-        )
-        .bind(id)
-        .execute(&self.pool)
+    async fn create(&self, owner_id: Option<i32>, name: String) -> anyhow::Result<Label> {
+        self.timed(async move {
+            let optional_label = checked_query_as!(
+                Label,
+                r#"
+                select * from labels where name = $1 and owner_id is not distinct from $2
+                "#,
+                name,
+                owner_id
+            )
+            .fetch_optional(&self.write_pool)
+            .await?;
+
+            // labelはTodo1つにつき1つを想定するため重複は許さない
+            if let Some(label) = optional_label {
+                return Err(RepositoryError::Duplicate(label.id).into());
+            }
+
+            let label = checked_query_as!(
+                Label,
+                r#"
+                insert into labels ( name, owner_id )
+                values ( $1, $2 )
+                returning *
+                "#,
+                name,
+                owner_id
+            )
+            .fetch_one(&self.write_pool)
+            .await?;
+
+            Ok(label)
+        })
+        .await
+    }
+
+    async fn all(&self, user_id: i32, query: LabelQuery) -> anyhow::Result<Vec<Label>> {
+        self.timed(async move {
+            let labels = checked_query_as!(
+                Label,
+                r#"
+                select * from labels
+                where (owner_id is null or owner_id = $1)
+                and ($2::text is null or name ilike $2)
+                order by case when $3::text = 'name' then name end asc, id asc
+                "#,
+                user_id,
+                query.q.as_deref().map(like_pattern),
+                query.sort.unwrap_or_default().key()
+            )
+            .fetch_all(self.read_pool())
+            .await?;
+
+            Ok(labels)
+        })
+        .await
+    }
+
+    async fn used(&self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        self.timed(async move {
+            let labels = checked_query_as!(
+                Label,
+                r#"
+                select distinct labels.* from labels
+                join todo_labels t1 on labels.id = t1.label_id
+                join todos on todos.id = t1.todo_id
+                where todos.user_id = $1 and (labels.owner_id is null or labels.owner_id = $1)
+                order by labels.name asc, labels.id asc
+                "#,
+                user_id
+            )
+            .fetch_all(self.read_pool())
+            .await?;
+
+            Ok(labels)
+        })
         .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.timed(async move {
+            checked_query!(
+                r#"
+                delete from labels where id=$1 and (owner_id is null or owner_id = $2)
+                "#,
+                id,
+                user_id
+            )
+            .execute(&self.write_pool)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => RepositoryError::NotFound(id).into(),
+                _ => anyhow::Error::from(e),
+            })?;
 
-        Ok(())
+            Ok(())
+        })
+        .await
     }
 }
 
@@ -100,107 +209,280 @@ impl LabelRepository for LabelRepositoryForDb {
 #[cfg(feature = "database-test")]
 mod test {
     use super::*;
-    use dotenv::dotenv;
-    use sqlx::PgPool;
-    use std::env;
+    use crate::repositories::test_database::db_pool_or_skip;
+    use crate::repositories::todo::{
+        test_utils::TEST_USER_ID, CreateTodo, TodoRepository, TodoRepositoryForDb,
+    };
 
     #[tokio::test]
     async fn crud_scenario() {
-        dotenv().ok();
-        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
-        let pool = PgPool::connect(database_url)
-            .await
-            .expect(&format!("fail connect database, url is [{}]", database_url));
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
 
         let repository = LabelRepositoryForDb::new(pool);
         let label_text = "test_label";
 
         // create
         let label = repository
-            .create(label_text.to_string())
+            .create(Some(TEST_USER_ID), label_text.to_string())
             .await
             .expect("[create] returned Err");
         assert_eq!(label.name, label_text);
+        assert_eq!(label.owner_id, Some(TEST_USER_ID));
 
         // all
-        let labels = repository.all().await.expect("[all] returned Err");
+        let labels = repository
+            .all(TEST_USER_ID, LabelQuery::default())
+            .await
+            .expect("[all] returned Err");
+        let names = labels.iter().map(|label| &label.name).collect::<Vec<_>>();
+        assert!(names.is_sorted(), "{:?}", names);
+        let labels = repository
+            .all(
+                TEST_USER_ID,
+                LabelQuery {
+                    sort: Some(LabelSort::Id),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("[all] returned Err");
         let label = labels.last().unwrap();
         assert_eq!(label.name, label_text);
+        let labels = repository
+            .all(
+                TEST_USER_ID,
+                LabelQuery {
+                    q: Some("TEST_LAB".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("[all] returned Err");
+        assert!(labels.iter().any(|other| other.id == label.id));
+        let labels = repository
+            .all(
+                TEST_USER_ID,
+                LabelQuery {
+                    q: Some("no such label".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("[all] returned Err");
+        assert!(labels.iter().all(|other| other.id != label.id));
+        let labels = repository
+            .all(TEST_USER_ID + 1, LabelQuery::default())
+            .await
+            .expect("[all] returned Err");
+        assert!(labels.iter().all(|other| other.id != label.id));
+
+        // used. todoに付くまでは含まれない
+        let used = repository
+            .used(TEST_USER_ID)
+            .await
+            .expect("[used] returned Err");
+        assert!(used.iter().all(|other| other.id != label.id));
+        let todos = TodoRepositoryForDb::new(repository.write_pool.clone());
+        let todo = todos
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[crud_scenario] labeled".to_string(), vec![label.id]),
+            )
+            .await
+            .expect("[create todo] returned Err");
+        let used = repository
+            .used(TEST_USER_ID)
+            .await
+            .expect("[used] returned Err");
+        assert_eq!(used.iter().filter(|other| other.id == label.id).count(), 1);
+        let names = used.iter().map(|label| &label.name).collect::<Vec<_>>();
+        assert!(names.is_sorted(), "{:?}", names);
+        todos
+            .delete(TEST_USER_ID, todo.id)
+            .await
+            .expect("[delete todo] returned Err");
 
         // delete
         repository
-            .delete(label.id)
+            .delete(TEST_USER_ID, label.id)
             .await
             .expect("[delete] returned Err");
     }
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "memory"))]
 pub mod test_utils {
+    use super::*;
+
+    impl Label {
+        pub fn new(id: i32, name: String) -> Self {
+            Self {
+                id,
+                name,
+                owner_id: None,
+            }
+        }
+
+        pub fn owned_by(self, owner_id: i32) -> Self {
+            Self {
+                owner_id: Some(owner_id),
+                ..self
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "memory"))]
+pub mod memory {
     use axum::async_trait;
     use std::{
-        collections::HashMap,
+        collections::{HashMap, HashSet},
         sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
     };
 
     use super::*;
 
     impl Label {
-        pub fn new(id: i32, name: String) -> Self {
-            Self { id, name }
+        // user_idがこのlabelを参照できるか(共有または本人所有)
+        pub fn is_visible_to(&self, user_id: i32) -> bool {
+            self.owner_id.is_none_or(|owner_id| owner_id == user_id)
         }
     }
 
     type LabelDatas = HashMap<i32, Label>;
 
-    #[derive(Debug, Clone)]
+    /// cloneしたものとlabelを共有する. TodoRepositoryForMemory::with_labelsに渡すと、
+    /// todoに付けるlabelもここから探す
+    #[derive(Debug, Clone, Default)]
     pub struct LabelRepositoryForMemory {
         store: Arc<RwLock<LabelDatas>>,
+        // todoを持たないため、todoに付いている(user_id, label_id)を別に記録する
+        attached: Arc<RwLock<HashSet<(i32, i32)>>>,
     }
 
     impl LabelRepositoryForMemory {
         pub fn new() -> Self {
-            LabelRepositoryForMemory {
-                store: Arc::default(),
-            }
+            Self::default()
+        }
+
+        /// labelsを持った状態から始める
+        pub fn with_labels(labels: Vec<Label>) -> Self {
+            let repository = Self::new();
+            repository
+                .write_store_ref()
+                .extend(labels.into_iter().map(|label| (label.id, label)));
+            repository
+        }
+
+        /// user_idが参照できるidのlabel
+        pub fn find_visible(&self, user_id: i32, id: i32) -> Option<Label> {
+            self.read_store_ref()
+                .get(&id)
+                .filter(|label| label.is_visible_to(user_id))
+                .cloned()
         }
 
-        fn write_store_ref(&self) -> RwLockWriteGuard<LabelDatas> {
+        /// user_idが参照できるlabelをid順に返す
+        pub fn visible(&self, user_id: i32) -> Vec<Label> {
+            let mut labels: Vec<Label> = self
+                .read_store_ref()
+                .values()
+                .filter(|label| label.is_visible_to(user_id))
+                .cloned()
+                .collect();
+            labels.sort_by_key(|label| label.id);
+            labels
+        }
+
+        /// user_idのtodoにlabel_idが付いているものとして記録する
+        pub fn attach(&self, user_id: i32, label_id: i32) {
+            self.attached.write().unwrap().insert((user_id, label_id));
+        }
+
+        fn write_store_ref(&self) -> RwLockWriteGuard<'_, LabelDatas> {
             self.store.write().unwrap()
         }
 
-        fn read_store_ref(&self) -> RwLockReadGuard<LabelDatas> {
+        fn read_store_ref(&self) -> RwLockReadGuard<'_, LabelDatas> {
             self.store.read().unwrap()
         }
     }
 
     #[async_trait]
     impl LabelRepository for LabelRepositoryForMemory {
-        async fn create(&self, name: String) -> anyhow::Result<Label> {
+        async fn create(&self, owner_id: Option<i32>, name: String) -> anyhow::Result<Label> {
             let mut store = self.write_store_ref();
             let id = (store.len() + 1) as i32;
-            let label = Label::new(id, name.clone());
+            let label = Label { id, name, owner_id };
             store.insert(id, label.clone());
             Ok(label)
         }
 
-        async fn all(&self) -> anyhow::Result<Vec<Label>> {
+        async fn all(&self, user_id: i32, query: LabelQuery) -> anyhow::Result<Vec<Label>> {
+            let store = self.read_store_ref();
+            let q = query.q.map(|q| q.to_lowercase());
+            let mut labels = Vec::from_iter(
+                store
+                    .values()
+                    .filter(|label| label.is_visible_to(user_id))
+                    .filter(|label| {
+                        q.as_ref()
+                            .is_none_or(|q| label.name.to_lowercase().contains(q.as_str()))
+                    })
+                    .cloned(),
+            );
+            // DBのorder by句と同じ順序にする
+            match query.sort.unwrap_or_default() {
+                LabelSort::Id => labels.sort_by_key(|label| label.id),
+                LabelSort::Name => labels.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id))),
+            }
+            Ok(labels)
+        }
+
+        async fn used(&self, user_id: i32) -> anyhow::Result<Vec<Label>> {
             let store = self.read_store_ref();
-            Ok(Vec::from_iter(store.values().map(|todo| todo.clone())))
+            let attached = self.attached.read().unwrap();
+            let mut labels: Vec<Label> = store
+                .values()
+                .filter(|label| label.is_visible_to(user_id))
+                .filter(|label| attached.contains(&(user_id, label.id)))
+                .cloned()
+                .collect();
+            labels.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+            Ok(labels)
         }
 
-        async fn delete(&self, id: i32) -> anyhow::Result<()> {
+        async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
             let mut store = self.write_store_ref();
-            store.remove(&id).ok_or(RepositoryError::NotFound(id))?;
+            if store
+                .get(&id)
+                .is_none_or(|label| !label.is_visible_to(user_id))
+            {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            store.remove(&id);
             Ok(())
         }
     }
 
+    #[cfg(test)]
     mod test {
         use std::vec;
 
-        use super::{LabelRepository, LabelRepositoryForMemory};
-        use crate::repositories::label::Label;
+        use super::{LabelQuery, LabelRepository, LabelRepositoryForMemory, LabelSort};
+        use crate::repositories::{label::Label, todo::test_utils::TEST_USER_ID};
+
+        #[tokio::test]
+        async fn default_repository_starts_empty() {
+            let repository = LabelRepositoryForMemory::default();
+            let labels = repository
+                .all(TEST_USER_ID, LabelQuery::default())
+                .await
+                .expect("failed get all label");
+            assert!(labels.is_empty());
+        }
 
         #[tokio::test]
         async fn label_crud_scenario() {
@@ -211,18 +493,135 @@ pub mod test_utils {
             // create
             let repository = LabelRepositoryForMemory::new();
             let label = repository
-                .create(text.clone())
+                .create(None, text.clone())
                 .await
                 .expect("failed label create");
             assert_eq!(expected, label);
 
             // all
-            let label = repository.all().await.unwrap();
+            let label = repository
+                .all(TEST_USER_ID, LabelQuery::default())
+                .await
+                .unwrap();
             assert_eq!(vec![expected], label);
 
             // delete
-            let res = repository.delete(id).await;
+            let res = repository.delete(TEST_USER_ID, id).await;
             assert!(res.is_ok());
         }
+
+        #[tokio::test]
+        async fn private_labels_are_only_visible_to_owner() {
+            let other_user_id = TEST_USER_ID + 1;
+            let repository = LabelRepositoryForMemory::new();
+            let shared = repository
+                .create(None, "shared".to_string())
+                .await
+                .expect("failed label create");
+            let private = repository
+                .create(Some(TEST_USER_ID), "private".to_string())
+                .await
+                .expect("failed label create");
+            assert_eq!(
+                private,
+                Label::new(2, "private".to_string()).owned_by(TEST_USER_ID)
+            );
+
+            let mut labels = repository
+                .all(TEST_USER_ID, LabelQuery::default())
+                .await
+                .unwrap();
+            labels.sort_by_key(|label| label.id);
+            assert_eq!(labels, vec![shared.clone(), private.clone()]);
+            let labels = repository
+                .all(other_user_id, LabelQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(labels, vec![shared]);
+
+            assert!(repository.delete(other_user_id, private.id).await.is_err());
+            assert!(repository.delete(TEST_USER_ID, private.id).await.is_ok());
+        }
+
+        #[tokio::test]
+        async fn used_returns_only_attached_labels_by_name() {
+            let repository = LabelRepositoryForMemory::new();
+            let mut created = vec![];
+            for name in ["work", "unused", "home"] {
+                created.push(
+                    repository
+                        .create(None, name.to_string())
+                        .await
+                        .expect("failed label create"),
+                );
+            }
+            let (work, home) = (created[0].clone(), created[2].clone());
+            repository.attach(TEST_USER_ID, work.id);
+            repository.attach(TEST_USER_ID, home.id);
+            // 同じlabelが複数のtodoに付いていても1回だけ返す
+            repository.attach(TEST_USER_ID, home.id);
+            repository.attach(TEST_USER_ID + 1, created[1].id);
+
+            let used = repository.used(TEST_USER_ID).await.unwrap();
+            assert_eq!(used, vec![home, work]);
+        }
+
+        #[tokio::test]
+        async fn searches_labels_by_name_ignoring_case() {
+            let repository = LabelRepositoryForMemory::new();
+            let work = repository
+                .create(None, "Work".to_string())
+                .await
+                .expect("failed label create");
+            let homework = repository
+                .create(Some(TEST_USER_ID), "homework".to_string())
+                .await
+                .expect("failed label create");
+            repository
+                .create(None, "private".to_string())
+                .await
+                .expect("failed label create");
+
+            let search = |q: &str| LabelQuery {
+                q: Some(q.to_string()),
+                ..Default::default()
+            };
+            let mut labels = repository.all(TEST_USER_ID, search("WORK")).await.unwrap();
+            labels.sort_by_key(|label| label.id);
+            assert_eq!(labels, vec![work, homework]);
+            let labels = repository.all(TEST_USER_ID, search("xyz")).await.unwrap();
+            assert!(labels.is_empty());
+            let labels = repository.all(TEST_USER_ID, search("")).await.unwrap();
+            assert_eq!(labels.len(), 3);
+        }
+
+        #[tokio::test]
+        async fn sorts_labels_by_name_unless_id_is_requested() {
+            let repository = LabelRepositoryForMemory::new();
+            for name in ["work", "home", "errand"] {
+                repository
+                    .create(None, name.to_string())
+                    .await
+                    .expect("failed label create");
+            }
+
+            let labels = repository
+                .all(TEST_USER_ID, LabelQuery::default())
+                .await
+                .unwrap();
+            let names = labels
+                .iter()
+                .map(|label| label.name.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec!["errand", "home", "work"]);
+
+            let query = LabelQuery {
+                sort: Some(LabelSort::Id),
+                ..Default::default()
+            };
+            let labels = repository.all(TEST_USER_ID, query).await.unwrap();
+            let ids = labels.iter().map(|label| label.id).collect::<Vec<_>>();
+            assert_eq!(ids, vec![1, 2, 3]);
+        }
     }
 }
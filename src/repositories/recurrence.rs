@@ -0,0 +1,140 @@
+use chrono::{Datelike, Days, Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+/// Todoの繰り返し設定. 完了時に次の期日で新しいTodoが作られる
+#[derive(
+    Debug,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    sqlx::Type,
+    async_graphql::Enum,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum Recurrence {
+    #[default]
+    None,
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Recurrence {
+    /// 期日がfromの回の次の期日を返す. 繰り返しなしの場合はNone
+    ///
+    /// Monthlyは最初の期日anchorからnか月後として求め、同じ日が存在しない月は月末に丸める.
+    /// 丸めた日から進めないので、1/31からの繰り返しは2/29の次が3/31になる
+    pub fn next_due_date(&self, anchor: NaiveDate, from: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Recurrence::None => None,
+            Recurrence::Daily => from.checked_add_days(Days::new(1)),
+            Recurrence::Weekly => from.checked_add_days(Days::new(7)),
+            Recurrence::Monthly => {
+                // fromがanchorより前の月なら、anchorの翌月にする
+                let months = (from.year() - anchor.year()) * 12 + from.month() as i32
+                    - anchor.month() as i32;
+                anchor.checked_add_months(Months::new(months.max(0) as u32 + 1))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    // anchorから始めた繰り返しのcount回分の期日
+    fn series(recurrence: Recurrence, anchor: NaiveDate, count: usize) -> Vec<NaiveDate> {
+        std::iter::successors(Some(anchor), |from| recurrence.next_due_date(anchor, *from))
+            .take(count)
+            .collect()
+    }
+
+    #[test]
+    fn next_due_date_advances_by_rule() {
+        let from = date(2024, 3, 15);
+        assert_eq!(Recurrence::None.next_due_date(from, from), None);
+        assert_eq!(
+            Recurrence::Daily.next_due_date(from, from),
+            Some(date(2024, 3, 16))
+        );
+        assert_eq!(
+            Recurrence::Weekly.next_due_date(from, from),
+            Some(date(2024, 3, 22))
+        );
+        assert_eq!(
+            Recurrence::Monthly.next_due_date(from, from),
+            Some(date(2024, 4, 15))
+        );
+    }
+
+    #[test]
+    fn next_due_date_crosses_year_boundary() {
+        let from = date(2023, 12, 31);
+        assert_eq!(
+            Recurrence::Daily.next_due_date(from, from),
+            Some(date(2024, 1, 1))
+        );
+        assert_eq!(
+            Recurrence::Monthly.next_due_date(from, from),
+            Some(date(2024, 1, 31))
+        );
+    }
+
+    #[test]
+    fn monthly_from_end_of_month_clamps_to_last_day() {
+        assert_eq!(
+            Recurrence::Monthly.next_due_date(date(2024, 1, 31), date(2024, 1, 31)),
+            Some(date(2024, 2, 29))
+        );
+        assert_eq!(
+            Recurrence::Monthly.next_due_date(date(2023, 1, 31), date(2023, 1, 31)),
+            Some(date(2023, 2, 28))
+        );
+        assert_eq!(
+            Recurrence::Monthly.next_due_date(date(2024, 3, 31), date(2024, 3, 31)),
+            Some(date(2024, 4, 30))
+        );
+    }
+
+    #[test]
+    fn monthly_series_does_not_drift_after_clamping() {
+        assert_eq!(
+            series(Recurrence::Monthly, date(2024, 1, 31), 6),
+            vec![
+                date(2024, 1, 31),
+                date(2024, 2, 29),
+                date(2024, 3, 31),
+                date(2024, 4, 30),
+                date(2024, 5, 31),
+                date(2024, 6, 30),
+            ]
+        );
+        assert_eq!(
+            series(Recurrence::Monthly, date(2023, 11, 30), 4),
+            vec![
+                date(2023, 11, 30),
+                date(2023, 12, 30),
+                date(2024, 1, 30),
+                date(2024, 2, 29),
+            ]
+        );
+    }
+
+    #[test]
+    fn monthly_before_anchor_starts_from_month_after_anchor() {
+        assert_eq!(
+            Recurrence::Monthly.next_due_date(date(2024, 3, 31), date(2024, 1, 10)),
+            Some(date(2024, 4, 30))
+        );
+    }
+}
@@ -0,0 +1,457 @@
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::{stream::BoxStream, StreamExt};
+use redis::{aio::ConnectionManager, Client, Cmd, FromRedisValue};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use super::{
+    activity::TodoEvent,
+    cache::CacheStats,
+    todo::{
+        BulkUpdateTodo, CreateTodo, DeletePreview, TimeseriesPoint, TimeseriesQuery, TodoEntity,
+        TodoQuery, TodoRepository, TodoStats, UpdateTodo,
+    },
+};
+
+/// 書き込みがあったユーザーのidを流すchannel
+pub const INVALIDATION_CHANNEL: &str = "todo-cache:invalidate";
+
+/// Redisへの1回の問い合わせの上限時間. 超えたらキャッシュを使わずinnerに問い合わせる
+const REDIS_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// 購読が切れてから再接続するまでの待ち時間
+const RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(1);
+
+// ユーザーの書き込みの度に増やすバージョン. キャッシュのキーに含め、古い結果は読まれないまま期限切れになる
+fn version_key(user_id: i32) -> String {
+    format!("todo-cache:{}:version", user_id)
+}
+
+fn entry_key(user_id: i32, version: u64, entry: &str) -> String {
+    format!("todo-cache:{}:{}:{}", user_id, version, entry)
+}
+
+#[derive(Debug, Default)]
+struct VersionStore {
+    versions: HashMap<i32, u64>,
+    // 捨てる度に増やす. Redisから読んでいる間に捨てられたバージョンを保存しない
+    generation: u64,
+}
+
+/// Redisから読んだバージョンの手元の控え. 購読している間だけ使い、
+/// 他のインスタンスからの通知で捨てる
+#[derive(Debug, Default)]
+struct LocalVersions {
+    store: RwLock<VersionStore>,
+    listening: AtomicBool,
+}
+
+impl LocalVersions {
+    fn get(&self, user_id: i32) -> Option<u64> {
+        if !self.listening.load(Ordering::Acquire) {
+            return None;
+        }
+        let store = self.store.read().expect("version store read lock error");
+        store.versions.get(&user_id).copied()
+    }
+
+    fn generation(&self) -> u64 {
+        self.store
+            .read()
+            .expect("version store read lock error")
+            .generation
+    }
+
+    fn insert(&self, user_id: i32, version: u64, generation: u64) {
+        let mut store = self.store.write().expect("version store write lock error");
+        if store.generation == generation {
+            store.versions.insert(user_id, version);
+        }
+    }
+
+    fn remove(&self, user_id: i32) {
+        let mut store = self.store.write().expect("version store write lock error");
+        store.generation += 1;
+        store.versions.remove(&user_id);
+    }
+
+    // 購読の開始と終了の間の通知は届かないため、控えを全て捨てる
+    fn set_listening(&self, listening: bool) {
+        let mut store = self.store.write().expect("version store write lock error");
+        store.generation += 1;
+        store.versions.clear();
+        self.listening.store(listening, Ordering::Release);
+    }
+}
+
+/// all, findの結果をRedisにttlの間保持するTodoRepositoryのdecorator. 複数のインスタンスで共有する.
+///
+/// 書き込みはユーザーのバージョンを上げてchannelに通知し、全てのインスタンスが古い結果を読まなくなる.
+/// Redisに繋がらなければログに残してinnerに問い合わせ、APIのレスポンスは妨げない
+#[derive(Clone)]
+pub struct RedisCachedTodoRepository<R> {
+    inner: R,
+    redis: ConnectionManager,
+    ttl: Duration,
+    versions: Arc<LocalVersions>,
+    stats: Arc<CacheStats>,
+}
+
+impl<R: TodoRepository> RedisCachedTodoRepository<R> {
+    pub fn new(inner: R, redis: ConnectionManager, ttl: Duration) -> Self {
+        Self {
+            inner,
+            redis,
+            ttl,
+            versions: Arc::default(),
+            stats: Arc::default(),
+        }
+    }
+
+    /// 命中と外れの回数. cloneしたdecoratorとも共有する
+    pub fn cache_stats(&self) -> Arc<CacheStats> {
+        self.stats.clone()
+    }
+
+    /// 他のインスタンスの書き込みの通知を購読するタスク. 切れたら再接続し、shutdownで止まる.
+    /// 購読していない間は、読み込みの度にRedisからバージョンを読む
+    pub fn invalidation_listener(
+        &self,
+        client: Client,
+        shutdown: watch::Receiver<bool>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        listen(client, self.versions.clone(), shutdown)
+    }
+
+    // 失敗や時間切れはログに残してNoneにする
+    async fn query<T: FromRedisValue>(&self, cmd: &Cmd) -> Option<T> {
+        let mut redis = self.redis.clone();
+        match tokio::time::timeout(REDIS_TIMEOUT, cmd.query_async::<_, T>(&mut redis)).await {
+            Ok(Ok(value)) => Some(value),
+            Ok(Err(e)) => {
+                tracing::warn!("bypassing redis cache: {}", e);
+                None
+            }
+            Err(_) => {
+                tracing::warn!("bypassing redis cache: no reply within {:?}", REDIS_TIMEOUT);
+                None
+            }
+        }
+    }
+
+    async fn version(&self, user_id: i32) -> Option<u64> {
+        if let Some(version) = self.versions.get(user_id) {
+            return Some(version);
+        }
+        let generation = self.versions.generation();
+        let version = self
+            .query::<Option<u64>>(redis::cmd("GET").arg(version_key(user_id)))
+            .await?
+            .unwrap_or(0);
+        self.versions.insert(user_id, version, generation);
+        Some(version)
+    }
+
+    // キャッシュにあれば返し、無ければloadの結果を保存する. Redisが使えなければloadの結果をそのまま返す
+    async fn cached<T, F>(&self, user_id: i32, entry: String, load: F) -> anyhow::Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        let Some(version) = self.version(user_id).await else {
+            return load.await;
+        };
+        let key = entry_key(user_id, version, &entry);
+        let cached = self
+            .query::<Option<String>>(redis::cmd("GET").arg(&key))
+            .await
+            .flatten();
+        if let Some(json) = cached {
+            match serde_json::from_str(&json) {
+                Ok(value) => {
+                    self.stats.record_hit();
+                    return Ok(value);
+                }
+                Err(e) => tracing::warn!("ignoring unreadable cache entry [{}]: {}", key, e),
+            }
+        }
+        self.stats.record_miss();
+        let value = load.await?;
+        let json = serde_json::to_string(&value)?;
+        self.query::<()>(
+            redis::cmd("SET")
+                .arg(&key)
+                .arg(json)
+                .arg("EX")
+                .arg(self.ttl.as_secs().max(1)),
+        )
+        .await;
+        Ok(value)
+    }
+
+    // 書き込みは失敗しても一部が反映されていることがあるため、結果に関わらず捨てる
+    async fn invalidate(&self, user_id: i32) {
+        self.versions.remove(user_id);
+        if self
+            .query::<u64>(redis::cmd("INCR").arg(version_key(user_id)))
+            .await
+            .is_some()
+        {
+            self.query::<i64>(redis::cmd("PUBLISH").arg(INVALIDATION_CHANNEL).arg(user_id))
+                .await;
+        }
+    }
+}
+
+#[async_trait]
+impl<R: TodoRepository> TodoRepository for RedisCachedTodoRepository<R> {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+        let result = self.inner.create(user_id, payload).await;
+        self.invalidate(user_id).await;
+        result
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEntity> {
+        let entry = format!("find:{}", id);
+        self.cached(user_id, entry, self.inner.find(user_id, id))
+            .await
+    }
+
+    async fn find_by_public_id(&self, user_id: i32, public_id: Uuid) -> anyhow::Result<TodoEntity> {
+        self.inner.find_by_public_id(user_id, public_id).await
+    }
+
+    async fn all(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<Vec<TodoEntity>> {
+        let entry = format!("all:{}", serde_json::to_string(&query)?);
+        self.cached(user_id, entry, self.inner.all(user_id, query))
+            .await
+    }
+
+    // exportは全件を1度だけ読むため、cacheを通さない
+    fn all_stream(
+        &self,
+        user_id: i32,
+        query: TodoQuery,
+    ) -> BoxStream<'static, anyhow::Result<TodoEntity>> {
+        self.inner.all_stream(user_id, query)
+    }
+
+    async fn count(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<i64> {
+        self.inner.count(user_id, query).await
+    }
+
+    async fn find_many(&self, user_id: i32, ids: &[i32]) -> anyhow::Result<Vec<TodoEntity>> {
+        self.inner.find_many(user_id, ids).await
+    }
+
+    async fn update(
+        &self,
+        user_id: i32,
+        id: i32,
+        payload: UpdateTodo,
+    ) -> anyhow::Result<TodoEntity> {
+        let result = self.inner.update(user_id, id, payload).await;
+        self.invalidate(user_id).await;
+        result
+    }
+
+    async fn update_where(
+        &self,
+        user_id: i32,
+        filter: TodoQuery,
+        set: BulkUpdateTodo,
+    ) -> anyhow::Result<Vec<TodoEntity>> {
+        let result = self.inner.update_where(user_id, filter, set).await;
+        self.invalidate(user_id).await;
+        result
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let result = self.inner.delete(user_id, id).await;
+        self.invalidate(user_id).await;
+        result
+    }
+
+    async fn preview_delete(&self, user_id: i32, id: i32) -> anyhow::Result<DeletePreview> {
+        self.inner.preview_delete(user_id, id).await
+    }
+
+    async fn subtasks(&self, user_id: i32, id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+        self.inner.subtasks(user_id, id).await
+    }
+
+    async fn restore(&self, user_id: i32, todo: TodoEntity) -> anyhow::Result<TodoEntity> {
+        let result = self.inner.restore(user_id, todo).await;
+        self.invalidate(user_id).await;
+        result
+    }
+
+    async fn undo(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEvent> {
+        let result = self.inner.undo(user_id, id).await;
+        self.invalidate(user_id).await;
+        result
+    }
+
+    // 通知済みにしたtodoの持ち主のバージョンだけを上げる
+    async fn claim_due_reminders(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<TodoEntity>> {
+        let todos = self.inner.claim_due_reminders(now).await?;
+        let owners: HashSet<i32> = todos.iter().map(|todo| todo.user_id).collect();
+        for user_id in owners {
+            self.invalidate(user_id).await;
+        }
+        Ok(todos)
+    }
+
+    async fn stats(&self, user_id: i32, today: NaiveDate) -> anyhow::Result<TodoStats> {
+        self.inner.stats(user_id, today).await
+    }
+
+    async fn timeseries(
+        &self,
+        user_id: i32,
+        query: TimeseriesQuery,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+        self.inner.timeseries(user_id, query).await
+    }
+}
+
+async fn listen(client: Client, versions: Arc<LocalVersions>, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        match subscribe(&client).await {
+            Ok(pubsub) => {
+                versions.set_listening(true);
+                let mut messages = pubsub.into_on_message();
+                loop {
+                    tokio::select! {
+                        message = messages.next() => match message {
+                            Some(message) => match message.get_payload::<i32>() {
+                                Ok(user_id) => versions.remove(user_id),
+                                Err(e) => tracing::warn!("ignoring cache invalidation: {}", e),
+                            },
+                            None => break,
+                        },
+                        _ = shutdown.changed() => return,
+                    }
+                }
+                tracing::warn!("cache invalidation channel closed, resubscribing");
+            }
+            Err(e) => tracing::warn!("failed to subscribe to cache invalidation: {}", e),
+        }
+        versions.set_listening(false);
+        tokio::select! {
+            _ = tokio::time::sleep(RESUBSCRIBE_BACKOFF) => {}
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+async fn subscribe(client: &Client) -> redis::RedisResult<redis::aio::PubSub> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(INVALIDATION_CHANNEL).await?;
+    Ok(pubsub)
+}
+
+#[cfg(test)]
+#[cfg(feature = "redis-test")]
+mod test {
+    use super::*;
+    use crate::repositories::todo::{memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID};
+    use dotenv::dotenv;
+    use std::env;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    // 同じDBを使う2つのインスタンスを、同じinnerを持つ2つのdecoratorで表す
+    async fn instances() -> (
+        TodoRepositoryForMemory,
+        Vec<RedisCachedTodoRepository<TodoRepositoryForMemory>>,
+        watch::Sender<bool>,
+    ) {
+        dotenv().ok();
+        let redis_url = env::var("REDIS_URL").expect("undefined [REDIS_URL]");
+        let client = Client::open(redis_url).expect("invalid [REDIS_URL]");
+        let inner = TodoRepositoryForMemory::for_test(vec![]);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut instances = vec![];
+        for _ in 0..2 {
+            let redis = ConnectionManager::new(client.clone())
+                .await
+                .expect("fail connect redis");
+            let instance = RedisCachedTodoRepository::new(inner.clone(), redis, TTL);
+            tokio::spawn(instance.invalidation_listener(client.clone(), shutdown_rx.clone()));
+            instances.push(instance);
+        }
+        // 以前のテストが残した結果を読まないようにする
+        instances[0].invalidate(TEST_USER_ID).await;
+        (inner, instances, shutdown_tx)
+    }
+
+    #[tokio::test]
+    async fn shares_cached_reads_and_invalidates_them_on_every_instance() {
+        let (inner, instances, _shutdown) = instances().await;
+        let (a, b) = (&instances[0], &instances[1]);
+        let todo = a
+            .create(TEST_USER_ID, CreateTodo::new("shared".to_string(), vec![]))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(
+            a.find(TEST_USER_ID, todo.id)
+                .await
+                .expect("[find] returned Err"),
+            todo
+        );
+
+        // innerを直接変更しても、別のインスタンスは共有した結果を返す
+        inner
+            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+            .await
+            .expect("[update] returned Err");
+        let cached = b
+            .find(TEST_USER_ID, todo.id)
+            .await
+            .expect("[find] returned Err");
+        assert!(!cached.completed);
+        assert_eq!(b.cache_stats().hits(), 1);
+
+        // 書き込んだインスタンス以外も新しい結果を返す
+        let updated = a
+            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(false))
+            .await
+            .expect("[update] returned Err");
+        let mut found = b
+            .find(TEST_USER_ID, todo.id)
+            .await
+            .expect("[find] returned Err");
+        for _ in 0..50 {
+            if found == updated {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            found = b
+                .find(TEST_USER_ID, todo.id)
+                .await
+                .expect("[find] returned Err");
+        }
+        assert_eq!(found, updated);
+
+        a.delete(TEST_USER_ID, todo.id)
+            .await
+            .expect("[delete] returned Err");
+        let all = b
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .expect("[all] returned Err");
+        assert!(all.is_empty());
+    }
+}
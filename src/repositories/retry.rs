@@ -0,0 +1,218 @@
+use std::{future::Future, time::Duration};
+
+/// 再試行までの待ち時間の上限
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// 一時的なDBエラーを再試行する方針
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// 最初の試行に加えて再試行する回数
+    pub max_retries: u32,
+    /// 1回目の再試行までの待ち時間. 以降は再試行ごとに2倍になり、MAX_BACKOFFで頭打ちになる
+    pub base_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            base_backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// attempt回目(0始まり)の再試行までの待ち時間
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        // 再試行回数が多いと2^attemptもDurationの積も溢れるので、飽和させてから上限で切る
+        let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+        self.base_backoff.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+
+    /// operationを実行し、一時的なエラーで失敗した場合はbackoffを挟んで再試行する
+    pub async fn run<T, F, Fut>(&self, mut operation: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match operation().await {
+                Err(e) if attempt < self.max_retries && is_transient(&e) => {
+                    let backoff = self.backoff(attempt);
+                    attempt += 1;
+                    tracing::warn!(
+                        "transient database error, retry {}/{} after {:?}: {}",
+                        attempt,
+                        self.max_retries,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// 再試行すれば成功する可能性があるエラーかを判定する.
+/// 接続断やシリアライズ失敗のみを対象とし、制約違反などは再試行しない
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut) => true,
+        Some(sqlx::Error::Database(e)) => e.code().is_some_and(|code| {
+            // 08: connection exception, 40001: serialization_failure,
+            // 40P01: deadlock_detected, 57P01: admin_shutdown
+            code.starts_with("08") || matches!(code.as_ref(), "40001" | "40P01" | "57P01")
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use std::{
+        borrow::Cow,
+        error::Error as StdError,
+        fmt, io,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    // SQLSTATEだけを持つDBエラー
+    #[derive(Debug)]
+    struct FakeDatabaseError(&'static str);
+
+    impl fmt::Display for FakeDatabaseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "fake database error {}", self.0)
+        }
+    }
+
+    impl StdError for FakeDatabaseError {}
+
+    impl DatabaseError for FakeDatabaseError {
+        fn message(&self) -> &str {
+            "fake database error"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn connection_reset() -> anyhow::Error {
+        sqlx::Error::Io(io::Error::from(io::ErrorKind::ConnectionReset)).into()
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            base_backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_error_until_success() {
+        let attempts = AtomicU32::new(0);
+        let res = policy()
+            .run(|| async {
+                // 1回目だけ接続が切れる
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err(connection_reset())
+                } else {
+                    Ok("done")
+                }
+            })
+            .await;
+
+        assert_eq!(res.unwrap(), "done");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let attempts = AtomicU32::new(0);
+        let res: anyhow::Result<()> = policy()
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(connection_reset())
+            })
+            .await;
+
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_constraint_violation() {
+        let attempts = AtomicU32::new(0);
+        let res: anyhow::Result<()> = policy()
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                // 23503: foreign_key_violation
+                Err(sqlx::Error::Database(Box::new(FakeDatabaseError("23503"))).into())
+            })
+            .await;
+
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_backoff() {
+        let policy = RetryPolicy {
+            max_retries: u32::MAX,
+            base_backoff: Duration::from_secs(1),
+        };
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(4), Duration::from_secs(16));
+        assert_eq!(policy.backoff(6), MAX_BACKOFF);
+        // 2^32とDurationの積が溢れる回数でもpanicしない
+        assert_eq!(policy.backoff(40), MAX_BACKOFF);
+        assert_eq!(policy.backoff(u32::MAX), MAX_BACKOFF);
+        let slow = RetryPolicy {
+            base_backoff: Duration::MAX,
+            ..policy
+        };
+        assert_eq!(slow.backoff(1), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn classifies_transient_errors() {
+        assert!(is_transient(&connection_reset()));
+        assert!(is_transient(&sqlx::Error::PoolTimedOut.into()));
+        for code in ["08006", "40001", "40P01", "57P01"] {
+            let error = sqlx::Error::Database(Box::new(FakeDatabaseError(code)));
+            assert!(is_transient(&error.into()), "{} should be transient", code);
+        }
+        for code in ["23505", "23503", "42P01"] {
+            let error = sqlx::Error::Database(Box::new(FakeDatabaseError(code)));
+            assert!(
+                !is_transient(&error.into()),
+                "{} should not be transient",
+                code
+            );
+        }
+        assert!(!is_transient(&sqlx::Error::RowNotFound.into()));
+        assert!(!is_transient(&anyhow::anyhow!("not a database error")));
+    }
+}
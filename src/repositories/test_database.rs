@@ -0,0 +1,192 @@
+use sqlx::{migrate::Migrator, PgPool};
+use std::{
+    env,
+    sync::{Arc, Weak},
+    thread,
+};
+use testcontainers_modules::{
+    postgres::Postgres,
+    testcontainers::{
+        core::error::Result as TestcontainersResult, runners::AsyncRunner, ContainerAsync, ImageExt,
+    },
+};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Dockerfileのdatabaseと同じバージョン
+const POSTGRES_TAG: &str = "13-alpine";
+
+// 実行中のテストが共有するコンテナ. 使っているテストが無くなった時点で削除される
+static CONTAINER: Mutex<Weak<ContainerAsync<Postgres>>> = Mutex::const_new(Weak::new());
+
+/// DBを使うテストの最初に呼び、接続先とそのpoolを返す.
+/// DATABASE_URLが無く、コンテナも起動できない(dockerが無い)時はスキップする旨を出してNoneを返すので、
+/// テストはそのまま終える. DATABASE_URLがあるのに繋がらない時は失敗させる
+pub async fn db_pool_or_skip() -> Option<(TestDatabase, PgPool)> {
+    let database = TestDatabase::start().await?;
+    let pool = database.pool().await;
+    Some((database, pool))
+}
+
+/// DBを使うテストの接続先. テストごとに専用のschemaを作ってmigrationsを適用し、dropで消す.
+/// 他のテストの行が見えないので、並列に実行しても結果が変わらない.
+/// DATABASE_URLが環境変数にあればそのDBを使い(CIなど)、無ければPostgresのコンテナを起動する.
+/// .envのDATABASE_URLは開発用のDBを指すため読まない
+pub struct TestDatabase {
+    // search_pathをschemaに向けたurl
+    url: String,
+    // schemaを消す時に使うurl
+    server_url: String,
+    schema: String,
+    _container: Option<Arc<ContainerAsync<Postgres>>>,
+}
+
+impl TestDatabase {
+    async fn start() -> Option<Self> {
+        let (server_url, container) = match env::var("DATABASE_URL") {
+            Ok(url) => (url, None),
+            Err(_) => {
+                let container = match shared_container().await {
+                    Ok(container) => container,
+                    Err(e) => {
+                        eprintln!(
+                            "skipping DB test: DATABASE_URL is unset and postgres container could not start ({})",
+                            e
+                        );
+                        return None;
+                    }
+                };
+                let host = container.get_host().await.expect("fail get container host");
+                let port = container
+                    .get_host_port_ipv4(5432)
+                    .await
+                    .expect("fail get container port");
+                (
+                    format!("postgres://postgres:postgres@{}:{}/postgres", host, port),
+                    Some(container),
+                )
+            }
+        };
+
+        let schema = format!("test_{}", Uuid::new_v4().simple());
+        let server = PgPool::connect(&server_url)
+            .await
+            .unwrap_or_else(|e| panic!("fail connect database, url is [{}]: {}", server_url, e));
+        sqlx::query(&format!("create schema {}", schema))
+            .execute(&server)
+            .await
+            .expect("fail create test schema");
+        server.close().await;
+
+        // gen_random_uuidなどpublicにある関数も使えるように後ろに残す
+        let separator = if server_url.contains('?') { '&' } else { '?' };
+        let database = Self {
+            url: format!(
+                "{}{}options[search_path]={},public",
+                server_url, separator, schema
+            ),
+            server_url,
+            schema,
+            _container: container,
+        };
+        MIGRATOR
+            .run(&database.pool().await)
+            .await
+            .expect("fail run migrations");
+        Some(database)
+    }
+
+    /// 専用のschemaを向く接続先. 独自のPgPoolOptionsで接続する時に使う
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// テストごとのruntimeで使うため、呼ぶ度に新しく接続する
+    pub async fn pool(&self) -> PgPool {
+        PgPool::connect(&self.url)
+            .await
+            .unwrap_or_else(|e| panic!("fail connect database, url is [{}]: {}", self.url, e))
+    }
+}
+
+// dropはasyncにできないので、別のthreadのruntimeでschemaを消して待つ.
+// コンテナはフィールドのdropで後から解放されるので、ここではまだ動いている
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let server_url = self.server_url.clone();
+        let schema = self.schema.clone();
+        let dropped = thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("fail build runtime")
+                .block_on(async {
+                    let server = PgPool::connect(&server_url).await?;
+                    sqlx::query(&format!("drop schema {} cascade", schema))
+                        .execute(&server)
+                        .await?;
+                    server.close().await;
+                    Ok::<_, sqlx::Error>(())
+                })
+        })
+        .join();
+        // テストが失敗してpanic中の時はさらにpanicせず、残ったschemaは諦める
+        match dropped {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!("fail drop test schema {}: {}", self.schema, e),
+            Err(_) => tracing::warn!("fail drop test schema {}", self.schema),
+        }
+    }
+}
+
+async fn shared_container() -> TestcontainersResult<Arc<ContainerAsync<Postgres>>> {
+    let mut shared = CONTAINER.lock().await;
+    if let Some(container) = shared.upgrade() {
+        return Ok(container);
+    }
+    let container = Arc::new(Postgres::default().with_tag(POSTGRES_TAG).start().await?);
+    *shared = Arc::downgrade(&container);
+    Ok(container)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn schemas_are_isolated_and_dropped() {
+        let Some((first, first_pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let Some((second, second_pool)) = db_pool_or_skip().await else {
+            return;
+        };
+
+        sqlx::query("insert into labels ( name ) values ( 'isolated' )")
+            .execute(&first_pool)
+            .await
+            .expect("fail insert label");
+        let count = |pool| async move {
+            sqlx::query_scalar::<_, i64>("select count(*) from labels")
+                .fetch_one(&pool)
+                .await
+                .expect("fail count labels")
+        };
+        assert_eq!(count(first_pool.clone()).await, 1);
+        assert_eq!(count(second_pool).await, 0);
+
+        let schema = first.schema.clone();
+        first_pool.close().await;
+        drop(first);
+        let exists = sqlx::query_scalar::<_, bool>(
+            "select exists(select 1 from information_schema.schemata where schema_name = $1)",
+        )
+        .bind(schema)
+        .fetch_one(&second.pool().await)
+        .await
+        .expect("fail find schema");
+        assert!(!exists);
+    }
+}
@@ -1,622 +1,6411 @@
+use async_graphql::{Enum, InputObject, MaybeUndefined, SimpleObject};
 use axum::async_trait;
-use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
-use validator::{self, Validate};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeDelta, Utc};
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use indexmap::IndexMap;
+use serde::{de::IntoDeserializer, Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
+use uuid::Uuid;
+use validator::{self, Validate, ValidationError};
 
-use super::{label::Label, RepositoryError};
+use std::{
+    env, fmt,
+    future::Future,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+use crate::audit::AuditEntry;
+
+use super::{
+    activity::{
+        history_with, insert_event, undo_target, ActivityRepository, NewTodoEvent, TodoDiff,
+        TodoEvent, TodoEventKind, TodoEventPayload,
+    },
+    ids,
+    label::Label,
+    like_pattern,
+    recurrence::Recurrence,
+    retry::RetryPolicy,
+    with_timeout, RepositoryError, DEFAULT_QUERY_TIMEOUT,
+};
+
+/// 実行時に選んだrepositoryをcreate_appなどに渡すための型
+pub type DynTodoRepository = Arc<dyn TodoRepository>;
+
+// dyn TodoRepositoryとして扱えるよう、Cloneは要求せずArcに包んだものを共有する
+#[async_trait]
+pub trait TodoRepository: std::marker::Send + std::marker::Sync + 'static {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<TodoEntity>;
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEntity>;
+    /// 公開idでtodoを取得する. 見つからなければPublicIdNotFound
+    async fn find_by_public_id(&self, user_id: i32, public_id: Uuid) -> anyhow::Result<TodoEntity>;
+    async fn all(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<Vec<TodoEntity>>;
+    /// allと同じtodoを同じ順に1件ずつ返す. 全件を読み込まずに書き出すexport向け.
+    /// streamはrepositoryを借用しないため、レスポンスの本文としてそのまま渡せる
+    fn all_stream(
+        &self,
+        user_id: i32,
+        query: TodoQuery,
+    ) -> BoxStream<'static, anyhow::Result<TodoEntity>>;
+    /// queryのlimit, offset, sortを除いた条件に当てはまるtodoの件数
+    async fn count(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<i64>;
+    /// allのページとcountの件数をまとめて返す
+    async fn page(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<TodoPage> {
+        let total = self.count(user_id, query.clone()).await?;
+        let todos = self.all(user_id, query).await?;
+        Ok(TodoPage { todos, total })
+    }
+    /// idsのtodoをidsの順に返す. 見つからないidは結果に含まれない
+    async fn find_many(&self, user_id: i32, ids: &[i32]) -> anyhow::Result<Vec<TodoEntity>>;
+    async fn update(
+        &self,
+        user_id: i32,
+        id: i32,
+        payload: UpdateTodo,
+    ) -> anyhow::Result<TodoEntity>;
+    /// filterに当てはまり、completedがsetと異なるtodoをまとめて変更し、変更したtodoをid順に返す.
+    /// filterのlimit, offset, sortは使わない. subtaskの完了や繰り返しの次回分の作成は行わない
+    async fn update_where(
+        &self,
+        user_id: i32,
+        filter: TodoQuery,
+        set: BulkUpdateTodo,
+    ) -> anyhow::Result<Vec<TodoEntity>>;
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()>;
+    /// idのtodoを削除した場合に消える・変わるものを、削除せずに返す. idのtodoが無ければNotFound
+    async fn preview_delete(&self, user_id: i32, id: i32) -> anyhow::Result<DeletePreview> {
+        let todo = self.find(user_id, id).await?;
+        let detached_subtasks = self.subtasks(user_id, id).await?.len() as u64;
+        Ok(DeletePreview {
+            todo_labels: todo.labels.len() as u64,
+            detached_subtasks,
+            todo,
+        })
+    }
+    /// idのtodoを親に持つtodoをid順に返す. idのtodoが無ければNotFound
+    async fn subtasks(&self, user_id: i32, id: i32) -> anyhow::Result<Vec<TodoEntity>>;
+    /// todoをtodo.idのまま指定した状態に戻す. 削除済みなら同じidで作り直す.
+    /// labelsはidだけを見て解決し直す
+    async fn restore(&self, user_id: i32, todo: TodoEntity) -> anyhow::Result<TodoEntity>;
+    /// idのtodoへの直前の変更を取り消し、取り消しの記録を返す.
+    /// 変更履歴を持たないrepositoryでは取り消せる変更が無いものとして扱う
+    async fn undo(&self, _user_id: i32, id: i32) -> anyhow::Result<TodoEvent> {
+        Err(RepositoryError::NothingToUndo(id).into())
+    }
+    /// remind_atを過ぎて未通知のtodoを通知済みにし、そのtodoを返す.
+    /// 同じtodoが2回返ることはない
+    async fn claim_due_reminders(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<TodoEntity>>;
+    /// user_idのtodoの件数を集計する. todayより前が期限の未完了todoをoverdueとする
+    async fn stats(&self, user_id: i32, today: NaiveDate) -> anyhow::Result<TodoStats>;
+    /// 期間内に作成・完了されたtodoの件数をbucketごとに返す. 件数の無いbucketも0件で含む
+    async fn timeseries(
+        &self,
+        user_id: i32,
+        query: TimeseriesQuery,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>>;
+}
 
 #[async_trait]
-pub trait TodoRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
-    async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity>;
-    async fn find(&self, id: i32) -> anyhow::Result<TodoEntity>;
-    async fn all(&self) -> anyhow::Result<Vec<TodoEntity>>;
-    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity>;
-    async fn delete(&self, id: i32) -> anyhow::Result<()>;
+impl<T: TodoRepository + ?Sized> TodoRepository for Arc<T> {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+        (**self).create(user_id, payload).await
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEntity> {
+        (**self).find(user_id, id).await
+    }
+
+    async fn find_by_public_id(&self, user_id: i32, public_id: Uuid) -> anyhow::Result<TodoEntity> {
+        (**self).find_by_public_id(user_id, public_id).await
+    }
+
+    async fn all(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<Vec<TodoEntity>> {
+        (**self).all(user_id, query).await
+    }
+
+    fn all_stream(
+        &self,
+        user_id: i32,
+        query: TodoQuery,
+    ) -> BoxStream<'static, anyhow::Result<TodoEntity>> {
+        (**self).all_stream(user_id, query)
+    }
+
+    async fn count(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<i64> {
+        (**self).count(user_id, query).await
+    }
+
+    async fn page(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<TodoPage> {
+        (**self).page(user_id, query).await
+    }
+
+    async fn find_many(&self, user_id: i32, ids: &[i32]) -> anyhow::Result<Vec<TodoEntity>> {
+        (**self).find_many(user_id, ids).await
+    }
+
+    async fn update(
+        &self,
+        user_id: i32,
+        id: i32,
+        payload: UpdateTodo,
+    ) -> anyhow::Result<TodoEntity> {
+        (**self).update(user_id, id, payload).await
+    }
+
+    async fn update_where(
+        &self,
+        user_id: i32,
+        filter: TodoQuery,
+        set: BulkUpdateTodo,
+    ) -> anyhow::Result<Vec<TodoEntity>> {
+        (**self).update_where(user_id, filter, set).await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        (**self).delete(user_id, id).await
+    }
+
+    async fn preview_delete(&self, user_id: i32, id: i32) -> anyhow::Result<DeletePreview> {
+        (**self).preview_delete(user_id, id).await
+    }
+
+    async fn subtasks(&self, user_id: i32, id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+        (**self).subtasks(user_id, id).await
+    }
+
+    async fn restore(&self, user_id: i32, todo: TodoEntity) -> anyhow::Result<TodoEntity> {
+        (**self).restore(user_id, todo).await
+    }
+
+    async fn undo(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEvent> {
+        (**self).undo(user_id, id).await
+    }
+
+    async fn claim_due_reminders(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<TodoEntity>> {
+        (**self).claim_due_reminders(now).await
+    }
+
+    async fn stats(&self, user_id: i32, today: NaiveDate) -> anyhow::Result<TodoStats> {
+        (**self).stats(user_id, today).await
+    }
+
+    async fn timeseries(
+        &self,
+        user_id: i32,
+        query: TimeseriesQuery,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+        (**self).timeseries(user_id, query).await
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow)]
 pub struct TodoWithLabelFromRow {
     id: i32,
+    public_id: Uuid,
+    user_id: i32,
     text: String,
     completed: bool,
+    due_date: Option<NaiveDate>,
+    recurrence: Recurrence,
+    recurrence_anchor: Option<NaiveDate>,
+    remind_at: Option<DateTime<Utc>>,
+    reminded_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    completed_at: Option<DateTime<Utc>>,
+    updated_at: DateTime<Utc>,
+    parent_id: Option<i32>,
     label_id: Option<i32>,
     label_name: Option<String>,
+    label_owner_id: Option<i32>,
+    /// pageの問い合わせでだけ選ぶ、limit, offsetを除いた条件に当てはまるtodoの件数
+    #[sqlx(default)]
+    total_count: Option<i64>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+// statsのうち、todosだけから数える件数
+#[derive(Debug, FromRow)]
+struct TodoCounts {
+    total: i64,
+    open: i64,
+    completed: i64,
+    overdue: i64,
+}
+
+// timeseriesの区間ごとの件数. bucketは区間の開始日
+#[derive(Debug, FromRow)]
+struct BucketCount {
+    bucket: NaiveDate,
+    count: i64,
+}
+
+// update_whereで変更したtodoのidと、変更前の完了日時
+#[derive(Debug, FromRow)]
+struct ChangedRow {
+    id: i32,
+    completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, SimpleObject)]
+#[graphql(name = "Todo")]
 pub struct TodoEntity {
     pub id: i32,
+    /// 外部に公開するid. 連番のidと違い、件数や他のtodoのidを推測できない.
+    /// 公開idを持たない以前の履歴やsnapshotは読み込み時に新しく割り当てる
+    #[serde(default = "Uuid::new_v4")]
+    pub public_id: Uuid,
+    pub user_id: i32,
     pub text: String,
     pub completed: bool,
     pub labels: Vec<Label>,
+    pub due_date: Option<NaiveDate>,
+    pub recurrence: Recurrence,
+    /// 繰り返しの最初の期日. 毎月の次回分はここからnか月後として求め、月末に丸めた日付からずれていかないようにする.
+    /// 期日か繰り返しを変更するとNoneに戻り、次に完了した時の期日が起点になる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[graphql(skip)]
+    pub recurrence_anchor: Option<NaiveDate>,
+    /// 時刻はtimestamptzで保存し、DBセッションのタイムゾーンに関わらずRFC3339(UTC, `Z`付き)で返す
+    pub remind_at: Option<DateTime<Utc>>,
+    pub reminded_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// 未完了->完了にした日時. 未完了に戻すとNoneになる
+    pub completed_at: Option<DateTime<Utc>>,
+    /// 最後に作成・変更された日時. updated_atを持たない以前の履歴やsnapshotは読み込み時の日時になる
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+    /// 親のtodo. 親が削除されるとNoneになる
+    pub parent_id: Option<i32>,
+    /// 繰り返しTodoを完了した更新でのみ設定される、新しく作られた次のTodo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_occurrence: Option<Box<TodoEntity>>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
-struct TodoFromRow {
-    id: i32,
-    text: String,
-    completed: bool,
+// todosをidsの順に並べる. idsに無いtodoは含めない
+fn in_requested_order(todos: Vec<TodoEntity>, ids: &[i32]) -> Vec<TodoEntity> {
+    ids.iter()
+        .filter_map(|id| todos.iter().find(|todo| todo.id == *id).cloned())
+        .collect()
 }
 
-fn fold_entities(rows: Vec<TodoWithLabelFromRow>) -> Vec<TodoEntity> {
-    let mut rows = rows.iter();
-    let mut accum: Vec<TodoEntity> = vec![];
-    'outer: while let Some(row) = rows.next() {
-        let mut todos = accum.iter_mut();
-        while let Some(todo) = todos.next() {
-            // idが一致=Todoに紐づくラベルが複数存在している
-            if todo.id == row.id {
-                todo.labels.push(Label {
-                    id: row.label_id.unwrap(),
-                    name: row.label_name.clone().unwrap(),
-                });
-                continue 'outer;
+impl TodoWithLabelFromRow {
+    // labelの列を取り出す. labelの無いtodoはlabelの列がnullの1行になる.
+    // idとnameの片方だけがある壊れた行はlabelを付けず、todoの列だけを使う
+    fn take_label(&mut self) -> Option<Label> {
+        match (self.label_id, self.label_name.take()) {
+            (Some(id), Some(name)) => Some(Label {
+                id,
+                name,
+                owner_id: self.label_owner_id,
+            }),
+            (None, None) => None,
+            (label_id, label_name) => {
+                tracing::warn!(
+                    todo_id = self.id,
+                    ?label_id,
+                    ?label_name,
+                    "skipped label of malformed todo row"
+                );
+                None
             }
         }
+    }
 
-        // Todoのidに一致がなかった時のみ到達、TodoEntityを作成
-        let labels = if row.label_id.is_some() {
-            vec![Label {
-                id: row.label_id.unwrap(),
-                name: row.label_name.clone().unwrap(),
-            }]
-        } else {
-            vec![]
-        };
+    // labelを除いたtodoの列
+    fn into_entity(self) -> TodoEntity {
+        TodoEntity {
+            id: self.id,
+            public_id: self.public_id,
+            user_id: self.user_id,
+            text: self.text,
+            completed: self.completed,
+            labels: vec![],
+            due_date: self.due_date,
+            recurrence: self.recurrence,
+            recurrence_anchor: self.recurrence_anchor,
+            remind_at: self.remind_at,
+            reminded_at: self.reminded_at,
+            created_at: self.created_at,
+            completed_at: self.completed_at,
+            updated_at: self.updated_at,
+            parent_id: self.parent_id,
+            next_occurrence: None,
+        }
+    }
+}
 
-        accum.push(TodoEntity {
-            id: row.id,
-            text: row.text.clone(),
-            completed: row.completed,
-            labels,
-        });
+/// labelと結合した行をtodoごとにまとめる. todoは最初に現れた行の順に並び、labelは行の順に入る
+pub fn fold_entities(rows: Vec<TodoWithLabelFromRow>) -> Vec<TodoEntity> {
+    let mut accum: IndexMap<i32, TodoEntity> = IndexMap::new();
+    for mut row in rows {
+        let label = row.take_label();
+        let todo = accum.entry(row.id).or_insert_with(|| row.into_entity());
+        todo.labels.extend(label);
     }
-    accum
+    accum.into_values().collect()
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
-pub struct CreateTodo {
-    #[validate(length(
-        min = 1,
-        max = 100,
-        message = "At least 1 character and less than 100 characters."
-    ))]
-    text: String,
-    labels: Vec<i32>,
+// fold_entitiesのstream版. 1つのtodoの行が続けて届く(order byがidで終わる)ことを前提に、
+// 次のtodoの行が届いた時点でまとめ終えたtodoを返す. 保持するのは組み立て中の1件だけ
+fn fold_stream<'a>(
+    rows: BoxStream<'a, Result<TodoWithLabelFromRow, sqlx::Error>>,
+) -> BoxStream<'a, anyhow::Result<TodoEntity>> {
+    stream::unfold(
+        (rows.fuse(), None::<TodoEntity>),
+        |(mut rows, mut pending)| async move {
+            loop {
+                match rows.next().await {
+                    Some(Ok(mut row)) => {
+                        let label = row.take_label();
+                        match pending.as_mut() {
+                            Some(todo) if todo.id == row.id => todo.labels.extend(label),
+                            _ => {
+                                let mut todo = row.into_entity();
+                                todo.labels.extend(label);
+                                if let Some(done) = pending.replace(todo) {
+                                    return Some((Ok(done), (rows, pending)));
+                                }
+                            }
+                        }
+                    }
+                    // 組み立て中のtodoは行が欠けている可能性があるため返さない
+                    Some(Err(e)) => return Some((Err(e.into()), (rows, None))),
+                    None => return pending.take().map(|todo| (Ok(todo), (rows, None))),
+                }
+            }
+        },
+    )
+    .boxed()
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
-pub struct UpdateTodo {
-    #[validate(length(
-        min = 1,
-        max = 100,
-        message = "At least 1 character and less than 100 characters."
-    ))]
-    text: Option<String>,
-    completed: Option<bool>,
-    labels: Option<Vec<i32>>,
+/// 一覧取得の並び順. 指定がない場合はrepositoryの既定(DEFAULT_TODO_ORDER, 未設定ならid desc)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum TodoSort {
+    IdAsc,
+    #[default]
+    IdDesc,
+    TextAsc,
+    TextDesc,
+    CreatedAsc,
+    CreatedDesc,
 }
 
-#[derive(Debug, Clone)]
-pub struct TodoRepositoryForDb {
-    pool: PgPool,
+impl TodoSort {
+    // クエリパラメータと同じ名前. 一覧のSQLはこれを受け取ってorder byを切り替える
+    fn key(&self) -> &'static str {
+        match self {
+            TodoSort::IdAsc => "id_asc",
+            TodoSort::IdDesc => "id_desc",
+            TodoSort::TextAsc => "text_asc",
+            TodoSort::TextDesc => "text_desc",
+            TodoSort::CreatedAsc => "created_asc",
+            TodoSort::CreatedDesc => "created_desc",
+        }
+    }
+
+    /// 環境変数DEFAULT_TODO_ORDERから既定の並び順を読む. 未設定ならid desc
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env::var("DEFAULT_TODO_ORDER") {
+            Ok(value) => value
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid [DEFAULT_TODO_ORDER]: {}", e)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
 }
 
-impl TodoRepositoryForDb {
-    pub fn new(pool: PgPool) -> Self {
-        TodoRepositoryForDb { pool }
+// クエリパラメータと同じ名前(id_desc等)で解釈する
+impl FromStr for TodoSort {
+    type Err = serde::de::value::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::deserialize(s.into_deserializer())
     }
 }
 
-#[async_trait]
-impl TodoRepository for TodoRepositoryForDb {
-    async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
-        let tx = self.pool.begin().await?;
-        let row = sqlx::query_as::<_, TodoFromRow>(
-            r#"
-            insert into todos (text, completed)
-            values ($1, false)
-            returning *
-            "#,
-        )
-        .bind(payload.text.clone()) // $1にCreateTodoのtextを渡す
-        .fetch_one(&self.pool) // query_asに渡した型のgenerics型を返す(Todo)
-        .await?;
+/// DELETE /todos/:id?dry_run=true のレスポンス
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DeletePreview {
+    /// 削除されるtodo
+    pub todo: TodoEntity,
+    /// 一緒に削除されるtodo_labelsの行数
+    pub todo_labels: u64,
+    /// 親の無いtodoになるsubtaskの数
+    pub detached_subtasks: u64,
+}
 
-        sqlx::query(
-            r#"
-            insert into todo_labels (todo_id, label_id)
-            select $1, id
-            from unnest($2) as t(id);
-            "#,
-        )
-        .bind(row.id)
-        .bind(payload.labels)
-        .execute(&self.pool)
-        .await?;
+/// 一覧の1ページと、limit, offsetを除いた条件に当てはまるtodoの件数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TodoPage {
+    pub todos: Vec<TodoEntity>,
+    pub total: i64,
+}
 
-        tx.commit().await?;
+/// GET /todos/stats のレスポンス
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct TodoStats {
+    pub total: i64,
+    pub open: i64,
+    pub completed: i64,
+    pub overdue: i64,
+    /// 参照できる全labelの内訳. todoが付いていないlabelも0件として含む
+    pub labels: Vec<LabelStats>,
+}
 
-        let todo = self.find(row.id).await?;
-        Ok(todo)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow)]
+pub struct LabelStats {
+    pub id: i32,
+    pub name: String,
+    pub total: i64,
+    pub open: i64,
+    pub completed: i64,
+}
+
+/// 時系列集計の1区間の長さ
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeseriesBucket {
+    #[default]
+    Day,
+    /// 月曜始まりの週
+    Week,
+}
+
+impl TimeseriesBucket {
+    // Postgresのdate_truncに渡す単位
+    fn date_trunc_field(&self) -> &'static str {
+        match self {
+            TimeseriesBucket::Day => "day",
+            TimeseriesBucket::Week => "week",
+        }
     }
 
-    async fn find(&self, id: i32) -> anyhow::Result<TodoEntity> {
-        let items = sqlx::query_as::<_, TodoWithLabelFromRow>(
-            r#"
-            select todos.*, labels.id as label_id, labels.name as label_name from todos
-            left outer join todo_labels t1 on todo.id = t1.todo_id
-            left outer join labels on labels.id = t1.label_id where todos.id=$1;
-            "#,
-        )
-        .bind(id)
-        .fetch_all(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+    // dateを含むbucketの開始日
+    fn truncate(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            TimeseriesBucket::Day => date,
+            TimeseriesBucket::Week => {
+                date - TimeDelta::days(date.weekday().num_days_from_monday() as i64)
+            }
+        }
+    }
+
+    fn step(&self) -> TimeDelta {
+        match self {
+            TimeseriesBucket::Day => TimeDelta::days(1),
+            TimeseriesBucket::Week => TimeDelta::weeks(1),
+        }
+    }
+}
 
-        let todos = fold_entities(items);
-        let todo = todos.first().ok_or(RepositoryError::NotFound(id))?;
+/// 1回の時系列集計で返すbucketの上限
+pub const MAX_TIMESERIES_BUCKETS: usize = 366;
 
-        Ok(todo.clone())
+/// GET /todos/stats/timeseries のクエリパラメータ. from, toはどちらも含む
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TimeseriesQuery {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    #[serde(default)]
+    pub bucket: TimeseriesBucket,
+}
+
+impl TimeseriesQuery {
+    /// 各bucketの開始日を返す. 範囲が逆転しているか広すぎる場合はInvalidQuery
+    pub fn bucket_starts(&self) -> anyhow::Result<Vec<NaiveDate>> {
+        if self.from > self.to {
+            return Err(
+                RepositoryError::InvalidQuery("from must not be after to".to_string()).into(),
+            );
+        }
+        let mut starts = vec![];
+        let mut start = self.bucket.truncate(self.from);
+        while start <= self.to {
+            if starts.len() == MAX_TIMESERIES_BUCKETS {
+                return Err(RepositoryError::InvalidQuery(format!(
+                    "range must not exceed {} buckets",
+                    MAX_TIMESERIES_BUCKETS
+                ))
+                .into());
+            }
+            starts.push(start);
+            start += self.bucket.step();
+        }
+        Ok(starts)
     }
 
-    async fn all(&self) -> anyhow::Result<Vec<TodoEntity>> {
-        let items = sqlx::query_as::<_, TodoWithLabelFromRow>(
-            r#"
-            select todos.*, labels.id as label_id, labels.name as label_name from todos
-            left outer join todo_labels t1 on todos.id = t1.todo_id
-            left outer join labels on labels.id = t1.label_id order by todos.id desc;
-            "#,
+    // 集計対象の期間 [最初のbucketの開始, 最後のbucketの終了) をUTCの日時で返す
+    fn bounds(&self, starts: &[NaiveDate]) -> (DateTime<Utc>, DateTime<Utc>) {
+        let first = starts.first().copied().unwrap_or(self.from);
+        let last = starts.last().copied().unwrap_or(self.to) + self.bucket.step();
+        (
+            first.and_time(NaiveTime::MIN).and_utc(),
+            last.and_time(NaiveTime::MIN).and_utc(),
         )
-        .fetch_all(&self.pool)
-        .await?;
-
-        Ok(fold_entities(items))
     }
 
-    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity> {
-        let tx = self.pool.begin().await?;
+    // bucketごとの件数を0件のbucketを補って並べる
+    fn fill(
+        &self,
+        starts: Vec<NaiveDate>,
+        created: &[(NaiveDate, i64)],
+        completed: &[(NaiveDate, i64)],
+    ) -> Vec<TimeseriesPoint> {
+        let count_at = |counts: &[(NaiveDate, i64)], date: NaiveDate| {
+            counts
+                .iter()
+                .filter(|(bucket, _)| *bucket == date)
+                .map(|(_, count)| count)
+                .sum()
+        };
+        starts
+            .into_iter()
+            .map(|date| TimeseriesPoint {
+                date,
+                created: count_at(created, date),
+                completed: count_at(completed, date),
+            })
+            .collect()
+    }
+}
 
-        // todo update
-        let old_todo = self.find(id).await?;
-        sqlx::query(
-            r#"
-            update todos set text=$1, completed=$2
-            where id=$3
-            returning *
-            "#,
-        )
-        .bind(payload.text.unwrap_or(old_todo.text))
-        .bind(payload.completed.unwrap_or(old_todo.completed))
-        .bind(id)
-        .fetch_one(&self.pool)
-        .await?;
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TimeseriesPoint {
+    /// bucketの開始日
+    pub date: NaiveDate,
+    pub created: i64,
+    pub completed: i64,
+}
 
-        if let Some(labels) = payload.labels {
-            // todo's label update
-            // 一度関連するレコードを削除
-            sqlx::query(
-                r#"
-                delete from todo_labels where todo_id=$1
-                "#,
-            )
-            .bind(id)
-            .execute(&self.pool)
-            .await?;
+/// GET /todos/report/completions のクエリパラメータ. from, toはどちらも含む
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct CompletionReportQuery {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
 
-            sqlx::query(
-                r#"
-                insert into todo_labels (todo_id, label_id)
-                select $1, id
-                from unnest($2) as t(id)
-                "#,
-            )
-            .bind(id)
-            .bind(labels)
-            .execute(&self.pool)
-            .await?;
-        };
+// 日ごとの時系列集計として求め、範囲の検証もそちらに任せる
+impl From<CompletionReportQuery> for TimeseriesQuery {
+    fn from(query: CompletionReportQuery) -> Self {
+        TimeseriesQuery {
+            from: query.from,
+            to: query.to,
+            bucket: TimeseriesBucket::Day,
+        }
+    }
+}
 
-        tx.commit().await?;
-        let todo = self.find(id).await?;
+/// 1日に完了したtodoの件数
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CompletionCount {
+    pub date: NaiveDate,
+    pub count: i64,
+}
 
-        Ok(todo)
+impl From<TimeseriesPoint> for CompletionCount {
+    fn from(point: TimeseriesPoint) -> Self {
+        CompletionCount {
+            date: point.date,
+            count: point.completed,
+        }
     }
+}
 
-    async fn delete(&self, id: i32) -> anyhow::Result<()> {
-        let tx = self.pool.begin().await?;
-        // todo's label delete
-        sqlx::query(
-            r#"
-            delete from todo_labels where todo_id=$1
-            "#,
-        )
-        .bind(id)
-        .execute(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
-        // todo delete
-        sqlx::query(
-            r#"
-            delete from todos where id=$1
-            "#,
-        )
-        .bind(id)
-        .execute(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+/// GET /todos のクエリパラメータ. 指定されたフィルタは全てAND条件で組み合わされる
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, Hash, InputObject)]
+#[graphql(name = "TodoFilter")]
+pub struct TodoQuery {
+    pub completed: Option<bool>,
+    pub label: Option<i32>,
+    pub q: Option<String>,
+    /// この日時以降に作成されたtodo(この日時を含む)
+    pub created_from: Option<DateTime<Utc>>,
+    /// この日時までに作成されたtodo(この日時を含む)
+    pub created_to: Option<DateTime<Utc>>,
+    pub sort: Option<TodoSort>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
 
-        tx.commit().await?;
+impl TodoQuery {
+    /// completed, label, q, 作成日時のいずれかで絞り込んでいるか. limit, offset, sortは絞り込みに数えない
+    pub fn has_filter(&self) -> bool {
+        self.completed.is_some()
+            || self.label.is_some()
+            || self.q.is_some()
+            || self.created_from.is_some()
+            || self.created_to.is_some()
+    }
 
+    /// 作成日時の範囲が逆転していればInvalidQuery
+    pub fn ensure_valid(&self) -> anyhow::Result<()> {
+        if let (Some(from), Some(to)) = (self.created_from, self.created_to) {
+            if from > to {
+                return Err(RepositoryError::InvalidQuery(
+                    "created_from must not be after created_to".to_string(),
+                )
+                .into());
+            }
+        }
         Ok(())
     }
 }
 
-#[cfg(test)]
-#[cfg(feature = "database-test")]
-mod test {
-    use super::*;
-    use dotenv::dotenv;
-    use sqlx::PgPool;
-    use std::env;
+/// todoのtextの最大文字数の既定値
+pub const DEFAULT_MAX_TODO_TEXT_LEN: usize = 100;
 
-    #[test]
-    fn fold_entities_test() {
-        let label_1 = Label {
-            id: 1,
-            name: String::from("label 1"),
-        };
-        let label_2 = Label {
-            id: 2,
-            name: String::from("label 2"),
-        };
-        let rows = vec![
-            TodoWithLabelFromRow {
-                id: 1,
-                text: String::from("todo 1"),
-                completed: false,
-                label_id: Some(label_1.id),
-                label_name: Some(label_1.name.clone()),
-            },
-            TodoWithLabelFromRow {
-                id: 1,
-                text: String::from("todo 1"),
-                completed: false,
-                label_id: Some(label_2.id),
-                label_name: Some(label_2.name.clone()),
-            },
-            TodoWithLabelFromRow {
-                id: 2,
-                text: String::from("todo 2"),
-                completed: false,
-                label_id: Some(label_1.id),
-                label_name: Some(label_1.name.clone()),
-            },
-        ];
-        let res = fold_entities(rows);
-        assert_eq!(
-            res,
-            vec![
-                TodoEntity {
-                    id: 1,
-                    text: String::from("todo 1"),
-                    completed: false,
-                    labels: vec![label_1.clone(), label_2.clone()],
-                },
-                TodoEntity {
-                    id: 2,
-                    text: String::from("todo 2"),
-                    completed: false,
-                    labels: vec![label_1.clone()],
-                },
-            ]
-        );
-    }
+// 起動時にMAX_TODO_TEXT_LENから読んだ最大文字数. 読んでいなければ既定値を使う
+static MAX_TODO_TEXT_LEN: OnceLock<usize> = OnceLock::new();
 
-    #[tokio::test]
-    async fn crud_scenario() {
-        // 環境変数の取得やdbとのconnection作成
-        dotenv().ok();
-        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
-        let pool = PgPool::connect(database_url)
-            .await
-            .expect(&format!("fail connect database, url is [{}]", database_url));
+/// 環境変数MAX_TODO_TEXT_LENからtodoのtextの最大文字数を読み、以降の検証に使う.
+/// 起動時に1度だけ呼ぶ. 2度目以降は最初に読んだ値を返す
+pub fn init_max_todo_text_len() -> anyhow::Result<usize> {
+    let max = parse_max_todo_text_len(env::var("MAX_TODO_TEXT_LEN").ok().as_deref())?;
+    Ok(*MAX_TODO_TEXT_LEN.get_or_init(|| max))
+}
 
-        // label data prepare
-        let label_name = String::from("test label");
-        let optional_label = sqlx::query_as::<_, Label>(
-            r#"
-            select * from labels where name = $1
-            "#,
-        )
-        .bind(label_name.clone())
-        .fetch_optional(&pool)
-        .await
-        .expect("Failed to prepare label data.");
-        let label_1 = if let Some(label) = optional_label {
-            label
-        } else {
-            let label = sqlx::query_as::<_, Label>(
-                r#"
-                insert into labels ( name )
-                values ( $1 )
-                returning *
-                "#,
-            )
-            .bind(label_name)
-            .fetch_one(&pool)
-            .await
-            .expect("Failed to insert label data.");
-            label
-        };
+/// todoのtextの最大文字数. init_max_todo_text_lenを呼んでいなければ100
+pub fn max_todo_text_len() -> usize {
+    MAX_TODO_TEXT_LEN
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_MAX_TODO_TEXT_LEN)
+}
 
-        let repository = TodoRepositoryForDb::new(pool.clone());
-        let todo_text = "[crud_scenario] text";
+// MAX_TODO_TEXT_LENの値を読む. 未設定なら既定値
+fn parse_max_todo_text_len(value: Option<&str>) -> anyhow::Result<usize> {
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid [MAX_TODO_TEXT_LEN]: {}", e)),
+        None => Ok(DEFAULT_MAX_TODO_TEXT_LEN),
+    }
+}
 
-        // create
-        let created = repository
-            .create(CreateTodo::new(todo_text.to_string(), vec![label_1.id]))
-            .await
-            .expect("[create] returned Err");
-        assert_eq!(created.text, todo_text); // 用意したtextでTodoが作成されているか確認
-        assert!(!created.completed); // completedがfalseでなければassert
-        assert_eq!(*created.labels.first().unwrap(), label_1);
+// textが1文字以上、最大文字数以下か. deriveの属性では設定を渡せないため関数で検証する
+fn validate_todo_text(text: &str) -> Result<(), ValidationError> {
+    validate_todo_text_len(text, max_todo_text_len())
+}
 
-        // find
-        let todo = repository
-            .find(created.id)
-            .await
-            .expect("[find] returned Err");
-        assert_eq!(created, todo); // createで作ったTodoが取得できるか確認
+fn validate_todo_text_len(text: &str, max: usize) -> Result<(), ValidationError> {
+    if (1..=max).contains(&text.chars().count()) {
+        Ok(())
+    } else {
+        let message = format!("At least 1 character and less than {} characters.", max);
+        Err(ValidationError::new("length").with_message(message.into()))
+    }
+}
 
-        // all
-        let todos = repository.all().await.expect("[all] returned Err");
-        let todo = todos.first().unwrap();
-        assert_eq!(created, *todo);
+// 更新で省略したtextは検証しない
+fn validate_optional_todo_text(text: &Option<String>) -> Result<(), ValidationError> {
+    text.as_deref().map_or(Ok(()), validate_todo_text)
+}
 
-        // update
-        let updated_text = "[crud_scenario] updated text";
-        let todo = repository
-            .update(
-                todo.id,
-                UpdateTodo {
-                    text: Some(updated_text.to_string()),
-                    completed: Some(true),
-                    labels: Some(vec![]),
-                },
-            )
-            .await
-            .expect("[update] returned Err");
-        assert_eq!(created.id, todo.id);
-        assert_eq!(todo.text, updated_text);
-        assert!(todo.labels.len() == 0);
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate, InputObject)]
+#[graphql(name = "CreateTodoInput")]
+#[serde(deny_unknown_fields)]
+pub struct CreateTodo {
+    #[validate(custom(function = "validate_todo_text"))]
+    text: String,
+    #[graphql(default)]
+    #[serde(deserialize_with = "ids::ids")]
+    labels: Vec<i32>,
+    due_date: Option<NaiveDate>,
+    #[serde(default)]
+    #[graphql(default)]
+    recurrence: Recurrence,
+    /// 繰り返しの次回分を作る時だけ引き継ぐ. APIからは指定できない
+    #[serde(skip)]
+    #[graphql(skip)]
+    recurrence_anchor: Option<NaiveDate>,
+    remind_at: Option<DateTime<Utc>>,
+    /// 指定するとこのtodoのsubtaskとして作る
+    #[serde(default, deserialize_with = "ids::optional_id")]
+    parent_id: Option<i32>,
+}
+
+/// JSON Merge Patch(RFC 7386)として扱う. 省略した項目は変更せず、
+/// due_date, remind_at, parent_idはnullで消せる. 消せない項目のnullは省略と同じ
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate, InputObject)]
+#[graphql(name = "UpdateTodoInput")]
+#[serde(deny_unknown_fields)]
+pub struct UpdateTodo {
+    #[validate(custom(function = "validate_optional_todo_text"))]
+    text: Option<String>,
+    completed: Option<bool>,
+    #[serde(default, deserialize_with = "ids::optional_ids")]
+    labels: Option<Vec<i32>>,
+    #[serde(default, skip_serializing_if = "MaybeUndefined::is_undefined")]
+    due_date: MaybeUndefined<NaiveDate>,
+    recurrence: Option<Recurrence>,
+    /// 変更するとreminded_atがリセットされ、再度通知される
+    #[serde(default, skip_serializing_if = "MaybeUndefined::is_undefined")]
+    remind_at: MaybeUndefined<DateTime<Utc>>,
+    /// 自分自身や自分のsubtaskは親にできない. nullなら親から外す
+    #[serde(
+        default,
+        deserialize_with = "ids::maybe_id",
+        skip_serializing_if = "MaybeUndefined::is_undefined"
+    )]
+    parent_id: MaybeUndefined<i32>,
+    /// trueなら完了にした時に未完了のsubtask(子孫を含む)も完了にする
+    complete_subtasks: Option<bool>,
+}
+
+/// PATCH /todos で絞り込んだtodoにまとめて設定する値
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct BulkUpdateTodo {
+    /// 完了にすると完了日時を変更した日時にし、未完了に戻すとNoneにする
+    pub completed: bool,
+}
+
+impl CreateTodo {
+    /// 期日・繰り返し・リマインドを指定しないtodo
+    pub fn new(text: String, labels: Vec<i32>) -> Self {
+        Self {
+            text,
+            labels,
+            due_date: None,
+            recurrence: Recurrence::None,
+            recurrence_anchor: None,
+            remind_at: None,
+            parent_id: None,
+        }
+    }
+}
+
+impl UpdateTodo {
+    /// text, completed, labelsを変更する. Noneの項目は変更しない
+    pub fn new(text: Option<String>, completed: Option<bool>, labels: Option<Vec<i32>>) -> Self {
+        Self {
+            text,
+            completed,
+            labels,
+            due_date: MaybeUndefined::Undefined,
+            recurrence: None,
+            remind_at: MaybeUndefined::Undefined,
+            parent_id: MaybeUndefined::Undefined,
+            complete_subtasks: None,
+        }
+    }
+
+    /// 完了状態だけを変更する
+    pub fn completion(completed: bool) -> Self {
+        Self::new(None, Some(completed), None)
+    }
+}
+
+impl TodoEntity {
+    // payloadで指定された項目だけを置き換えたtodoを返す(labelsは呼び出し側で解決する).
+    // nowは完了日時の記録に使う
+    fn apply(&self, payload: &UpdateTodo, now: DateTime<Utc>) -> TodoEntity {
+        let remind_at = merge(payload.remind_at, self.remind_at);
+        let completed = payload.completed.unwrap_or(self.completed);
+        let due_date = merge(payload.due_date, self.due_date);
+        let recurrence = payload.recurrence.unwrap_or(self.recurrence);
+        TodoEntity {
+            text: payload.text.clone().unwrap_or(self.text.clone()),
+            completed,
+            completed_at: match (self.completed, completed) {
+                (false, true) => Some(now),
+                (_, false) => None,
+                (true, true) => self.completed_at,
+            },
+            due_date,
+            recurrence,
+            // 期日や繰り返しを変えたら、変えた後の期日から数え直す
+            recurrence_anchor: if due_date == self.due_date && recurrence == self.recurrence {
+                self.recurrence_anchor
+            } else {
+                None
+            },
+            parent_id: merge(payload.parent_id, self.parent_id),
+            updated_at: now,
+            remind_at,
+            // リマインド日時が変わったら再度通知する
+            reminded_at: if remind_at == self.remind_at {
+                self.reminded_at
+            } else {
+                None
+            },
+            ..self.clone()
+        }
+    }
+
+    // 繰り返しTodoが未完了->完了になった時に作る次回分.
+    // 期日とリマインド日時は同じ間隔だけ進め、親と繰り返しの起点は引き継ぐ
+    fn next_occurrence_on(&self, updated: &TodoEntity, labels: Vec<i32>) -> Option<CreateTodo> {
+        if self.completed || !updated.completed {
+            return None;
+        }
+        // 期日が無ければ完了した日から数える
+        let base = updated
+            .due_date
+            .or(updated.completed_at.map(|at| at.date_naive()))?;
+        let anchor = updated.recurrence_anchor.unwrap_or(base);
+        let due_date = updated.recurrence.next_due_date(anchor, base)?;
+        Some(CreateTodo {
+            text: updated.text.clone(),
+            labels,
+            due_date: Some(due_date),
+            recurrence: updated.recurrence,
+            recurrence_anchor: Some(anchor),
+            remind_at: updated
+                .remind_at
+                .map(|remind_at| remind_at + (due_date - base)),
+            parent_id: updated.parent_id,
+        })
+    }
+}
+
+// Merge Patchの1項目を現在の値に適用する. Undefinedなら現在の値のまま、Nullなら消す
+fn merge<T>(patch: MaybeUndefined<T>, current: Option<T>) -> Option<T> {
+    match patch {
+        MaybeUndefined::Undefined => current,
+        patch => patch.take(),
+    }
+}
+
+impl UpdateTodo {
+    // subtaskも完了にする更新か
+    fn completes_subtasks(&self, updated: &TodoEntity) -> bool {
+        updated.completed && self.complete_subtasks == Some(true)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TodoRepositoryForDb {
+    write_pool: PgPool,
+    read_pool: Option<PgPool>,
+    query_timeout: Duration,
+    retry_policy: RetryPolicy,
+    default_sort: TodoSort,
+    activity: Option<ActivityLog>,
+}
+
+// 変更と同じトランザクションでtodo_eventsに書き込んだ記録を、commitした後に知らせる先
+#[derive(Clone)]
+struct ActivityLog(Arc<dyn Fn(&TodoEvent) + std::marker::Send + std::marker::Sync>);
+
+impl fmt::Debug for ActivityLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ActivityLog")
+    }
+}
+
+// 変更が確定した後に知らせるもの. eventsは記録する設定の時だけ書き込まれる
+#[derive(Debug, Default)]
+struct Logged {
+    audit: Vec<AuditEntry>,
+    events: Vec<TodoEvent>,
+}
+
+impl TodoRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        TodoRepositoryForDb {
+            write_pool: pool,
+            read_pool: None,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            retry_policy: RetryPolicy::default(),
+            default_sort: TodoSort::default(),
+            activity: None,
+        }
+    }
+
+    pub fn with_query_timeout(self, query_timeout: Duration) -> Self {
+        Self {
+            query_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Self {
+            retry_policy,
+            ..self
+        }
+    }
+
+    /// 読み取り(find, allなど)をreplicaに向ける. 書き込みと、書き込み直後の取得はnewのpoolで行う
+    pub fn with_read_pool(self, read_pool: PgPool) -> Self {
+        Self {
+            read_pool: Some(read_pool),
+            ..self
+        }
+    }
+
+    /// sortが指定されていない一覧取得の並び順
+    pub fn with_default_sort(self, default_sort: TodoSort) -> Self {
+        Self {
+            default_sort,
+            ..self
+        }
+    }
+
+    /// 作成・更新・削除をtodo_eventsにも記録し、undoで取り消せるようにする.
+    /// 記録は変更と同じトランザクションで書き込み、commitした後にactivityの購読者へ知らせる.
+    /// activityは同じDBのActivityRepositoryForDb(をpublishで包んだもの)を渡す
+    pub fn with_activity<A: ActivityRepository>(self, activity: A) -> Self {
+        Self {
+            activity: Some(ActivityLog(Arc::new(move |event| activity.publish(event)))),
+            ..self
+        }
+    }
+
+    // eventsを監査ログに残すものとし、記録する設定なら変更と同じトランザクションでtodo_eventsに書き込む
+    async fn log_with(
+        &self,
+        conn: &mut PgConnection,
+        events: impl IntoIterator<Item = NewTodoEvent>,
+    ) -> anyhow::Result<Logged> {
+        let mut logged = Logged::default();
+        for event in events {
+            logged.audit.push(AuditEntry::from_event(&event));
+            if self.activity.is_some() {
+                logged.events.push(insert_event(&mut *conn, &event).await?);
+            }
+        }
+        Ok(logged)
+    }
+
+    // commitした後に、log_withの変更を監査ログに残し、書き込んだ記録を知らせる
+    fn publish(&self, logged: &Logged) {
+        logged.audit.iter().for_each(AuditEntry::emit);
+        if let Some(ActivityLog(publish)) = &self.activity {
+            logged.events.iter().for_each(|event| publish(event));
+        }
+    }
+
+    // 読み取りをquery_timeoutで打ち切り、一時的なエラーならretry_policyに従って再試行する
+    async fn read<T, F, Fut>(&self, mut operation: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        self.retry_policy
+            .run(|| with_timeout(self.query_timeout, operation()))
+            .await
+    }
+
+    // 書き込みはquery_timeoutで打ち切るだけで再試行しない.
+    // commit中に接続が切れるとcommitされたか分からず、再試行すると二重に作成・更新しかねない
+    async fn write<T, Fut>(&self, operation: impl FnOnce() -> Fut) -> anyhow::Result<T>
+    where
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        with_timeout(self.query_timeout, operation()).await
+    }
+
+    // 読み取り用のpool. 設定されていなければ書き込みと同じpoolを使う
+    fn read_pool(&self) -> &PgPool {
+        self.read_pool.as_ref().unwrap_or(&self.write_pool)
+    }
+}
+
+// トランザクション内でも使えるようにconnectionを受け取ってuser_idのtodoを1件取得する.
+// labelと結合し、labelが複数あれば1つずつ別の行になる
+async fn find_with(conn: &mut PgConnection, user_id: i32, id: i32) -> anyhow::Result<TodoEntity> {
+    let items = checked_query_as!(
+        TodoWithLabelFromRow,
+        r#"
+        select todos.id, todos.public_id, todos.user_id, todos.text, todos.completed,
+            todos.due_date, todos.recurrence as "recurrence: Recurrence", todos.recurrence_anchor,
+            todos.remind_at, todos.reminded_at, todos.created_at, todos.completed_at,
+            todos.updated_at, todos.parent_id, labels.id as "label_id?",
+            labels.name as "label_name?", labels.owner_id as label_owner_id,
+            null::int8 as total_count
+        from todos
+        left outer join todo_labels t1 on todos.id = t1.todo_id
+        left outer join labels on labels.id = t1.label_id
+        where todos.id = $1 and todos.user_id = $2
+        "#,
+        id,
+        user_id
+    )
+    .fetch_all(conn)
+    .await?;
+    first_found(items, id)
+}
+
+// idsのuser_idのtodoをid順に取得する. 見つからないidは含めない.
+// read_poolからも、トランザクション内のconnectionからも読めるようにexecutorを受け取る
+async fn find_many_with<'e>(
+    executor: impl PgExecutor<'e>,
+    user_id: i32,
+    ids: &[i32],
+) -> anyhow::Result<Vec<TodoEntity>> {
+    let items = checked_query_as!(
+        TodoWithLabelFromRow,
+        r#"
+        select todos.id, todos.public_id, todos.user_id, todos.text, todos.completed, todos.due_date,
+            todos.recurrence as "recurrence: Recurrence", todos.recurrence_anchor, todos.remind_at,
+            todos.reminded_at, todos.created_at, todos.completed_at, todos.updated_at, todos.parent_id,
+            labels.id as "label_id?", labels.name as "label_name?",
+            labels.owner_id as label_owner_id, null::int8 as total_count
+        from todos
+        left outer join todo_labels t1 on todos.id = t1.todo_id
+        left outer join labels on labels.id = t1.label_id
+        where todos.id = any($1) and todos.user_id = $2
+        order by todos.id
+        "#,
+        ids,
+        user_id
+    )
+    .fetch_all(executor)
+    .await?;
+    Ok(fold_entities(items))
+}
+
+// find_withと同じだが、トランザクションが終わるまでtodoの行をロックする
+async fn find_for_update(
+    conn: &mut PgConnection,
+    user_id: i32,
+    id: i32,
+) -> anyhow::Result<TodoEntity> {
+    let items = checked_query_as!(
+        TodoWithLabelFromRow,
+        r#"
+        select todos.id, todos.public_id, todos.user_id, todos.text, todos.completed,
+            todos.due_date, todos.recurrence as "recurrence: Recurrence", todos.recurrence_anchor,
+            todos.remind_at, todos.reminded_at, todos.created_at, todos.completed_at,
+            todos.updated_at, todos.parent_id, labels.id as "label_id?",
+            labels.name as "label_name?", labels.owner_id as label_owner_id,
+            null::int8 as total_count
+        from todos
+        left outer join todo_labels t1 on todos.id = t1.todo_id
+        left outer join labels on labels.id = t1.label_id
+        where todos.id = $1 and todos.user_id = $2
+        for update of todos
+        "#,
+        id,
+        user_id
+    )
+    .fetch_all(conn)
+    .await?;
+    first_found(items, id)
+}
+
+// idのtodoの行を1件のtodoにまとめる. 行が無ければNotFound
+fn first_found(items: Vec<TodoWithLabelFromRow>, id: i32) -> anyhow::Result<TodoEntity> {
+    fold_entities(items)
+        .pop()
+        .ok_or(RepositoryError::NotFound(id).into())
+}
+
+// labelsが全てuser_idから参照できる(共有または本人所有)か確認する
+async fn ensure_labels_available(
+    conn: &mut PgConnection,
+    user_id: i32,
+    labels: &[i32],
+) -> anyhow::Result<()> {
+    if labels.is_empty() {
+        return Ok(());
+    }
+    let available: Vec<i32> = checked_query_scalar!(
+        r#"
+        select id from labels
+        where id = any($1) and (owner_id is null or owner_id = $2)
+        "#,
+        labels,
+        user_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    match labels.iter().find(|id| !available.contains(id)) {
+        Some(id) => Err(RepositoryError::LabelNotAvailable(*id).into()),
+        None => Ok(()),
+    }
+}
+
+/// all_streamが読み込んでおく、送り先が受け取っていないtodoの数の上限
+const STREAM_BUFFER: usize = 64;
+
+// allの問い合わせ. limit/offsetをTodo単位で効かせるため、絞り込みはサブクエリ内で行ってからlabelをjoinする.
+// 外側も同じ順に並べ、1つのtodoの行が続くようにする. 絞り込みと並び順は引数で切り替え、
+// 条件によらず同じprepared statementを使う. null(未指定)の条件は絞り込まない
+macro_rules! select_all {
+    ($user_id:expr, $query:expr, $sort:expr) => {
+        checked_query_as!(
+            TodoWithLabelFromRow,
+            r#"
+            select todos.id, todos.public_id, todos.user_id, todos.text, todos.completed,
+                todos.due_date, todos.recurrence as "recurrence: Recurrence",
+                todos.recurrence_anchor, todos.remind_at, todos.reminded_at, todos.created_at,
+                todos.completed_at, todos.updated_at, todos.parent_id, labels.id as "label_id?",
+                labels.name as "label_name?", labels.owner_id as label_owner_id,
+                null::int8 as total_count
+            from (
+                select * from todos
+                where user_id = $1
+                and ($2::bool is null or completed = $2)
+                and ($3::int4 is null or exists (
+                    select 1 from todo_labels tl where tl.todo_id = todos.id and tl.label_id = $3
+                ))
+                and ($4::text is null or text ilike $4)
+                and ($5::timestamptz is null or created_at >= $5)
+                and ($6::timestamptz is null or created_at <= $6)
+                order by
+                    case when $7::text = 'text_asc' then text end asc,
+                    case when $7 = 'text_desc' then text end desc,
+                    case when $7 = 'created_asc' then created_at end asc,
+                    case when $7 = 'created_desc' then created_at end desc,
+                    case when $7 in ('id_asc', 'text_asc', 'created_asc') then id end asc,
+                    id desc
+                limit $8 offset $9
+            ) todos
+            left outer join todo_labels t1 on todos.id = t1.todo_id
+            left outer join labels on labels.id = t1.label_id
+            order by
+                case when $7 = 'text_asc' then todos.text end asc,
+                case when $7 = 'text_desc' then todos.text end desc,
+                case when $7 = 'created_asc' then todos.created_at end asc,
+                case when $7 = 'created_desc' then todos.created_at end desc,
+                case when $7 in ('id_asc', 'text_asc', 'created_asc') then todos.id end asc,
+                todos.id desc
+            "#,
+            $user_id,
+            $query.completed,
+            $query.label,
+            $query.q.as_deref().map(like_pattern),
+            $query.created_from,
+            $query.created_to,
+            $sort.key(),
+            $query.limit,
+            $query.offset
+        )
+    };
+}
+
+/// 親を辿る深さの上限. これより深い階層は循環とみなして親の変更を拒否する
+const MAX_PARENT_DEPTH: usize = 64;
+
+// parent_idがuser_idのtodoで、idのtodo自身やその子孫でないか確認する. 作成時はidがNone
+async fn ensure_parent_available(
+    conn: &mut PgConnection,
+    user_id: i32,
+    id: Option<i32>,
+    parent_id: i32,
+) -> anyhow::Result<()> {
+    let exists: bool = checked_query_scalar!(
+        r#"select exists (select 1 from todos where id = $1 and user_id = $2) as "exists!""#,
+        parent_id,
+        user_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if !exists {
+        return Err(RepositoryError::InvalidParent(parent_id).into());
+    }
+    if let Some(id) = id {
+        if would_create_cycle(conn, id, parent_id).await? {
+            return Err(RepositoryError::InvalidParent(parent_id).into());
+        }
+    }
+    Ok(())
+}
+
+// new_parentから親をMAX_PARENT_DEPTHまで辿り、idに行き着くか上限を超えるならtrue
+async fn would_create_cycle(
+    conn: &mut PgConnection,
+    id: i32,
+    new_parent: i32,
+) -> anyhow::Result<bool> {
+    // 既に循環したデータがあっても終わるよう、深さで再帰を打ち切る
+    let cyclic = checked_query_scalar!(
+        r#"
+        with recursive ancestors as (
+            select id, parent_id, 1 as depth from todos where id = $2
+            union all
+            select todos.id, todos.parent_id, ancestors.depth + 1 from todos
+            join ancestors on todos.id = ancestors.parent_id
+            where ancestors.depth < $3
+        )
+        select exists (
+            select 1 from ancestors
+            where id = $1 or (depth = $3 and parent_id is not null)
+        ) as "cyclic!"
+        "#,
+        id,
+        new_parent,
+        MAX_PARENT_DEPTH as i32
+    )
+    .fetch_one(conn)
+    .await?;
+    Ok(cyclic)
+}
+
+// user_idのtodoを1件insertし、labelを紐づけたtodoを返す.
+// todoとtodo_labelsのinsertとlabelとの結合を1文で行う
+async fn insert_with(
+    conn: &mut PgConnection,
+    user_id: i32,
+    payload: &CreateTodo,
+) -> anyhow::Result<TodoEntity> {
+    ensure_labels_available(&mut *conn, user_id, &payload.labels).await?;
+    if let Some(parent_id) = payload.parent_id {
+        ensure_parent_available(&mut *conn, user_id, None, parent_id).await?;
+    }
+
+    // 同じ文のCTEでinsertした行はtodo_labelsからは見えないため、linkedのreturningと結合する
+    let items = checked_query_as!(
+        TodoWithLabelFromRow,
+        r#"
+        with new_todo as (
+            insert into todos (text, completed, due_date, recurrence, recurrence_anchor,
+                remind_at, parent_id, user_id)
+            values ($1, false, $2, $3, $4, $5, $6, $7)
+            returning *
+        ), linked as (
+            insert into todo_labels (todo_id, label_id)
+            select new_todo.id, t.id
+            from new_todo, unnest($8::int[]) as t(id)
+            returning todo_id, label_id
+        )
+        select new_todo.id, new_todo.public_id, new_todo.user_id, new_todo.text, new_todo.completed, new_todo.due_date,
+            new_todo.recurrence as "recurrence: Recurrence", new_todo.recurrence_anchor, new_todo.remind_at,
+            new_todo.reminded_at, new_todo.created_at, new_todo.completed_at, new_todo.updated_at, new_todo.parent_id,
+            labels.id as "label_id?", labels.name as "label_name?",
+            labels.owner_id as label_owner_id, null::int8 as total_count
+        from new_todo
+        left outer join linked on new_todo.id = linked.todo_id
+        left outer join labels on labels.id = linked.label_id
+        "#,
+        payload.text, // $1にCreateTodoのtextを渡す
+        payload.due_date,
+        payload.recurrence as _,
+        payload.recurrence_anchor,
+        payload.remind_at,
+        payload.parent_id,
+        user_id,
+        &payload.labels
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let todo = fold_entities(items)
+        .pop()
+        .ok_or(RepositoryError::Unexpected(
+            "inserted todo was not returned".to_string(),
+        ))?;
+    Ok(todo)
+}
+
+// user_idのtodoとそのlabelの紐づけを削除し、親から外れたsubtaskのidを返す.
+// 他のユーザーのtodoは存在しないものとしてNotFoundにする
+async fn delete_with(conn: &mut PgConnection, user_id: i32, id: i32) -> anyhow::Result<Vec<i32>> {
+    // subtaskのparent_idは外部キーでnullになるので、削除する前に読んでおく
+    let detached = checked_query_scalar!(
+        "select id from todos where parent_id=$1 and user_id=$2 order by id",
+        id,
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    // todo's label delete
+    checked_query!(
+        r#"
+        delete from todo_labels
+        where todo_id in (select id from todos where id=$1 and user_id=$2)
+        "#,
+        id,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    // todo delete
+    let deleted = checked_query!(
+        r#"
+        delete from todos where id=$1 and user_id=$2
+        "#,
+        id,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(RepositoryError::NotFound(id).into());
+    }
+    Ok(detached)
+}
+
+// 完了の取り消しで、その完了で作った次回分を削除し、一緒に完了にしたsubtaskを未完了に戻す.
+// 既に削除されたものはそのままにし、行った変更の記録を返す
+async fn undo_completion_with(
+    conn: &mut PgConnection,
+    user_id: i32,
+    diff: &TodoDiff,
+) -> anyhow::Result<Vec<NewTodoEvent>> {
+    let mut events = vec![];
+    if let Some(spawned_id) = diff.spawned_id {
+        if let Some(spawned) = find_many_with(&mut *conn, user_id, &[spawned_id])
+            .await?
+            .pop()
+        {
+            let detached = delete_with(conn, user_id, spawned_id).await?;
+            events.push(NewTodoEvent::deleted(user_id, spawned, detached));
+        }
+    }
+    let ids = &diff.completed_subtasks;
+    if !ids.is_empty() {
+        let old = find_many_with(&mut *conn, user_id, ids).await?;
+        checked_query!(
+            r#"
+            update todos set completed = false, completed_at = null, updated_at = now()
+            where id = any($1) and user_id = $2 and completed
+            "#,
+            ids,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        let reopened = find_many_with(&mut *conn, user_id, ids).await?;
+        events.extend(
+            old.iter()
+                .zip(&reopened)
+                .filter_map(|(old, todo)| NewTodoEvent::changed(user_id, old, todo)),
+        );
+    }
+    Ok(events)
+}
+
+// 削除の取り消しで、削除で親から外れたsubtaskを親に戻す. 既に別の親に付け替えたものはそのままにする
+async fn relink_subtasks_with(
+    conn: &mut PgConnection,
+    user_id: i32,
+    parent_id: i32,
+    ids: &[i32],
+) -> anyhow::Result<Vec<NewTodoEvent>> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let old = find_many_with(&mut *conn, user_id, ids).await?;
+    checked_query!(
+        r#"
+        update todos set parent_id = $1, updated_at = now()
+        where id = any($2) and user_id = $3 and parent_id is null
+        "#,
+        parent_id,
+        ids,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    let relinked = find_many_with(&mut *conn, user_id, ids).await?;
+    Ok(old
+        .iter()
+        .zip(&relinked)
+        .filter_map(|(old, todo)| NewTodoEvent::changed(user_id, old, todo))
+        .collect())
+}
+
+// todoをtodo.idのまま指定した状態に戻す. 削除済みなら同じidで作り直す
+async fn restore_with(
+    conn: &mut PgConnection,
+    user_id: i32,
+    todo: &TodoEntity,
+) -> anyhow::Result<TodoEntity> {
+    let label_ids: Vec<i32> = todo.labels.iter().map(|label| label.id).collect();
+    ensure_labels_available(&mut *conn, user_id, &label_ids).await?;
+
+    // 他のユーザーのtodoと同じidなら上書きせずNotFoundにする.
+    // 親が削除済みならsubtaskではなくなる. 戻すのも変更なのでupdated_atは今にする
+    let _: i32 = checked_query_scalar!(
+        r#"
+        insert into todos (id, user_id, text, completed, due_date, recurrence,
+            remind_at, reminded_at, created_at, completed_at, parent_id, public_id,
+            updated_at, recurrence_anchor)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10,
+            (select id from todos where id = $11 and user_id = $2), $12, now(), $13)
+        on conflict (id) do update set text=excluded.text,
+            completed=excluded.completed, due_date=excluded.due_date,
+            recurrence=excluded.recurrence, recurrence_anchor=excluded.recurrence_anchor,
+            remind_at=excluded.remind_at,
+            reminded_at=excluded.reminded_at, completed_at=excluded.completed_at,
+            parent_id=excluded.parent_id, updated_at=excluded.updated_at
+        where todos.user_id = excluded.user_id
+        returning id
+        "#,
+        todo.id,
+        user_id,
+        todo.text,
+        todo.completed,
+        todo.due_date,
+        todo.recurrence as _,
+        todo.remind_at,
+        todo.reminded_at,
+        todo.created_at,
+        todo.completed_at,
+        todo.parent_id,
+        todo.public_id,
+        todo.recurrence_anchor
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(RepositoryError::NotFound(todo.id))?;
+
+    checked_query!(
+        r#"
+        delete from todo_labels where todo_id=$1
+        "#,
+        todo.id
+    )
+    .execute(&mut *conn)
+    .await?;
+    checked_query!(
+        r#"
+        insert into todo_labels (todo_id, label_id)
+        select $1, id
+        from unnest($2::int[]) as t(id)
+        "#,
+        todo.id,
+        &label_ids
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    find_with(conn, user_id, todo.id).await
+}
+
+#[async_trait]
+impl TodoRepository for TodoRepositoryForDb {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+        let payload = &payload;
+        let (todo, logged) = self
+            .write(|| async move {
+                let mut tx = self.write_pool.begin().await?;
+                let todo = insert_with(&mut tx, user_id, payload).await?;
+                let logged = self
+                    .log_with(&mut tx, [NewTodoEvent::created(user_id, &todo)])
+                    .await?;
+                tx.commit().await?;
+                Ok((todo, logged))
+            })
+            .await?;
+        self.publish(&logged);
+        Ok(todo)
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEntity> {
+        self.read(|| async move {
+            let mut conn = self.read_pool().acquire().await?;
+            find_with(&mut conn, user_id, id).await
+        })
+        .await
+    }
+
+    async fn find_by_public_id(&self, user_id: i32, public_id: Uuid) -> anyhow::Result<TodoEntity> {
+        self.read(|| async move {
+            let items = checked_query_as!(
+                TodoWithLabelFromRow,
+                r#"
+                select todos.id, todos.public_id, todos.user_id, todos.text, todos.completed, todos.due_date,
+                    todos.recurrence as "recurrence: Recurrence", todos.recurrence_anchor, todos.remind_at,
+                    todos.reminded_at, todos.created_at, todos.completed_at, todos.updated_at, todos.parent_id,
+                    labels.id as "label_id?", labels.name as "label_name?",
+                    labels.owner_id as label_owner_id, null::int8 as total_count
+                from todos
+                left outer join todo_labels t1 on todos.id = t1.todo_id
+                left outer join labels on labels.id = t1.label_id
+                where todos.public_id = $1 and todos.user_id = $2
+                "#,
+                public_id,
+                user_id
+            )
+            .fetch_all(self.read_pool())
+            .await?;
+
+            let todo = fold_entities(items)
+                .pop()
+                .ok_or(RepositoryError::PublicIdNotFound(public_id))?;
+            Ok(todo)
+        })
+        .await
+    }
+
+    async fn all(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<Vec<TodoEntity>> {
+        query.ensure_valid()?;
+        let query = &query;
+        self.read(|| async move {
+            let sort = query.sort.unwrap_or(self.default_sort);
+            let items = select_all!(user_id, query, sort)
+                .fetch_all(self.read_pool())
+                .await?;
+
+            Ok(fold_entities(items))
+        })
+        .await
+    }
+
+    // 件数はページの問い合わせにwindow関数で含め、1回の問い合わせで取得する.
+    // 絞り込みと並び順はallと同じで、limit, offsetを適用する前の件数をtotal_countの列に入れる
+    async fn page(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<TodoPage> {
+        query.ensure_valid()?;
+        let (todos, total) = {
+            let query = &query;
+            self.read(|| async move {
+                let sort = query.sort.unwrap_or(self.default_sort);
+                let items = checked_query_as!(
+                    TodoWithLabelFromRow,
+                    r#"
+                    select todos.id, todos.public_id, todos.user_id, todos.text, todos.completed,
+                        todos.due_date, todos.recurrence as "recurrence: Recurrence",
+                        todos.recurrence_anchor, todos.remind_at, todos.reminded_at,
+                        todos.created_at, todos.completed_at, todos.updated_at, todos.parent_id,
+                        labels.id as "label_id?", labels.name as "label_name?",
+                        labels.owner_id as label_owner_id, todos.total_count
+                    from (
+                        select *, count(*) over () as total_count from todos
+                        where user_id = $1
+                        and ($2::bool is null or completed = $2)
+                        and ($3::int4 is null or exists (
+                            select 1 from todo_labels tl
+                            where tl.todo_id = todos.id and tl.label_id = $3
+                        ))
+                        and ($4::text is null or text ilike $4)
+                        and ($5::timestamptz is null or created_at >= $5)
+                        and ($6::timestamptz is null or created_at <= $6)
+                        order by
+                            case when $7::text = 'text_asc' then text end asc,
+                            case when $7 = 'text_desc' then text end desc,
+                            case when $7 = 'created_asc' then created_at end asc,
+                            case when $7 = 'created_desc' then created_at end desc,
+                            case when $7 in ('id_asc', 'text_asc', 'created_asc') then id end asc,
+                            id desc
+                        limit $8 offset $9
+                    ) todos
+                    left outer join todo_labels t1 on todos.id = t1.todo_id
+                    left outer join labels on labels.id = t1.label_id
+                    order by
+                        case when $7 = 'text_asc' then todos.text end asc,
+                        case when $7 = 'text_desc' then todos.text end desc,
+                        case when $7 = 'created_asc' then todos.created_at end asc,
+                        case when $7 = 'created_desc' then todos.created_at end desc,
+                        case when $7 in ('id_asc', 'text_asc', 'created_asc') then todos.id end asc,
+                        todos.id desc
+                    "#,
+                    user_id,
+                    query.completed,
+                    query.label,
+                    query.q.as_deref().map(like_pattern),
+                    query.created_from,
+                    query.created_to,
+                    sort.key(),
+                    query.limit,
+                    query.offset
+                )
+                .fetch_all(self.read_pool())
+                .await?;
+                let total = items.first().and_then(|row| row.total_count);
+                Ok((fold_entities(items), total))
+            })
+            .await?
+        };
+        let total = match total {
+            Some(total) => total,
+            // offsetが件数以上だと行が無く件数も読めないため、数え直す
+            None if query.offset.unwrap_or(0) > 0 => self.count(user_id, query).await?,
+            None => 0,
+        };
+        Ok(TodoPage { todos, total })
+    }
+
+    fn all_stream(
+        &self,
+        user_id: i32,
+        query: TodoQuery,
+    ) -> BoxStream<'static, anyhow::Result<TodoEntity>> {
+        if let Err(e) = query.ensure_valid() {
+            return stream::once(async { Err(e) }).boxed();
+        }
+        let pool = self.read_pool().clone();
+        let sort = query.sort.unwrap_or(self.default_sort);
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        // 読み込んだ行はtaskから送る.
+        // 書き出しの遅いクライアントでも最後まで読めるよう、問い合わせの上限時間は掛けない
+        tokio::spawn(async move {
+            let rows = select_all!(user_id, query, sort).fetch(&pool);
+            let mut todos = fold_stream(rows);
+            while let Some(todo) = todos.next().await {
+                // 受け手が居なくなれば読み込みをやめる
+                if tx.send(todo).await.is_err() {
+                    break;
+                }
+            }
+        });
+        stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|todo| (todo, rx))
+        })
+        .boxed()
+    }
+
+    async fn count(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<i64> {
+        query.ensure_valid()?;
+        let query = &query;
+        self.read(|| async move {
+            // 絞り込みはallと同じ
+            let count = checked_query_scalar!(
+                r#"
+                select count(*) as "count!" from todos
+                where user_id = $1
+                and ($2::bool is null or completed = $2)
+                and ($3::int4 is null or exists (
+                    select 1 from todo_labels tl where tl.todo_id = todos.id and tl.label_id = $3
+                ))
+                and ($4::text is null or text ilike $4)
+                and ($5::timestamptz is null or created_at >= $5)
+                and ($6::timestamptz is null or created_at <= $6)
+                "#,
+                user_id,
+                query.completed,
+                query.label,
+                query.q.as_deref().map(like_pattern),
+                query.created_from,
+                query.created_to
+            )
+            .fetch_one(self.read_pool())
+            .await?;
+            Ok(count)
+        })
+        .await
+    }
+
+    async fn find_many(&self, user_id: i32, ids: &[i32]) -> anyhow::Result<Vec<TodoEntity>> {
+        self.read(|| async move {
+            let todos = find_many_with(self.read_pool(), user_id, ids).await?;
+            Ok(in_requested_order(todos, ids))
+        })
+        .await
+    }
+
+    async fn update(
+        &self,
+        user_id: i32,
+        id: i32,
+        payload: UpdateTodo,
+    ) -> anyhow::Result<TodoEntity> {
+        let payload = &payload;
+        let (todo, logged) = self
+            .write(|| async move {
+                let mut tx = self.write_pool.begin().await?;
+
+                // 変更前のtodoの取得と同時に行をロックし、同時に完了された場合に
+                // 次のTodoが二重に作られないようにする. 無ければNotFound
+                let old_todo = find_for_update(&mut tx, user_id, id).await?;
+
+                if let MaybeUndefined::Value(parent_id) = payload.parent_id {
+                    ensure_parent_available(&mut tx, user_id, Some(id), parent_id).await?;
+                }
+
+                // todo update
+                let now = Utc::now();
+                let updated = old_todo.apply(payload, now);
+                let result = checked_query!(
+                    r#"
+                update todos set text=$1, completed=$2, due_date=$3, recurrence=$4,
+                    remind_at=$5, reminded_at=$6, completed_at=$7, parent_id=$8, updated_at=$9,
+                    recurrence_anchor=$10
+                where id=$11 and user_id=$12
+                "#,
+                    updated.text,
+                    updated.completed,
+                    updated.due_date,
+                    updated.recurrence as _,
+                    updated.remind_at,
+                    updated.reminded_at,
+                    updated.completed_at,
+                    updated.parent_id,
+                    updated.updated_at,
+                    updated.recurrence_anchor,
+                    id,
+                    user_id
+                )
+                .execute(&mut *tx)
+                .await?;
+                if result.rows_affected() == 0 {
+                    return Err(RepositoryError::NotFound(id).into());
+                }
+
+                // subtaskは完了にするだけで、繰り返しの次回分は作らない.
+                // 取り消せるように、完了にしたsubtaskのidを記録する
+                let mut completed_subtasks = vec![];
+                if payload.completes_subtasks(&updated) {
+                    completed_subtasks = checked_query_scalar!(
+                        r#"
+                    with recursive descendants as (
+                        select id from todos where parent_id = $1
+                        union all
+                        select todos.id from todos
+                        join descendants on todos.parent_id = descendants.id
+                    )
+                    update todos set completed = true, completed_at = $2, updated_at = $2
+                    where id in (select id from descendants) and not completed
+                    returning id
+                    "#,
+                        id,
+                        now
+                    )
+                    .fetch_all(&mut *tx)
+                    .await?;
+                    completed_subtasks.sort_unstable();
+                }
+
+                let label_ids = match &payload.labels {
+                    Some(labels) => {
+                        ensure_labels_available(&mut tx, user_id, labels).await?;
+                        // todo's label update
+                        // 一度関連するレコードを削除
+                        checked_query!(
+                            r#"
+                        delete from todo_labels where todo_id=$1
+                        "#,
+                            id
+                        )
+                        .execute(&mut *tx)
+                        .await?;
+
+                        checked_query!(
+                            r#"
+                        insert into todo_labels (todo_id, label_id)
+                        select $1, id
+                        from unnest($2::int[]) as t(id)
+                        "#,
+                            id,
+                            labels
+                        )
+                        .execute(&mut *tx)
+                        .await?;
+                        labels.clone()
+                    }
+                    None => old_todo.labels.iter().map(|label| label.id).collect(),
+                };
+
+                // 繰り返しTodoが未完了->完了になった場合は同じトランザクションで次のTodoを作る
+                let next = match old_todo.next_occurrence_on(&updated, label_ids) {
+                    Some(next) => Some(insert_with(&mut tx, user_id, &next).await?),
+                    None => None,
+                };
+
+                // コミット前に同じトランザクションで取得し、他の更新が混ざらないこの更新の結果を返す
+                let mut todo = find_with(&mut tx, user_id, id).await?;
+                todo.next_occurrence = next.map(Box::new);
+                let events = NewTodoEvent::updated(user_id, &old_todo, &todo, completed_subtasks);
+                let logged = self.log_with(&mut tx, events).await?;
+                tx.commit().await?;
+                Ok((todo, logged))
+            })
+            .await?;
+        self.publish(&logged);
+        Ok(todo)
+    }
+
+    async fn update_where(
+        &self,
+        user_id: i32,
+        filter: TodoQuery,
+        set: BulkUpdateTodo,
+    ) -> anyhow::Result<Vec<TodoEntity>> {
+        filter.ensure_valid()?;
+        let filter = &filter;
+        let (todos, logged) = self
+            .write(|| async move {
+                let mut tx = self.write_pool.begin().await?;
+                // 1文で変更し、絞り込みと変更の間に他の更新が入らないようにする.
+                // 既にsetと同じ値のtodoは変えず、完了日時も保つ.
+                // 記録のために変更前の完了日時も返す
+                let now = Utc::now();
+                // 絞り込みはallと同じく、null(未指定)の条件では絞り込まない
+                let changed = checked_query_as!(
+                    ChangedRow,
+                    r#"
+                    with old as (
+                        select id, completed_at from todos
+                        where user_id = $1 and completed <> $2
+                        and ($3::bool is null or completed = $3)
+                        and ($4::int4 is null or exists (
+                            select 1 from todo_labels tl
+                            where tl.todo_id = todos.id and tl.label_id = $4
+                        ))
+                        and ($5::text is null or text ilike $5)
+                        and ($6::timestamptz is null or created_at >= $6)
+                        and ($7::timestamptz is null or created_at <= $7)
+                        for update
+                    )
+                    update todos set completed = $2, completed_at = $8, updated_at = $9
+                    from old where todos.id = old.id
+                    returning todos.id, old.completed_at
+                    "#,
+                    user_id,
+                    set.completed,
+                    filter.completed,
+                    filter.label,
+                    filter.q.as_deref().map(like_pattern),
+                    filter.created_from,
+                    filter.created_to,
+                    set.completed.then_some(now),
+                    now
+                )
+                .fetch_all(&mut *tx)
+                .await?;
+
+                // コミット前に同じトランザクションで取得し、この変更の結果を返す
+                let ids: Vec<i32> = changed.iter().map(|row| row.id).collect();
+                let todos = find_many_with(&mut *tx, user_id, &ids).await?;
+                // completedとcompleted_at以外は変わらないので、変更後から変更前を組み立てる
+                let changes = todos.iter().filter_map(|todo| {
+                    let row = changed.iter().find(|row| row.id == todo.id)?;
+                    let old = TodoEntity {
+                        completed: !set.completed,
+                        completed_at: row.completed_at,
+                        ..todo.clone()
+                    };
+                    NewTodoEvent::changed(user_id, &old, todo)
+                });
+                let logged = self.log_with(&mut tx, changes.collect::<Vec<_>>()).await?;
+                tx.commit().await?;
+                Ok((todos, logged))
+            })
+            .await?;
+        self.publish(&logged);
+        Ok(todos)
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let logged = self
+            .write(|| async move {
+                let mut tx = self.write_pool.begin().await?;
+                // 記録する削除前のtodo. 他のユーザーのtodoは存在しないものとしてNotFoundにする
+                let old = find_for_update(&mut tx, user_id, id).await?;
+                let detached = delete_with(&mut tx, user_id, id).await?;
+                let logged = self
+                    .log_with(&mut tx, [NewTodoEvent::deleted(user_id, old, detached)])
+                    .await?;
+                tx.commit().await?;
+                Ok(logged)
+            })
+            .await?;
+        self.publish(&logged);
+        Ok(())
+    }
+
+    // deleteと同じ削除を行って件数を数え、トランザクションを取り消す
+    async fn preview_delete(&self, user_id: i32, id: i32) -> anyhow::Result<DeletePreview> {
+        self.read(|| async move {
+            let mut tx = self.write_pool.begin().await?;
+            let todo = find_with(&mut tx, user_id, id).await?;
+            let detached_subtasks: i64 = checked_query_scalar!(
+                r#"select count(*) as "count!" from todos where parent_id=$1"#,
+                id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            let todo_labels = checked_query!("delete from todo_labels where todo_id=$1", id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            checked_query!("delete from todos where id=$1 and user_id=$2", id, user_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.rollback().await?;
+
+            Ok(DeletePreview {
+                todo,
+                todo_labels,
+                detached_subtasks: detached_subtasks as u64,
+            })
+        })
+        .await
+    }
+
+    async fn subtasks(&self, user_id: i32, id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+        self.read(|| async move {
+            let mut conn = self.read_pool().acquire().await?;
+            find_with(&mut conn, user_id, id).await?;
+            let items = checked_query_as!(
+                TodoWithLabelFromRow,
+                r#"
+                select todos.id, todos.public_id, todos.user_id, todos.text, todos.completed, todos.due_date,
+                    todos.recurrence as "recurrence: Recurrence", todos.recurrence_anchor, todos.remind_at,
+                    todos.reminded_at, todos.created_at, todos.completed_at, todos.updated_at, todos.parent_id,
+                    labels.id as "label_id?", labels.name as "label_name?",
+                    labels.owner_id as label_owner_id, null::int8 as total_count
+                from todos
+                left outer join todo_labels t1 on todos.id = t1.todo_id
+                left outer join labels on labels.id = t1.label_id
+                where todos.parent_id = $1 and todos.user_id = $2
+                order by todos.id asc
+                "#,
+                id,
+                user_id
+            )
+            .fetch_all(&mut *conn)
+            .await?;
+
+            Ok(fold_entities(items))
+        })
+        .await
+    }
+
+    async fn restore(&self, user_id: i32, todo: TodoEntity) -> anyhow::Result<TodoEntity> {
+        let todo = &todo;
+        self.write(|| async move {
+            let mut tx = self.write_pool.begin().await?;
+            let restored = restore_with(&mut tx, user_id, todo).await?;
+            tx.commit().await?;
+            Ok(restored)
+        })
+        .await
+    }
+
+    // 取り消す変更の選択から変更とその記録までを1つのトランザクションで行う
+    async fn undo(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEvent> {
+        if self.activity.is_none() {
+            return Err(RepositoryError::NothingToUndo(id).into());
+        }
+        let logged = self
+            .write(|| async move {
+                let mut tx = self.write_pool.begin().await?;
+                // todoの変更の記録の行をロックし、同じtodoの取り消しを他のプロセスとも直列化する.
+                // 先の取り消しのcommitを待った後、その記録も含めた履歴を次の文で読み直す
+                checked_query!(
+                    "select id from todo_events where actor_id = $1 and todo_id = $2 for update",
+                    user_id,
+                    id
+                )
+                .fetch_all(&mut *tx)
+                .await?;
+                let history = history_with(&mut *tx, user_id, id).await?;
+                let target = undo_target(&history).ok_or(RepositoryError::NothingToUndo(id))?;
+
+                // 作成は削除、削除は同じidでの再作成、更新は変更前の値の書き戻しで取り消す.
+                // 削除で外れたsubtaskや完了で作った次回分など、他のtodoへの変更も戻す
+                let (change, others) = match (target.kind, &target.payload) {
+                    (TodoEventKind::Created, TodoEventPayload::Snapshot(_)) => {
+                        let current = find_for_update(&mut tx, user_id, id).await?;
+                        delete_with(&mut tx, user_id, id).await?;
+                        (TodoEventPayload::Snapshot(current), vec![])
+                    }
+                    // subtaskを記録する前の削除
+                    (TodoEventKind::Deleted, TodoEventPayload::Snapshot(snapshot)) => {
+                        let restored = restore_with(&mut tx, user_id, snapshot).await?;
+                        (TodoEventPayload::Snapshot(restored), vec![])
+                    }
+                    (
+                        TodoEventKind::Deleted,
+                        TodoEventPayload::Deletion {
+                            todo: snapshot,
+                            detached_subtasks,
+                        },
+                    ) => {
+                        let restored = restore_with(&mut tx, user_id, snapshot).await?;
+                        let others =
+                            relink_subtasks_with(&mut tx, user_id, id, detached_subtasks).await?;
+                        (TodoEventPayload::Snapshot(restored), others)
+                    }
+                    (
+                        TodoEventKind::Updated | TodoEventKind::Completed,
+                        TodoEventPayload::Diff(diff),
+                    ) => {
+                        let current = find_for_update(&mut tx, user_id, id).await?;
+                        let restored =
+                            restore_with(&mut tx, user_id, &diff.revert(&current)).await?;
+                        let others = undo_completion_with(&mut tx, user_id, diff).await?;
+                        (
+                            TodoEventPayload::Diff(TodoDiff::between(&current, &restored)),
+                            others,
+                        )
+                    }
+                    _ => {
+                        return Err(RepositoryError::Unexpected(format!(
+                            "event {} cannot be undone",
+                            target.id
+                        ))
+                        .into())
+                    }
+                };
+
+                let undone = NewTodoEvent::undone(user_id, target, change);
+                let mut logged = Logged {
+                    audit: vec![AuditEntry::from_undo(&undone, target)],
+                    events: vec![insert_event(&mut *tx, &undone).await?],
+                };
+                // 他のtodoへの変更は、取り消しの記録の後にそれぞれのtodoの変更として記録する
+                let others = self.log_with(&mut tx, others).await?;
+                logged.audit.extend(others.audit);
+                logged.events.extend(others.events);
+                tx.commit().await?;
+                Ok(logged)
+            })
+            .await?;
+        self.publish(&logged);
+        Ok(logged.events[0].clone())
+    }
+
+    async fn claim_due_reminders(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<TodoEntity>> {
+        self.write(|| async move {
+            // update ... returning で取得と通知済みの記録を1文で行い、二重通知を防ぐ
+            let items = checked_query_as!(
+                TodoWithLabelFromRow,
+                r#"
+                with claimed as (
+                    update todos set reminded_at = $1, updated_at = $1
+                    where remind_at <= $1 and reminded_at is null
+                    returning *
+                )
+                select claimed.id, claimed.public_id, claimed.user_id, claimed.text, claimed.completed, claimed.due_date,
+                    claimed.recurrence as "recurrence: Recurrence", claimed.recurrence_anchor, claimed.remind_at,
+                    claimed.reminded_at, claimed.created_at, claimed.completed_at, claimed.updated_at, claimed.parent_id,
+                    labels.id as "label_id?", labels.name as "label_name?",
+                    labels.owner_id as label_owner_id, null::int8 as total_count
+                from claimed
+                left outer join todo_labels t1 on claimed.id = t1.todo_id
+                left outer join labels on labels.id = t1.label_id
+                order by claimed.id asc
+                "#,
+                now
+            )
+            .fetch_all(&self.write_pool)
+            .await?;
+
+            Ok(fold_entities(items))
+        })
+        .await
+    }
+
+    async fn stats(&self, user_id: i32, today: NaiveDate) -> anyhow::Result<TodoStats> {
+        self.read(|| async move {
+            let counts = checked_query_as!(
+                TodoCounts,
+                r#"
+                select count(*) as "total!",
+                    count(*) filter (where not completed) as "open!",
+                    count(*) filter (where completed) as "completed!",
+                    count(*) filter (where not completed and due_date < $2) as "overdue!"
+                from todos where user_id = $1
+                "#,
+                user_id,
+                today
+            )
+            .fetch_one(self.read_pool())
+            .await?;
+
+            // todoの無いlabelも残すため、labels側からleft joinして集計する
+            let labels = checked_query_as!(
+                LabelStats,
+                r#"
+                select labels.id, labels.name,
+                    count(todos.id) as "total!",
+                    count(todos.id) filter (where not todos.completed) as "open!",
+                    count(todos.id) filter (where todos.completed) as "completed!"
+                from labels
+                left outer join todo_labels t1 on labels.id = t1.label_id
+                left outer join todos on todos.id = t1.todo_id and todos.user_id = $1
+                where labels.owner_id is null or labels.owner_id = $1
+                group by labels.id
+                order by labels.id asc
+                "#,
+                user_id
+            )
+            .fetch_all(self.read_pool())
+            .await?;
+
+            Ok(TodoStats {
+                total: counts.total,
+                open: counts.open,
+                completed: counts.completed,
+                overdue: counts.overdue,
+                labels,
+            })
+        })
+        .await
+    }
+
+    async fn timeseries(
+        &self,
+        user_id: i32,
+        query: TimeseriesQuery,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+        let starts = query.bucket_starts()?;
+        let (from, to) = query.bounds(&starts);
+        let field = query.bucket.date_trunc_field();
+        let (created, completed) = self
+            .read(|| async move {
+                // date_truncはUTCの日付で区切る
+                let created = checked_query_as!(
+                    BucketCount,
+                    r#"
+                    select date_trunc($2, created_at at time zone 'UTC')::date as "bucket!",
+                        count(*) as "count!"
+                    from todos
+                    where user_id = $1 and created_at >= $3 and created_at < $4
+                    group by 1
+                    "#,
+                    user_id,
+                    field,
+                    from,
+                    to
+                )
+                .fetch_all(self.read_pool())
+                .await?;
+                let completed = checked_query_as!(
+                    BucketCount,
+                    r#"
+                    select date_trunc($2, completed_at at time zone 'UTC')::date as "bucket!",
+                        count(*) as "count!"
+                    from todos
+                    where user_id = $1 and completed_at >= $3 and completed_at < $4
+                    group by 1
+                    "#,
+                    user_id,
+                    field,
+                    from,
+                    to
+                )
+                .fetch_all(self.read_pool())
+                .await?;
+                Ok((created, completed))
+            })
+            .await?;
+
+        let pairs = |rows: Vec<BucketCount>| -> Vec<(NaiveDate, i64)> {
+            rows.into_iter()
+                .map(|row| (row.bucket, row.count))
+                .collect()
+        };
+        Ok(query.fill(starts, &pairs(created), &pairs(completed)))
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::test_utils::{fold_entities_nested, test_now, TEST_USER_ID};
+    use super::*;
+    use crate::repositories::test_database::db_pool_or_skip;
+    use crate::test_support::{self, TodoEntityBuilder};
+    use futures_util::TryStreamExt;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn fold_entities_test() {
+        let labels = test_support::labels(2);
+        let todo_1 = TodoEntityBuilder::new()
+            .text("todo 1")
+            .with_label(labels[0].clone())
+            .with_label(labels[1].clone())
+            .build();
+        let todo_2 = TodoEntityBuilder::new()
+            .id(2)
+            .text("todo 2")
+            .with_label(labels[0].clone())
+            .build();
+        let rows = test_support::todo_rows(&[todo_1.clone(), todo_2.clone()]);
+        assert_eq!(rows.len(), 3);
+
+        let res = fold_entities(rows);
+        assert_eq!(res, vec![todo_1, todo_2]);
+    }
+
+    #[test]
+    fn fold_entities_ignores_half_null_labels() {
+        let row = |label_id: Option<i32>, label_name: Option<&str>| TodoWithLabelFromRow {
+            id: 1,
+            public_id: Uuid::from_u128(1),
+            user_id: TEST_USER_ID,
+            text: String::from("todo 1"),
+            completed: false,
+            due_date: None,
+            recurrence: Recurrence::None,
+            recurrence_anchor: None,
+            remind_at: None,
+            reminded_at: None,
+            created_at: test_now(),
+            completed_at: None,
+            updated_at: test_now(),
+            parent_id: None,
+            label_id,
+            label_name: label_name.map(String::from),
+            label_owner_id: None,
+            total_count: None,
+        };
+
+        // labelの列が片方だけnullの行はlabelとして扱わない
+        for rows in [
+            vec![row(Some(1), None)],
+            vec![row(None, Some("label 1"))],
+            vec![row(Some(1), None), row(None, Some("label 2"))],
+        ] {
+            let todos = fold_entities(rows);
+            assert_eq!(todos.len(), 1);
+            assert!(todos[0].labels.is_empty());
+        }
+
+        // 同じtodoのlabelのある行と無い行が交互に並んでも、labelのある行だけを順に集める
+        let todos = fold_entities(vec![
+            row(None, None),
+            row(Some(1), Some("label 1")),
+            row(Some(2), None),
+            row(Some(3), Some("label 3")),
+            row(None, None),
+        ]);
+        assert_eq!(todos.len(), 1);
+        assert_eq!(
+            todos[0].labels,
+            vec![
+                Label::new(1, "label 1".to_string()),
+                Label::new(3, "label 3".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn fold_entities_restores_generated_todos() {
+        // labelの数を超えるfan_outはlabelの数に揃う
+        let labels = test_support::labels(8);
+        for fan_out in [0, 1, 4, 8, 16] {
+            let todos = test_support::todos(100, &labels, fan_out);
+            assert_eq!(fold_entities(test_support::todo_rows(&todos)), todos);
+        }
+    }
+
+    // labelの無いtodoは1行、あるtodoはlabelごとに1行. 1つのtodoの行は続けて並ぶ
+    fn rows_by_todo() -> impl Strategy<Value = Vec<TodoWithLabelFromRow>> {
+        proptest::collection::btree_map(1..=100, 0..4, 0..30).prop_map(|todos| {
+            todos
+                .into_iter()
+                .flat_map(|(id, labels)| {
+                    let labels = (1..=labels)
+                        .map(|label| Label {
+                            id: label,
+                            name: format!("label {}", label),
+                            owner_id: (label % 3 == 0).then_some(TEST_USER_ID),
+                        })
+                        .collect();
+                    let todo = TodoEntity {
+                        completed: id % 2 == 0,
+                        ..TodoEntity::new(id, format!("todo {}", id), labels)
+                    };
+                    TodoWithLabelFromRow::rows_of(&todo)
+                })
+                .collect::<Vec<_>>()
+        })
+    }
+
+    // rows_by_todoの行を、joinの結果と同じく順を揃えずに並べる
+    fn joined_rows() -> impl Strategy<Value = Vec<TodoWithLabelFromRow>> {
+        rows_by_todo().prop_shuffle()
+    }
+
+    // idは重複し、同じtodoの行が続かず、labelの列が片方だけnullの行も混ざる
+    fn arbitrary_rows() -> impl Strategy<Value = Vec<TodoWithLabelFromRow>> {
+        let row = (
+            1..=13,
+            proptest::option::weighted(0.8, 1..10),
+            proptest::option::weighted(0.8, any::<u8>()),
+            proptest::option::weighted(0.3, Just(TEST_USER_ID)),
+        )
+            .prop_map(|(id, label_id, label_name, label_owner_id)| {
+                let todo = TodoEntity::new(id, format!("todo {}", id), vec![]);
+                TodoWithLabelFromRow {
+                    label_id,
+                    label_name: label_name.map(|name| format!("label {}", name)),
+                    label_owner_id,
+                    ..TodoWithLabelFromRow::rows_of(&todo).remove(0)
+                }
+            });
+        proptest::collection::vec(row, 0..60)
+    }
+
+    proptest! {
+        #[test]
+        fn fold_entities_matches_nested_fold(rows in joined_rows()) {
+            prop_assert_eq!(fold_entities(rows.clone()), fold_entities_nested(rows));
+        }
+
+        #[test]
+        fn fold_entities_keeps_invariants_for_arbitrary_rows(rows in arbitrary_rows()) {
+            let mut first_seen: Vec<i32> = vec![];
+            let mut labels: HashMap<i32, Vec<Label>> = HashMap::new();
+            for row in &rows {
+                if !first_seen.contains(&row.id) {
+                    first_seen.push(row.id);
+                }
+                let todo_labels = labels.entry(row.id).or_default();
+                if let (Some(id), Some(name)) = (row.label_id, &row.label_name) {
+                    todo_labels.push(Label {
+                        id,
+                        name: name.clone(),
+                        owner_id: row.label_owner_id,
+                    });
+                }
+            }
+
+            let todos = fold_entities(rows);
+            // 異なるidはそれぞれ1度だけ、最初に現れた順に並ぶ
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            prop_assert_eq!(ids, first_seen);
+            // labelはidごとの完全な行だけを、行の順に持つ
+            for todo in &todos {
+                prop_assert_eq!(&todo.labels, &labels[&todo.id]);
+            }
+        }
+
+        #[test]
+        fn fold_stream_matches_fold_entities(rows in rows_by_todo()) {
+            // order byがidで終わるため、1つのtodoの行は続けて届く
+            let streamed: Vec<TodoEntity> = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(fold_stream(stream::iter(rows.clone()).map(Ok).boxed()).try_collect())
+                .expect("fold_stream returned Err");
+            prop_assert_eq!(streamed, fold_entities(rows));
+        }
+    }
+
+    #[tokio::test]
+    async fn fold_stream_drops_the_pending_todo_on_error() {
+        let row = |id: i32, label: i32| TodoWithLabelFromRow {
+            label_id: Some(label),
+            label_name: Some(format!("label {}", label)),
+            ..TodoWithLabelFromRow::rows_of(&TodoEntity::new(id, format!("todo {}", id), vec![]))
+                .remove(0)
+        };
+
+        // 読み込みに失敗したら、組み立て中のtodoは返さずにErrを返す
+        let rows = vec![Ok(row(1, 1)), Ok(row(2, 1)), Err(sqlx::Error::RowNotFound)];
+        let results: Vec<anyhow::Result<TodoEntity>> =
+            fold_stream(stream::iter(rows).boxed()).collect().await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().expect("first todo was Err").id, 1);
+        assert!(results[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn crud_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+
+        // label data prepare. schemaはこのテスト専用なので、毎回作ってもぶつからない
+        let label_1 = sqlx::query_as::<_, Label>(
+            r#"
+            insert into labels ( name )
+            values ( $1 )
+            returning *
+            "#,
+        )
+        .bind("test label")
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to insert label data.");
+
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let todo_text = "[crud_scenario] text";
+
+        // create
+        let created = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new(todo_text.to_string(), vec![label_1.id]),
+            )
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(created.text, todo_text); // 用意したtextでTodoが作成されているか確認
+        assert!(!created.completed); // completedがfalseでなければassert
+        assert_eq!(*created.labels.first().unwrap(), label_1);
+
+        // find
+        let todo = repository
+            .find(TEST_USER_ID, created.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(created, todo); // createで作ったTodoが取得できるか確認
+
+        // find_by_public_id
+        let todo = repository
+            .find_by_public_id(TEST_USER_ID, created.public_id)
+            .await
+            .expect("[find_by_public_id] returned Err");
+        assert_eq!(created, todo);
+        let err = repository
+            .find_by_public_id(TEST_USER_ID, Uuid::new_v4())
+            .await
+            .expect_err("[find_by_public_id] found a random public id");
+        assert!(matches!(
+            err.downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::PublicIdNotFound(_))
+        ));
+
+        // all
+        let todos = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .expect("[all] returned Err");
+        let todo = todos.first().unwrap();
+        assert_eq!(created, *todo);
+
+        // count
+        let query = TodoQuery {
+            q: Some("[crud_scenario]".to_string()),
+            completed: Some(false),
+            ..Default::default()
+        };
+        let count = repository
+            .count(TEST_USER_ID, query.clone())
+            .await
+            .expect("[count] returned Err");
+        let matched = repository
+            .all(TEST_USER_ID, query)
+            .await
+            .expect("[all] returned Err");
+        assert_eq!(count, matched.len() as i64);
+
+        // find_many
+        let todos = repository
+            .find_many(TEST_USER_ID, &[i32::MAX, created.id])
+            .await
+            .expect("[find_many] returned Err");
+        assert_eq!(todos, vec![created.clone()]);
+
+        // update
+        let updated_text = "[crud_scenario] updated text";
+        let todo = repository
+            .update(
+                TEST_USER_ID,
+                todo.id,
+                UpdateTodo::builder()
+                    .text(updated_text)
+                    .completed(true)
+                    .labels(vec![])
+                    .build(),
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(created.id, todo.id);
+        assert_eq!(todo.text, updated_text);
+        assert!(todo.labels.is_empty());
+
+        // dry runではtodoもtodo_labelsも残る
+        let subtask = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[crud_scenario] subtask".to_string(), vec![]).subtask_of(todo.id),
+            )
+            .await
+            .expect("[create] returned Err");
+        let labeled = repository
+            .update(
+                TEST_USER_ID,
+                todo.id,
+                UpdateTodo::new(None, None, Some(vec![label_1.id])),
+            )
+            .await
+            .expect("[update] returned Err");
+        let preview = repository
+            .preview_delete(TEST_USER_ID, todo.id)
+            .await
+            .expect("[preview_delete] returned Err");
+        assert_eq!(
+            preview,
+            DeletePreview {
+                todo: labeled.clone(),
+                todo_labels: 1,
+                detached_subtasks: 1,
+            }
+        );
+        let kept = repository
+            .find(TEST_USER_ID, todo.id)
+            .await
+            .expect("[find] returned Err after dry run");
+        assert_eq!(kept, labeled);
+        // labelsを指定しない更新ではlabelは変わらない
+        let renamed = repository
+            .update(
+                TEST_USER_ID,
+                todo.id,
+                UpdateTodo::new(Some(updated_text.to_string()), None, None),
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(renamed.labels, vec![label_1.clone()]);
+        repository
+            .delete(TEST_USER_ID, subtask.id)
+            .await
+            .expect("[delete] returned Err");
+
+        // delete
+        repository
+            .delete(TEST_USER_ID, todo.id)
+            .await
+            .expect("[delete] returned Err");
+        let res = repository.find(TEST_USER_ID, created.id).await;
+        assert!(res.is_err());
+
+        // 削除したtodoや他のユーザーのtodoは更新できない
+        let other = repository
+            .create(
+                TEST_USER_ID + 1,
+                CreateTodo::new("[crud_scenario] other".to_string(), vec![]),
+            )
+            .await
+            .expect("[create] returned Err");
+        for id in [todo.id, other.id] {
+            let err = repository
+                .update(TEST_USER_ID, id, UpdateTodo::completion(true))
+                .await
+                .expect_err("[update] updated a missing todo");
+            assert!(matches!(
+                err.downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::NotFound(missing)) if *missing == id
+            ));
+        }
+        let unchanged = repository
+            .find(TEST_USER_ID + 1, other.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(unchanged, other);
+        repository
+            .delete(TEST_USER_ID + 1, other.id)
+            .await
+            .expect("[delete] returned Err");
+
+        // schemaはこのテスト専用なので、作ったtodoを全て消せば行は何も残らない
+        for table in ["todos", "todo_labels"] {
+            let rows = sqlx::query_scalar::<_, i64>(&format!("select count(*) from {}", table))
+                .fetch_one(&pool)
+                .await
+                .expect("[delete] fetch error");
+            assert_eq!(rows, 0, "{} has residual rows", table);
+        }
+    }
+
+    #[tokio::test]
+    async fn recurring_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let due_date = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+
+        let created = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[recurring_scenario] text".to_string(), vec![])
+                    .recurring(Recurrence::Weekly, Some(due_date)),
+            )
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(created.recurrence, Recurrence::Weekly);
+        assert_eq!(created.due_date, Some(due_date));
+
+        let completed = repository
+            .update(
+                TEST_USER_ID,
+                created.id,
+                UpdateTodo::builder().completed(true).build(),
+            )
+            .await
+            .expect("[update] returned Err");
+        assert!(completed.completed);
+        let next = completed
+            .next_occurrence
+            .expect("[update] next occurrence not created");
+        assert!(!next.completed);
+        assert_eq!(next.text, created.text);
+        assert_eq!(next.due_date, NaiveDate::from_ymd_opt(2024, 2, 7));
+        assert_eq!(
+            repository
+                .find(TEST_USER_ID, next.id)
+                .await
+                .expect("[find] returned Err"),
+            *next
+        );
+
+        for id in [created.id, next.id] {
+            repository
+                .delete(TEST_USER_ID, id)
+                .await
+                .expect("[delete] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn monthly_recurring_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let mut todo = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[monthly_recurring_scenario] text".to_string(), vec![])
+                    .recurring(Recurrence::Monthly, NaiveDate::from_ymd_opt(2024, 1, 31)),
+            )
+            .await
+            .expect("[create] returned Err");
+
+        // 起点の1/31は次回分の行に保存され、月末に丸めた後も引き継がれる
+        for (month, day) in [(2, 29), (3, 31), (4, 30)] {
+            let next = repository
+                .update(
+                    TEST_USER_ID,
+                    todo.id,
+                    UpdateTodo::builder().completed(true).build(),
+                )
+                .await
+                .expect("[update] returned Err")
+                .next_occurrence
+                .expect("[update] next occurrence not created");
+            assert_eq!(next.due_date, NaiveDate::from_ymd_opt(2024, month, day));
+            todo = repository
+                .find(TEST_USER_ID, next.id)
+                .await
+                .expect("[find] returned Err");
+            assert_eq!(todo.recurrence_anchor, NaiveDate::from_ymd_opt(2024, 1, 31));
+        }
+    }
+
+    #[tokio::test]
+    async fn subtask_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let create = |text: &str| CreateTodo::new(format!("[subtask_scenario] {}", text), vec![]);
+
+        let parent = repository
+            .create(TEST_USER_ID, create("parent"))
+            .await
+            .expect("[create] returned Err");
+        let child = repository
+            .create(TEST_USER_ID, create("child").subtask_of(parent.id))
+            .await
+            .expect("[create] returned Err");
+        let grandchild = repository
+            .create(TEST_USER_ID, create("grandchild").subtask_of(child.id))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(child.parent_id, Some(parent.id));
+
+        let subtasks = repository
+            .subtasks(TEST_USER_ID, parent.id)
+            .await
+            .expect("[subtasks] returned Err");
+        assert_eq!(subtasks, vec![child.clone()]);
+
+        // 自分自身や子、孫を親にすると循環する
+        for parent_id in [parent.id, child.id, grandchild.id] {
+            let err = repository
+                .update(
+                    TEST_USER_ID,
+                    parent.id,
+                    UpdateTodo::builder().parent_id(Some(parent_id)).build(),
+                )
+                .await
+                .expect_err("[update] accepted a cyclic parent");
+            assert!(matches!(
+                err.downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::InvalidParent(_))
+            ));
+        }
+
+        repository
+            .update(
+                TEST_USER_ID,
+                parent.id,
+                UpdateTodo::builder()
+                    .completed(true)
+                    .complete_subtasks(true)
+                    .build(),
+            )
+            .await
+            .expect("[update] returned Err");
+        for id in [child.id, grandchild.id] {
+            let subtask = repository
+                .find(TEST_USER_ID, id)
+                .await
+                .expect("[find] returned Err");
+            assert!(subtask.completed);
+            assert!(subtask.completed_at.is_some());
+        }
+        // 未完了に戻すと完了日時は消える
+        let reopened = repository
+            .update(TEST_USER_ID, grandchild.id, UpdateTodo::completion(false))
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(reopened.completed_at, None);
+        let reopened = repository
+            .find(TEST_USER_ID, grandchild.id)
+            .await
+            .expect("[find] returned Err");
+        assert!(!reopened.completed);
+        assert_eq!(reopened.completed_at, None);
+
+        // 親を削除するとsubtaskは親の無いtodoになる
+        repository
+            .delete(TEST_USER_ID, parent.id)
+            .await
+            .expect("[delete] returned Err");
+        let child = repository
+            .find(TEST_USER_ID, child.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(child.parent_id, None);
+
+        for id in [grandchild.id, child.id] {
+            repository
+                .delete(TEST_USER_ID, id)
+                .await
+                .expect("[delete] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn reminder_claim_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let now = Utc::now();
+
+        let created = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[reminder_claim_scenario] text".to_string(), vec![])
+                    .remind_at(now - chrono::TimeDelta::minutes(1)),
+            )
+            .await
+            .expect("[create] returned Err");
+
+        let claimed = repository
+            .claim_due_reminders(now)
+            .await
+            .expect("[claim_due_reminders] returned Err");
+        assert!(claimed.iter().any(|todo| todo.id == created.id));
+
+        // 一度確保したリマインドは再度返らない
+        let claimed = repository
+            .claim_due_reminders(now)
+            .await
+            .expect("[claim_due_reminders] returned Err");
+        assert!(claimed.iter().all(|todo| todo.id != created.id));
+
+        repository
+            .delete(TEST_USER_ID, created.id)
+            .await
+            .expect("[delete] returned Err");
+    }
+
+    #[tokio::test]
+    async fn stats_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        // 他のテストのデータと混ざらないよう専用のユーザーで集計する
+        let user_id = 848_002;
+        let today = NaiveDate::from_ymd_opt(2024, 4, 15).unwrap();
+        sqlx::query(
+            "delete from todo_labels where todo_id in (select id from todos where user_id = $1)",
+        )
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("delete from todos where user_id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("delete from labels where owner_id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut labels = vec![];
+        for name in ["[stats_scenario] work", "[stats_scenario] unused"] {
+            let label = sqlx::query_as::<_, Label>(
+                "insert into labels (name, owner_id) values ($1, $2) returning *",
+            )
+            .bind(name)
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await
+            .expect("Failed to insert label data.");
+            labels.push(label);
+        }
+        let (work, unused) = (&labels[0], &labels[1]);
+
+        for (label_ids, due_date, completed) in [
+            (vec![work.id], today.pred_opt(), false),
+            (vec![work.id], today.pred_opt(), true),
+            (vec![], today.succ_opt(), false),
+        ] {
+            let todo = repository
+                .create(
+                    user_id,
+                    CreateTodo::new("[stats_scenario] text".to_string(), label_ids)
+                        .recurring(Recurrence::None, due_date),
+                )
+                .await
+                .expect("[create] returned Err");
+            if completed {
+                repository
+                    .update(
+                        user_id,
+                        todo.id,
+                        UpdateTodo::builder().completed(true).build(),
+                    )
+                    .await
+                    .expect("[update] returned Err");
+            }
+        }
+
+        let stats = repository
+            .stats(user_id, today)
+            .await
+            .expect("[stats] returned Err");
+        assert_eq!(
+            (stats.total, stats.open, stats.completed, stats.overdue),
+            (3, 2, 1, 1)
+        );
+        let label_stats = |id: i32| stats.labels.iter().find(|label| label.id == id).cloned();
+        assert_eq!(
+            label_stats(work.id),
+            Some(LabelStats {
+                id: work.id,
+                name: work.name.clone(),
+                total: 2,
+                open: 1,
+                completed: 1,
+            })
+        );
+        assert_eq!(
+            label_stats(unused.id),
+            Some(LabelStats {
+                id: unused.id,
+                name: unused.name.clone(),
+                total: 0,
+                open: 0,
+                completed: 0,
+            })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_update_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool);
+        let id = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[concurrent_update_scenario] text".to_string(), vec![]),
+            )
+            .await
+            .expect("[create] returned Err")
+            .id;
+
+        // 同じtodoへの2つの更新を並行させ、どちらのレスポンスも自分の更新の結果であることを確かめる
+        for round in 0..10 {
+            let updates = ["first", "second"].map(|name| {
+                let repository = repository.clone();
+                let text = format!("[concurrent_update_scenario] {} {}", name, round);
+                tokio::spawn(async move {
+                    let updated = repository
+                        .update(
+                            TEST_USER_ID,
+                            id,
+                            UpdateTodo::new(Some(text.clone()), None, None),
+                        )
+                        .await
+                        .expect("[update] returned Err");
+                    (text, updated)
+                })
+            });
+            for update in updates {
+                let (text, updated) = update.await.unwrap();
+                assert_eq!(updated.text, text);
+            }
+        }
+
+        repository
+            .delete(TEST_USER_ID, id)
+            .await
+            .expect("[delete] returned Err");
+    }
+
+    #[tokio::test]
+    async fn read_pool_scenario() {
+        use crate::repositories::label::{LabelQuery, LabelRepository, LabelRepositoryForDb};
+        use sqlx::postgres::PgPoolOptions;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let Some((database, write_pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        // replicaの代わりに同じDBへ読み取り専用で接続し、接続を取り出した回数を数える.
+        // 新しい接続ではafter_connect、使い回しではbefore_acquireが呼ばれる
+        let acquired = Arc::new(AtomicUsize::new(0));
+        let read_pool = {
+            let (connected, reused) = (acquired.clone(), acquired.clone());
+            PgPoolOptions::new()
+                .after_connect(move |conn, _| {
+                    connected.fetch_add(1, Ordering::SeqCst);
+                    Box::pin(async move {
+                        // 書き込みが向けられたらエラーになる
+                        sqlx::query("set session characteristics as transaction read only")
+                            .execute(conn)
+                            .await?;
+                        Ok(())
+                    })
+                })
+                .before_acquire(move |_, _| {
+                    reused.fetch_add(1, Ordering::SeqCst);
+                    Box::pin(async { Ok(true) })
+                })
+                // connectは最初の接続をすぐに作ってafter_connectを呼ぶので、読み取りまで遅らせる
+                .connect_lazy(database.url())
+                .expect("fail connect read database")
+        };
+        let reads = || acquired.load(Ordering::SeqCst);
+        let repository =
+            TodoRepositoryForDb::new(write_pool.clone()).with_read_pool(read_pool.clone());
+
+        // 書き込みと直後の取得はprimaryで行う
+        let todo = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[read_pool_scenario] text".to_string(), vec![]),
+            )
+            .await
+            .expect("[create] returned Err");
+        let todo = repository
+            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(reads(), 0);
+
+        assert_eq!(
+            repository
+                .find(TEST_USER_ID, todo.id)
+                .await
+                .expect("[find] returned Err"),
+            todo
+        );
+        assert_eq!(reads(), 1);
+        let todos = repository
+            .all(TEST_USER_ID, TodoQuery::default())
+            .await
+            .expect("[all] returned Err");
+        assert!(todos.contains(&todo));
+        assert_eq!(reads(), 2);
+        repository
+            .stats(TEST_USER_ID, Utc::now().date_naive())
+            .await
+            .expect("[stats] returned Err");
+        // todoとlabelの集計で1回ずつ
+        assert_eq!(reads(), 4);
+
+        let labels = LabelRepositoryForDb::new(write_pool).with_read_pool(read_pool);
+        labels
+            .all(TEST_USER_ID, LabelQuery::default())
+            .await
+            .expect("[labels all] returned Err");
+        assert_eq!(reads(), 5);
+
+        repository
+            .delete(TEST_USER_ID, todo.id)
+            .await
+            .expect("[delete] returned Err");
+        assert_eq!(reads(), 5);
+    }
+
+    #[tokio::test]
+    async fn prepared_statement_cache_scenario() {
+        use sqlx::postgres::PgPoolOptions;
+
+        let Some((database, _)) = db_pool_or_skip().await else {
+            return;
+        };
+        // 同じconnectionで繰り返し呼び、prepareが最初の1回で済んでいるかを見る
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(database.url())
+            .await
+            .expect("fail connect database");
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let todo = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new(
+                    "[prepared_statement_cache_scenario] text".to_string(),
+                    vec![],
+                ),
+            )
+            .await
+            .expect("[create] returned Err");
+
+        let prepared = || async {
+            sqlx::query_scalar::<_, i64>("select count(*) from pg_prepared_statements")
+                .fetch_one(&pool)
+                .await
+                .expect("fail count prepared statements")
+        };
+        let read = |query: TodoQuery| {
+            let repository = repository.clone();
+            async move {
+                repository
+                    .find(TEST_USER_ID, todo.id)
+                    .await
+                    .expect("[find] returned Err");
+                repository
+                    .all(TEST_USER_ID, query.clone())
+                    .await
+                    .expect("[all] returned Err");
+                repository
+                    .page(TEST_USER_ID, query)
+                    .await
+                    .expect("[page] returned Err");
+            }
+        };
+
+        // 行の無いoffsetで、pageが件数を数え直す問い合わせもprepareしておく
+        read(TodoQuery {
+            offset: Some(1),
+            ..Default::default()
+        })
+        .await;
+        let before = prepared().await;
+        // 絞り込みや並び順を変えても、SQLは同じなので新たにprepareしない
+        for query in [
+            TodoQuery::default(),
+            TodoQuery {
+                completed: Some(false),
+                q: Some("prepared".to_string()),
+                sort: Some(TodoSort::TextAsc),
+                limit: Some(10),
+                ..Default::default()
+            },
+            TodoQuery {
+                label: Some(1),
+                created_from: Some(Utc::now() - chrono::Duration::days(1)),
+                sort: Some(TodoSort::CreatedDesc),
+                offset: Some(1),
+                ..Default::default()
+            },
+        ] {
+            read(query).await;
+        }
+        assert_eq!(prepared().await, before);
+    }
+
+    #[tokio::test]
+    async fn insert_round_trip_scenario() {
+        use crate::repositories::label::{LabelRepository, LabelRepositoryForDb};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tracing::{subscriber::set_default, Event, Subscriber};
+        use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+        // sqlxが実行した文ごとに出すeventを数える
+        #[derive(Clone, Default)]
+        struct StatementCounter(Arc<AtomicUsize>);
+
+        impl<S: Subscriber> Layer<S> for StatementCounter {
+            fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+                if event.metadata().target() == "sqlx::query" {
+                    self.0.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let labels = LabelRepositoryForDb::new(pool.clone());
+        let label = labels
+            .create(
+                Some(TEST_USER_ID),
+                "[insert_round_trip_scenario] label".to_string(),
+            )
+            .await
+            .expect("[create label] returned Err");
+        let mut conn = pool.acquire().await.expect("fail acquire connection");
+        // recurrence型のoidはconnectionごとに最初のbindで1文かけて引くので、数える前に済ませておく
+        sqlx::query("select $1::text")
+            .bind(Recurrence::None)
+            .execute(&mut *conn)
+            .await
+            .expect("fail resolve recurrence type");
+
+        let counter = StatementCounter::default();
+        let statements = || counter.0.load(Ordering::SeqCst);
+        let guard = set_default(tracing_subscriber::registry().with(counter.clone()));
+        // labelが無ければinsertの1文だけ
+        let unlabeled = insert_with(
+            &mut conn,
+            TEST_USER_ID,
+            &CreateTodo::new("[insert_round_trip_scenario] unlabeled".to_string(), vec![]),
+        )
+        .await
+        .expect("[insert] returned Err");
+        assert_eq!(statements(), 1);
+        // labelがあれば利用できるかの確認が1文増える
+        let labeled = insert_with(
+            &mut conn,
+            TEST_USER_ID,
+            &CreateTodo::new(
+                "[insert_round_trip_scenario] labeled".to_string(),
+                vec![label.id],
+            ),
+        )
+        .await
+        .expect("[insert] returned Err");
+        assert_eq!(statements(), 3);
+        drop(guard);
+
+        assert!(unlabeled.labels.is_empty());
+        assert_eq!(labeled.labels, vec![label.clone()]);
+        for todo in [&unlabeled, &labeled] {
+            assert_eq!(
+                &find_with(&mut conn, TEST_USER_ID, todo.id)
+                    .await
+                    .expect("[find] returned Err"),
+                todo
+            );
+        }
+        drop(conn);
+
+        let repository = TodoRepositoryForDb::new(pool);
+        for todo in [unlabeled, labeled] {
+            repository
+                .delete(TEST_USER_ID, todo.id)
+                .await
+                .expect("[delete] returned Err");
+        }
+
+        labels
+            .delete(TEST_USER_ID, label.id)
+            .await
+            .expect("[delete label] returned Err");
+    }
+
+    #[tokio::test]
+    async fn find_keeps_source_of_transient_error() {
+        use crate::repositories::retry::is_transient;
+
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let mut conn = pool.acquire().await.expect("fail acquire connection");
+        let pid = sqlx::query_scalar::<_, i32>("select pg_backend_pid()")
+            .fetch_one(&mut *conn)
+            .await
+            .expect("fail get backend pid");
+        // 別の接続から切断し、接続断のエラーを起こす
+        sqlx::query("select pg_terminate_backend($1)")
+            .bind(pid)
+            .execute(&pool)
+            .await
+            .expect("fail terminate backend");
+
+        let err = find_with(&mut conn, TEST_USER_ID, 1)
+            .await
+            .expect_err("[find] on a terminated connection returned Ok");
+        assert!(is_transient(&err), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn reads_are_retried_after_dropped_connections_but_writes_are_not() {
+        use crate::repositories::retry::is_transient;
+        use sqlx::postgres::PgPoolOptions;
+
+        let Some((database, admin)) = db_pool_or_skip().await else {
+            return;
+        };
+        // 使い回す前のpingをせず、切断済みの接続をそのまま渡すpool
+        let pool = PgPoolOptions::new()
+            .max_connections(2)
+            .test_before_acquire(false)
+            .connect_lazy(database.url())
+            .expect("fail connect database");
+        // poolに接続をcount個開いて戻してから、別の接続から切断する
+        let drop_connections = |count: usize| {
+            let (pool, admin) = (pool.clone(), admin.clone());
+            async move {
+                // 接続はdropの後で非同期にpoolへ戻るので、使い終わった接続が全て戻るまで待つ
+                let settle = || async {
+                    while pool.num_idle() < pool.size() as usize {
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                };
+                settle().await;
+                let mut conns = Vec::new();
+                let mut pids = Vec::new();
+                for _ in 0..count {
+                    let mut conn = pool.acquire().await.expect("fail acquire connection");
+                    pids.push(
+                        sqlx::query_scalar::<_, i32>("select pg_backend_pid()")
+                            .fetch_one(&mut *conn)
+                            .await
+                            .expect("fail get backend pid"),
+                    );
+                    conns.push(conn);
+                }
+                drop(conns);
+                settle().await;
+                sqlx::query("select pg_terminate_backend(pid) from unnest($1::int[]) as pid")
+                    .bind(&pids)
+                    .execute(&admin)
+                    .await
+                    .expect("fail terminate backend");
+                // 切断はsignalで非同期に行われるので、接続が消えるまで待つ
+                while sqlx::query_scalar::<_, bool>(
+                    "select exists(select 1 from pg_stat_activity where pid = any($1))",
+                )
+                .bind(&pids)
+                .fetch_one(&admin)
+                .await
+                .expect("fail find backend")
+                {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone()).with_retry_policy(RetryPolicy {
+            max_retries: 2,
+            base_backoff: Duration::from_millis(1),
+        });
+        let todo = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[retry] text".to_string(), vec![]),
+            )
+            .await
+            .expect("[create] returned Err");
+
+        // 2回失敗した後、新しい接続で成功する
+        drop_connections(2).await;
+        assert_eq!(
+            repository
+                .find(TEST_USER_ID, todo.id)
+                .await
+                .expect("[find] returned Err"),
+            todo
+        );
+
+        // 書き込みは1回目の失敗で返し、作り直さない
+        drop_connections(1).await;
+        let err = repository
+            .create(
+                TEST_USER_ID,
+                CreateTodo::new("[retry] text".to_string(), vec![]),
+            )
+            .await
+            .expect_err("[create] on a terminated connection returned Ok");
+        assert!(is_transient(&err), "{:?}", err);
+        let count = sqlx::query_scalar::<_, i64>("select count(*) from todos")
+            .fetch_one(&admin)
+            .await
+            .expect("fail count todos");
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn timestamp_round_trip_in_non_utc_session() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let remind_at = DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let mut conn = pool.acquire().await.unwrap();
+        sqlx::query("set time zone 'Asia/Tokyo'")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let id = insert_with(
+            &mut conn,
+            TEST_USER_ID,
+            &CreateTodo::new("[timestamp_round_trip] text".to_string(), vec![])
+                .remind_at(remind_at),
+        )
+        .await
+        .expect("[insert] returned Err")
+        .id;
+
+        // セッションのタイムゾーンでは+09として表示される
+        let local: String = sqlx::query_scalar("select remind_at::text from todos where id = $1")
+            .bind(id)
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(local, "2024-01-01 21:00:00+09");
+
+        let todo = find_with(&mut conn, TEST_USER_ID, id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(todo.remind_at, Some(remind_at));
+        drop(conn);
+
+        TodoRepositoryForDb::new(pool)
+            .delete(TEST_USER_ID, id)
+            .await
+            .expect("[delete] returned Err");
+    }
+
+    #[tokio::test]
+    async fn page_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let user_id = 884_002;
+        sqlx::query("delete from todos where user_id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        for text in ["buy milk", "buy eggs", "call mom", "buy bread"] {
+            repository
+                .create(user_id, CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("[create] returned Err");
+        }
+        let milk = repository
+            .all(user_id, TodoQuery::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|todo| todo.text == "buy milk")
+            .unwrap();
+        repository
+            .update(user_id, milk.id, UpdateTodo::completion(true))
+            .await
+            .expect("[update] returned Err");
+
+        let query = |completed, q: &str, limit, offset| TodoQuery {
+            completed,
+            q: Some(q.to_string()),
+            limit,
+            offset,
+            ..Default::default()
+        };
+        for (query, len, total) in [
+            (query(None, "buy", Some(2), None), 2, 3),
+            (query(Some(false), "buy", Some(1), None), 1, 2),
+            (query(None, "", None, Some(3)), 1, 4),
+            // offsetが件数を超えても件数を返す
+            (query(None, "buy", Some(2), Some(10)), 0, 3),
+            (query(None, "nothing", None, None), 0, 0),
+        ] {
+            let page = repository
+                .page(user_id, query.clone())
+                .await
+                .expect("[page] returned Err");
+            assert_eq!(page.todos.len(), len, "{:?}", query);
+            assert_eq!(page.total, total, "{:?}", query);
+            assert_eq!(
+                page.todos,
+                repository.all(user_id, query.clone()).await.unwrap(),
+                "{:?}",
+                query
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn created_range_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let user_id = 890_002;
+        sqlx::query("delete from todos where user_id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let day = |d: i64| test_now() + TimeDelta::days(d - 1);
+        let mut todos = vec![];
+        for d in 1..=3 {
+            let todo = repository
+                .create(user_id, CreateTodo::new(format!("day {}", d), vec![]))
+                .await
+                .expect("[create] returned Err");
+            sqlx::query("update todos set created_at = $1 where id = $2")
+                .bind(day(d))
+                .bind(todo.id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            todos.push(todo.id);
+        }
+
+        // 境界の日時ちょうどに作成されたtodoも含む
+        for (from, to, expected) in [
+            (Some(day(1)), Some(day(2)), &todos[..2]),
+            (Some(day(2)), Some(day(2)), &todos[1..2]),
+            (Some(day(2)), None, &todos[1..]),
+            (None, Some(day(1)), &todos[..1]),
+        ] {
+            let query = TodoQuery {
+                created_from: from,
+                created_to: to,
+                sort: Some(TodoSort::IdAsc),
+                ..Default::default()
+            };
+            let ids: Vec<i32> = repository
+                .all(user_id, query.clone())
+                .await
+                .expect("[all] returned Err")
+                .iter()
+                .map(|todo| todo.id)
+                .collect();
+            assert_eq!(ids, expected, "{:?}", query);
+            let count = repository.count(user_id, query.clone()).await.unwrap();
+            assert_eq!(count, expected.len() as i64, "{:?}", query);
+        }
+
+        let inverted = TodoQuery {
+            created_from: Some(day(3)),
+            created_to: Some(day(1)),
+            ..Default::default()
+        };
+        let err = repository.all(user_id, inverted).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::InvalidQuery(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn update_where_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let user_id = 883_002;
+        sqlx::query("delete from todos where user_id = any($1)")
+            .bind([user_id, user_id + 1])
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut ids = vec![];
+        for text in ["buy milk", "call mom", "buy bread"] {
+            let todo = repository
+                .create(user_id, CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("[create] returned Err");
+            ids.push(todo.id);
+        }
+        let other = repository
+            .create(user_id + 1, CreateTodo::new("buy tea".to_string(), vec![]))
+            .await
+            .expect("[create] returned Err");
+        repository
+            .update(user_id, ids[2], UpdateTodo::completion(true))
+            .await
+            .expect("[update] returned Err");
+        let completed_at = repository.find(user_id, ids[2]).await.unwrap().completed_at;
+
+        let filter = TodoQuery {
+            q: Some("buy".to_string()),
+            ..Default::default()
+        };
+        let updated = repository
+            .update_where(user_id, filter.clone(), BulkUpdateTodo { completed: true })
+            .await
+            .expect("[update_where] returned Err");
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].id, ids[0]);
+        assert!(updated[0].completed && updated[0].completed_at.is_some());
+
+        // 絞り込みに当てはまらないtodo、既に完了していたtodo、他のユーザーのtodoは変わらない
+        assert!(!repository.find(user_id, ids[1]).await.unwrap().completed);
+        assert_eq!(
+            repository.find(user_id, ids[2]).await.unwrap().completed_at,
+            completed_at
+        );
+        assert!(
+            !repository
+                .find(user_id + 1, other.id)
+                .await
+                .unwrap()
+                .completed
+        );
+
+        let reopened = repository
+            .update_where(user_id, filter, BulkUpdateTodo { completed: false })
+            .await
+            .expect("[update_where] returned Err");
+        assert_eq!(
+            reopened.iter().map(|todo| todo.id).collect::<Vec<_>>(),
+            vec![ids[0], ids[2]]
+        );
+        assert!(reopened.iter().all(|todo| todo.completed_at.is_none()));
+    }
+
+    #[tokio::test]
+    async fn timeseries_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let user_id = 849_002;
+        sqlx::query("delete from todos where user_id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let todo = repository
+            .create(
+                user_id,
+                CreateTodo::new("[timeseries_scenario] text".to_string(), vec![]),
+            )
+            .await
+            .expect("[create] returned Err");
+        sqlx::query(
+            "update todos set created_at = '2024-12-31T23:30:00Z', completed_at = '2025-01-06T00:00:00Z' where id = $1",
+        )
+        .bind(todo.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let points = repository
+            .timeseries(
+                user_id,
+                TimeseriesQuery {
+                    from: NaiveDate::from_ymd_opt(2024, 12, 25).unwrap(),
+                    to: NaiveDate::from_ymd_opt(2025, 1, 6).unwrap(),
+                    bucket: TimeseriesBucket::Week,
+                },
+            )
+            .await
+            .expect("[timeseries] returned Err");
+        let counts: Vec<(NaiveDate, i64, i64)> = points
+            .iter()
+            .map(|point| (point.date, point.created, point.completed))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (NaiveDate::from_ymd_opt(2024, 12, 23).unwrap(), 0, 0),
+                (NaiveDate::from_ymd_opt(2024, 12, 30).unwrap(), 1, 0),
+                (NaiveDate::from_ymd_opt(2025, 1, 6).unwrap(), 0, 1),
+            ]
+        );
+
+        repository
+            .delete(user_id, todo.id)
+            .await
+            .expect("[delete] returned Err");
+    }
+}
+
+#[cfg(any(test, feature = "memory"))]
+pub mod test_utils {
+    use super::memory::TodoRepositoryForMemory;
+    use super::*;
+    use crate::repositories::label::memory::LabelRepositoryForMemory;
+
+    /// テストで使うデフォルトのユーザー
+    pub const TEST_USER_ID: i32 = 1;
+
+    /// TodoRepositoryForMemoryがidから決める公開id. テストで作成結果を予測できるようにする
+    pub fn test_public_id(id: i32) -> Uuid {
+        memory::public_id(id)
+    }
+
+    /// TodoRepositoryForMemory::for_testが最初に返す現在時刻
+    pub fn test_now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    impl TodoEntity {
+        pub fn new(id: i32, text: String, labels: Vec<Label>) -> Self {
+            Self {
+                id,
+                public_id: test_public_id(id),
+                user_id: TEST_USER_ID,
+                text,
+                completed: false,
+                labels,
+                due_date: None,
+                recurrence: Recurrence::None,
+                recurrence_anchor: None,
+                remind_at: None,
+                reminded_at: None,
+                created_at: test_now(),
+                completed_at: None,
+                updated_at: test_now(),
+                parent_id: None,
+                next_occurrence: None,
+            }
+        }
+    }
+
+    /// 以前のfold_entities. todoごとにそれまでの結果を走査する. 比較とベンチマークのために残す
+    #[allow(clippy::while_let_on_iterator, clippy::unnecessary_unwrap)]
+    pub fn fold_entities_nested(rows: Vec<TodoWithLabelFromRow>) -> Vec<TodoEntity> {
+        let mut rows = rows.iter();
+        let mut accum: Vec<TodoEntity> = vec![];
+        'outer: while let Some(row) = rows.next() {
+            let mut todos = accum.iter_mut();
+            while let Some(todo) = todos.next() {
+                // idが一致=Todoに紐づくラベルが複数存在している
+                if todo.id == row.id {
+                    todo.labels.push(Label {
+                        id: row.label_id.unwrap(),
+                        name: row.label_name.clone().unwrap(),
+                        owner_id: row.label_owner_id,
+                    });
+                    continue 'outer;
+                }
+            }
+
+            // Todoのidに一致がなかった時のみ到達、TodoEntityを作成
+            let labels = if row.label_id.is_some() {
+                vec![Label {
+                    id: row.label_id.unwrap(),
+                    name: row.label_name.clone().unwrap(),
+                    owner_id: row.label_owner_id,
+                }]
+            } else {
+                vec![]
+            };
+
+            accum.push(TodoEntity {
+                id: row.id,
+                public_id: row.public_id,
+                user_id: row.user_id,
+                text: row.text.clone(),
+                completed: row.completed,
+                labels,
+                due_date: row.due_date,
+                recurrence: row.recurrence,
+                recurrence_anchor: row.recurrence_anchor,
+                remind_at: row.remind_at,
+                reminded_at: row.reminded_at,
+                created_at: row.created_at,
+                completed_at: row.completed_at,
+                updated_at: row.updated_at,
+                parent_id: row.parent_id,
+                next_occurrence: None,
+            });
+        }
+        accum
+    }
+
+    impl TodoWithLabelFromRow {
+        /// todoをlabelと結合した行. labelの無いtodoはlabelの列がnullの1行になる
+        pub fn rows_of(todo: &TodoEntity) -> Vec<Self> {
+            let row = |label: Option<&Label>| Self {
+                id: todo.id,
+                public_id: todo.public_id,
+                user_id: todo.user_id,
+                text: todo.text.clone(),
+                completed: todo.completed,
+                due_date: todo.due_date,
+                recurrence: todo.recurrence,
+                recurrence_anchor: todo.recurrence_anchor,
+                remind_at: todo.remind_at,
+                reminded_at: todo.reminded_at,
+                created_at: todo.created_at,
+                completed_at: todo.completed_at,
+                updated_at: todo.updated_at,
+                parent_id: todo.parent_id,
+                label_id: label.map(|label| label.id),
+                label_name: label.map(|label| label.name.clone()),
+                label_owner_id: label.and_then(|label| label.owner_id),
+                total_count: None,
+            };
+            if todo.labels.is_empty() {
+                return vec![row(None)];
+            }
+            todo.labels.iter().map(|label| row(Some(label))).collect()
+        }
+    }
+
+    impl CreateTodo {
+        pub fn recurring(self, recurrence: Recurrence, due_date: Option<NaiveDate>) -> Self {
+            Self {
+                recurrence,
+                due_date,
+                ..self
+            }
+        }
+
+        pub fn remind_at(self, remind_at: DateTime<Utc>) -> Self {
+            Self {
+                remind_at: Some(remind_at),
+                ..self
+            }
+        }
+
+        pub fn subtask_of(self, parent_id: i32) -> Self {
+            Self {
+                parent_id: Some(parent_id),
+                ..self
+            }
+        }
+    }
+
+    impl UpdateTodo {
+        /// 指定した項目だけを変更するUpdateTodoを組み立てる
+        pub fn builder() -> UpdateTodoBuilder {
+            UpdateTodoBuilder(Self::new(None, None, None))
+        }
+    }
+
+    // Noneは省略(Undefined)ではなくnull(値を消す)にする
+    fn value_or_null<T>(value: Option<T>) -> MaybeUndefined<T> {
+        value.map_or(MaybeUndefined::Null, MaybeUndefined::Value)
+    }
+
+    /// テスト用のUpdateTodoのbuilder. 呼ばなかった項目は変更しない
+    #[derive(Debug, Clone)]
+    pub struct UpdateTodoBuilder(UpdateTodo);
+
+    impl UpdateTodoBuilder {
+        pub fn text(mut self, text: impl Into<String>) -> Self {
+            self.0.text = Some(text.into());
+            self
+        }
+
+        pub fn completed(mut self, completed: bool) -> Self {
+            self.0.completed = Some(completed);
+            self
+        }
+
+        pub fn labels(mut self, labels: Vec<i32>) -> Self {
+            self.0.labels = Some(labels);
+            self
+        }
+
+        /// Noneなら期限を外す
+        pub fn due_date(mut self, due_date: Option<NaiveDate>) -> Self {
+            self.0.due_date = value_or_null(due_date);
+            self
+        }
+
+        pub fn recurrence(mut self, recurrence: Recurrence) -> Self {
+            self.0.recurrence = Some(recurrence);
+            self
+        }
+
+        /// Noneならリマインダーを外す
+        pub fn remind_at(mut self, remind_at: Option<DateTime<Utc>>) -> Self {
+            self.0.remind_at = value_or_null(remind_at);
+            self
+        }
+
+        /// Noneなら親から外す
+        pub fn parent_id(mut self, parent_id: Option<i32>) -> Self {
+            self.0.parent_id = value_or_null(parent_id);
+            self
+        }
+
+        pub fn complete_subtasks(mut self, complete_subtasks: bool) -> Self {
+            self.0.complete_subtasks = Some(complete_subtasks);
+            self
+        }
+
+        pub fn build(self) -> UpdateTodo {
+            self.0
+        }
+    }
+
+    impl TodoRepositoryForMemory {
+        /// labelsだけを持ったlabelのrepositoryを使い、現在時刻をtest_nowに固定したrepository
+        pub fn for_test(labels: Vec<Label>) -> Self {
+            let repository =
+                Self::new().with_labels(&LabelRepositoryForMemory::with_labels(labels));
+            repository.set_now(test_now());
+            repository
+        }
+    }
+}
+
+#[cfg(any(test, feature = "memory"))]
+pub mod memory {
+    use anyhow::Context;
+    use axum::async_trait;
+    use std::{
+        cmp::Reverse,
+        collections::HashMap,
+        fs, io,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicI32, Ordering},
+            Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        },
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::repositories::{
+        activity::memory::ActivityRepositoryForMemory, label::memory::LabelRepositoryForMemory,
+    };
+
+    /// 指定するとTodoRepositoryForMemoryの内容をこのパスのJSONに保存し、次の起動時に読み込む
+    pub const SNAPSHOT_PATH: &str = "SNAPSHOT_PATH";
+
+    /// 変更をまとめてsnapshotに書き出すまでの待ち時間
+    pub const SNAPSHOT_DEBOUNCE: Duration = Duration::from_millis(500);
+
+    // idから決める公開id. DBと違い、作成結果を予測できる
+    pub(crate) fn public_id(id: i32) -> Uuid {
+        Uuid::from_u128(id as u128)
+    }
+
+    // 期日や親の無い、nowに作成したtodo
+    fn new_todo(
+        id: i32,
+        user_id: i32,
+        text: String,
+        labels: Vec<Label>,
+        now: DateTime<Utc>,
+    ) -> TodoEntity {
+        TodoEntity {
+            id,
+            public_id: public_id(id),
+            user_id,
+            text,
+            completed: false,
+            labels,
+            due_date: None,
+            recurrence: Recurrence::None,
+            recurrence_anchor: None,
+            remind_at: None,
+            reminded_at: None,
+            created_at: now,
+            completed_at: None,
+            updated_at: now,
+            parent_id: None,
+            next_occurrence: None,
+        }
+    }
+
+    impl UpdateTodo {
+        // 親の付け替えや、完了時のsubtask・繰り返しの次回分のように、他のtodoを読み書きしうる変更か
+        fn touches_other_todos(&self) -> bool {
+            self.parent_id.is_value() || self.completed == Some(true)
+        }
+    }
+
+    impl TodoSort {
+        // DBのorder by句と同じ順序でメモリ上のtodoを並び替える
+        fn sort(&self, todos: &mut [TodoEntity]) {
+            match self {
+                TodoSort::IdAsc => todos.sort_by_key(|todo| todo.id),
+                TodoSort::IdDesc => todos.sort_by_key(|todo| Reverse(todo.id)),
+                TodoSort::TextAsc => {
+                    todos.sort_by(|a, b| a.text.cmp(&b.text).then(a.id.cmp(&b.id)))
+                }
+                TodoSort::TextDesc => {
+                    todos.sort_by(|a, b| b.text.cmp(&a.text).then(b.id.cmp(&a.id)))
+                }
+                TodoSort::CreatedAsc => todos.sort_by_key(|todo| (todo.created_at, todo.id)),
+                TodoSort::CreatedDesc => {
+                    todos.sort_by_key(|todo| Reverse((todo.created_at, todo.id)))
+                }
+            }
+        }
+    }
+
+    impl TodoQuery {
+        // DBのwhere句と同じ条件でメモリ上のtodoを絞り込む. qは大文字小文字を区別しない
+        fn matches(&self, todo: &TodoEntity) -> bool {
+            self.completed
+                .is_none_or(|completed| todo.completed == completed)
+                && self
+                    .label
+                    .is_none_or(|label| todo.labels.iter().any(|l| l.id == label))
+                && self
+                    .q
+                    .as_ref()
+                    .is_none_or(|q| todo.text.to_lowercase().contains(q.to_lowercase().as_str()))
+                && self.created_from.is_none_or(|from| todo.created_at >= from)
+                && self.created_to.is_none_or(|to| todo.created_at <= to)
+        }
+    }
+
+    type TodoDatas = HashMap<i32, TodoEntity>;
+
+    /// TodoStoreのshardの数
+    const STORE_SHARDS: usize = 16;
+
+    // ロック中にpanicしたスレッドがあっても、以降の読み書きは続ける
+    fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+        lock.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+        lock.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // todoをidでshardに分けて持つ. 別のshardのtodoへの書き込みは互いに待たない
+    #[derive(Debug)]
+    struct TodoStore {
+        shards: Vec<RwLock<TodoDatas>>,
+        // 最後に払い出したid. 削除されたidを再利用しないよう、shardとは別に数える
+        last_id: AtomicI32,
+    }
+
+    impl Default for TodoStore {
+        fn default() -> Self {
+            Self {
+                shards: (0..STORE_SHARDS).map(|_| RwLock::default()).collect(),
+                last_id: AtomicI32::new(0),
+            }
+        }
+    }
+
+    impl TodoStore {
+        fn shard_of(id: i32) -> usize {
+            id.rem_euclid(STORE_SHARDS as i32) as usize
+        }
+
+        fn next_id(&self) -> i32 {
+            self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+        }
+
+        // 全てのtodoを置き換える. 以降のidは読み込んだtodoのidの最大値の次から払い出す
+        fn replace(&self, todos: Vec<TodoEntity>) {
+            let mut locked = self.lock_all();
+            for shard in locked.guards.iter_mut() {
+                shard.1.clear();
+            }
+            let last_id = todos.iter().map(|todo| todo.id).max().unwrap_or(0);
+            for todo in todos {
+                locked.insert(todo);
+            }
+            self.last_id.fetch_max(last_id, Ordering::SeqCst);
+        }
+
+        fn get(&self, id: i32) -> Option<TodoEntity> {
+            read_lock(&self.shards[Self::shard_of(id)])
+                .get(&id)
+                .cloned()
+        }
+
+        // shardを1つずつ読み、条件に当てはまるtodoだけをcloneする
+        fn filter(&self, predicate: impl Fn(&TodoEntity) -> bool) -> Vec<TodoEntity> {
+            self.shards
+                .iter()
+                .flat_map(|shard| {
+                    read_lock(shard)
+                        .values()
+                        .filter(|todo| predicate(todo))
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        }
+
+        fn count(&self, predicate: impl Fn(&TodoEntity) -> bool) -> usize {
+            self.shards
+                .iter()
+                .map(|shard| {
+                    read_lock(shard)
+                        .values()
+                        .filter(|todo| predicate(todo))
+                        .count()
+                })
+                .sum()
+        }
+
+        // shardを1つずつ書き換え、変更したtodoを返す
+        fn update_each(&self, mut update: impl FnMut(&mut TodoEntity) -> bool) -> Vec<TodoEntity> {
+            let mut updated: Vec<TodoEntity> = self
+                .shards
+                .iter()
+                .flat_map(|shard| {
+                    write_lock(shard)
+                        .values_mut()
+                        .filter_map(|todo| update(todo).then(|| todo.clone()))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            updated.sort_by_key(|todo| todo.id);
+            updated
+        }
+
+        // idのshardだけを書き込みロックする
+        fn lock(&self, id: i32) -> LockedTodos<'_> {
+            let index = Self::shard_of(id);
+            LockedTodos {
+                guards: vec![(index, write_lock(&self.shards[index]))],
+            }
+        }
+
+        // 全てのshardを書き込みロックする. 常に同じ順にロックするのでデッドロックしない
+        fn lock_all(&self) -> LockedTodos<'_> {
+            LockedTodos {
+                guards: self.shards.iter().map(write_lock).enumerate().collect(),
+            }
+        }
+    }
+
+    // 書き込みロックを取ったshardのtodo. ロックしていないshardのidを渡すのは呼び出し側の誤り
+    struct LockedTodos<'a> {
+        guards: Vec<(usize, RwLockWriteGuard<'a, TodoDatas>)>,
+    }
+
+    impl<'a> LockedTodos<'a> {
+        fn shard(&self, id: i32) -> &TodoDatas {
+            let index = TodoStore::shard_of(id);
+            self.guards
+                .iter()
+                .find(|(i, _)| *i == index)
+                .map(|(_, guard)| &**guard)
+                .unwrap_or_else(|| panic!("shard of todo {} is not locked", id))
+        }
+
+        fn shard_mut(&mut self, id: i32) -> &mut TodoDatas {
+            let index = TodoStore::shard_of(id);
+            self.guards
+                .iter_mut()
+                .find(|(i, _)| *i == index)
+                .map(|(_, guard)| &mut **guard)
+                .unwrap_or_else(|| panic!("shard of todo {} is not locked", id))
+        }
+
+        fn get(&self, id: &i32) -> Option<&TodoEntity> {
+            self.shard(*id).get(id)
+        }
+
+        fn get_mut(&mut self, id: &i32) -> Option<&mut TodoEntity> {
+            self.shard_mut(*id).get_mut(id)
+        }
+
+        fn insert(&mut self, todo: TodoEntity) {
+            self.shard_mut(todo.id).insert(todo.id, todo);
+        }
+
+        fn remove(&mut self, id: &i32) -> Option<TodoEntity> {
+            self.shard_mut(*id).remove(id)
+        }
+
+        fn values(&self) -> impl Iterator<Item = &TodoEntity> {
+            self.guards.iter().flat_map(|(_, guard)| guard.values())
+        }
+
+        fn values_mut(&mut self) -> impl Iterator<Item = &mut TodoEntity> + use<'_, 'a> {
+            self.guards
+                .iter_mut()
+                .flat_map(|(_, guard)| guard.values_mut())
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct TodoRepositoryForMemory {
+        store: Arc<TodoStore>,
+        // todoに付けられるlabel. with_labelsで渡したものと共有する
+        labels: LabelRepositoryForMemory,
+        // 作成・完了日時に使う時刻. Noneなら実際の時刻
+        now: Arc<RwLock<Option<DateTime<Utc>>>>,
+        default_sort: TodoSort,
+        snapshot: Option<Snapshot>,
+        activity: Option<MemoryActivityLog>,
+    }
+
+    // todoの変更と同じロックの中で書き込む記録先と、ロックを外した後に知らせる先
+    #[derive(Debug, Clone)]
+    struct MemoryActivityLog {
+        events: ActivityRepositoryForMemory,
+        publish: ActivityLog,
+    }
+
+    #[derive(Debug, Clone)]
+    struct Snapshot {
+        path: PathBuf,
+        // 変更を書き出しタスクへ知らせる. 溢れた通知は書き出し待ちにまとめる
+        changes: mpsc::Sender<()>,
+    }
+
+    impl Default for TodoRepositoryForMemory {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl TodoRepositoryForMemory {
+        /// 実際の時刻を使い、labelは自身だけが持つ空のrepositoryから探す
+        pub fn new() -> Self {
+            TodoRepositoryForMemory {
+                store: Arc::default(),
+                labels: LabelRepositoryForMemory::new(),
+                now: Arc::default(),
+                default_sort: TodoSort::default(),
+                snapshot: None,
+                activity: None,
+            }
+        }
+
+        /// todoに付けるlabelをlabelsから探す. /labelsで作成したlabelを付けられるよう、
+        /// サーバーにも同じlabelsを渡す
+        pub fn with_labels(self, labels: &LabelRepositoryForMemory) -> Self {
+            Self {
+                labels: labels.clone(),
+                ..self
+            }
+        }
+
+        /// SNAPSHOT_PATHが設定されていればwith_snapshotと同じ
+        pub fn with_snapshot_from_env(self) -> Self {
+            match env::var(SNAPSHOT_PATH) {
+                Ok(path) => self.with_snapshot(path),
+                Err(_) => self,
+            }
+        }
+
+        /// pathのsnapshotを読み込み、以降の変更をSNAPSHOT_DEBOUNCEごとに書き出す.
+        /// 読めないsnapshotはログに残して空の状態から始める
+        pub fn with_snapshot(self, path: impl Into<PathBuf>) -> Self {
+            let path = path.into();
+            match load_snapshot(&path) {
+                Ok(todos) => self.store.replace(todos),
+                Err(e) => tracing::warn!(
+                    "failed to load snapshot [{}], starting empty: {:#}",
+                    path.display(),
+                    e
+                ),
+            }
+            let (tx, rx) = mpsc::channel(1);
+            tokio::spawn(write_snapshots(self.store.clone(), path.clone(), rx));
+            Self {
+                snapshot: Some(Snapshot { path, changes: tx }),
+                ..self
+            }
+        }
+
+        /// 書き出し待ちを待たずに今の状態をsnapshotに書き出す.
+        /// プロセスの終了で書き出しタスクごと止まる前に呼ぶ
+        pub fn flush_snapshot(&self) -> anyhow::Result<()> {
+            match &self.snapshot {
+                Some(snapshot) => save_snapshot(&snapshot.path, &self.store),
+                None => Ok(()),
+            }
+        }
+
+        // snapshotの書き出しを予約する. 書き出し待ちがあればそれに含まれる
+        fn changed(&self) {
+            if let Some(snapshot) = &self.snapshot {
+                snapshot.changes.try_send(()).ok();
+            }
+        }
+
+        pub fn with_default_sort(self, default_sort: TodoSort) -> Self {
+            Self {
+                default_sort,
+                ..self
+            }
+        }
+
+        /// 以降の作成・完了日時を実際の時刻ではなくnowにする
+        pub fn set_now(&self, now: DateTime<Utc>) {
+            *write_lock(&self.now) = Some(now);
+        }
+
+        fn now(&self) -> DateTime<Utc> {
+            read_lock(&self.now).unwrap_or_else(Utc::now)
+        }
+
+        /// 作成・更新・削除をeventsにも記録し、undoで取り消せるようにする.
+        /// 記録はtodoの変更と同じロックの中で書き込み、ロックを外した後にactivityの購読者へ知らせる.
+        /// activityはeventsそのものか、eventsを包んだもの(PublishingActivityなど)を渡す
+        pub fn with_activity<A: ActivityRepository>(
+            self,
+            events: &ActivityRepositoryForMemory,
+            activity: A,
+        ) -> Self {
+            Self {
+                activity: Some(MemoryActivityLog {
+                    events: events.clone(),
+                    publish: ActivityLog(Arc::new(move |event| activity.publish(event))),
+                }),
+                ..self
+            }
+        }
+
+        // eventsを監査ログに残すものとし、記録する設定ならtodoのロックを持ったまま記録する
+        fn log(&self, events: impl IntoIterator<Item = NewTodoEvent>) -> Logged {
+            let events: Vec<NewTodoEvent> = events.into_iter().collect();
+            Logged {
+                audit: events.iter().map(AuditEntry::from_event).collect(),
+                events: match &self.activity {
+                    Some(activity) => {
+                        let mut locked = activity.events.lock();
+                        events.into_iter().map(|event| locked.push(event)).collect()
+                    }
+                    None => vec![],
+                },
+            }
+        }
+
+        // todoのロックを外した後に、logの変更を監査ログに残し、記録したものを知らせる
+        fn publish(&self, logged: &Logged) {
+            logged.audit.iter().for_each(AuditEntry::emit);
+            if let Some(MemoryActivityLog {
+                publish: ActivityLog(publish),
+                ..
+            }) = &self.activity
+            {
+                logged.events.iter().for_each(|event| publish(event));
+            }
+        }
+
+        // todoをtodo.idのまま書き戻す. 親が削除済みならsubtaskではなくなる
+        fn restore_with(
+            &self,
+            store: &mut LockedTodos,
+            user_id: i32,
+            todo: TodoEntity,
+        ) -> anyhow::Result<TodoEntity> {
+            if store
+                .get(&todo.id)
+                .is_some_and(|stored| stored.user_id != user_id)
+            {
+                return Err(RepositoryError::NotFound(todo.id).into());
+            }
+            let label_ids = todo.labels.iter().map(|label| label.id).collect();
+            let parent_id = todo.parent_id.filter(|parent_id| {
+                store
+                    .get(parent_id)
+                    .is_some_and(|parent| parent.user_id == user_id)
+            });
+            let todo = TodoEntity {
+                user_id,
+                labels: self.resolve_labels(user_id, label_ids)?,
+                parent_id,
+                updated_at: self.now(),
+                next_occurrence: None,
+                ..todo
+            };
+            store.insert(todo.clone());
+            Ok(todo)
+        }
+
+        // queryに当てはまるtodoをallの順に並べ、limit, offsetを適用する
+        fn select(&self, user_id: i32, query: &TodoQuery) -> Vec<TodoEntity> {
+            let mut todos = self
+                .store
+                .filter(|todo| todo.user_id == user_id && query.matches(todo));
+            query.sort.unwrap_or(self.default_sort).sort(&mut todos);
+
+            let offset = query.offset.unwrap_or(0).max(0) as usize;
+            let limit = query
+                .limit
+                .map_or(usize::MAX, |limit| limit.max(0) as usize);
+            todos.into_iter().skip(offset).take(limit).collect()
+        }
+
+        // user_idから参照できないlabelが含まれていればLabelNotAvailableを返す
+        fn resolve_labels(&self, user_id: i32, labels: Vec<i32>) -> anyhow::Result<Vec<Label>> {
+            labels
+                .iter()
+                .map(|id| {
+                    self.labels
+                        .find_visible(user_id, *id)
+                        .ok_or(RepositoryError::LabelNotAvailable(*id).into())
+                })
+                .collect()
+        }
+    }
+
+    // parent_idがuser_idのtodoで、idのtodo自身やその子孫でないか確認する. 作成時はidがNone
+    fn ensure_parent_available(
+        store: &LockedTodos,
+        user_id: i32,
+        id: Option<i32>,
+        parent_id: i32,
+    ) -> anyhow::Result<()> {
+        store
+            .get(&parent_id)
+            .filter(|todo| todo.user_id == user_id)
+            .ok_or(RepositoryError::InvalidParent(parent_id))?;
+        if id.is_some_and(|id| would_create_cycle(store, id, parent_id)) {
+            return Err(RepositoryError::InvalidParent(parent_id).into());
+        }
+        Ok(())
+    }
+
+    // new_parentから親をMAX_PARENT_DEPTHまで辿り、idに行き着くか上限を超えるならtrue
+    fn would_create_cycle(store: &LockedTodos, id: i32, new_parent: i32) -> bool {
+        let mut ancestor = Some(new_parent);
+        for _ in 0..MAX_PARENT_DEPTH {
+            match ancestor {
+                Some(ancestor_id) if ancestor_id == id => return true,
+                Some(ancestor_id) => {
+                    ancestor = store.get(&ancestor_id).and_then(|todo| todo.parent_id)
+                }
+                None => return false,
+            }
+        }
+        ancestor.is_some()
+    }
+
+    // idのtodoを削除し、削除したtodoを返す.
+    // DBの外部キー(on delete set null)と同じく、subtaskは親の無いtodoになる
+    fn remove_with(
+        store: &mut LockedTodos,
+        user_id: i32,
+        id: i32,
+    ) -> anyhow::Result<(TodoEntity, Vec<i32>)> {
+        if store.get(&id).is_none_or(|todo| todo.user_id != user_id) {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        let removed = store.remove(&id).unwrap();
+        let mut detached = vec![];
+        for todo in store.values_mut() {
+            if todo.parent_id == Some(id) {
+                todo.parent_id = None;
+                detached.push(todo.id);
+            }
+        }
+        detached.sort_unstable();
+        Ok((removed, detached))
+    }
+
+    // DBのundo_completion_withと同じく、完了で作った次回分を削除し、
+    // 一緒に完了にしたsubtaskを未完了に戻して、行った変更の記録を返す
+    fn undo_completion_with(
+        store: &mut LockedTodos,
+        user_id: i32,
+        diff: &TodoDiff,
+        now: DateTime<Utc>,
+    ) -> Vec<NewTodoEvent> {
+        let mut events = vec![];
+        if let Some(spawned_id) = diff.spawned_id {
+            if let Ok((spawned, detached)) = remove_with(store, user_id, spawned_id) {
+                events.push(NewTodoEvent::deleted(user_id, spawned, detached));
+            }
+        }
+        for id in &diff.completed_subtasks {
+            let Some(todo) = store
+                .get_mut(id)
+                .filter(|todo| todo.user_id == user_id && todo.completed)
+            else {
+                continue;
+            };
+            let old = todo.clone();
+            todo.completed = false;
+            todo.completed_at = None;
+            todo.updated_at = now;
+            events.extend(NewTodoEvent::changed(user_id, &old, todo));
+        }
+        events
+    }
+
+    // DBのrelink_subtasks_withと同じく、削除で親から外れたsubtaskを親に戻す
+    fn relink_subtasks_with(
+        store: &mut LockedTodos,
+        user_id: i32,
+        parent_id: i32,
+        ids: &[i32],
+        now: DateTime<Utc>,
+    ) -> Vec<NewTodoEvent> {
+        let mut events = vec![];
+        for id in ids {
+            let Some(todo) = store
+                .get_mut(id)
+                .filter(|todo| todo.user_id == user_id && todo.parent_id.is_none())
+            else {
+                continue;
+            };
+            let old = todo.clone();
+            todo.parent_id = Some(parent_id);
+            todo.updated_at = now;
+            events.extend(NewTodoEvent::changed(user_id, &old, todo));
+        }
+        events
+    }
+
+    // idのtodoの子孫のid
+    fn descendants(store: &LockedTodos, id: i32) -> Vec<i32> {
+        let mut found = vec![];
+        let mut parents = vec![id];
+        while let Some(parent_id) = parents.pop() {
+            for todo in store.values() {
+                if todo.parent_id == Some(parent_id) {
+                    found.push(todo.id);
+                    parents.push(todo.id);
+                }
+            }
+        }
+        found
+    }
+
+    // ファイルがなければ空として扱う
+    fn load_snapshot(path: &Path) -> anyhow::Result<Vec<TodoEntity>> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // 一時ファイルに書いてからrenameするので、書き込み途中のsnapshotを読むことはない
+    fn save_snapshot(path: &Path, store: &TodoStore) -> anyhow::Result<()> {
+        let mut todos = store.filter(|_| true);
+        todos.sort_by_key(|todo| todo.id);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&todos)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // 書き出しはこのタスクだけが行う. repositoryが全て破棄されたら残りを書き出して終わる
+    async fn write_snapshots(
+        store: Arc<TodoStore>,
+        path: PathBuf,
+        mut changes: mpsc::Receiver<()>,
+    ) {
+        while changes.recv().await.is_some() {
+            tokio::time::sleep(SNAPSHOT_DEBOUNCE).await;
+            // 待っている間の変更も今回の書き出しに含まれる
+            while changes.try_recv().is_ok() {}
+            if let Err(e) = save_snapshot(&path, &store) {
+                tracing::error!("failed to write snapshot [{}]: {:#}", path.display(), e);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TodoRepository for TodoRepositoryForMemory {
+        // 実行時にエラーになる可能性があるのでanyhow::Result型
+        async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+            let id = self.store.next_id(); // 削除されたidとも重ならない次のid
+            let labels = self.resolve_labels(user_id, payload.labels)?;
+            // 親を確かめる時だけ全てのshardをロックし、確かめてから作るまでに親が消えないようにする
+            let mut store = match payload.parent_id {
+                Some(_) => self.store.lock_all(),
+                None => self.store.lock(id),
+            };
+            if let Some(parent_id) = payload.parent_id {
+                ensure_parent_available(&store, user_id, None, parent_id)?;
+            }
+            let todo = TodoEntity {
+                due_date: payload.due_date,
+                recurrence: payload.recurrence,
+                recurrence_anchor: payload.recurrence_anchor,
+                remind_at: payload.remind_at,
+                parent_id: payload.parent_id,
+                ..new_todo(id, user_id, payload.text.clone(), labels, self.now()) // Todoインスタンスを新しく作成
+            };
+            store.insert(todo.clone()); // storeに追加
+            let logged = self.log([NewTodoEvent::created(user_id, &todo)]);
+            drop(store);
+            self.changed();
+            self.publish(&logged);
+            Ok(todo) // Todoを返すことで、作成されたtodoのidやインスタンスを知れる
+        }
+
+        async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEntity> {
+            let todo = self
+                .store
+                .get(id) // 指定されたidのcloneを取得
+                .filter(|todo| todo.user_id == user_id) // 他のユーザーのtodoは見つからない扱い
+                .ok_or(RepositoryError::NotFound(id))?;
+            Ok(todo)
+        }
+
+        async fn find_by_public_id(
+            &self,
+            user_id: i32,
+            public_id: Uuid,
+        ) -> anyhow::Result<TodoEntity> {
+            let todo = self
+                .store
+                .filter(|todo| todo.user_id == user_id && todo.public_id == public_id)
+                .pop()
+                .ok_or(RepositoryError::PublicIdNotFound(public_id))?;
+            Ok(todo)
+        }
+
+        async fn all(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<Vec<TodoEntity>> {
+            query.ensure_valid()?;
+            Ok(self.select(user_id, &query))
+        }
+
+        // 対象のidだけを先に決め、todoは取り出す時に1件ずつstoreから読む.
+        // 読むまでに削除されたtodoは返さない
+        fn all_stream(
+            &self,
+            user_id: i32,
+            query: TodoQuery,
+        ) -> BoxStream<'static, anyhow::Result<TodoEntity>> {
+            if let Err(e) = query.ensure_valid() {
+                return stream::once(async { Err(e) }).boxed();
+            }
+            let ids: Vec<i32> = self
+                .select(user_id, &query)
+                .into_iter()
+                .map(|todo| todo.id)
+                .collect();
+            let store = self.store.clone();
+            stream::iter(ids)
+                .filter_map(move |id| {
+                    let todo = store.get(id);
+                    async move { todo.map(Ok) }
+                })
+                .boxed()
+        }
+
+        async fn count(&self, user_id: i32, query: TodoQuery) -> anyhow::Result<i64> {
+            query.ensure_valid()?;
+            let count = self
+                .store
+                .count(|todo| todo.user_id == user_id && query.matches(todo));
+            Ok(count as i64)
+        }
+
+        async fn find_many(&self, user_id: i32, ids: &[i32]) -> anyhow::Result<Vec<TodoEntity>> {
+            let todos = self
+                .store
+                .filter(|todo| todo.user_id == user_id && ids.contains(&todo.id));
+            Ok(in_requested_order(todos, ids))
+        }
+
+        async fn update(
+            &self,
+            user_id: i32,
+            id: i32,
+            payload: UpdateTodo,
+        ) -> anyhow::Result<TodoEntity> {
+            // 他のtodoを読み書きしない変更は、idのshardだけをロックする
+            let mut store = if payload.touches_other_todos() {
+                self.store.lock_all()
+            } else {
+                self.store.lock(id)
+            };
+            let now = self.now();
+            // 親の確認はstore全体を読むので、todoを書き換え始める前に済ませる.
+            // todoが無ければ下でNotFoundにする
+            if let MaybeUndefined::Value(parent_id) = payload.parent_id {
+                if store.get(&id).is_some_and(|todo| todo.user_id == user_id) {
+                    ensure_parent_available(&store, user_id, Some(id), parent_id)?;
+                }
+            }
+            let todo = store
+                .get_mut(&id)
+                .filter(|todo| todo.user_id == user_id)
+                .context(RepositoryError::NotFound(id))?; // idの値をget. なければNotFoundエラー
+            let old = todo.clone();
+            let labels = match &payload.labels {
+                Some(label_ids) => Some(self.resolve_labels(user_id, label_ids.clone())?),
+                None => None,
+            };
+            // 新しいtodoを作成. 全ての確認が済むまでstoreのtodoは書き換えない
+            let mut updated = todo.apply(&payload, now);
+            if let Some(labels) = labels {
+                updated.labels = labels;
+            }
+            let label_ids = updated.labels.iter().map(|label| label.id).collect();
+            // 繰り返しTodoが未完了->完了になった場合は次のTodoを作る
+            let next = todo.next_occurrence_on(&updated, label_ids);
+            // 同じ場所を書き換える. 返す分とstoreに残す分の2つが要る
+            *todo = updated.clone();
+            // subtaskは完了にするだけで、繰り返しの次回分は作らない.
+            // 取り消せるように、完了にしたsubtaskのidを記録する
+            let mut completed_subtasks = vec![];
+            if payload.completes_subtasks(&updated) {
+                for subtask_id in descendants(&store, id) {
+                    let subtask = store.get_mut(&subtask_id).unwrap();
+                    if !subtask.completed {
+                        subtask.completed = true;
+                        subtask.completed_at = Some(now);
+                        subtask.updated_at = now;
+                        completed_subtasks.push(subtask_id);
+                    }
+                }
+                completed_subtasks.sort_unstable();
+            }
+            if let Some(next) = next {
+                let next_id = self.store.next_id();
+                // 次回分のlabelは更新したtodoと同じで、解決済み
+                let labels = updated.labels.clone();
+                let next = TodoEntity {
+                    due_date: next.due_date,
+                    recurrence: next.recurrence,
+                    recurrence_anchor: next.recurrence_anchor,
+                    remind_at: next.remind_at,
+                    parent_id: next.parent_id,
+                    ..new_todo(next_id, user_id, next.text, labels, now)
+                };
+                store.insert(next.clone());
+                updated.next_occurrence = Some(Box::new(next));
+            }
+            let logged = self.log(NewTodoEvent::updated(
+                user_id,
+                &old,
+                &updated,
+                completed_subtasks,
+            ));
+            drop(store);
+            self.changed();
+            self.publish(&logged);
+            Ok(updated) // 成功したらOkで新しいtodoを返す
+        }
+
+        async fn update_where(
+            &self,
+            user_id: i32,
+            filter: TodoQuery,
+            set: BulkUpdateTodo,
+        ) -> anyhow::Result<Vec<TodoEntity>> {
+            filter.ensure_valid()?;
+            let now = self.now();
+            // 変更と記録の間に他の変更が入らないよう、全てのshardをロックして1度に変更する
+            let mut store = self.store.lock_all();
+            let mut changed: Vec<(TodoEntity, TodoEntity)> = store
+                .values_mut()
+                .filter(|todo| {
+                    todo.user_id == user_id
+                        && todo.completed != set.completed
+                        && filter.matches(todo)
+                })
+                .map(|todo| {
+                    let old = todo.clone();
+                    todo.completed = set.completed;
+                    todo.completed_at = set.completed.then_some(now);
+                    todo.updated_at = now;
+                    (old, todo.clone())
+                })
+                .collect();
+            changed.sort_by_key(|(_, todo)| todo.id);
+            let logged = self.log(
+                changed
+                    .iter()
+                    .filter_map(|(old, todo)| NewTodoEvent::changed(user_id, old, todo)),
+            );
+            drop(store);
+            if !changed.is_empty() {
+                self.changed();
+            }
+            self.publish(&logged);
+            Ok(changed.into_iter().map(|(_, todo)| todo).collect())
+        }
+
+        async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+            let mut store = self.store.lock_all(); // subtaskも書き換えるので全てのshardをロック
+            let (old, detached) = remove_with(&mut store, user_id, id)?;
+            let logged = self.log([NewTodoEvent::deleted(user_id, old, detached)]);
+            drop(store);
+            self.changed();
+            self.publish(&logged);
+            Ok(()) // 成功すればOkを返す
+        }
+
+        async fn subtasks(&self, user_id: i32, id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+            if self
+                .store
+                .get(id)
+                .is_none_or(|todo| todo.user_id != user_id)
+            {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            let mut todos = self
+                .store
+                .filter(|todo| todo.user_id == user_id && todo.parent_id == Some(id));
+            todos.sort_by_key(|todo| todo.id);
+            Ok(todos)
+        }
+
+        async fn restore(&self, user_id: i32, todo: TodoEntity) -> anyhow::Result<TodoEntity> {
+            let mut store = self.store.lock_all(); // 親が残っているかも確かめる
+            let todo = self.restore_with(&mut store, user_id, todo)?;
+            drop(store);
+            self.changed();
+            Ok(todo)
+        }
+
+        // 取り消す変更の選択から変更とその記録までを、todoと記録のロックを持ったまま行う
+        async fn undo(&self, user_id: i32, id: i32) -> anyhow::Result<TodoEvent> {
+            let Some(activity) = &self.activity else {
+                return Err(RepositoryError::NothingToUndo(id).into());
+            };
+            let mut store = self.store.lock_all();
+            let mut events = activity.events.lock();
+            let history = events.history(user_id, id);
+            let target = undo_target(&history).ok_or(RepositoryError::NothingToUndo(id))?;
+
+            // 作成は削除、削除は同じidでの再作成、更新は変更前の値の書き戻しで取り消す.
+            // 削除で外れたsubtaskや完了で作った次回分など、他のtodoへの変更も戻す
+            let now = self.now();
+            let (change, others) = match (target.kind, &target.payload) {
+                (TodoEventKind::Created, TodoEventPayload::Snapshot(_)) => {
+                    let (removed, _) = remove_with(&mut store, user_id, id)?;
+                    (TodoEventPayload::Snapshot(removed), vec![])
+                }
+                // subtaskを記録する前の削除
+                (TodoEventKind::Deleted, TodoEventPayload::Snapshot(snapshot)) => {
+                    let restored = self.restore_with(&mut store, user_id, snapshot.clone())?;
+                    (TodoEventPayload::Snapshot(restored), vec![])
+                }
+                (
+                    TodoEventKind::Deleted,
+                    TodoEventPayload::Deletion {
+                        todo: snapshot,
+                        detached_subtasks,
+                    },
+                ) => {
+                    let restored = self.restore_with(&mut store, user_id, snapshot.clone())?;
+                    let others =
+                        relink_subtasks_with(&mut store, user_id, id, detached_subtasks, now);
+                    (TodoEventPayload::Snapshot(restored), others)
+                }
+                (
+                    TodoEventKind::Updated | TodoEventKind::Completed,
+                    TodoEventPayload::Diff(diff),
+                ) => {
+                    let current = store
+                        .get(&id)
+                        .filter(|todo| todo.user_id == user_id)
+                        .cloned()
+                        .ok_or(RepositoryError::NotFound(id))?;
+                    let restored = self.restore_with(&mut store, user_id, diff.revert(&current))?;
+                    let others = undo_completion_with(&mut store, user_id, diff, now);
+                    (
+                        TodoEventPayload::Diff(TodoDiff::between(&current, &restored)),
+                        others,
+                    )
+                }
+                _ => {
+                    return Err(RepositoryError::Unexpected(format!(
+                        "event {} cannot be undone",
+                        target.id
+                    ))
+                    .into())
+                }
+            };
+
+            let undone = NewTodoEvent::undone(user_id, target, change);
+            let mut logged = Logged {
+                audit: vec![AuditEntry::from_undo(&undone, target)],
+                events: vec![events.push(undone)],
+            };
+            // 記録のロックを持っているので、logを使わずに同じ順で記録する
+            for other in others {
+                logged.audit.push(AuditEntry::from_event(&other));
+                logged.events.push(events.push(other));
+            }
+            drop(events);
+            drop(store);
+            self.changed();
+            self.publish(&logged);
+            Ok(logged.events[0].clone())
+        }
+
+        async fn claim_due_reminders(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<TodoEntity>> {
+            let claimed = self.store.update_each(|todo| {
+                if todo.reminded_at.is_some()
+                    || todo.remind_at.is_none_or(|remind_at| remind_at > now)
+                {
+                    return false;
+                }
+                todo.reminded_at = Some(now);
+                todo.updated_at = now;
+                true
+            });
+            if !claimed.is_empty() {
+                self.changed();
+            }
+            Ok(claimed)
+        }
+
+        async fn stats(&self, user_id: i32, today: NaiveDate) -> anyhow::Result<TodoStats> {
+            let todos = self.store.filter(|todo| todo.user_id == user_id);
+            let todos: Vec<&TodoEntity> = todos.iter().collect();
+            let count = |todos: &[&TodoEntity], completed: bool| {
+                todos
+                    .iter()
+                    .filter(|todo| todo.completed == completed)
+                    .count() as i64
+            };
+
+            let labels: Vec<LabelStats> = self
+                .labels
+                .visible(user_id)
+                .into_iter()
+                .map(|label| {
+                    let labeled: Vec<&TodoEntity> = todos
+                        .iter()
+                        .filter(|todo| todo.labels.iter().any(|l| l.id == label.id))
+                        .copied()
+                        .collect();
+                    LabelStats {
+                        id: label.id,
+                        name: label.name.clone(),
+                        total: labeled.len() as i64,
+                        open: count(&labeled, false),
+                        completed: count(&labeled, true),
+                    }
+                })
+                .collect();
+
+            Ok(TodoStats {
+                total: todos.len() as i64,
+                open: count(&todos, false),
+                completed: count(&todos, true),
+                overdue: todos
+                    .iter()
+                    .filter(|todo| !todo.completed)
+                    .filter(|todo| todo.due_date.is_some_and(|due_date| due_date < today))
+                    .count() as i64,
+                labels,
+            })
+        }
+
+        async fn timeseries(
+            &self,
+            user_id: i32,
+            query: TimeseriesQuery,
+        ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+            let starts = query.bucket_starts()?;
+            let (from, to) = query.bounds(&starts);
+            // 期間内の日時をbucketの開始日ごとに1件として数える
+            let bucketed = |at: Option<DateTime<Utc>>| {
+                at.filter(|at| from <= *at && *at < to)
+                    .map(|at| (query.bucket.truncate(at.date_naive()), 1))
+            };
+            let todos = self.store.filter(|todo| todo.user_id == user_id);
+            let created: Vec<(NaiveDate, i64)> = todos
+                .iter()
+                .filter_map(|todo| bucketed(Some(todo.created_at)))
+                .collect();
+            let completed: Vec<(NaiveDate, i64)> = todos
+                .iter()
+                .filter_map(|todo| bucketed(todo.completed_at))
+                .collect();
+            Ok(query.fill(starts, &created, &completed))
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::repositories::todo::test_utils::{test_now, TEST_USER_ID};
+        use crate::test_support::{self, TodoEntityBuilder};
+        use futures_util::TryStreamExt;
+        use proptest::prelude::*;
+        use std::collections::HashSet;
+
+        #[tokio::test]
+        async fn builder_updates_only_the_given_fields() {
+            let payload = UpdateTodo::builder().completed(true).build();
+            assert_eq!(payload, UpdateTodo::completion(true));
+
+            let label = Label::new(1, "label".to_string());
+            let repository = TodoRepositoryForMemory::for_test(vec![label.clone()]);
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("builder".to_string(), vec![label.id]),
+                )
+                .await
+                .expect("failed create todo");
+
+            let updated = repository
+                .update(TEST_USER_ID, todo.id, payload)
+                .await
+                .expect("failed update todo");
+            assert!(updated.completed);
+            assert_eq!(updated.text, todo.text);
+            assert_eq!(updated.labels, todo.labels);
+            assert_eq!(updated.due_date, todo.due_date);
+            assert_eq!(updated.parent_id, todo.parent_id);
+        }
+
+        #[tokio::test]
+        async fn default_repository_uses_system_clock_without_labels() {
+            let repository = TodoRepositoryForMemory::default();
+            let before = Utc::now();
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new("default".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+            assert!(before <= todo.created_at && todo.created_at <= Utc::now());
+            assert_eq!(todo.updated_at, todo.created_at);
+            assert_eq!(
+                todo,
+                TodoEntity {
+                    created_at: todo.created_at,
+                    updated_at: todo.updated_at,
+                    ..TodoEntityBuilder::new().text("default").build()
+                }
+            );
+            // labelを持たないので、labelを付けた作成は失敗する
+            assert!(repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("labeled".to_string(), vec![1])
+                )
+                .await
+                .is_err());
+        }
+
+        #[tokio::test]
+        async fn todo_crud_scenario() {
+            let label = test_support::a_label("test label");
+            let expected = TodoEntityBuilder::new()
+                .text("todo text")
+                .with_label(label.clone())
+                .build();
+
+            // create
+            let repository = TodoRepositoryForMemory::for_test(vec![label.clone()]);
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("todo text".to_string(), vec![label.id]),
+                )
+                .await
+                .expect("failed create todo");
+            assert_eq!(expected, todo);
+
+            // find
+            let todo = repository.find(TEST_USER_ID, todo.id).await.unwrap();
+            assert_eq!(expected, todo);
+
+            // all
+            let todo = repository
+                .all(TEST_USER_ID, TodoQuery::default())
+                .await
+                .expect("failed get all todos");
+            assert_eq!(vec![expected.clone()], todo);
+
+            // update
+            let todo = repository
+                .update(
+                    TEST_USER_ID,
+                    expected.id,
+                    UpdateTodo::builder()
+                        .text("update todo text")
+                        .completed(true)
+                        .labels(vec![])
+                        .build(),
+                )
+                .await
+                .expect("failed update todo");
+            assert_eq!(
+                TodoEntityBuilder::new()
+                    .text("update todo text")
+                    .completed()
+                    .build(),
+                todo
+            );
+
+            // delete
+            let res = repository.delete(TEST_USER_ID, expected.id).await;
+            assert!(res.is_ok());
+        }
+
+        fn complete() -> UpdateTodo {
+            UpdateTodo::builder().completed(true).build()
+        }
+
+        #[tokio::test]
+        async fn completing_recurring_todo_spawns_next_occurrence() {
+            let label = Label::new(1, "chore".to_string());
+            let due_date = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+            let repository = TodoRepositoryForMemory::for_test(vec![label.clone()]);
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("pay rent".to_string(), vec![label.id])
+                        .recurring(Recurrence::Monthly, Some(due_date)),
+                )
+                .await
+                .expect("failed create todo");
+
+            let completed = repository
+                .update(TEST_USER_ID, todo.id, complete())
+                .await
+                .expect("failed update todo");
+            assert!(completed.completed);
+            let next = *completed
+                .next_occurrence
+                .expect("next occurrence not created");
+            assert_eq!(next.text, "pay rent");
+            assert!(!next.completed);
+            assert_eq!(next.labels, vec![label]);
+            assert_eq!(next.recurrence, Recurrence::Monthly);
+            assert_eq!(next.due_date, NaiveDate::from_ymd_opt(2024, 2, 29));
+            assert_eq!(repository.find(TEST_USER_ID, next.id).await.unwrap(), next);
+
+            // 次のTodoを完了するとさらに次が作られる
+            let completed = repository
+                .update(TEST_USER_ID, next.id, complete())
+                .await
+                .expect("failed update todo");
+            let next = completed
+                .next_occurrence
+                .expect("next occurrence not created");
+            // 2/29から1か月ではなく、1/31から2か月後
+            assert_eq!(next.due_date, NaiveDate::from_ymd_opt(2024, 3, 31));
+
+            // 期日を変えると、変えた期日から数え直す
+            let moved = repository
+                .update(
+                    TEST_USER_ID,
+                    next.id,
+                    UpdateTodo::builder()
+                        .due_date(NaiveDate::from_ymd_opt(2024, 3, 15))
+                        .build(),
+                )
+                .await
+                .expect("failed update todo");
+            let next = repository
+                .update(TEST_USER_ID, moved.id, complete())
+                .await
+                .expect("failed update todo")
+                .next_occurrence
+                .expect("next occurrence not created");
+            assert_eq!(next.due_date, NaiveDate::from_ymd_opt(2024, 4, 15));
+
+            // 既に完了済みのTodoを再度完了しても作られない
+            let completed = repository
+                .update(TEST_USER_ID, todo.id, complete())
+                .await
+                .expect("failed update todo");
+            assert!(completed.next_occurrence.is_none());
+        }
+
+        #[tokio::test]
+        async fn recurring_todo_without_due_date_advances_from_completion_date() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("water plants".to_string(), vec![])
+                        .recurring(Recurrence::Weekly, None),
+                )
+                .await
+                .expect("failed create todo");
+
+            // 作成した日ではなく、完了した日から1週間後
+            repository.set_now(test_now() + chrono::TimeDelta::days(10));
+            let next = repository
+                .update(TEST_USER_ID, todo.id, complete())
+                .await
+                .expect("failed update todo")
+                .next_occurrence
+                .expect("next occurrence not created");
+            assert_eq!(next.due_date, NaiveDate::from_ymd_opt(2024, 1, 18));
+        }
+
+        #[tokio::test]
+        async fn update_where_changes_only_matching_todos() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            test_support::seed_todos(
+                &repository,
+                TEST_USER_ID,
+                ["buy milk", "buy eggs", "call mom", "buy bread"]
+                    .map(|text| CreateTodo::new(text.to_string(), vec![])),
+            )
+            .await;
+            repository
+                .create(
+                    TEST_USER_ID + 1,
+                    CreateTodo::new("buy tea".to_string(), vec![]),
+                )
+                .await
+                .expect("failed create todo");
+            repository
+                .update(TEST_USER_ID, 4, complete())
+                .await
+                .expect("failed update todo");
+
+            let filter = TodoQuery {
+                q: Some("buy".to_string()),
+                ..Default::default()
+            };
+            let set = BulkUpdateTodo { completed: true };
+            let updated = repository
+                .update_where(TEST_USER_ID, filter.clone(), set)
+                .await
+                .expect("failed update todos");
+            // 既に完了していたtodo 4や他のユーザーのtodoは変えない
+            assert_eq!(
+                updated.iter().map(|todo| todo.id).collect::<Vec<_>>(),
+                vec![1, 2]
+            );
+            for id in 1..=5 {
+                let todo = repository.store.get(id).unwrap();
+                assert_eq!(todo.completed, [1, 2, 4].contains(&id), "todo {}", id);
+                assert_eq!(todo.completed_at.is_some(), todo.completed, "todo {}", id);
+            }
+
+            let updated = repository
+                .update_where(TEST_USER_ID, filter, set)
+                .await
+                .expect("failed update todos");
+            assert!(updated.is_empty());
+        }
+
+        #[tokio::test]
+        async fn all_stream_reads_todos_one_at_a_time() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            test_support::seed_todos(
+                &repository,
+                TEST_USER_ID,
+                ["first", "second", "third"].map(|text| CreateTodo::new(text.to_string(), vec![])),
+            )
+            .await;
+            let query = TodoQuery {
+                sort: Some(TodoSort::IdAsc),
+                ..Default::default()
+            };
+            let all = repository
+                .all(TEST_USER_ID, query.clone())
+                .await
+                .expect("failed get all todo");
+            let streamed: Vec<TodoEntity> = repository
+                .all_stream(TEST_USER_ID, query.clone())
+                .try_collect()
+                .await
+                .expect("failed stream todo");
+            assert_eq!(streamed, all);
+
+            // 最初の1件は残りを読む前に届き、残りは読む時点のstoreから返る
+            let mut todos = repository.all_stream(TEST_USER_ID, query);
+            let first = todos.next().await.expect("stream ended").unwrap();
+            assert_eq!(first.text, "first");
+            repository
+                .delete(TEST_USER_ID, all[2].id)
+                .await
+                .expect("failed delete todo");
+            let rest: Vec<TodoEntity> = todos.try_collect().await.expect("failed stream todo");
+            assert_eq!(rest, vec![all[1].clone()]);
+        }
+
+        #[tokio::test]
+        async fn completed_at_follows_completion() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("file taxes".to_string(), vec![]),
+                )
+                .await
+                .expect("failed create todo");
+            assert_eq!(todo.completed_at, None);
+
+            let completed = repository
+                .update(TEST_USER_ID, todo.id, complete())
+                .await
+                .expect("failed update todo");
+            assert_eq!(completed.completed_at, Some(test_now()));
+
+            // 完了以外の変更では完了日時は変わらない
+            let renamed = repository
+                .update(
+                    TEST_USER_ID,
+                    todo.id,
+                    UpdateTodo::new(Some("file taxes early".to_string()), None, None),
+                )
+                .await
+                .expect("failed update todo");
+            assert_eq!(renamed.completed_at, Some(test_now()));
+
+            let reopened = repository
+                .update(TEST_USER_ID, todo.id, UpdateTodo::completion(false))
+                .await
+                .expect("failed update todo");
+            assert!(!reopened.completed);
+            assert_eq!(reopened.completed_at, None);
+            assert_eq!(
+                repository.find(TEST_USER_ID, todo.id).await.unwrap(),
+                reopened
+            );
+        }
+
+        #[tokio::test]
+        async fn update_keeps_clears_or_replaces_labels() {
+            let work = Label::new(1, "work".to_string());
+            let home = Label::new(2, "home".to_string());
+            let repository = TodoRepositoryForMemory::for_test(vec![work.clone(), home.clone()]);
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("sort mail".to_string(), vec![work.id]),
+                )
+                .await
+                .expect("failed create todo");
+
+            // Noneは変えない
+            let kept = repository
+                .update(TEST_USER_ID, todo.id, UpdateTodo::completion(true))
+                .await
+                .expect("failed update todo");
+            assert_eq!(kept.labels, vec![work]);
+
+            // Some([x])は置き換える
+            let replaced = repository
+                .update(
+                    TEST_USER_ID,
+                    todo.id,
+                    UpdateTodo::new(None, None, Some(vec![home.id])),
+                )
+                .await
+                .expect("failed update todo");
+            assert_eq!(replaced.labels, vec![home]);
+
+            // Some([])は全て外す
+            let cleared = repository
+                .update(
+                    TEST_USER_ID,
+                    todo.id,
+                    UpdateTodo::new(None, None, Some(vec![])),
+                )
+                .await
+                .expect("failed update todo");
+            assert!(cleared.labels.is_empty());
+            assert_eq!(
+                repository.find(TEST_USER_ID, todo.id).await.unwrap(),
+                cleared
+            );
+        }
+
+        #[tokio::test]
+        async fn removing_recurrence_stops_the_cycle() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("water plants".to_string(), vec![])
+                        .recurring(Recurrence::Daily, None),
+                )
+                .await
+                .expect("failed create todo");
+
+            let completed = repository
+                .update(
+                    TEST_USER_ID,
+                    todo.id,
+                    UpdateTodo::builder()
+                        .completed(true)
+                        .recurrence(Recurrence::None)
+                        .build(),
+                )
+                .await
+                .expect("failed update todo");
+            assert_eq!(completed.recurrence, Recurrence::None);
+            assert!(completed.next_occurrence.is_none());
+            assert_eq!(
+                repository
+                    .all(TEST_USER_ID, TodoQuery::default())
+                    .await
+                    .unwrap()
+                    .len(),
+                1
+            );
+        }
+
+        #[tokio::test]
+        async fn default_order_follows_env() {
+            // idの順と作成日時の順が一致しないように作成する
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            for (text, days) in [("first", 2), ("second", 0), ("third", 1)] {
+                repository.set_now(test_now() + TimeDelta::days(days));
+                repository
+                    .create(TEST_USER_ID, CreateTodo::new(text.to_string(), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+
+            let ids = |sort: TodoSort, query: TodoQuery| {
+                let repository = repository.clone().with_default_sort(sort);
+                async move {
+                    let todos = repository.all(TEST_USER_ID, query).await.unwrap();
+                    todos.iter().map(|todo| todo.id).collect::<Vec<_>>()
+                }
+            };
+
+            env::remove_var("DEFAULT_TODO_ORDER");
+            let sort = TodoSort::from_env().unwrap();
+            assert_eq!(ids(sort, TodoQuery::default()).await, vec![3, 2, 1]);
+
+            env::set_var("DEFAULT_TODO_ORDER", "id_asc");
+            let sort = TodoSort::from_env().unwrap();
+            assert_eq!(ids(sort, TodoQuery::default()).await, vec![1, 2, 3]);
+
+            env::set_var("DEFAULT_TODO_ORDER", "created_desc");
+            let sort = TodoSort::from_env().unwrap();
+            assert_eq!(ids(sort, TodoQuery::default()).await, vec![1, 3, 2]);
+
+            // ?sortの指定は既定より優先される
+            let query = TodoQuery {
+                sort: Some(TodoSort::IdDesc),
+                ..Default::default()
+            };
+            assert_eq!(ids(sort, query).await, vec![3, 2, 1]);
+
+            env::set_var("DEFAULT_TODO_ORDER", "newest");
+            assert!(TodoSort::from_env().is_err());
+            env::remove_var("DEFAULT_TODO_ORDER");
+        }
+
+        #[test]
+        fn text_length_limit_is_configurable() {
+            assert_eq!(
+                parse_max_todo_text_len(None).unwrap(),
+                DEFAULT_MAX_TODO_TEXT_LEN
+            );
+            assert_eq!(parse_max_todo_text_len(Some("200")).unwrap(), 200);
+            assert!(parse_max_todo_text_len(Some("many")).is_err());
+
+            // 設定していなければ既定の100文字までになる
+            let text = "a".repeat(150);
+            let create = CreateTodo::new(text.clone(), vec![]);
+            let update: UpdateTodo =
+                serde_json::from_value(serde_json::json!({ "text": text })).unwrap();
+            assert!(create.validate().is_err());
+            assert!(update.validate().is_err());
+
+            assert!(validate_todo_text_len(&text, 200).is_ok());
+            // 空文字は上限に関わらず不正
+            assert!(validate_todo_text_len("", 200).is_err());
+            let error = validate_todo_text_len(&"a".repeat(201), 200).unwrap_err();
+            let message = error.message.unwrap().to_string();
+            assert!(message.contains("less than 200 characters"), "{}", message);
+        }
+
+        #[tokio::test]
+        async fn todos_are_isolated_between_users() {
+            let other_user_id = TEST_USER_ID + 1;
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let mine = repository
+                .create(TEST_USER_ID, CreateTodo::new("mine".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+            let theirs = repository
+                .create(other_user_id, CreateTodo::new("theirs".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+            assert_eq!(theirs.user_id, other_user_id);
+
+            let todos = repository
+                .all(TEST_USER_ID, TodoQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(todos, vec![mine.clone()]);
+
+            // 他のユーザーのtodoは存在しないものとして扱われる
+            assert!(repository.find(TEST_USER_ID, theirs.id).await.is_err());
+            assert!(repository
+                .update(TEST_USER_ID, theirs.id, complete())
+                .await
+                .is_err());
+            assert!(repository.delete(TEST_USER_ID, theirs.id).await.is_err());
+            let theirs_after = repository.find(other_user_id, theirs.id).await.unwrap();
+            assert_eq!(theirs, theirs_after);
+        }
+
+        #[tokio::test]
+        async fn shared_labels_are_usable_by_all_users() {
+            let other_user_id = TEST_USER_ID + 1;
+            let shared = Label::new(1, "shared".to_string());
+            let private = Label::new(2, "private".to_string()).owned_by(TEST_USER_ID);
+            let repository =
+                TodoRepositoryForMemory::for_test(vec![shared.clone(), private.clone()]);
+
+            for user_id in [TEST_USER_ID, other_user_id] {
+                let todo = repository
+                    .create(
+                        user_id,
+                        CreateTodo::new("todo".to_string(), vec![shared.id]),
+                    )
+                    .await
+                    .expect("failed create todo");
+                assert_eq!(todo.labels, vec![shared.clone()]);
+            }
+
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("mine".to_string(), vec![private.id]),
+                )
+                .await
+                .expect("failed create todo");
+            assert_eq!(todo.labels, vec![private.clone()]);
+
+            // 他のユーザーの非公開labelは付けられない
+            let err = repository
+                .create(
+                    other_user_id,
+                    CreateTodo::new("theirs".to_string(), vec![private.id]),
+                )
+                .await
+                .expect_err("private label attached by other user");
+            assert!(matches!(
+                err.downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::LabelNotAvailable(id)) if *id == private.id
+            ));
+            let err = repository
+                .update(
+                    other_user_id,
+                    2,
+                    UpdateTodo::builder()
+                        .completed(true)
+                        .labels(vec![private.id])
+                        .build(),
+                )
+                .await
+                .expect_err("private label attached by other user");
+            assert!(matches!(
+                err.downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::LabelNotAvailable(_))
+            ));
+        }
 
-        // delete
-        let _ = repository
-            .delete(todo.id)
-            .await
-            .expect("[delete] returned Err");
-        let res = repository.find(created.id).await;
-        assert!(res.is_err());
+        #[tokio::test]
+        async fn stats_counts_seeded_todos() {
+            let today = NaiveDate::from_ymd_opt(2024, 4, 15).unwrap();
+            let work = Label::new(1, "work".to_string());
+            let home = Label::new(2, "home".to_string());
+            let unused = Label::new(3, "unused".to_string());
+            let theirs = Label::new(4, "theirs".to_string()).owned_by(TEST_USER_ID + 1);
+            let repository = TodoRepositoryForMemory::for_test(vec![
+                work.clone(),
+                home.clone(),
+                unused.clone(),
+                theirs.clone(),
+            ]);
 
-        let todo_rows = sqlx::query(
-            r#"
-            select * from todos where id=$1
-            "#,
-        )
-        .bind(todo.id)
-        .fetch_all(&pool)
-        .await
-        .expect("[delete] todo_labels fetch error");
-        assert!(todo_rows.len() == 0);
+            // (labels, due_date, completed)
+            let yesterday = today.pred_opt();
+            let tomorrow = today.succ_opt();
+            for (labels, due_date, completed) in [
+                (vec![work.id], yesterday, false),
+                (vec![work.id, home.id], yesterday, true),
+                (vec![home.id], tomorrow, false),
+                (vec![], Some(today), false),
+                (vec![], None, true),
+            ] {
+                let todo = repository
+                    .create(
+                        TEST_USER_ID,
+                        CreateTodo::new("todo".to_string(), labels)
+                            .recurring(Recurrence::None, due_date),
+                    )
+                    .await
+                    .expect("failed create todo");
+                if completed {
+                    repository
+                        .update(TEST_USER_ID, todo.id, complete())
+                        .await
+                        .expect("failed update todo");
+                }
+            }
+            // 他のユーザーのtodoは数えない
+            repository
+                .create(
+                    TEST_USER_ID + 1,
+                    CreateTodo::new("theirs".to_string(), vec![work.id]),
+                )
+                .await
+                .expect("failed create todo");
 
-        let rows = sqlx::query(
-            r#"
-            select * from todo_labels where todo_id=$1
-            "#,
-        )
-        .bind(todo.id)
-        .fetch_all(&pool)
-        .await
-        .expect("[delete] todo_labels fetch error");
-        assert!(rows.len() == 0);
-    }
-}
+            let stats = repository.stats(TEST_USER_ID, today).await.unwrap();
+            assert_eq!(
+                stats,
+                TodoStats {
+                    total: 5,
+                    open: 3,
+                    completed: 2,
+                    overdue: 1,
+                    labels: vec![
+                        LabelStats {
+                            id: work.id,
+                            name: work.name,
+                            total: 2,
+                            open: 1,
+                            completed: 1,
+                        },
+                        LabelStats {
+                            id: home.id,
+                            name: home.name,
+                            total: 2,
+                            open: 1,
+                            completed: 1,
+                        },
+                        LabelStats {
+                            id: unused.id,
+                            name: unused.name,
+                            total: 0,
+                            open: 0,
+                            completed: 0,
+                        },
+                    ],
+                }
+            );
+        }
 
-#[cfg(test)]
-pub mod test_utils {
-    use anyhow::Context;
-    use axum::async_trait;
-    use std::{
-        collections::HashMap,
-        sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
-    };
+        #[test]
+        fn timestamps_serialize_as_rfc3339_utc() {
+            // 入力のオフセットに関わらずUTCに揃えて返す
+            let payload: CreateTodo = serde_json::from_str(
+                r#"{ "text": "meeting", "labels": [], "remind_at": "2024-01-01T21:00:00+09:00" }"#,
+            )
+            .unwrap();
+            let remind_at = payload.remind_at.unwrap();
+            let todo = TodoEntityBuilder::new()
+                .text(payload.text)
+                .remind_at(remind_at)
+                .reminded_at(remind_at)
+                .build();
 
-    use super::*;
+            let json = serde_json::to_value(&todo).unwrap();
+            assert_eq!(json["remind_at"], "2024-01-01T12:00:00Z");
+            assert_eq!(json["reminded_at"], "2024-01-01T12:00:00Z");
+        }
 
-    impl TodoEntity {
-        pub fn new(id: i32, text: String, labels: Vec<Label>) -> Self {
-            Self {
-                id,
-                text,
-                completed: false,
-                labels,
-            }
+        fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+            NaiveDate::from_ymd_opt(y, m, d).unwrap()
         }
-    }
 
-    impl CreateTodo {
-        pub fn new(text: String, labels: Vec<i32>) -> Self {
-            Self { text, labels }
+        fn at(y: i32, m: u32, d: u32) -> DateTime<Utc> {
+            date(y, m, d).and_hms_opt(12, 0, 0).unwrap().and_utc()
         }
-    }
 
-    type TodoDatas = HashMap<i32, TodoEntity>;
+        #[tokio::test]
+        async fn timeseries_fills_gaps_with_zero() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            repository.set_now(at(2024, 3, 1));
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new("a".to_string(), vec![]))
+                .await
+                .unwrap();
+            repository
+                .create(TEST_USER_ID, CreateTodo::new("b".to_string(), vec![]))
+                .await
+                .unwrap();
+            repository.set_now(at(2024, 3, 4));
+            repository
+                .update(TEST_USER_ID, todo.id, complete())
+                .await
+                .unwrap();
+            // 期間外と他のユーザーは数えない
+            repository
+                .create(TEST_USER_ID + 1, CreateTodo::new("c".to_string(), vec![]))
+                .await
+                .unwrap();
+            repository.set_now(at(2024, 3, 10));
+            repository
+                .create(TEST_USER_ID, CreateTodo::new("d".to_string(), vec![]))
+                .await
+                .unwrap();
 
-    #[derive(Debug, Clone)]
-    pub struct TodoRepositoryForMemory {
-        store: Arc<RwLock<TodoDatas>>,
-        labels: Vec<Label>,
-    }
+            let points = repository
+                .timeseries(
+                    TEST_USER_ID,
+                    TimeseriesQuery {
+                        from: date(2024, 3, 1),
+                        to: date(2024, 3, 4),
+                        bucket: TimeseriesBucket::Day,
+                    },
+                )
+                .await
+                .unwrap();
+            let counts: Vec<(NaiveDate, i64, i64)> = points
+                .iter()
+                .map(|point| (point.date, point.created, point.completed))
+                .collect();
+            assert_eq!(
+                counts,
+                vec![
+                    (date(2024, 3, 1), 2, 0),
+                    (date(2024, 3, 2), 0, 0),
+                    (date(2024, 3, 3), 0, 0),
+                    (date(2024, 3, 4), 0, 1),
+                ]
+            );
+        }
 
-    impl TodoRepositoryForMemory {
-        pub fn new(labels: Vec<Label>) -> Self {
-            TodoRepositoryForMemory {
-                store: Arc::default(),
-                labels,
+        #[tokio::test]
+        async fn timeseries_weeks_span_year_boundary() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            // 2024-12-30(月)から始まる週は2025-01-05までを含む
+            for day in [at(2024, 12, 29), at(2024, 12, 31), at(2025, 1, 5)] {
+                repository.set_now(day);
+                repository
+                    .create(TEST_USER_ID, CreateTodo::new("todo".to_string(), vec![]))
+                    .await
+                    .unwrap();
+            }
+
+            let points = repository
+                .timeseries(
+                    TEST_USER_ID,
+                    TimeseriesQuery {
+                        from: date(2024, 12, 25),
+                        to: date(2025, 1, 6),
+                        bucket: TimeseriesBucket::Week,
+                    },
+                )
+                .await
+                .unwrap();
+            let counts: Vec<(NaiveDate, i64)> = points
+                .iter()
+                .map(|point| (point.date, point.created))
+                .collect();
+            assert_eq!(
+                counts,
+                vec![
+                    (date(2024, 12, 23), 1),
+                    (date(2024, 12, 30), 2),
+                    (date(2025, 1, 6), 0),
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn timeseries_of_empty_range() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let query = |from: NaiveDate, to: NaiveDate| TimeseriesQuery {
+                from,
+                to,
+                bucket: TimeseriesBucket::Day,
+            };
+
+            // todoが無くても全bucketを0件で返す
+            let points = repository
+                .timeseries(TEST_USER_ID, query(date(2024, 1, 1), date(2024, 1, 1)))
+                .await
+                .unwrap();
+            assert_eq!(
+                points,
+                vec![TimeseriesPoint {
+                    date: date(2024, 1, 1),
+                    created: 0,
+                    completed: 0,
+                }]
+            );
+
+            // 範囲の逆転と上限を超える範囲はInvalidQuery
+            for (from, to) in [
+                (date(2024, 1, 2), date(2024, 1, 1)),
+                (date(2024, 1, 1), date(2025, 1, 1)),
+            ] {
+                let err = repository
+                    .timeseries(TEST_USER_ID, query(from, to))
+                    .await
+                    .expect_err("invalid range accepted");
+                assert!(matches!(
+                    err.downcast_ref::<RepositoryError>(),
+                    Some(RepositoryError::InvalidQuery(_))
+                ));
             }
+            assert!(repository
+                .timeseries(TEST_USER_ID, query(date(2024, 1, 1), date(2024, 12, 31)))
+                .await
+                .is_ok());
         }
 
-        // write権限を持ったHashMapをスレッドセーフに取得
-        fn write_store_ref(&self) -> RwLockWriteGuard<TodoDatas> {
-            self.store.write().unwrap()
+        #[tokio::test]
+        async fn find_many_keeps_requested_order() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            test_support::seed_todos(
+                &repository,
+                TEST_USER_ID,
+                ["first", "second", "third"].map(|text| CreateTodo::new(text.to_string(), vec![])),
+            )
+            .await;
+            repository
+                .create(
+                    TEST_USER_ID + 1,
+                    CreateTodo::new("other".to_string(), vec![]),
+                )
+                .await
+                .unwrap();
+
+            // 見つからないidと他のユーザーのtodoは含まれない
+            let texts: Vec<String> = repository
+                .find_many(TEST_USER_ID, &[3, 99, 1, 4])
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|todo| todo.text)
+                .collect();
+            assert_eq!(texts, vec!["third", "first"]);
         }
 
-        // read権限を持ったHashMapをスレッドセーフに取得
-        fn read_store_ref(&self) -> RwLockReadGuard<TodoDatas> {
-            self.store.read().unwrap()
+        #[tokio::test]
+        async fn merge_patch_clears_null_fields_and_keeps_omitted_ones() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let parent = repository
+                .create(TEST_USER_ID, CreateTodo::new("trip".to_string(), vec![]))
+                .await
+                .unwrap();
+            let due_date = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+            let remind_at = Utc::now();
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("tickets".to_string(), vec![])
+                        .recurring(Recurrence::None, Some(due_date))
+                        .remind_at(remind_at)
+                        .subtask_of(parent.id),
+                )
+                .await
+                .unwrap();
+            let patch = |json: &str| serde_json::from_str::<UpdateTodo>(json).unwrap();
+
+            // 省略した項目はそのまま
+            let updated = repository
+                .update(
+                    TEST_USER_ID,
+                    todo.id,
+                    patch(r#"{ "text": "train tickets" }"#),
+                )
+                .await
+                .unwrap();
+            assert_eq!(updated.text, "train tickets");
+            assert_eq!(updated.due_date, Some(due_date));
+            assert_eq!(updated.remind_at, Some(remind_at));
+            assert_eq!(updated.parent_id, Some(parent.id));
+
+            // nullの項目だけを消す
+            let updated = repository
+                .update(
+                    TEST_USER_ID,
+                    todo.id,
+                    patch(r#"{ "due_date": null, "parent_id": null }"#),
+                )
+                .await
+                .unwrap();
+            assert_eq!(updated.due_date, None);
+            assert_eq!(updated.parent_id, None);
+            assert_eq!(updated.remind_at, Some(remind_at));
+            assert_eq!(updated.text, "train tickets");
+
+            // 省略した項目はシリアライズしても現れない
+            assert_eq!(
+                serde_json::to_value(UpdateTodo::completion(true)).unwrap(),
+                serde_json::json!({
+                    "text": null,
+                    "completed": true,
+                    "labels": null,
+                    "recurrence": null,
+                    "complete_subtasks": null
+                })
+            );
         }
 
-        fn resolve_labels(&self, labels: Vec<i32>) -> Vec<Label> {
-            let mut label_list = self.labels.iter().cloned();
-            let labels = labels
-                .iter()
-                .map(|id| label_list.find(|label| label.id == *id).unwrap())
+        #[tokio::test]
+        async fn rejects_direct_and_indirect_parent_cycles() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let create = |text: &str| CreateTodo::new(text.to_string(), vec![]);
+            let a = repository.create(TEST_USER_ID, create("a")).await.unwrap();
+            let b = repository
+                .create(TEST_USER_ID, create("b").subtask_of(a.id))
+                .await
+                .unwrap();
+            let c = repository
+                .create(TEST_USER_ID, create("c").subtask_of(b.id))
+                .await
+                .unwrap();
+            let reparent = |id: i32, parent_id: i32| {
+                repository.update(
+                    TEST_USER_ID,
+                    id,
+                    UpdateTodo::builder().parent_id(Some(parent_id)).build(),
+                )
+            };
+
+            // a -> b -> a (直接), a -> b -> c -> a (間接)
+            for parent_id in [b.id, c.id] {
+                let err = reparent(a.id, parent_id)
+                    .await
+                    .expect_err("cyclic parent accepted");
+                assert!(matches!(
+                    err.downcast_ref::<RepositoryError>(),
+                    Some(RepositoryError::InvalidParent(invalid)) if *invalid == parent_id
+                ));
+            }
+            assert_eq!(
+                repository.find(TEST_USER_ID, a.id).await.unwrap().parent_id,
+                None
+            );
+
+            // 循環しない付け替えはできる
+            let moved = reparent(c.id, a.id).await.unwrap();
+            assert_eq!(moved.parent_id, Some(a.id));
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn concurrent_writes_keep_ids_unique_and_count_consistent() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let tasks: Vec<_> = (0..100)
+                .map(|i| {
+                    let repository = repository.clone();
+                    tokio::spawn(async move {
+                        let todo = repository
+                            .create(TEST_USER_ID, CreateTodo::new(format!("todo {}", i), vec![]))
+                            .await
+                            .expect("failed create todo");
+                        repository
+                            .update(TEST_USER_ID, todo.id, UpdateTodo::completion(i % 2 == 0))
+                            .await
+                            .expect("failed update todo");
+                        // 3つに1つは削除する
+                        let deleted = i % 3 == 0;
+                        if deleted {
+                            repository
+                                .delete(TEST_USER_ID, todo.id)
+                                .await
+                                .expect("failed delete todo");
+                        }
+                        (todo.id, deleted)
+                    })
+                })
                 .collect();
-            labels
+            let mut ids = HashSet::new();
+            let mut remaining = 0;
+            for task in tasks {
+                let (id, deleted) = task.await.unwrap();
+                assert!(ids.insert(id), "id {} is used twice", id);
+                remaining += usize::from(!deleted);
+            }
+            let todos = repository
+                .all(TEST_USER_ID, TodoQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(todos.len(), remaining);
+            let count = repository
+                .count(TEST_USER_ID, TodoQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(count, remaining as i64);
+            // 削除されたidは再利用しない
+            let todo = repository
+                .create(TEST_USER_ID, CreateTodo::new("last".to_string(), vec![]))
+                .await
+                .unwrap();
+            assert_eq!(todo.id, 101);
         }
-    }
 
-    #[async_trait]
-    impl TodoRepository for TodoRepositoryForMemory {
-        // 実行時にエラーになる可能性があるのでanyhow::Result型
-        async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
-            let mut store = self.write_store_ref(); // スレッドセーフな書き込み権限ありHashMap
-            let id = (store.len() + 1) as i32; // HashMapの長さ+1をidにする(i32)
-            let labels = self.resolve_labels(payload.labels);
-            let todo = TodoEntity::new(id, payload.text.clone(), labels); // Todoインスタンスを新しく作成
-            store.insert(id, todo.clone()); // store(HashMap)に追加
-            Ok(todo) // Todoを返すことで、作成されたtodoのidやインスタンスを知れる
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn concurrent_updates_to_one_todo_are_not_lost() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("hammered".to_string(), vec![]),
+                )
+                .await
+                .unwrap();
+            // textだけの変更と完了だけの変更を同じtodoへ並行に行う
+            let tasks: Vec<_> = (0..100)
+                .map(|i| {
+                    let repository = repository.clone();
+                    tokio::spawn(async move {
+                        let payload = if i % 2 == 0 {
+                            UpdateTodo::new(Some(format!("text {}", i)), None, None)
+                        } else {
+                            UpdateTodo::completion(true)
+                        };
+                        let updated = repository
+                            .update(TEST_USER_ID, todo.id, payload)
+                            .await
+                            .expect("failed update todo");
+                        if i % 2 == 0 {
+                            assert_eq!(updated.text, format!("text {}", i));
+                        } else {
+                            assert!(updated.completed);
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+
+            // 読んでから書くまでの間に他の変更が入れば、textの変更が完了を未完了に戻してしまう
+            let todos = repository
+                .all(TEST_USER_ID, TodoQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(todos.len(), 1);
+            let hammered = &todos[0];
+            assert!(hammered.completed);
+            assert!(hammered.completed_at.is_some());
+            assert!((0..100)
+                .step_by(2)
+                .any(|i| hammered.text == format!("text {}", i)));
         }
 
-        async fn find(&self, id: i32) -> anyhow::Result<TodoEntity> {
-            let store = self.read_store_ref(); // read権限のあるstore
-            let todo = store
-                .get(&id)
-                .map(|todo| todo.clone()) // 指定されたidをgetして,そのcloneを返す
-                .ok_or(RepositoryError::NotFound(id))?;
-            Ok(todo)
+        // 1回の操作. idはそれまでに採番したidより2つ先までから選び、削除済み・未作成のidも含める
+        #[derive(Debug, Clone)]
+        enum Operation {
+            Create,
+            Find,
+            Update { text: bool, completed: Option<bool> },
+            Delete,
+            All,
         }
 
-        async fn all(&self) -> anyhow::Result<Vec<TodoEntity>> {
-            let store = self.read_store_ref(); // read権限のあるstore
-            Ok(Vec::from_iter(store.values().map(|todo| todo.clone()))) // storeの全データをクローンしたVector
+        fn operations() -> impl Strategy<Value = Vec<(i32, prop::sample::Index, Operation)>> {
+            let operation = prop_oneof![
+                Just(Operation::Create),
+                Just(Operation::Find),
+                (any::<bool>(), any::<Option<bool>>())
+                    .prop_map(|(text, completed)| Operation::Update { text, completed }),
+                Just(Operation::Delete),
+                Just(Operation::All),
+            ];
+            // 他のユーザーのtodoも選ぶ
+            prop::collection::vec((0..2, any::<prop::sample::Index>(), operation), 0..100)
         }
 
-        async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity> {
-            let mut store = self.write_store_ref(); // read権限のあるstore
-            let todo = store.get(&id).context(RepositoryError::NotFound(id))?; // idnの値をget. なければNotFoundエラー
-            let text = payload.text.unwrap_or(todo.text.clone()); // 引数のtext. なければtodoのtextのclone
-            let completed = payload.completed.unwrap_or(todo.completed);
-            let labels = match payload.labels {
-                Some(label_ids) => self.resolve_labels(label_ids),
-                None => todo.labels.clone(),
-            };
-            // 新しいtodoを作成
-            let todo = TodoEntity {
-                id,
-                text,
-                completed,
-                labels,
+        proptest! {
+            #[test]
+            fn random_operations_match_a_vec_model(operations in operations()) {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(run_against_vec_model(operations));
+            }
+        }
+
+        async fn run_against_vec_model(operations: Vec<(i32, prop::sample::Index, Operation)>) {
+            // (id, user_id, text, completed)をid順に持つだけのmodel
+            type Model = Vec<(i32, i32, String, bool)>;
+
+            let not_found = |err: anyhow::Error, id: i32| {
+                matches!(
+                    err.downcast_ref::<RepositoryError>(),
+                    Some(RepositoryError::NotFound(not_found)) if *not_found == id
+                )
             };
-            store.insert(id, todo.clone()); // idの場所へinsert
-            Ok(todo) // 成功したらOkで新しいtodoを返す
+
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let mut model: Model = vec![];
+            let mut last_id = 0;
+            for (step, (user, id, operation)) in operations.into_iter().enumerate() {
+                let context = format!("step {} {:?}", step, operation);
+                let user_id = TEST_USER_ID + user;
+                let id = id.index(last_id as usize + 2) as i32 + 1;
+                let owned = model
+                    .iter()
+                    .position(|todo| todo.0 == id && todo.1 == user_id);
+                match operation {
+                    Operation::Create => {
+                        let text = format!("todo {}", step);
+                        let todo = repository
+                            .create(user_id, CreateTodo::new(text.clone(), vec![]))
+                            .await
+                            .expect(&context);
+                        // idは削除されても再利用されない
+                        last_id += 1;
+                        assert_eq!(todo.id, last_id, "{}", context);
+                        model.push((todo.id, user_id, text, false));
+                    }
+                    Operation::Find => match (repository.find(user_id, id).await, owned) {
+                        (Ok(todo), Some(i)) => {
+                            let (_, _, text, completed) = &model[i];
+                            assert_eq!(&todo.text, text, "{}", context);
+                            assert_eq!(todo.completed, *completed, "{}", context);
+                        }
+                        (Err(err), None) => assert!(not_found(err, id), "{}", context),
+                        (res, owned) => panic!("{}: {:?} {:?}", context, res, owned),
+                    },
+                    Operation::Update { text, completed } => {
+                        let text = text.then(|| format!("updated {}", step));
+                        let payload = UpdateTodo::new(text.clone(), completed, None);
+                        match (repository.update(user_id, id, payload).await, owned) {
+                            (Ok(todo), Some(i)) => {
+                                let expected = &mut model[i];
+                                expected.2 = text.unwrap_or(expected.2.clone());
+                                expected.3 = completed.unwrap_or(expected.3);
+                                assert_eq!(todo.text, expected.2, "{}", context);
+                                assert_eq!(todo.completed, expected.3, "{}", context);
+                            }
+                            (Err(err), None) => assert!(not_found(err, id), "{}", context),
+                            (res, owned) => panic!("{}: {:?} {:?}", context, res, owned),
+                        }
+                    }
+                    Operation::Delete => match (repository.delete(user_id, id).await, owned) {
+                        (Ok(()), Some(i)) => {
+                            model.remove(i);
+                        }
+                        (Err(err), None) => assert!(not_found(err, id), "{}", context),
+                        (res, owned) => panic!("{}: {:?} {:?}", context, res, owned),
+                    },
+                    Operation::All => {
+                        let query = TodoQuery {
+                            sort: Some(TodoSort::IdAsc),
+                            ..TodoQuery::default()
+                        };
+                        let todos = repository.all(user_id, query).await.expect(&context);
+                        let actual: Vec<(i32, i32, String, bool)> = todos
+                            .into_iter()
+                            .map(|todo| (todo.id, todo.user_id, todo.text, todo.completed))
+                            .collect();
+                        let expected: Model = model
+                            .iter()
+                            .filter(|todo| todo.1 == user_id)
+                            .cloned()
+                            .collect();
+                        assert_eq!(actual, expected, "{}", context);
+                    }
+                }
+            }
         }
 
-        async fn delete(&self, id: i32) -> anyhow::Result<()> {
-            let mut store = self.write_store_ref(); // 書き込み権限ありsotre
-            store.remove(&id).ok_or(RepositoryError::NotFound(id))?; // idのデータがあればremove
-            Ok(()) // 成功すればOkを返す
+        #[tokio::test]
+        async fn keeps_working_after_panic_while_locked() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let store = repository.store.clone();
+            std::thread::spawn(move || {
+                let _locked = store.lock_all();
+                panic!("panic while holding the store");
+            })
+            .join()
+            .unwrap_err();
+
+            let todo = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateTodo::new("after panic".to_string(), vec![]),
+                )
+                .await
+                .expect("failed create todo");
+            assert_eq!(repository.find(TEST_USER_ID, todo.id).await.unwrap(), todo);
         }
-    }
 
-    #[cfg(test)]
-    mod test {
-        use super::*;
+        #[test]
+        fn treats_chains_deeper_than_the_limit_as_cycles() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let mut store = repository.store.lock_all();
+            // 1 <- 2 <- ... <- MAX_PARENT_DEPTH + 1 の連なり
+            for id in 1..=MAX_PARENT_DEPTH as i32 + 1 {
+                let mut todo = TodoEntity::new(id, format!("todo {}", id), vec![]);
+                todo.parent_id = (id > 1).then_some(id - 1);
+                store.insert(todo);
+            }
+            let deepest = MAX_PARENT_DEPTH as i32 + 1;
+            assert!(!would_create_cycle(&store, 0, MAX_PARENT_DEPTH as i32));
+            assert!(would_create_cycle(&store, 0, deepest));
+            assert!(would_create_cycle(&store, 1, 2));
+        }
 
         #[tokio::test]
-        async fn todo_crud_scenario() {
-            let text = "todo text".to_string();
-            let id = 1;
-            let label_data = Label {
-                id: 1,
-                name: String::from("test label"),
+        async fn filters_by_inclusive_created_range() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let day = |d: i64| test_now() + TimeDelta::days(d - 1);
+            for d in 1..=3 {
+                repository.set_now(day(d));
+                repository
+                    .create(TEST_USER_ID, CreateTodo::new(format!("day {}", d), vec![]))
+                    .await
+                    .unwrap();
+            }
+
+            for (from, to, expected) in [
+                (Some(day(1)), Some(day(2)), vec![1, 2]),
+                (Some(day(2)), Some(day(2)), vec![2]),
+                (Some(day(2)), None, vec![2, 3]),
+                (None, Some(day(1)), vec![1]),
+                (Some(day(4)), None, vec![]),
+            ] {
+                let query = TodoQuery {
+                    created_from: from,
+                    created_to: to,
+                    sort: Some(TodoSort::IdAsc),
+                    ..Default::default()
+                };
+                let ids: Vec<i32> = repository
+                    .all(TEST_USER_ID, query.clone())
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|todo| todo.id)
+                    .collect();
+                assert_eq!(ids, expected, "{:?}", query);
+                let count = repository.count(TEST_USER_ID, query).await.unwrap();
+                assert_eq!(count, expected.len() as i64);
+            }
+
+            // 他の条件とはANDで組み合わさる
+            let query = TodoQuery {
+                q: Some("day 3".to_string()),
+                created_to: Some(day(2)),
+                ..Default::default()
             };
-            let labels = vec![label_data.clone()];
-            let expected = TodoEntity {
-                id,
-                text: text.clone(),
-                completed: false,
-                labels: labels.clone(),
+            assert!(repository
+                .all(TEST_USER_ID, query)
+                .await
+                .unwrap()
+                .is_empty());
+
+            let inverted = TodoQuery {
+                created_from: Some(day(3)),
+                created_to: Some(day(1)),
+                ..Default::default()
             };
+            for err in [
+                repository
+                    .all(TEST_USER_ID, inverted.clone())
+                    .await
+                    .unwrap_err(),
+                repository
+                    .count(TEST_USER_ID, inverted.clone())
+                    .await
+                    .unwrap_err(),
+                repository
+                    .all_stream(TEST_USER_ID, inverted)
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap_err(),
+            ] {
+                assert!(matches!(
+                    err.downcast_ref::<RepositoryError>(),
+                    Some(RepositoryError::InvalidQuery(_))
+                ));
+            }
+        }
 
-            // create
-            let label_data = Label {
-                id: 1,
-                name: String::from("test label"),
+        #[tokio::test]
+        async fn count_applies_filters_but_not_paging() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            test_support::seed_todos(
+                &repository,
+                TEST_USER_ID,
+                ["buy milk", "Buy eggs", "call mom"]
+                    .map(|text| CreateTodo::new(text.to_string(), vec![])),
+            )
+            .await;
+            repository
+                .create(
+                    TEST_USER_ID + 1,
+                    CreateTodo::new("buy bread".to_string(), vec![]),
+                )
+                .await
+                .unwrap();
+
+            let query = TodoQuery {
+                q: Some("buy".to_string()),
+                limit: Some(1),
+                offset: Some(1),
+                ..Default::default()
             };
-            let labels = vec![label_data.clone()];
-            let repository = TodoRepositoryForMemory::new(labels.clone());
+            assert_eq!(repository.count(TEST_USER_ID, query).await.unwrap(), 2);
+            assert_eq!(
+                repository
+                    .count(TEST_USER_ID, TodoQuery::default())
+                    .await
+                    .unwrap(),
+                3
+            );
+        }
+
+        #[tokio::test]
+        async fn finds_todo_by_public_id() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
             let todo = repository
-                .create(CreateTodo::new(text, vec![label_data.id]))
+                .create(TEST_USER_ID, CreateTodo::new("public".to_string(), vec![]))
                 .await
-                .expect("failed create todo");
-            assert_eq!(expected, todo);
+                .unwrap();
 
-            // find
-            let todo = repository.find(todo.id).await.unwrap();
-            assert_eq!(expected, todo);
+            let found = repository
+                .find_by_public_id(TEST_USER_ID, todo.public_id)
+                .await
+                .unwrap();
+            assert_eq!(found, todo);
 
-            // all
-            let todo = repository.all().await.expect("failed get all todos");
-            assert_eq!(vec![expected], todo);
+            // 他のユーザーのtodoや存在しない公開idは見つからない
+            for (user_id, public_id) in [
+                (TEST_USER_ID + 1, todo.public_id),
+                (TEST_USER_ID, Uuid::new_v4()),
+            ] {
+                let err = repository
+                    .find_by_public_id(user_id, public_id)
+                    .await
+                    .expect_err("found todo by public id");
+                assert!(matches!(
+                    err.downcast_ref::<RepositoryError>(),
+                    Some(RepositoryError::PublicIdNotFound(id)) if *id == public_id
+                ));
+            }
+        }
 
-            // update
-            let text = "update todo text".to_string();
+        #[tokio::test]
+        async fn subtasks_follow_their_parent() {
+            let repository = TodoRepositoryForMemory::for_test(vec![]);
+            let create = |text: &str| CreateTodo::new(text.to_string(), vec![]);
+            let parent = repository
+                .create(TEST_USER_ID, create("trip"))
+                .await
+                .unwrap();
+            let packing = repository
+                .create(TEST_USER_ID, create("packing").subtask_of(parent.id))
+                .await
+                .unwrap();
+            let socks = repository
+                .create(TEST_USER_ID, create("socks").subtask_of(packing.id))
+                .await
+                .unwrap();
+            let tickets = repository
+                .create(TEST_USER_ID, create("tickets").subtask_of(parent.id))
+                .await
+                .unwrap();
+            assert_eq!(packing.parent_id, Some(parent.id));
+
+            // 孫は含まない
+            let subtasks = repository.subtasks(TEST_USER_ID, parent.id).await.unwrap();
+            assert_eq!(subtasks, vec![packing.clone(), tickets.clone()]);
+            assert!(repository
+                .subtasks(TEST_USER_ID, tickets.id)
+                .await
+                .unwrap()
+                .is_empty());
+
+            // 自分自身、子孫、存在しないtodo、他のユーザーのtodoは親にできない
+            let other = repository
+                .create(TEST_USER_ID + 1, create("other"))
+                .await
+                .unwrap();
+            for (id, parent_id) in [
+                (parent.id, parent.id),
+                (parent.id, socks.id),
+                (packing.id, 99),
+                (packing.id, other.id),
+            ] {
+                let payload = UpdateTodo::builder().parent_id(Some(parent_id)).build();
+                let err = repository
+                    .update(TEST_USER_ID, id, payload)
+                    .await
+                    .expect_err("invalid parent accepted");
+                assert!(matches!(
+                    err.downcast_ref::<RepositoryError>(),
+                    Some(RepositoryError::InvalidParent(invalid)) if *invalid == parent_id
+                ));
+            }
+            let err = repository
+                .create(TEST_USER_ID, create("orphan").subtask_of(99))
+                .await
+                .expect_err("missing parent accepted");
+            assert!(matches!(
+                err.downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::InvalidParent(99))
+            ));
+
+            // 指定しなければsubtaskは完了にならない
+            repository
+                .update(TEST_USER_ID, packing.id, UpdateTodo::completion(true))
+                .await
+                .unwrap();
+            let socks_now = repository.find(TEST_USER_ID, socks.id).await.unwrap();
+            assert!(!socks_now.completed);
+
+            let payload = UpdateTodo::builder()
+                .completed(true)
+                .complete_subtasks(true)
+                .build();
+            repository
+                .update(TEST_USER_ID, parent.id, payload)
+                .await
+                .unwrap();
+            for id in [packing.id, socks.id, tickets.id] {
+                let subtask = repository.find(TEST_USER_ID, id).await.unwrap();
+                assert!(subtask.completed, "{}", subtask.text);
+                assert_eq!(subtask.completed_at, Some(test_now()));
+            }
+
+            // 親を削除するとsubtaskは親の無いtodoになる
+            repository.delete(TEST_USER_ID, parent.id).await.unwrap();
+            let tickets = repository.find(TEST_USER_ID, tickets.id).await.unwrap();
+            assert_eq!(tickets.parent_id, None);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn snapshot_survives_dropping_the_repository() {
+            let path = env::temp_dir().join(format!("todo-snapshot-{}.json", std::process::id()));
+            fs::remove_file(&path).ok();
+
+            let repository = TodoRepositoryForMemory::for_test(vec![]).with_snapshot(&path);
+            test_support::seed_todos(
+                &repository,
+                TEST_USER_ID,
+                ["first", "second", "third"].map(|text| CreateTodo::new(text.to_string(), vec![])),
+            )
+            .await;
+            repository
+                .update(TEST_USER_ID, 1, UpdateTodo::completion(true))
+                .await
+                .unwrap();
+            repository.delete(TEST_USER_ID, 2).await.unwrap();
+            let expected = repository
+                .all(TEST_USER_ID, TodoQuery::default())
+                .await
+                .unwrap();
+            drop(repository);
+            // 破棄された後も書き出し待ちの変更は書き出される
+            tokio::time::sleep(SNAPSHOT_DEBOUNCE * 2).await;
+
+            let reloaded = TodoRepositoryForMemory::for_test(vec![]).with_snapshot(&path);
+            let todos = reloaded
+                .all(TEST_USER_ID, TodoQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(todos, expected);
+            assert!(todos.iter().any(|todo| todo.id == 1 && todo.completed));
+            fs::remove_file(&path).ok();
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn flush_snapshot_writes_without_waiting_for_debounce() {
+            let path =
+                env::temp_dir().join(format!("todo-snapshot-flush-{}.json", std::process::id()));
+            fs::remove_file(&path).ok();
+
+            let repository = TodoRepositoryForMemory::for_test(vec![]).with_snapshot(&path);
             let todo = repository
-                .update(
-                    id,
-                    UpdateTodo {
-                        text: Some(text.clone()),
-                        completed: Some(true),
-                        labels: Some(vec![]),
-                    },
-                )
+                .create(TEST_USER_ID, CreateTodo::new("flushed".to_string(), vec![]))
                 .await
-                .expect("failed update todo");
+                .unwrap();
+            // 時間を進めないので、書き出しタスクはまだ書き出していない
+            assert!(!path.exists());
+            repository.flush_snapshot().unwrap();
 
-            assert_eq!(
-                TodoEntity {
-                    id,
-                    text,
-                    completed: true,
-                    labels: vec![],
-                },
-                todo
-            );
+            assert_eq!(load_snapshot(&path).unwrap(), vec![todo]);
+            fs::remove_file(&path).ok();
+        }
 
-            // delete
-            let res = repository.delete(id).await;
-            assert!(res.is_ok());
+        #[tokio::test]
+        async fn corrupt_snapshot_starts_empty() {
+            let path =
+                env::temp_dir().join(format!("todo-snapshot-corrupt-{}.json", std::process::id()));
+            fs::write(&path, b"{ not json").unwrap();
+
+            let repository = TodoRepositoryForMemory::for_test(vec![]).with_snapshot(&path);
+            assert!(repository
+                .all(TEST_USER_ID, TodoQuery::default())
+                .await
+                .unwrap()
+                .is_empty());
+            fs::remove_file(&path).ok();
         }
     }
 }
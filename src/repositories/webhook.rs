@@ -0,0 +1,721 @@
+use super::{with_timeout, RepositoryError, DEFAULT_QUERY_TIMEOUT};
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    FromRow, PgPool,
+};
+use std::{future::Future, time::Duration};
+use validator::{Validate, ValidationError};
+
+#[async_trait]
+pub trait WebhookRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    /// secretはサーバー側で生成する
+    async fn create(&self, user_id: i32, payload: CreateWebhook) -> anyhow::Result<Webhook>;
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<Webhook>;
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Webhook>>;
+    async fn update(
+        &self,
+        user_id: i32,
+        id: i32,
+        payload: UpdateWebhook,
+    ) -> anyhow::Result<Webhook>;
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()>;
+    /// user_idの有効なwebhookのうちeventを購読しているものを返す
+    async fn subscribed(&self, user_id: i32, event: WebhookEvent) -> anyhow::Result<Vec<Webhook>>;
+    /// webhook_idへのevent_idの配信記録をpendingで作成する
+    async fn start_delivery(
+        &self,
+        webhook_id: i32,
+        event_id: i32,
+    ) -> anyhow::Result<WebhookDelivery>;
+    /// 試行の結果を配信記録に反映する
+    async fn save_delivery(&self, delivery: &WebhookDelivery) -> anyhow::Result<()>;
+    /// user_idのwebhookへの配信記録を新しい順にDELIVERY_LOG_LIMIT件まで返す
+    async fn deliveries(
+        &self,
+        user_id: i32,
+        webhook_id: i32,
+    ) -> anyhow::Result<Vec<WebhookDelivery>>;
+}
+
+/// GET /webhooks/:id/deliveries で返す配信記録の件数
+pub const DELIVERY_LOG_LIMIT: i64 = 100;
+
+/// webhookで通知するtodoの変更の種類
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, sqlx::Type)]
+#[sqlx(type_name = "text")]
+pub enum WebhookEvent {
+    #[serde(rename = "todo.created")]
+    #[sqlx(rename = "todo.created")]
+    Created,
+    #[serde(rename = "todo.updated")]
+    #[sqlx(rename = "todo.updated")]
+    Updated,
+    #[serde(rename = "todo.completed")]
+    #[sqlx(rename = "todo.completed")]
+    Completed,
+    #[serde(rename = "todo.deleted")]
+    #[sqlx(rename = "todo.deleted")]
+    Deleted,
+    #[serde(rename = "todo.reminded")]
+    #[sqlx(rename = "todo.reminded")]
+    Reminded,
+}
+
+// eventsはtext[]として保存する
+impl PgHasArrayType for WebhookEvent {
+    fn array_type_info() -> PgTypeInfo {
+        <String as PgHasArrayType>::array_type_info()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow)]
+pub struct Webhook {
+    pub id: i32,
+    pub user_id: i32,
+    pub url: String,
+    /// 配信の署名に使う. 作成時のレスポンス以外ではmaskedで伏せて返す
+    pub secret: String,
+    pub enabled: bool,
+    /// 通知するイベントの種類
+    pub events: Vec<WebhookEvent>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    /// secretを末尾4文字以外伏せたwebhookを返す
+    pub fn masked(self) -> Self {
+        let visible = self.secret.len().saturating_sub(4);
+        Self {
+            secret: format!("****{}", &self.secret[visible..]),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// 配信中または再試行待ち
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+/// 1つのイベントのwebhookへの配信記録. 試行のたびに更新する
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event_id: i32,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    /// 最後の試行のHTTPステータス. 接続できなかった場合はNone
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// 32byteの乱数をhexにしたsecret
+fn generate_secret() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+// secretを送るためhttpsのURLしか受け付けない
+fn validate_https(url: &str) -> Result<(), ValidationError> {
+    if url.to_ascii_lowercase().starts_with("https://") {
+        Ok(())
+    } else {
+        Err(ValidationError::new("https").with_message("Only https URLs are allowed.".into()))
+    }
+}
+
+// 更新で省略したURLは検証しない
+fn validate_optional_https(url: &Option<String>) -> Result<(), ValidationError> {
+    url.as_deref().map_or(Ok(()), validate_https)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct CreateWebhook {
+    #[validate(url(message = "Invalid URL."), custom(function = "validate_https"))]
+    url: String,
+    #[validate(length(min = 1, message = "At least 1 event."))]
+    events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateWebhook {
+    #[validate(
+        url(message = "Invalid URL."),
+        custom(function = "validate_optional_https")
+    )]
+    url: Option<String>,
+    enabled: Option<bool>,
+    #[validate(length(min = 1, message = "At least 1 event."))]
+    events: Option<Vec<WebhookEvent>>,
+}
+
+impl UpdateWebhook {
+    /// 配信を止めるための更新
+    pub fn disable() -> Self {
+        Self {
+            url: None,
+            enabled: Some(false),
+            events: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookRepositoryForDb {
+    pool: PgPool,
+    query_timeout: Duration,
+}
+
+impl WebhookRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+        }
+    }
+
+    pub fn with_query_timeout(self, query_timeout: Duration) -> Self {
+        Self {
+            query_timeout,
+            ..self
+        }
+    }
+
+    // DBへの問い合わせをquery_timeoutで打ち切る
+    async fn timed<T>(&self, future: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        with_timeout(self.query_timeout, future).await
+    }
+}
+
+#[async_trait]
+impl WebhookRepository for WebhookRepositoryForDb {
+    async fn create(&self, user_id: i32, payload: CreateWebhook) -> anyhow::Result<Webhook> {
+        self.timed(async move {
+            let webhook = checked_query_as!(
+                Webhook,
+                r#"
+                insert into webhooks (user_id, url, secret, events)
+                values ($1, $2, $3, $4)
+                returning id, user_id, url, secret, enabled, events as "events: Vec<WebhookEvent>", created_at
+                "#,
+                user_id,
+                payload.url,
+                generate_secret(),
+                &payload.events as _
+            )
+            .fetch_one(&self.pool)
+            .await?;
+
+            Ok(webhook)
+        })
+        .await
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<Webhook> {
+        self.timed(async move {
+            let webhook = checked_query_as!(
+                Webhook,
+                r#"
+                select id, user_id, url, secret, enabled, events as "events: Vec<WebhookEvent>", created_at
+                from webhooks where id=$1 and user_id=$2
+                "#,
+                id,
+                user_id
+            )
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(RepositoryError::NotFound(id))?;
+
+            Ok(webhook)
+        })
+        .await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Webhook>> {
+        self.timed(async move {
+            let webhooks = checked_query_as!(
+                Webhook,
+                r#"
+                select id, user_id, url, secret, enabled, events as "events: Vec<WebhookEvent>", created_at
+                from webhooks
+                where user_id = $1
+                order by id asc
+                "#,
+                user_id
+            )
+            .fetch_all(&self.pool)
+            .await?;
+
+            Ok(webhooks)
+        })
+        .await
+    }
+
+    async fn update(
+        &self,
+        user_id: i32,
+        id: i32,
+        payload: UpdateWebhook,
+    ) -> anyhow::Result<Webhook> {
+        self.timed(async move {
+            // 指定されなかった項目は元の値のままにする
+            let webhook = checked_query_as!(
+                Webhook,
+                r#"
+                update webhooks set url = coalesce($1, url),
+                    enabled = coalesce($2, enabled), events = coalesce($3, events)
+                where id=$4 and user_id=$5
+                returning id, user_id, url, secret, enabled, events as "events: Vec<WebhookEvent>", created_at
+                "#,
+                payload.url,
+                payload.enabled,
+                &payload.events as _,
+                id,
+                user_id
+            )
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(RepositoryError::NotFound(id))?;
+
+            Ok(webhook)
+        })
+        .await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.timed(async move {
+            let deleted = checked_query!(
+                r#"
+                delete from webhooks where id=$1 and user_id=$2
+                "#,
+                id,
+                user_id
+            )
+            .execute(&self.pool)
+            .await?;
+            if deleted.rows_affected() == 0 {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn subscribed(&self, user_id: i32, event: WebhookEvent) -> anyhow::Result<Vec<Webhook>> {
+        self.timed(async move {
+            let webhooks = checked_query_as!(
+                Webhook,
+                r#"
+                select id, user_id, url, secret, enabled, events as "events: Vec<WebhookEvent>", created_at
+                from webhooks
+                where user_id = $1 and enabled and $2 = any(events)
+                order by id asc
+                "#,
+                user_id,
+                event as _
+            )
+            .fetch_all(&self.pool)
+            .await?;
+
+            Ok(webhooks)
+        })
+        .await
+    }
+
+    async fn start_delivery(
+        &self,
+        webhook_id: i32,
+        event_id: i32,
+    ) -> anyhow::Result<WebhookDelivery> {
+        self.timed(async move {
+            let status = DeliveryStatus::Pending;
+            let delivery = checked_query_as!(
+                WebhookDelivery,
+                r#"
+                insert into webhook_deliveries (webhook_id, event_id, status)
+                values ($1, $2, $3)
+                returning id, webhook_id, event_id, status as "status: DeliveryStatus", attempts,
+                    response_status, last_error, created_at, updated_at
+                "#,
+                webhook_id,
+                event_id,
+                status as _
+            )
+            .fetch_one(&self.pool)
+            .await?;
+
+            Ok(delivery)
+        })
+        .await
+    }
+
+    async fn save_delivery(&self, delivery: &WebhookDelivery) -> anyhow::Result<()> {
+        self.timed(async move {
+            checked_query!(
+                r#"
+                update webhook_deliveries set status=$1, attempts=$2, response_status=$3,
+                    last_error=$4, updated_at=now()
+                where id=$5
+                "#,
+                delivery.status as _,
+                delivery.attempts,
+                delivery.response_status,
+                delivery.last_error,
+                delivery.id
+            )
+            .execute(&self.pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn deliveries(
+        &self,
+        user_id: i32,
+        webhook_id: i32,
+    ) -> anyhow::Result<Vec<WebhookDelivery>> {
+        // 他のユーザーのwebhookはNotFoundにする
+        self.find(user_id, webhook_id).await?;
+        self.timed(async move {
+            let deliveries = checked_query_as!(
+                WebhookDelivery,
+                r#"
+                select id, webhook_id, event_id, status as "status: DeliveryStatus", attempts,
+                    response_status, last_error, created_at, updated_at
+                from webhook_deliveries
+                where webhook_id = $1
+                order by id desc
+                limit $2
+                "#,
+                webhook_id,
+                DELIVERY_LOG_LIMIT
+            )
+            .fetch_all(&self.pool)
+            .await?;
+
+            Ok(deliveries)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use crate::repositories::test_database::db_pool_or_skip;
+    use crate::repositories::todo::test_utils::TEST_USER_ID;
+
+    #[tokio::test]
+    async fn crud_scenario() {
+        let Some((_database, pool)) = db_pool_or_skip().await else {
+            return;
+        };
+        let repository = WebhookRepositoryForDb::new(pool);
+
+        // create
+        let webhook = repository
+            .create(
+                TEST_USER_ID,
+                CreateWebhook::new(
+                    "https://example.com/hook",
+                    vec![WebhookEvent::Created, WebhookEvent::Completed],
+                ),
+            )
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(webhook.secret.len(), 64);
+        assert_eq!(
+            webhook.events,
+            vec![WebhookEvent::Created, WebhookEvent::Completed]
+        );
+
+        // find
+        let found = repository
+            .find(TEST_USER_ID, webhook.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(found, webhook);
+        assert!(repository.find(TEST_USER_ID + 1, webhook.id).await.is_err());
+
+        // update
+        let updated = repository
+            .update(TEST_USER_ID, webhook.id, UpdateWebhook::disable())
+            .await
+            .expect("[update] returned Err");
+        assert!(!updated.enabled);
+        assert_eq!(updated.url, webhook.url);
+
+        // all
+        let webhooks = repository
+            .all(TEST_USER_ID)
+            .await
+            .expect("[all] returned Err");
+        assert_eq!(webhooks.last(), Some(&updated));
+
+        // delete
+        repository
+            .delete(TEST_USER_ID, webhook.id)
+            .await
+            .expect("[delete] returned Err");
+        assert!(repository.find(TEST_USER_ID, webhook.id).await.is_err());
+    }
+}
+
+#[cfg(any(test, feature = "memory"))]
+pub mod test_utils {
+    use super::*;
+
+    impl CreateWebhook {
+        pub fn new(url: &str, events: Vec<WebhookEvent>) -> Self {
+            Self {
+                url: url.to_string(),
+                events,
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "memory"))]
+pub mod memory {
+    use axum::async_trait;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, RwLock},
+    };
+
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    pub struct WebhookRepositoryForMemory {
+        store: Arc<RwLock<HashMap<i32, Webhook>>>,
+        deliveries: Arc<RwLock<Vec<WebhookDelivery>>>,
+    }
+
+    impl WebhookRepositoryForMemory {
+        pub fn new() -> Self {
+            Self::default()
+        }
+    }
+
+    #[async_trait]
+    impl WebhookRepository for WebhookRepositoryForMemory {
+        async fn create(&self, user_id: i32, payload: CreateWebhook) -> anyhow::Result<Webhook> {
+            let mut store = self.store.write().unwrap();
+            let id = (store.len() + 1) as i32;
+            let webhook = Webhook {
+                id,
+                user_id,
+                url: payload.url,
+                secret: generate_secret(),
+                enabled: true,
+                events: payload.events,
+                created_at: Utc::now(),
+            };
+            store.insert(id, webhook.clone());
+            Ok(webhook)
+        }
+
+        async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<Webhook> {
+            let store = self.store.read().unwrap();
+            store
+                .get(&id)
+                .filter(|webhook| webhook.user_id == user_id)
+                .cloned()
+                .ok_or(RepositoryError::NotFound(id).into())
+        }
+
+        async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Webhook>> {
+            let store = self.store.read().unwrap();
+            let mut webhooks: Vec<Webhook> = store
+                .values()
+                .filter(|webhook| webhook.user_id == user_id)
+                .cloned()
+                .collect();
+            webhooks.sort_by_key(|webhook| webhook.id);
+            Ok(webhooks)
+        }
+
+        async fn update(
+            &self,
+            user_id: i32,
+            id: i32,
+            payload: UpdateWebhook,
+        ) -> anyhow::Result<Webhook> {
+            let mut store = self.store.write().unwrap();
+            let webhook = store
+                .get_mut(&id)
+                .filter(|webhook| webhook.user_id == user_id)
+                .ok_or(RepositoryError::NotFound(id))?;
+            if let Some(url) = payload.url {
+                webhook.url = url;
+            }
+            if let Some(enabled) = payload.enabled {
+                webhook.enabled = enabled;
+            }
+            if let Some(events) = payload.events {
+                webhook.events = events;
+            }
+            Ok(webhook.clone())
+        }
+
+        async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+            let mut store = self.store.write().unwrap();
+            if store
+                .get(&id)
+                .is_none_or(|webhook| webhook.user_id != user_id)
+            {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            store.remove(&id);
+            Ok(())
+        }
+
+        async fn subscribed(
+            &self,
+            user_id: i32,
+            event: WebhookEvent,
+        ) -> anyhow::Result<Vec<Webhook>> {
+            let webhooks = self.all(user_id).await?;
+            Ok(webhooks
+                .into_iter()
+                .filter(|webhook| webhook.enabled && webhook.events.contains(&event))
+                .collect())
+        }
+
+        async fn start_delivery(
+            &self,
+            webhook_id: i32,
+            event_id: i32,
+        ) -> anyhow::Result<WebhookDelivery> {
+            let mut deliveries = self.deliveries.write().unwrap();
+            let now = Utc::now();
+            let delivery = WebhookDelivery {
+                id: (deliveries.len() + 1) as i32,
+                webhook_id,
+                event_id,
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                response_status: None,
+                last_error: None,
+                created_at: now,
+                updated_at: now,
+            };
+            deliveries.push(delivery.clone());
+            Ok(delivery)
+        }
+
+        async fn save_delivery(&self, delivery: &WebhookDelivery) -> anyhow::Result<()> {
+            let mut deliveries = self.deliveries.write().unwrap();
+            if let Some(saved) = deliveries.iter_mut().find(|saved| saved.id == delivery.id) {
+                *saved = WebhookDelivery {
+                    updated_at: Utc::now(),
+                    ..delivery.clone()
+                };
+            }
+            Ok(())
+        }
+
+        async fn deliveries(
+            &self,
+            user_id: i32,
+            webhook_id: i32,
+        ) -> anyhow::Result<Vec<WebhookDelivery>> {
+            self.find(user_id, webhook_id).await?;
+            let deliveries = self.deliveries.read().unwrap();
+            Ok(deliveries
+                .iter()
+                .rev()
+                .filter(|delivery| delivery.webhook_id == webhook_id)
+                .take(DELIVERY_LOG_LIMIT as usize)
+                .cloned()
+                .collect())
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::repositories::todo::test_utils::TEST_USER_ID;
+
+        #[tokio::test]
+        async fn webhook_crud_scenario() {
+            let repository = WebhookRepositoryForMemory::new();
+            let webhook = repository
+                .create(
+                    TEST_USER_ID,
+                    CreateWebhook::new("https://example.com/hook", vec![WebhookEvent::Created]),
+                )
+                .await
+                .expect("failed webhook create");
+            assert!(webhook.enabled);
+            assert_eq!(webhook.secret.len(), 64);
+            assert!(webhook.secret.chars().all(|c| c.is_ascii_hexdigit()));
+
+            // 他のユーザーからは見えない
+            assert!(repository.find(TEST_USER_ID + 1, webhook.id).await.is_err());
+            assert!(repository.all(TEST_USER_ID + 1).await.unwrap().is_empty());
+
+            let updated = repository
+                .update(TEST_USER_ID, webhook.id, UpdateWebhook::disable())
+                .await
+                .expect("failed webhook update");
+            assert_eq!(
+                updated,
+                Webhook {
+                    enabled: false,
+                    ..webhook.clone()
+                }
+            );
+            assert_eq!(repository.all(TEST_USER_ID).await.unwrap(), vec![updated]);
+
+            assert!(repository
+                .delete(TEST_USER_ID + 1, webhook.id)
+                .await
+                .is_err());
+            assert!(repository.delete(TEST_USER_ID, webhook.id).await.is_ok());
+            assert!(repository.find(TEST_USER_ID, webhook.id).await.is_err());
+        }
+
+        #[test]
+        fn masked_hides_all_but_last_four_characters() {
+            let webhook = Webhook {
+                id: 1,
+                user_id: TEST_USER_ID,
+                url: "https://example.com/hook".to_string(),
+                secret: "0123456789abcdef".to_string(),
+                enabled: true,
+                events: vec![WebhookEvent::Deleted],
+                created_at: Utc::now(),
+            };
+            assert_eq!(webhook.masked().secret, "****cdef");
+        }
+
+        #[test]
+        fn rejects_non_https_urls() {
+            let valid = CreateWebhook::new("https://example.com/hook", vec![WebhookEvent::Created]);
+            assert!(valid.validate().is_ok());
+            let http = CreateWebhook::new("http://example.com/hook", vec![WebhookEvent::Created]);
+            assert!(http.validate().is_err());
+            let no_events = CreateWebhook::new("https://example.com/hook", vec![]);
+            assert!(no_events.validate().is_err());
+        }
+    }
+}
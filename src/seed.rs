@@ -0,0 +1,203 @@
+use anyhow::{bail, Context};
+use sqlx::PgPool;
+use std::env;
+
+use crate::repositories::{
+    label::{LabelQuery, LabelRepository},
+    todo::{CreateTodo, TodoQuery, TodoRepository, UpdateTodo},
+};
+
+/// 本番環境かどうかを表す環境変数. productionならseedを実行しない
+pub const APP_ENV: &str = "APP_ENV";
+
+// 作成するlabelの名前. 足りない分は番号を付けて使い回す
+const LABEL_NAMES: [&str; 8] = [
+    "work", "home", "errand", "health", "finance", "study", "family", "travel",
+];
+// todoのtextは動詞と目的語の組み合わせで作る
+const VERBS: [&str; 8] = [
+    "Buy", "Call", "Review", "Plan", "Clean", "Write", "Fix", "Book",
+];
+const OBJECTS: [&str; 7] = [
+    "groceries",
+    "the dentist",
+    "the quarterly report",
+    "the weekend trip",
+    "the garage",
+    "a blog post",
+    "the leaky faucet",
+];
+
+/// seedサブコマンドの設定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeedOptions {
+    /// データを作成するユーザー
+    pub user_id: i32,
+    /// 作成後にユーザーが持つtodoの数
+    pub todos: usize,
+    /// 作成後にユーザーが参照できるseedのlabelの数
+    pub labels: usize,
+}
+
+/// seedで新しく作成した件数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SeedReport {
+    pub labels_created: usize,
+    pub todos_created: usize,
+}
+
+// APP_ENVがproductionならエラーにする
+pub fn ensure_not_production() -> anyhow::Result<()> {
+    match env::var(APP_ENV) {
+        Ok(app_env) if app_env.eq_ignore_ascii_case("production") => {
+            bail!("refusing to seed: [{}] is [{}]", APP_ENV, app_env)
+        }
+        _ => Ok(()),
+    }
+}
+
+// seedで作るデータを含め、todoとlabelを全て削除してidを振り直す
+pub async fn truncate(pool: &PgPool) -> anyhow::Result<()> {
+    sqlx::query("truncate table todo_labels, todos, labels restart identity cascade")
+        .execute(pool)
+        .await
+        .context("failed to truncate todos and labels")?;
+    Ok(())
+}
+
+// 同じ名前のlabelがあれば使い回し、todoは足りない分だけ作るので、繰り返し実行しても増え続けない
+pub async fn seed<T: TodoRepository, L: LabelRepository>(
+    todos: &T,
+    labels: &L,
+    options: &SeedOptions,
+) -> anyhow::Result<SeedReport> {
+    let mut report = SeedReport::default();
+
+    let existing = labels.all(options.user_id, LabelQuery::default()).await?;
+    let mut label_ids = Vec::with_capacity(options.labels);
+    for i in 0..options.labels {
+        let name = label_name(i);
+        let id = match existing.iter().find(|label| label.name == name) {
+            Some(label) => label.id,
+            None => {
+                report.labels_created += 1;
+                labels.create(Some(options.user_id), name).await?.id
+            }
+        };
+        label_ids.push(id);
+    }
+
+    let current = todos
+        .all(options.user_id, TodoQuery::default())
+        .await?
+        .len();
+    for i in current..options.todos {
+        // 3件に2件はlabelを付ける
+        let todo_labels = if label_ids.is_empty() || i % 3 == 2 {
+            vec![]
+        } else {
+            vec![label_ids[i % label_ids.len()]]
+        };
+        let todo = todos
+            .create(options.user_id, CreateTodo::new(todo_text(i), todo_labels))
+            .await?;
+        // 4件に1件は完了済みにする
+        if i % 4 == 0 {
+            todos
+                .update(options.user_id, todo.id, UpdateTodo::completion(true))
+                .await?;
+        }
+        report.todos_created += 1;
+    }
+    Ok(report)
+}
+
+fn label_name(i: usize) -> String {
+    let name = LABEL_NAMES[i % LABEL_NAMES.len()];
+    match i / LABEL_NAMES.len() {
+        0 => name.to_string(),
+        n => format!("{}-{}", name, n + 1),
+    }
+}
+
+fn todo_text(i: usize) -> String {
+    // 動詞と目的語の数が互いに素なので、56件までは同じtextにならない
+    format!("{} {}", VERBS[i % VERBS.len()], OBJECTS[i % OBJECTS.len()])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::memory::LabelRepositoryForMemory,
+        todo::{memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID},
+    };
+
+    #[tokio::test]
+    async fn seeds_memory_repositories_without_duplicating() {
+        let labels = LabelRepositoryForMemory::new();
+        let todos = TodoRepositoryForMemory::for_test(vec![]).with_labels(&labels);
+        let options = SeedOptions {
+            user_id: TEST_USER_ID,
+            todos: 10,
+            labels: 3,
+        };
+
+        let report = seed(&todos, &labels, &options).await.unwrap();
+        assert_eq!(
+            report,
+            SeedReport {
+                labels_created: 3,
+                todos_created: 10
+            }
+        );
+        let seeded = todos.all(TEST_USER_ID, TodoQuery::default()).await.unwrap();
+        assert_eq!(seeded.len(), 10);
+        assert_eq!(seeded.iter().filter(|todo| todo.completed).count(), 3);
+        assert!(seeded.iter().any(|todo| !todo.labels.is_empty()));
+
+        // 2回目は足りない分だけを作る
+        let report = seed(
+            &todos,
+            &labels,
+            &SeedOptions {
+                todos: 12,
+                ..options
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            report,
+            SeedReport {
+                labels_created: 0,
+                todos_created: 2
+            }
+        );
+        let names: Vec<String> = labels
+            .all(TEST_USER_ID, LabelQuery::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|label| label.name)
+            .collect();
+        assert_eq!(names, vec!["errand", "home", "work"]);
+    }
+
+    #[test]
+    fn numbers_label_names_beyond_the_list() {
+        assert_eq!(label_name(0), "work");
+        assert_eq!(label_name(LABEL_NAMES.len()), "work-2");
+        assert_eq!(label_name(LABEL_NAMES.len() * 2 + 1), "home-3");
+    }
+
+    #[test]
+    fn refuses_to_seed_production() {
+        env::set_var(APP_ENV, "Production");
+        assert!(ensure_not_production().is_err());
+        env::set_var(APP_ENV, "development");
+        assert!(ensure_not_production().is_ok());
+        env::remove_var(APP_ENV);
+        assert!(ensure_not_production().is_ok());
+    }
+}
@@ -0,0 +1,157 @@
+//! テストとベンチマーク(benches/)で使う合成データ. 乱数を使わず、同じ引数なら同じデータになる
+use crate::repositories::{
+    label::Label,
+    recurrence::Recurrence,
+    todo::{
+        test_utils::{test_now, test_public_id},
+        CreateTodo, TodoEntity, TodoRepository, TodoWithLabelFromRow,
+    },
+};
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// 誰でも使えるid 1のlabel. 別のidが要る時はlabelsを使う
+pub fn a_label(name: &str) -> Label {
+    Label::new(1, name.to_string())
+}
+
+/// 期日やlabelの無いtodoを作成するpayload
+pub fn a_create_todo() -> CreateTodo {
+    CreateTodo::new("todo".to_string(), vec![])
+}
+
+/// テスト用のTodoEntityのbuilder. 呼ばなかった項目は、TodoRepositoryForMemoryが
+/// id 1のtodoを作成した直後に返す値になる
+#[derive(Debug, Clone)]
+pub struct TodoEntityBuilder(TodoEntity);
+
+impl Default for TodoEntityBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TodoEntityBuilder {
+    pub fn new() -> Self {
+        Self(TodoEntity::new(1, "todo".to_string(), vec![]))
+    }
+
+    /// 公開idもTodoRepositoryForMemoryと同じくidから決める
+    pub fn id(mut self, id: i32) -> Self {
+        self.0.id = id;
+        self.0.public_id = test_public_id(id);
+        self
+    }
+
+    pub fn user_id(mut self, user_id: i32) -> Self {
+        self.0.user_id = user_id;
+        self
+    }
+
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.0.text = text.into();
+        self
+    }
+
+    /// 作成と同時に完了したtodoにする
+    pub fn completed(mut self) -> Self {
+        self.0.completed = true;
+        self.0.completed_at = Some(test_now());
+        self
+    }
+
+    pub fn with_label(mut self, label: Label) -> Self {
+        self.0.labels.push(label);
+        self
+    }
+
+    pub fn due_date(mut self, due_date: NaiveDate) -> Self {
+        self.0.due_date = Some(due_date);
+        self
+    }
+
+    pub fn recurrence(mut self, recurrence: Recurrence) -> Self {
+        self.0.recurrence = recurrence;
+        self
+    }
+
+    pub fn remind_at(mut self, remind_at: DateTime<Utc>) -> Self {
+        self.0.remind_at = Some(remind_at);
+        self
+    }
+
+    pub fn reminded_at(mut self, reminded_at: DateTime<Utc>) -> Self {
+        self.0.reminded_at = Some(reminded_at);
+        self
+    }
+
+    pub fn parent_id(mut self, parent_id: i32) -> Self {
+        self.0.parent_id = Some(parent_id);
+        self
+    }
+
+    pub fn build(self) -> TodoEntity {
+        self.0
+    }
+}
+
+/// 誰でも使えるn個のlabel. idは1から
+pub fn labels(n: usize) -> Vec<Label> {
+    (1..=n as i32)
+        .map(|id| Label::new(id, format!("label {}", id)))
+        .collect()
+}
+
+// i番目のtodoが持つlabelの位置. todoごとに1つずつずらし、labelが偏らないようにする
+fn label_positions(i: usize, labels: usize, fan_out: usize) -> impl Iterator<Item = usize> {
+    (0..fan_out.min(labels)).map(move |j| (i + j) % labels)
+}
+
+/// idが1からのn件のtodo. 各todoはlabelsからfan_out個(labelsの数まで)のlabelを持つ
+pub fn todos(n: usize, labels: &[Label], fan_out: usize) -> Vec<TodoEntity> {
+    (0..n)
+        .map(|i| {
+            let labels = label_positions(i, labels.len(), fan_out)
+                .map(|position| labels[position].clone())
+                .collect();
+            TodoEntity::new(i as i32 + 1, format!("todo {}", i + 1), labels)
+        })
+        .collect()
+}
+
+/// todosをlabelと結合した、DBから読んだ時と同じ行にする
+pub fn todo_rows(todos: &[TodoEntity]) -> Vec<TodoWithLabelFromRow> {
+    todos
+        .iter()
+        .flat_map(TodoWithLabelFromRow::rows_of)
+        .collect()
+}
+
+/// todosと同じ並びでlabelを付けて作成するn件のpayload
+pub fn create_todos(n: usize, label_ids: &[i32], fan_out: usize) -> Vec<CreateTodo> {
+    (0..n)
+        .map(|i| {
+            let labels = label_positions(i, label_ids.len(), fan_out)
+                .map(|position| label_ids[position])
+                .collect();
+            CreateTodo::new(format!("todo {}", i + 1), labels)
+        })
+        .collect()
+}
+
+/// payloadsを順にuser_idのtodoとして作成し、作成したtodoを返す
+pub async fn seed_todos<T: TodoRepository>(
+    repository: &T,
+    user_id: i32,
+    payloads: impl IntoIterator<Item = CreateTodo>,
+) -> Vec<TodoEntity> {
+    let mut todos = Vec::new();
+    for payload in payloads {
+        todos.push(
+            repository
+                .create(user_id, payload)
+                .await
+                .expect("failed create todo"),
+        );
+    }
+    todos
+}
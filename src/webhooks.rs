@@ -0,0 +1,680 @@
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use hyper::header::CONTENT_TYPE;
+use serde::Serialize;
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::JoinSet,
+};
+
+use crate::{
+    reminders::Notifier,
+    repositories::{
+        activity::{
+            ActivityQuery, ActivityRepository, NewTodoEvent, TodoEvent, TodoEventKind,
+            TodoEventPayload,
+        },
+        retry::RetryPolicy,
+        todo::TodoEntity,
+        webhook::{
+            DeliveryStatus, UpdateWebhook, Webhook, WebhookDelivery, WebhookEvent,
+            WebhookRepository,
+        },
+        RepositoryError,
+    },
+};
+
+/// 配信するbodyの署名を入れるヘッダ
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// 5xxと接続エラーを最大5回、1秒から倍々の間隔で再試行する
+pub const DEFAULT_DELIVERY_POLICY: RetryPolicy = RetryPolicy {
+    max_retries: 5,
+    base_backoff: Duration::from_secs(1),
+};
+
+/// 停止時に配信中のtaskを待つ時間. 過ぎたら残りの配信は中断し、配信記録はpendingのまま残る
+pub const DELIVERY_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// webhookの送信に使うHTTPクライアント
+#[async_trait]
+pub trait WebhookClient: Clone + std::marker::Send + std::marker::Sync + 'static {
+    /// bodyをurlにPOSTしてレスポンスのステータスコードを返す. 接続できない・タイムアウトした場合はErr
+    async fn post(&self, url: &str, signature: &str, body: Vec<u8>) -> anyhow::Result<u16>;
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpWebhookClient {
+    client: reqwest::Client,
+}
+
+impl HttpWebhookClient {
+    /// timeoutを過ぎても応答が無ければ失敗として再試行に回す
+    pub fn new(timeout: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .expect("failed to build webhook http client");
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl WebhookClient for HttpWebhookClient {
+    async fn post(&self, url: &str, signature: &str, body: Vec<u8>) -> anyhow::Result<u16> {
+        let res = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(SIGNATURE_HEADER, signature)
+            .body(body)
+            .send()
+            .await?;
+        Ok(res.status().as_u16())
+    }
+}
+
+/// bodyのHMAC-SHA256を`sha256=<hex>`の形式で返す
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// 取り消しは変更の一種としてtodo.updatedで通知する
+fn webhook_event(kind: TodoEventKind) -> WebhookEvent {
+    match kind {
+        TodoEventKind::Created => WebhookEvent::Created,
+        TodoEventKind::Updated | TodoEventKind::Undone => WebhookEvent::Updated,
+        TodoEventKind::Completed => WebhookEvent::Completed,
+        TodoEventKind::Deleted => WebhookEvent::Deleted,
+        TodoEventKind::Reminded => WebhookEvent::Reminded,
+    }
+}
+
+/// webhookでPOSTするbody
+#[derive(Debug, Serialize)]
+struct WebhookBody<'a> {
+    /// 記録したイベントのid. 受信側での重複排除に使える
+    id: i32,
+    #[serde(rename = "type")]
+    event: WebhookEvent,
+    todo_id: i32,
+    created_at: DateTime<Utc>,
+    data: &'a TodoEventPayload,
+}
+
+/// 記録したイベントをwebhookの配信キューにも送るActivityRepositoryのdecorator.
+///
+/// キューが一杯なら配信を諦めてログに残し、記録とAPIのレスポンスは妨げない
+#[derive(Debug, Clone)]
+pub struct PublishingActivity<A> {
+    inner: A,
+    queue: mpsc::Sender<TodoEvent>,
+}
+
+impl<A: ActivityRepository> PublishingActivity<A> {
+    pub fn new(inner: A, queue: mpsc::Sender<TodoEvent>) -> Self {
+        Self { inner, queue }
+    }
+
+    fn enqueue(&self, event: &TodoEvent) {
+        if let Err(e) = self.queue.try_send(event.clone()) {
+            tracing::warn!(event_id = event.id, "dropped webhook event: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl<A: ActivityRepository> ActivityRepository for PublishingActivity<A> {
+    async fn record(&self, event: NewTodoEvent) -> anyhow::Result<TodoEvent> {
+        let event = self.inner.record(event).await?;
+        self.enqueue(&event);
+        Ok(event)
+    }
+
+    async fn list(&self, actor_id: i32, query: ActivityQuery) -> anyhow::Result<Vec<TodoEvent>> {
+        self.inner.list(actor_id, query).await
+    }
+
+    async fn count(&self, actor_id: i32) -> anyhow::Result<i64> {
+        self.inner.count(actor_id).await
+    }
+
+    async fn history(&self, actor_id: i32, todo_id: i32) -> anyhow::Result<Vec<TodoEvent>> {
+        self.inner.history(actor_id, todo_id).await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TodoEvent> {
+        self.inner.subscribe()
+    }
+
+    fn publish(&self, event: &TodoEvent) {
+        self.inner.publish(event);
+        self.enqueue(event);
+    }
+}
+
+/// リマインドをtodo.remindedのイベントとして記録するNotifier.
+///
+/// activityがPublishingActivityなら、todoの変更と同じキューからwebhookへ配信される
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier<A> {
+    activity: A,
+}
+
+impl<A: ActivityRepository> WebhookNotifier<A> {
+    pub fn new(activity: A) -> Self {
+        Self { activity }
+    }
+}
+
+#[async_trait]
+impl<A: ActivityRepository> Notifier for WebhookNotifier<A> {
+    async fn notify(&self, todo: &TodoEntity) -> anyhow::Result<()> {
+        tracing::info!(todo_id = todo.id, text = %todo.text, "reminder");
+        self.activity
+            .record(NewTodoEvent {
+                todo_id: todo.id,
+                actor_id: todo.user_id,
+                kind: TodoEventKind::Reminded,
+                payload: TodoEventPayload::Snapshot(todo.clone()),
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+// 配信を始めた後で無効にされたり削除されたりしていないか
+async fn is_active<W: WebhookRepository>(
+    repository: &W,
+    webhook: &Webhook,
+) -> anyhow::Result<bool> {
+    match repository.find(webhook.user_id, webhook.id).await {
+        Ok(webhook) => Ok(webhook.enabled),
+        Err(e) if matches!(e.downcast_ref(), Some(RepositoryError::NotFound(_))) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// eventをwebhookに配信し、試行ごとに配信記録を更新する.
+///
+/// 5xxと接続エラーはpolicyに従って再試行し、410 Goneが返ったwebhookは無効にする.
+/// 試行の前にwebhookがまだ有効かを確かめ、無効・削除済みなら送らずに失敗とする
+pub async fn deliver<W: WebhookRepository, C: WebhookClient>(
+    repository: &W,
+    client: &C,
+    policy: &RetryPolicy,
+    webhook: &Webhook,
+    event: &TodoEvent,
+) -> anyhow::Result<WebhookDelivery> {
+    let body = serde_json::to_vec(&WebhookBody {
+        id: event.id,
+        event: webhook_event(event.kind),
+        todo_id: event.todo_id,
+        created_at: event.created_at,
+        data: &event.payload,
+    })?;
+    let signature = sign(&webhook.secret, &body);
+    let mut delivery = repository.start_delivery(webhook.id, event.id).await?;
+
+    loop {
+        if !is_active(repository, webhook).await? {
+            delivery.status = DeliveryStatus::Failed;
+            delivery.last_error = Some("webhook is disabled or deleted".to_string());
+            repository.save_delivery(&delivery).await?;
+            return Ok(delivery);
+        }
+        let retry = delivery.attempts as u32;
+        delivery.attempts += 1;
+        let retryable = match client.post(&webhook.url, &signature, body.clone()).await {
+            Ok(status) => {
+                delivery.response_status = Some(status as i32);
+                delivery.last_error = (!(200..300).contains(&status))
+                    .then(|| format!("unexpected response status {}", status));
+                if status == 410 {
+                    repository
+                        .update(webhook.user_id, webhook.id, UpdateWebhook::disable())
+                        .await?;
+                    tracing::info!(webhook_id = webhook.id, "webhook is gone, disabled");
+                }
+                status >= 500
+            }
+            Err(e) => {
+                delivery.response_status = None;
+                delivery.last_error = Some(e.to_string());
+                true
+            }
+        };
+
+        if retryable && retry < policy.max_retries {
+            repository.save_delivery(&delivery).await?;
+            tokio::time::sleep(policy.backoff(retry)).await;
+            continue;
+        }
+        delivery.status = if delivery.last_error.is_none() {
+            DeliveryStatus::Succeeded
+        } else {
+            DeliveryStatus::Failed
+        };
+        repository.save_delivery(&delivery).await?;
+        return Ok(delivery);
+    }
+}
+
+/// queueから受け取ったイベントを購読している有効なwebhookへ配信する.
+/// shutdownにtrueが送られると、配信中のtaskをDELIVERY_DRAIN_TIMEOUTまで待って終了する
+///
+/// 配信はwebhookごとに別のtaskで行い、再試行待ちのwebhookが他の配信を遅らせないようにする
+pub async fn run_webhook_delivery<W: WebhookRepository, C: WebhookClient>(
+    repository: W,
+    client: C,
+    policy: RetryPolicy,
+    mut queue: mpsc::Receiver<TodoEvent>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut deliveries = JoinSet::new();
+    loop {
+        tokio::select! {
+            Some(event) = queue.recv() => {
+                let webhooks = match repository
+                    .subscribed(event.actor_id, webhook_event(event.kind))
+                    .await
+                {
+                    Ok(webhooks) => webhooks,
+                    Err(e) => {
+                        tracing::error!(event_id = event.id, "failed to find webhooks: {:?}", e);
+                        continue;
+                    }
+                };
+                for webhook in webhooks {
+                    let (repository, client, event) =
+                        (repository.clone(), client.clone(), event.clone());
+                    deliveries.spawn(async move {
+                        if let Err(e) = deliver(&repository, &client, &policy, &webhook, &event).await
+                        {
+                            tracing::error!(webhook_id = webhook.id, "failed to deliver webhook: {:?}", e);
+                        }
+                    });
+                }
+            }
+            // 終わった配信を取り除く
+            Some(_) = deliveries.join_next(), if !deliveries.is_empty() => {}
+            _ = shutdown.changed() => break,
+            else => break,
+        }
+    }
+    let drained = tokio::time::timeout(DELIVERY_DRAIN_TIMEOUT, async {
+        while deliveries.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!(
+            remaining = deliveries.len(),
+            "webhook deliveries did not finish before shutdown, aborted"
+        );
+        deliveries.shutdown().await;
+    }
+    tracing::debug!("webhook delivery task stopped");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        activity::memory::ActivityRepositoryForMemory,
+        todo::{
+            memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID, CreateTodo, TodoRepository,
+        },
+        webhook::{memory::WebhookRepositoryForMemory, CreateWebhook},
+    };
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+    use tokio::time::Instant;
+
+    #[derive(Debug, Clone)]
+    struct SentRequest {
+        url: String,
+        signature: String,
+        body: Vec<u8>,
+        at: Instant,
+    }
+
+    // 送信内容を記録し、あらかじめ決めた結果を順に返すクライアント. 尽きたら200を返す
+    #[derive(Debug, Clone, Default)]
+    struct FakeClient {
+        responses: Arc<Mutex<VecDeque<Option<u16>>>>,
+        sent: Arc<Mutex<Vec<SentRequest>>>,
+    }
+
+    impl FakeClient {
+        // Noneは接続エラー
+        fn responding(responses: Vec<Option<u16>>) -> Self {
+            Self {
+                responses: Arc::new(Mutex::new(responses.into())),
+                ..Self::default()
+            }
+        }
+
+        fn sent(&self) -> Vec<SentRequest> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl WebhookClient for FakeClient {
+        async fn post(&self, url: &str, signature: &str, body: Vec<u8>) -> anyhow::Result<u16> {
+            self.sent.lock().unwrap().push(SentRequest {
+                url: url.to_string(),
+                signature: signature.to_string(),
+                body,
+                at: Instant::now(),
+            });
+            match self.responses.lock().unwrap().pop_front() {
+                Some(Some(status)) => Ok(status),
+                Some(None) => Err(anyhow::anyhow!("connection refused")),
+                None => Ok(200),
+            }
+        }
+    }
+
+    async fn subscription(
+        repository: &WebhookRepositoryForMemory,
+        events: Vec<WebhookEvent>,
+    ) -> Webhook {
+        repository
+            .create(
+                TEST_USER_ID,
+                CreateWebhook::new("https://example.com/hook", events),
+            )
+            .await
+            .expect("failed webhook create")
+    }
+
+    async fn recorded_event() -> TodoEvent {
+        let activity = ActivityRepositoryForMemory::new();
+        TodoRepositoryForMemory::for_test(vec![])
+            .with_activity(&activity, activity.clone())
+            .create(TEST_USER_ID, CreateTodo::new("todo".to_string(), vec![]))
+            .await
+            .unwrap();
+        activity
+            .list(TEST_USER_ID, ActivityQuery::default())
+            .await
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_event() {
+        let repository = WebhookRepositoryForMemory::new();
+        let webhook = subscription(&repository, vec![WebhookEvent::Created]).await;
+        let event = recorded_event().await;
+        let client = FakeClient::default();
+
+        let delivery = deliver(
+            &repository,
+            &client,
+            &DEFAULT_DELIVERY_POLICY,
+            &webhook,
+            &event,
+        )
+        .await
+        .unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Succeeded);
+        assert_eq!(delivery.attempts, 1);
+
+        let sent = &client.sent()[0];
+        assert_eq!(sent.url, webhook.url);
+        assert_eq!(sent.signature, sign(&webhook.secret, &sent.body));
+        let body: serde_json::Value = serde_json::from_slice(&sent.body).unwrap();
+        assert_eq!(body["type"], "todo.created");
+        assert_eq!(body["todo_id"], event.todo_id);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_server_errors_with_exponential_backoff() {
+        let repository = WebhookRepositoryForMemory::new();
+        let webhook = subscription(&repository, vec![WebhookEvent::Created]).await;
+        let event = recorded_event().await;
+        let client = FakeClient::responding(vec![Some(503), None, Some(500)]);
+
+        let delivery = deliver(
+            &repository,
+            &client,
+            &DEFAULT_DELIVERY_POLICY,
+            &webhook,
+            &event,
+        )
+        .await
+        .unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Succeeded);
+        assert_eq!(delivery.attempts, 4);
+
+        let times: Vec<Instant> = client.sent().into_iter().map(|sent| sent.at).collect();
+        let gaps: Vec<Duration> = times.windows(2).map(|w| w[1] - w[0]).collect();
+        assert_eq!(
+            gaps,
+            vec![
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(4)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_retries() {
+        let repository = WebhookRepositoryForMemory::new();
+        let webhook = subscription(&repository, vec![WebhookEvent::Created]).await;
+        let event = recorded_event().await;
+        let client = FakeClient::responding(vec![Some(500); 10]);
+
+        let delivery = deliver(
+            &repository,
+            &client,
+            &DEFAULT_DELIVERY_POLICY,
+            &webhook,
+            &event,
+        )
+        .await
+        .unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts, 6);
+        assert_eq!(delivery.response_status, Some(500));
+        let log = repository
+            .deliveries(TEST_USER_ID, webhook.id)
+            .await
+            .unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(
+            (log[0].id, log[0].status, log[0].attempts),
+            (delivery.id, DeliveryStatus::Failed, 6)
+        );
+    }
+
+    #[tokio::test]
+    async fn gone_response_disables_subscription() {
+        let repository = WebhookRepositoryForMemory::new();
+        let webhook = subscription(&repository, vec![WebhookEvent::Created]).await;
+        let event = recorded_event().await;
+        let client = FakeClient::responding(vec![Some(410)]);
+
+        let delivery = deliver(
+            &repository,
+            &client,
+            &DEFAULT_DELIVERY_POLICY,
+            &webhook,
+            &event,
+        )
+        .await
+        .unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts, 1);
+        let webhook = repository.find(TEST_USER_ID, webhook.id).await.unwrap();
+        assert!(!webhook.enabled);
+        assert!(repository
+            .subscribed(TEST_USER_ID, WebhookEvent::Created)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_retrying_when_webhook_is_disabled() {
+        let repository = WebhookRepositoryForMemory::new();
+        let webhook = subscription(&repository, vec![WebhookEvent::Created]).await;
+        let event = recorded_event().await;
+        let client = FakeClient::responding(vec![Some(503); 10]);
+        let task = tokio::spawn({
+            let (repository, client, webhook) =
+                (repository.clone(), client.clone(), webhook.clone());
+            async move {
+                deliver(
+                    &repository,
+                    &client,
+                    &DEFAULT_DELIVERY_POLICY,
+                    &webhook,
+                    &event,
+                )
+                .await
+            }
+        });
+
+        // 1回目が失敗して再試行を待っている間に無効にする
+        while client.sent().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        repository
+            .update(TEST_USER_ID, webhook.id, UpdateWebhook::disable())
+            .await
+            .unwrap();
+
+        let delivery = task.await.unwrap().unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(
+            delivery.last_error.as_deref(),
+            Some("webhook is disabled or deleted")
+        );
+        assert_eq!(client.sent().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drains_deliveries_on_shutdown() {
+        let repository = WebhookRepositoryForMemory::new();
+        let webhook = subscription(&repository, vec![WebhookEvent::Created]).await;
+        let client = FakeClient::responding(vec![Some(503)]);
+        let (queue_tx, queue_rx) = mpsc::channel(16);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(run_webhook_delivery(
+            repository.clone(),
+            client.clone(),
+            DEFAULT_DELIVERY_POLICY,
+            queue_rx,
+            shutdown_rx,
+        ));
+        queue_tx.send(recorded_event().await).await.unwrap();
+
+        // 1回目が失敗して再試行を待っている間に停止する
+        while client.sent().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        shutdown_tx.send(true).unwrap();
+        task.await.unwrap();
+
+        let deliveries = repository
+            .deliveries(TEST_USER_ID, webhook.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            (deliveries[0].status, deliveries[0].attempts),
+            (DeliveryStatus::Succeeded, 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn notifier_publishes_reminder_events() {
+        let (queue_tx, mut queue_rx) = mpsc::channel(16);
+        let notifier = WebhookNotifier::new(PublishingActivity::new(
+            ActivityRepositoryForMemory::new(),
+            queue_tx,
+        ));
+        let todo = TodoEntity::new(1, "todo".to_string(), vec![]);
+
+        notifier.notify(&todo).await.unwrap();
+        let event = queue_rx.try_recv().unwrap();
+        assert_eq!(
+            (event.todo_id, event.actor_id, event.kind),
+            (todo.id, todo.user_id, TodoEventKind::Reminded)
+        );
+        assert_eq!(webhook_event(event.kind), WebhookEvent::Reminded);
+    }
+
+    #[tokio::test]
+    async fn delivers_published_events_to_matching_webhooks() {
+        let repository = WebhookRepositoryForMemory::new();
+        let created = subscription(&repository, vec![WebhookEvent::Created]).await;
+        subscription(&repository, vec![WebhookEvent::Deleted]).await;
+        let client = FakeClient::default();
+        let (queue_tx, queue_rx) = mpsc::channel(16);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(run_webhook_delivery(
+            repository.clone(),
+            client.clone(),
+            DEFAULT_DELIVERY_POLICY,
+            queue_rx,
+            shutdown_rx,
+        ));
+
+        let events = ActivityRepositoryForMemory::new();
+        let todos = TodoRepositoryForMemory::for_test(vec![])
+            .with_activity(&events, PublishingActivity::new(events.clone(), queue_tx));
+        todos
+            .create(TEST_USER_ID, CreateTodo::new("todo".to_string(), vec![]))
+            .await
+            .unwrap();
+
+        // 配信は別taskで行われるので記録されるまで待つ
+        let deliveries = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                let deliveries = repository
+                    .deliveries(TEST_USER_ID, created.id)
+                    .await
+                    .unwrap();
+                if deliveries
+                    .first()
+                    .is_some_and(|delivery| delivery.status != DeliveryStatus::Pending)
+                {
+                    return deliveries;
+                }
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("webhook was not delivered");
+        assert_eq!(deliveries[0].status, DeliveryStatus::Succeeded);
+        // todo.deletedだけを購読しているwebhookには送られない
+        assert_eq!(client.sent().len(), 1);
+
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("webhook task did not stop")
+            .unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+// メモリ上のrepositoryでcreate_appを組み立て、HTTPのリクエストから応答までを確かめる
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use my_todo::{
+    auth::{AuthConfig, USER_ID_HEADER},
+    create_app,
+    handlers::ListFormat,
+    repositories::{
+        activity::memory::ActivityRepositoryForMemory,
+        label::memory::LabelRepositoryForMemory,
+        todo::{memory::TodoRepositoryForMemory, test_utils::TEST_USER_ID},
+        webhook::memory::WebhookRepositoryForMemory,
+    },
+    DEFAULT_REQUEST_TIMEOUT,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+fn app() -> Router {
+    // POST /labelsで作ったlabelをtodoに付けられるよう、todoと同じlabelのrepositoryを渡す
+    let labels = LabelRepositoryForMemory::new();
+    create_app(
+        TodoRepositoryForMemory::for_test(vec![]).with_labels(&labels),
+        labels,
+        ActivityRepositoryForMemory::new(),
+        WebhookRepositoryForMemory::new(),
+        AuthConfig::default(),
+        DEFAULT_REQUEST_TIMEOUT,
+        ListFormat::Bare,
+    )
+}
+
+// リクエストを送り、ステータスとJSONの本文を返す. 本文が空ならNull
+async fn send(
+    app: &Router,
+    method: Method,
+    path: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let req = Request::builder()
+        .uri(path)
+        .method(method)
+        .header(USER_ID_HEADER, TEST_USER_ID);
+    let req = match body {
+        Some(body) => req
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(body.to_string())),
+        None => req.body(Body::empty()),
+    }
+    .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let status = res.status();
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    if bytes.is_empty() {
+        return (status, Value::Null);
+    }
+    let body = serde_json::from_slice(&bytes)
+        .unwrap_or_else(|e| panic!("{}: {}", e, String::from_utf8_lossy(&bytes)));
+    (status, body)
+}
+
+// 応答のtodoの形. 時刻はmemoryのrepositoryの固定の時計(2024-01-01)になる
+fn todo_json(id: i32, text: &str, completed: bool, labels: Value) -> Value {
+    json!({
+        "id": id,
+        "public_id": format!("00000000-0000-0000-0000-{:012}", id),
+        "user_id": TEST_USER_ID,
+        "text": text,
+        "completed": completed,
+        "labels": labels,
+        "due_date": null,
+        "recurrence": "none",
+        "remind_at": null,
+        "reminded_at": null,
+        "created_at": "2024-01-01T00:00:00Z",
+        "completed_at": if completed { json!("2024-01-01T00:00:00Z") } else { Value::Null },
+        "updated_at": "2024-01-01T00:00:00Z",
+        "parent_id": null,
+    })
+}
+
+fn label_json() -> Value {
+    json!({ "id": 1, "name": "work", "owner_id": TEST_USER_ID })
+}
+
+fn validation_error(field: &str) -> Value {
+    json!({
+        "error": format!(
+            "Validation error: [{}: At least 1 character and less than 100 characters.]",
+            field
+        )
+    })
+}
+
+#[tokio::test]
+async fn todo_lifecycle() {
+    let app = app();
+
+    let (status, body) = send(
+        &app,
+        Method::POST,
+        "/todos",
+        Some(json!({ "text": "buy milk", "labels": [] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body, todo_json(1, "buy milk", false, json!([])));
+
+    let (status, body) = send(&app, Method::GET, "/todos", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([todo_json(1, "buy milk", false, json!([]))]));
+
+    let (status, body) = send(&app, Method::GET, "/todos/1", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, todo_json(1, "buy milk", false, json!([])));
+
+    let (status, body) = send(
+        &app,
+        Method::PATCH,
+        "/todos/1",
+        Some(json!({ "text": "buy oat milk" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body, todo_json(1, "buy oat milk", false, json!([])));
+
+    // 完了にして戻す
+    for completed in [true, false] {
+        let (status, body) = send(
+            &app,
+            Method::PATCH,
+            "/todos/1",
+            Some(json!({ "completed": completed })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, todo_json(1, "buy oat milk", completed, json!([])));
+    }
+
+    let (status, body) = send(&app, Method::DELETE, "/todos/1", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(body, Value::Null);
+
+    let (status, _) = send(&app, Method::GET, "/todos/1", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, body) = send(&app, Method::GET, "/todos", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([]));
+}
+
+#[tokio::test]
+async fn label_crud() {
+    let app = app();
+
+    let (status, body) = send(
+        &app,
+        Method::POST,
+        "/labels",
+        Some(json!({ "name": "work" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body, label_json());
+
+    let (status, body) = send(&app, Method::GET, "/labels", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([label_json()]));
+
+    let (status, body) = send(&app, Method::DELETE, "/labels/1", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(body, Value::Null);
+
+    let (status, body) = send(&app, Method::GET, "/labels", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([]));
+}
+
+#[tokio::test]
+async fn attach_and_detach_labels() {
+    let app = app();
+    send(
+        &app,
+        Method::POST,
+        "/labels",
+        Some(json!({ "name": "work" })),
+    )
+    .await;
+
+    let (status, body) = send(
+        &app,
+        Method::POST,
+        "/todos",
+        Some(json!({ "text": "report", "labels": [1] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body, todo_json(1, "report", false, json!([label_json()])));
+
+    let (status, body) = send(
+        &app,
+        Method::PATCH,
+        "/todos/1",
+        Some(json!({ "labels": [] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body, todo_json(1, "report", false, json!([])));
+
+    let (status, body) = send(
+        &app,
+        Method::PATCH,
+        "/todos/1",
+        Some(json!({ "labels": [1] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body, todo_json(1, "report", false, json!([label_json()])));
+
+    // labelsを省略すると付いているlabelはそのまま
+    let (_, body) = send(
+        &app,
+        Method::PATCH,
+        "/todos/1",
+        Some(json!({ "text": "memo" })),
+    )
+    .await;
+    assert_eq!(body, todo_json(1, "memo", false, json!([label_json()])));
+
+    let (status, body) = send(&app, Method::GET, "/todos?label=1", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        json!([todo_json(1, "memo", false, json!([label_json()]))])
+    );
+
+    // 存在しないlabelは付けられない. 作成・更新ともどのidかを本文で示す
+    let (status, body) = send(
+        &app,
+        Method::POST,
+        "/todos",
+        Some(json!({ "text": "other", "labels": [9] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        body,
+        json!({ "error": "Label is not available to this user, id is 9" })
+    );
+    let (status, body) = send(
+        &app,
+        Method::PATCH,
+        "/todos/1",
+        Some(json!({ "labels": [9] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        body,
+        json!({ "error": "Label is not available to this user, id is 9" })
+    );
+    let (_, body) = send(&app, Method::GET, "/todos/1", None).await;
+    assert_eq!(body, todo_json(1, "memo", false, json!([label_json()])));
+}
+
+#[tokio::test]
+async fn rejects_invalid_text() {
+    let app = app();
+    let too_long = "a".repeat(101);
+
+    for text in ["", too_long.as_str()] {
+        let (status, body) = send(
+            &app,
+            Method::POST,
+            "/todos",
+            Some(json!({ "text": text, "labels": [] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", text);
+        assert_eq!(body, validation_error("text"));
+    }
+    let (_, body) = send(&app, Method::GET, "/todos", None).await;
+    assert_eq!(body, json!([]));
+
+    send(
+        &app,
+        Method::POST,
+        "/todos",
+        Some(json!({ "text": "valid", "labels": [] })),
+    )
+    .await;
+    let (status, body) = send(&app, Method::PATCH, "/todos/1", Some(json!({ "text": "" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body, validation_error("text"));
+
+    let (status, body) = send(&app, Method::POST, "/labels", Some(json!({ "name": "" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body, validation_error("name"));
+
+    let (_, body) = send(&app, Method::GET, "/todos/1", None).await;
+    assert_eq!(body, todo_json(1, "valid", false, json!([])));
+}
+
+#[tokio::test]
+async fn missing_resources_return_404() {
+    let app = app();
+
+    for (method, path, body) in [
+        (Method::GET, "/todos/99", None),
+        (
+            Method::PATCH,
+            "/todos/99",
+            Some(json!({ "completed": true })),
+        ),
+        (Method::DELETE, "/todos/99", None),
+        (Method::DELETE, "/labels/99", None),
+    ] {
+        let (status, _) = send(&app, method.clone(), path, body).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{} {}", method, path);
+    }
+}
@@ -0,0 +1,7 @@
+// ValidatedJsonは本文を読み取るため、handlerの最後の引数でなければコンパイルできない
+#[test]
+fn validated_json_must_be_the_last_extractor() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/validated_json_last.rs");
+    t.compile_fail("tests/ui/validated_json_not_last.rs");
+}
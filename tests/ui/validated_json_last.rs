@@ -0,0 +1,19 @@
+use axum::{
+    extract::{Path, State},
+    routing::patch,
+    Router,
+};
+use my_todo::{auth::UserId, handlers::ValidatedJson, repositories::todo::UpdateTodo};
+
+#[axum::debug_handler]
+async fn update(
+    State(_state): State<()>,
+    UserId(_user_id): UserId,
+    Path(_id): Path<i32>,
+    ValidatedJson(_payload): ValidatedJson<UpdateTodo>,
+) {
+}
+
+fn main() {
+    let _: Router = Router::new().route("/todos/:id", patch(update));
+}
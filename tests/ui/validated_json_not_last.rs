@@ -0,0 +1,12 @@
+use axum::extract::{Path, State};
+use my_todo::{handlers::ValidatedJson, repositories::todo::UpdateTodo};
+
+#[axum::debug_handler]
+async fn update(
+    State(_state): State<()>,
+    ValidatedJson(_payload): ValidatedJson<UpdateTodo>,
+    Path(_id): Path<i32>,
+) {
+}
+
+fn main() {}